@@ -1,9 +1,16 @@
 #![allow(non_snake_case)]
-use dioxus::prelude::*;
-use log::{debug, Level};
+use dioxus::{html::input_data::keyboard_types::Key, prelude::*};
+use log::Level;
 use riscv::{
-    executor::{Diff, ExecResult, Executor, RegisterSnapshot, Update, REGISTERS},
-    parse::Program,
+    executor::{
+        effect::Effect,
+        gpio::{Gpio, PINS},
+        paging::{pte, Access, Translation},
+        uart::Uart,
+        Config, ExecResult, ExecUpdate, Executor, RegisterSnapshot, FREGISTERS, REGISTERS,
+        VREGISTERS,
+    },
+    parse::{Base, Csr, ElementWidth, Program},
 };
 
 fn main() {
@@ -15,11 +22,15 @@ fn main() {
 fn App(cx: Scope) -> Element {
     use_shared_state_provider(cx, || Executor::new("".parse::<Program>().unwrap()));
     let executor = use_shared_state::<Executor>(cx).expect("program context was provided");
-    let diff = use_state::<Option<ExecResult<Update>>>(cx, || None);
+    let diff = use_state::<Option<ExecResult<ExecUpdate>>>(cx, || None);
     let forward = move |_| {
         let mut guard = executor.write();
         diff.set(Some(guard.execute()));
     };
+    let back = move |_| {
+        executor.write().revert();
+        diff.set(None);
+    };
 
     let run = move |_| {
         let mut guard = executor.write();
@@ -36,11 +47,15 @@ fn App(cx: Scope) -> Element {
     };
 
     let style = "bg-red-400 p-2 m-2";
+    // Props can only borrow from the scope, so copy the registers into it
+    let regs = cx.bump().alloc(executor.read().regfile.clone());
+    let base = executor.read().config.base;
+    let vector = executor.read().config.v;
 
     cx.render(rsx! {
         button {
             class: style,
-            onclick: |_| todo!(),
+            onclick: back,
             "<< back"
         }
         button {
@@ -61,10 +76,24 @@ fn App(cx: Scope) -> Element {
             div {
                 class: "bg-blue-400",
                 Registers {
-                    regs: &executor.read().regfile,
+                    regs: regs,
+                    base: base,
+                    vector: vector,
                     diff: diff.get().as_ref(),
                 }
             }
+            div {
+                class: "bg-green-400",
+                PageTable {}
+            }
+            div {
+                class: "bg-purple-400",
+                Pins {}
+            }
+            div {
+                class: "bg-orange-400",
+                Console {}
+            }
         }
     })
 }
@@ -72,29 +101,60 @@ fn App(cx: Scope) -> Element {
 #[derive(Props)]
 struct RegisterProps<'a> {
     regs: &'a RegisterSnapshot,
+    base: Base,
+    /// Whether to show the vector registers
+    vector: bool,
     #[props(!optional)]
-    diff: Option<&'a ExecResult<Update>>,
+    diff: Option<&'a ExecResult<ExecUpdate>>,
 }
 
 fn Registers<'a>(cx: Scope<'a, RegisterProps<'a>>) -> Element {
-    let status = match cx.props.diff {
-        Some(Ok(Update {
-            nextpc,
-            diff: Some(Diff::Register { reg, val }),
-        })) => format!("pc -> {nextpc},  {reg} -> {val}"),
-        Some(Ok(Update {
-            nextpc,
-            diff: Some(Diff::Memory { addr, val, op }),
-        })) => format!("pc -> {nextpc},  {addr} -> {val} via {op}"),
-        Some(Ok(Update { nextpc, diff: None })) => format!("pc -> {nextpc}"),
-        Some(Err(e)) => format!("error executing: {e}"),
-        None => "execution complete".to_string(),
+    let update = match cx.props.diff {
+        Some(Ok(update)) => Some(update),
+        _ => None,
+    };
+    let status = match (cx.props.diff, update) {
+        (_, Some(update)) => match update.trap() {
+            Some(cause) => format!("trap: {cause}, pc -> {}", update.processor_update().nextpc),
+            None => format!("pc -> {}", update.processor_update().nextpc),
+        },
+        (Some(Err(e)), _) => format!("error executing: {e}"),
+        _ => "execution complete".to_string(),
     };
 
-    let changed = if let Some(Ok(Update { diff: Some(Diff::Register { reg, .. }), .. })) = cx.props.diff {
-        Some(reg)
-    } else {
-        None
+    let effects = update.map_or(&[][..], |update| update.effects());
+    let changed: Vec<_> = effects
+        .iter()
+        .filter_map(|effect| match effect {
+            Effect::Register { reg, .. } => Some(*reg),
+            _ => None,
+        })
+        .collect();
+    let changed_float: Vec<_> = effects
+        .iter()
+        .filter_map(|effect| match effect {
+            Effect::FRegister { reg, .. } => Some(*reg),
+            _ => None,
+        })
+        .collect();
+    // A write to a register group changes several registers
+    let vlenb = cx.props.regs.vector(VREGISTERS[0]).len();
+    let changed_vector: Vec<_> = effects
+        .iter()
+        .filter_map(|effect| match effect {
+            Effect::VRegister { reg, new, .. } => {
+                Some(*reg as usize..*reg as usize + new.len() / vlenb)
+            }
+            _ => None,
+        })
+        .flatten()
+        .collect();
+    // Show vector elements at the current element width
+    let vtype = cx.props.regs.vtype();
+    let sew = vtype.map_or(ElementWidth::E8, |vtype| vtype.sew);
+    let vconfig = match vtype {
+        Some(vtype) => format!("vl = {}, vtype = {vtype}", cx.props.regs.csr(Csr::Vl)),
+        None => "vtype is invalid, run vsetvli first".to_string(),
     };
 
     cx.render(rsx! {
@@ -102,44 +162,366 @@ fn Registers<'a>(cx: Scope<'a, RegisterProps<'a>>) -> Element {
             div {
                 status
             }
+            for effect in effects {
+                div {
+                    "{effect}"
+                }
+            }
             div {
                 class: "grid grid-cols-4 grid-flow-row gap-4",
-                for reg in REGISTERS {
+                for reg in REGISTERS.into_iter().filter(|reg| reg.available(cx.props.base)) {
                     div {
                         class: format_args!(
                             "{}",
-                            if Some(&reg) == changed { "bg-red-400" } else { "" }
+                            if changed.contains(&reg) { "bg-red-400" } else { "" }
                         ),
                         "{reg}: {cx.props.regs[reg]}"
                     }
                 }
             }
+            div {
+                class: "grid grid-cols-4 grid-flow-row gap-4 mt-4",
+                for reg in FREGISTERS {
+                    div {
+                        class: format_args!(
+                            "{}",
+                            if changed_float.contains(&reg) { "bg-red-400" } else { "" }
+                        ),
+                        "{reg}: {cx.props.regs.float(reg)}"
+                    }
+                }
+            }
+            if cx.props.vector {
+                rsx! {
+                    div {
+                        class: "mt-4",
+                        vconfig
+                    }
+                    div {
+                        class: "grid grid-cols-2 grid-flow-row gap-4",
+                        for reg in VREGISTERS {
+                            div {
+                                class: format_args!(
+                                    "{}",
+                                    if changed_vector.contains(&(reg as usize)) { "bg-red-400" } else { "" }
+                                ),
+                                "{reg}: {cx.props.regs.vector_elements(reg, sew):?}"
+                            }
+                        }
+                    }
+                }
+            }
         }
     })
 }
 
-fn CodeInput(cx: Scope<'_>) -> Element {
+/// The flags of a page table entry, with a dash for each clear bit.
+fn pte_flags(entry: u32) -> String {
+    [
+        (pte::V, 'v'),
+        (pte::R, 'r'),
+        (pte::W, 'w'),
+        (pte::X, 'x'),
+        (pte::U, 'u'),
+        (pte::G, 'g'),
+        (pte::A, 'a'),
+        (pte::D, 'd'),
+    ]
+    .into_iter()
+    .map(|(bit, name)| if entry & bit != 0 { name } else { '-' })
+    .collect()
+}
+
+/// Walks the page tables in `satp` for a virtual address typed in by the user.
+fn PageTable(cx: Scope<'_>) -> Element {
     let exec = use_shared_state::<Executor>(cx).expect("executor context was provided");
-    let error = use_state::<Option<anyhow::Error>>(cx, || None);
-    let lines = use_state::<usize>(cx, || 1);
+    let vaddr = use_state::<Option<i64>>(cx, || None);
+
+    let guard = exec.read();
+    let satp = guard.regfile.csr(Csr::Satp);
+    let privilege = guard.regfile.privilege();
+    let lines = match (guard.page_table(satp), vaddr.get()) {
+        (None, _) => vec!["paging is off".to_string()],
+        (Some(_), None) => vec![],
+        (Some(root), Some(vaddr)) => {
+            // Machine mode doesn't translate, so show what supervisor mode would see
+            let translation = guard.translation().unwrap_or(Translation {
+                root,
+                user: false,
+                sum: false,
+                mxr: false,
+            });
+            let walk = guard.memory.walk(*vaddr, translation, Access::Read);
+            let mut lines: Vec<_> = walk
+                .steps
+                .iter()
+                .map(|step| {
+                    format!(
+                        "level {}: {:#010x} at {:#010x} {}",
+                        step.level,
+                        step.pte,
+                        step.addr,
+                        pte_flags(step.pte)
+                    )
+                })
+                .collect();
+            lines.push(match walk.result {
+                Ok(paddr) => format!("{vaddr:#010x} -> {paddr:#010x}"),
+                Err(e) => e.to_string(),
+            });
+            lines
+        }
+    };
+
     cx.render(rsx! {
         div {
             class: "p-2",
-            textarea {
+            div {
+                "{privilege} mode, satp {satp:#010x}"
+            }
+            input {
+                placeholder: "virtual address",
                 oninput: move |e| {
-                    let text = e.value.clone();
-                    let parsed = text.as_str().parse::<Program>();
-                    match parsed {
-                        Ok(p) => {
-                            *exec.write() = Executor::new(p);
-                            error.set(None);
+                    let text = e.value.trim().trim_start_matches("0x");
+                    vaddr.set(i64::from_str_radix(text, 16).ok());
+                },
+            }
+            ul {
+                for line in lines {
+                    li {
+                        line
+                    }
+                }
+            }
+        }
+    })
+}
+
+/// LEDs for the GPIO pins the program outputs on, and buttons to drive the
+/// rest.
+fn Pins(cx: Scope) -> Element {
+    let exec = use_shared_state::<Executor>(cx).expect("executor context was provided");
+    let guard = exec.read();
+    let Some(gpio) = guard.memory.device::<Gpio>() else {
+        return cx.render(rsx! {
+            div {
+                class: "p-2",
+                "no GPIO"
+            }
+        });
+    };
+    let pins: Vec<_> = (0..PINS)
+        .map(|pin| (pin, gpio.output(pin), gpio.input(pin)))
+        .collect();
+
+    cx.render(rsx! {
+        div {
+            class: "p-2 grid grid-cols-4 gap-2",
+            for (pin, output, input) in pins {
+                match output {
+                    Some(high) => rsx! {
+                        div {
+                            class: format_args!(
+                                "rounded-full px-2 {}",
+                                if high { "bg-yellow-200" } else { "bg-gray-600 text-white" }
+                            ),
+                            "GPIO{pin}"
+                        }
+                    },
+                    None => rsx! {
+                        button {
+                            class: format_args!(
+                                "px-2 border {}",
+                                if input { "bg-blue-200" } else { "bg-white" }
+                            ),
+                            onclick: move |_| {
+                                if let Some(gpio) = exec.write().memory.device_mut::<Gpio>() {
+                                    gpio.set_input(pin, !input);
+                                }
+                            },
+                            "GPIO{pin}"
                         }
-                        Err(e) => {
-                            error.set(Some(e))
+                    },
+                }
+            }
+        }
+    })
+}
+
+/// What the program transmitted on the UART, and a line to queue input for it.
+fn Console(cx: Scope) -> Element {
+    let exec = use_shared_state::<Executor>(cx).expect("executor context was provided");
+    let line = use_state::<String>(cx, String::new);
+    let guard = exec.read();
+    let Some(uart) = guard.memory.device::<Uart>() else {
+        return cx.render(rsx! {
+            div {
+                class: "p-2",
+                "no UART"
+            }
+        });
+    };
+    let output = String::from_utf8_lossy(uart.output()).into_owned();
+    let pending = uart.pending().len();
+
+    cx.render(rsx! {
+        div {
+            class: "p-2",
+            pre {
+                class: "bg-black text-white p-1 min-h-[4rem]",
+                output
+            }
+            input {
+                placeholder: "input",
+                value: "{line}",
+                oninput: move |e| line.set(e.value.clone()),
+                onkeydown: move |e| {
+                    if e.key() == Key::Enter {
+                        if let Some(uart) = exec.write().memory.device_mut::<Uart>() {
+                            uart.push_input(format!("{line}\n").as_bytes());
                         }
+                        line.set(String::new());
                     }
-                    lines.set(text.split('\n').count());
                 },
+            }
+            span {
+                class: "ml-2",
+                "{pending} bytes queued"
+            }
+        }
+    })
+}
+
+fn CodeInput(cx: Scope<'_>) -> Element {
+    let exec = use_shared_state::<Executor>(cx).expect("executor context was provided");
+    let error = use_state::<Option<anyhow::Error>>(cx, || None);
+    let lines = use_state::<usize>(cx, || 1);
+    let source = use_state::<String>(cx, String::new);
+    let config = use_state::<Config>(cx, Config::default);
+
+    // Parse the source for the given configuration and swap in a fresh executor
+    let load = move |text: String, new_config: Config| {
+        match Executor::from_source(&text, new_config.clone()) {
+            Ok(new) => {
+                *exec.write() = new;
+                error.set(None);
+            }
+            Err(e) => error.set(Some(e)),
+        }
+        lines.set(text.split('\n').count());
+        source.set(text);
+        config.set(new_config);
+    };
+
+    cx.render(rsx! {
+        div {
+            class: "p-2",
+            label {
+                input {
+                    r#type: "checkbox",
+                    checked: config.base == Base::E,
+                    oninput: move |_| {
+                        let mut toggled = config.get().clone();
+                        toggled.base = if config.base == Base::E { Base::I } else { Base::E };
+                        load(source.get().clone(), toggled);
+                    },
+                }
+                " RV32E"
+            }
+            label {
+                class: "ml-2",
+                input {
+                    r#type: "checkbox",
+                    checked: config.v,
+                    oninput: move |_| {
+                        let mut toggled = config.get().clone();
+                        toggled.v = !config.v;
+                        load(source.get().clone(), toggled);
+                    },
+                }
+                " V"
+            }
+            label {
+                class: "ml-2",
+                input {
+                    r#type: "checkbox",
+                    checked: config.c,
+                    oninput: move |_| {
+                        let mut toggled = config.get().clone();
+                        toggled.c = !config.c;
+                        load(source.get().clone(), toggled);
+                    },
+                }
+                " C"
+            }
+            label {
+                class: "ml-2",
+                input {
+                    r#type: "checkbox",
+                    checked: config.text_base.is_some(),
+                    oninput: move |_| {
+                        let mut toggled = config.get().clone();
+                        toggled.text_base = match config.text_base {
+                            Some(_) => None,
+                            None => Some(0x00400000),
+                        };
+                        load(source.get().clone(), toggled);
+                    },
+                }
+                " text in memory"
+            }
+            label {
+                class: "ml-2",
+                input {
+                    r#type: "checkbox",
+                    checked: config.gpio,
+                    oninput: move |_| {
+                        let mut toggled = config.get().clone();
+                        toggled.gpio = !config.gpio;
+                        load(source.get().clone(), toggled);
+                    },
+                }
+                " GPIO"
+            }
+            label {
+                class: "ml-2",
+                input {
+                    r#type: "checkbox",
+                    checked: config.uart,
+                    oninput: move |_| {
+                        let mut toggled = config.get().clone();
+                        toggled.uart = !config.uart;
+                        load(source.get().clone(), toggled);
+                    },
+                }
+                " UART"
+            }
+            label {
+                class: "ml-2",
+                "ELF: "
+                input {
+                    r#type: "file",
+                    onchange: move |e| {
+                        let Some(files) = e.files.clone() else { return };
+                        let config = config.get().clone();
+                        to_owned![exec, error];
+                        cx.spawn(async move {
+                            for file in files.files() {
+                                let Some(bytes) = files.read_file(&file).await else { continue };
+                                match Executor::from_elf(&bytes, config.clone()) {
+                                    Ok(new) => {
+                                        *exec.write() = new;
+                                        error.set(None);
+                                    }
+                                    Err(e) => error.set(Some(e.into())),
+                                }
+                            }
+                        });
+                    },
+                }
+            }
+            textarea {
+                oninput: move |e| load(e.value.clone(), config.get().clone()),
                 cols: 20,
                 rows: 20, 
                 spellcheck: false,
@@ -0,0 +1,531 @@
+//! The C extension: which base instructions each compressed instruction
+//! expands to, and shrinking programs by compressing what fits.
+//!
+//! A compressed instruction is stored as the base instruction it expands to,
+//! in a canonical form. `c.li a0, 1` is `addi a0, zero, 1` and `c.mv a0, a1` is
+//! `add a0, zero, a1`, as in the RVC spec, so `mv` and `li` have to be
+//! rewritten before they can be compressed.
+
+use std::{collections::HashMap, ops::Range};
+
+use crate::parse::{
+    BranchOp, BranchZeroOp, CompressedOp, Instruction, LoadImmOp, LoadOp, Program, RegImmOp,
+    RegRegOp, Register, StoreOp, UnaryOp,
+};
+
+/// Where the bits of an immediate go in a compressed instruction, listed for
+/// instruction bits 12 down to 2. `None` bits hold registers or opcode bits.
+pub(crate) type ImmediateLayout = [Option<u32>; 11];
+
+const fn layout(bits: [i8; 11]) -> ImmediateLayout {
+    let mut layout = [None; 11];
+    let mut i = 0;
+    while i < 11 {
+        if bits[i] >= 0 {
+            layout[i] = Some(bits[i] as u32);
+        }
+        i += 1;
+    }
+    layout
+}
+
+/// `c.addi4spn`
+pub(crate) const CIW: ImmediateLayout = layout([5, 4, 9, 8, 7, 6, 2, 3, -1, -1, -1]);
+/// `c.lw` and `c.sw`
+pub(crate) const CL: ImmediateLayout = layout([5, 4, 3, -1, -1, -1, 2, 6, -1, -1, -1]);
+/// 6-bit immediates, like `c.addi` and the shifts
+pub(crate) const CI: ImmediateLayout = layout([5, -1, -1, -1, -1, -1, 4, 3, 2, 1, 0]);
+/// `c.lui`, which holds bits 17 to 12 of the loaded value
+pub(crate) const CI_LUI: ImmediateLayout = layout([17, -1, -1, -1, -1, -1, 16, 15, 14, 13, 12]);
+/// `c.addi16sp`
+pub(crate) const CI_SP: ImmediateLayout = layout([9, -1, -1, -1, -1, -1, 4, 6, 8, 7, 5]);
+/// `c.lwsp`
+pub(crate) const CI_LWSP: ImmediateLayout = layout([5, -1, -1, -1, -1, -1, 4, 3, 2, 7, 6]);
+/// `c.swsp`
+pub(crate) const CSS: ImmediateLayout = layout([5, 4, 3, 2, 7, 6, -1, -1, -1, -1, -1]);
+/// `c.beqz` and `c.bnez`
+pub(crate) const CB: ImmediateLayout = layout([8, 4, 3, -1, -1, -1, 7, 6, 2, 1, 5]);
+/// `c.j` and `c.jal`
+pub(crate) const CJ: ImmediateLayout = layout([11, 4, 9, 8, 10, 6, 7, 3, 2, 1, 5]);
+
+/// Scatter the bits of `imm` into their places in an instruction.
+pub(crate) fn scatter(imm: u32, layout: ImmediateLayout) -> u32 {
+    layout
+        .iter()
+        .enumerate()
+        .filter_map(|(i, bit)| bit.map(|bit| (imm >> bit & 1) << (12 - i)))
+        .fold(0, |word, bit| word | bit)
+}
+
+/// Gather the bits of an immediate from an instruction, the inverse of
+/// [`scatter`]. The result isn't sign-extended.
+pub(crate) fn gather(word: u32, layout: ImmediateLayout) -> u32 {
+    layout
+        .iter()
+        .enumerate()
+        .filter_map(|(i, bit)| bit.map(|bit| (word >> (12 - i) & 1) << bit))
+        .fold(0, |imm, bit| imm | bit)
+}
+
+/// Whether a register is one of the eight `x8`-`x15` that 3-bit register fields
+/// can hold.
+pub(crate) fn compact(reg: Register) -> bool {
+    (8..16).contains(&reg.number())
+}
+
+impl CompressedOp {
+    /// Whether `instr` is in the canonical form this op expands to, with
+    /// operands that fit in its encoding. Labels aren't checked, as that depends
+    /// on where the instruction is.
+    pub fn fits(self, instr: &Instruction) -> bool {
+        use {CompressedOp::*, Register::*};
+        let word_offset = |offset: i32, end| offset % 4 == 0 && (0..end).contains(&offset);
+        let small = |imm: i32| (-32..32).contains(&imm);
+        match (self, instr) {
+            (
+                Addi4spn,
+                Instruction::RegImm {
+                    rd,
+                    r1: sp,
+                    imm,
+                    op: RegImmOp::Addi,
+                },
+            ) => compact(*rd) && *imm != 0 && word_offset(*imm, 1024),
+            (
+                Lw,
+                Instruction::Load {
+                    rd,
+                    offset,
+                    r1,
+                    op: LoadOp::Lw,
+                },
+            ) => compact(*rd) && compact(*r1) && word_offset(*offset, 128),
+            (
+                Sw,
+                Instruction::Store {
+                    r2,
+                    offset,
+                    r1,
+                    op: StoreOp::Sw,
+                },
+            ) => compact(*r2) && compact(*r1) && word_offset(*offset, 128),
+            (
+                Nop,
+                Instruction::RegImm {
+                    rd: x0,
+                    r1: x0,
+                    imm: 0,
+                    op: RegImmOp::Addi,
+                },
+            ) => true,
+            (
+                Addi,
+                Instruction::RegImm {
+                    rd,
+                    r1,
+                    imm,
+                    op: RegImmOp::Addi,
+                },
+            ) => rd == r1 && *rd != x0 && *imm != 0 && small(*imm),
+            (Jal, Instruction::jal { rd: ra, .. }) => true,
+            (
+                Li,
+                Instruction::RegImm {
+                    rd,
+                    r1: x0,
+                    imm,
+                    op: RegImmOp::Addi,
+                },
+            ) => *rd != x0 && small(*imm),
+            (
+                Addi16sp,
+                Instruction::RegImm {
+                    rd: sp,
+                    r1: sp,
+                    imm,
+                    op: RegImmOp::Addi,
+                },
+            ) => *imm != 0 && imm % 16 == 0 && (-512..512).contains(imm),
+            (
+                Lui,
+                Instruction::LoadImm {
+                    rd,
+                    imm,
+                    op: LoadImmOp::Lui,
+                },
+            ) => {
+                // The 6-bit immediate is sign-extended to all 20 bits
+                let value = (*imm as i32) << 12 >> 12;
+                !matches!(rd, x0 | sp) && (0..0x100000).contains(imm) && value != 0 && small(value)
+            }
+            (Srli | Srai | Andi, Instruction::RegImm { rd, r1, imm, op }) => {
+                let range = match self {
+                    Andi => -32..32,
+                    _ => 1..32,
+                };
+                let same = matches!(
+                    (self, op),
+                    (Srli, RegImmOp::Srli) | (Srai, RegImmOp::Srai) | (Andi, RegImmOp::Andi)
+                );
+                same && rd == r1 && compact(*rd) && range.contains(imm)
+            }
+            (Sub | Xor | Or | And, Instruction::RegReg { rd, r1, r2, op }) => {
+                let same = matches!(
+                    (self, op),
+                    (Sub, RegRegOp::Sub)
+                        | (Xor, RegRegOp::Xor)
+                        | (Or, RegRegOp::Or)
+                        | (And, RegRegOp::And)
+                );
+                same && rd == r1 && compact(*rd) && compact(*r2)
+            }
+            (J, Instruction::j { .. }) => true,
+            (
+                Beqz,
+                Instruction::Branch {
+                    r1,
+                    r2: x0,
+                    op: BranchOp::Beq,
+                    ..
+                },
+            )
+            | (
+                Bnez,
+                Instruction::Branch {
+                    r1,
+                    r2: x0,
+                    op: BranchOp::Bne,
+                    ..
+                },
+            ) => compact(*r1),
+            (
+                Slli,
+                Instruction::RegImm {
+                    rd,
+                    r1,
+                    imm,
+                    op: RegImmOp::Slli,
+                },
+            ) => rd == r1 && *rd != x0 && (1..32).contains(imm),
+            (
+                Lwsp,
+                Instruction::Load {
+                    rd,
+                    offset,
+                    r1: sp,
+                    op: LoadOp::Lw,
+                },
+            ) => *rd != x0 && word_offset(*offset, 256),
+            (
+                Swsp,
+                Instruction::Store {
+                    offset,
+                    r1: sp,
+                    op: StoreOp::Sw,
+                    ..
+                },
+            ) => word_offset(*offset, 256),
+            (Jr, Instruction::ret {}) => true,
+            (Jr, Instruction::jr { rs }) => *rs != x0,
+            (
+                Mv,
+                Instruction::RegReg {
+                    rd,
+                    r1: x0,
+                    r2,
+                    op: RegRegOp::Add,
+                },
+            ) => *rd != x0 && *r2 != x0,
+            (
+                Jalr,
+                Instruction::jalr {
+                    rd: ra,
+                    offset: 0,
+                    r1,
+                },
+            ) => *r1 != x0,
+            (
+                Add,
+                Instruction::RegReg {
+                    rd,
+                    r1,
+                    r2,
+                    op: RegRegOp::Add,
+                },
+            ) => rd == r1 && *rd != x0 && *r2 != x0,
+            _ => false,
+        }
+    }
+
+    /// The offsets from the instruction to its label it can reach, if it has
+    /// one.
+    pub fn reach(self) -> Option<Range<i64>> {
+        match self {
+            CompressedOp::J | CompressedOp::Jal => Some(-2048..2048),
+            CompressedOp::Beqz | CompressedOp::Bnez => Some(-256..256),
+            _ => None,
+        }
+    }
+}
+
+impl Instruction {
+    /// The compressed form of the instruction, if it has one. Pseudoinstructions
+    /// like `mv` and `li` are rewritten to the canonical form first. The label
+    /// of a jump or branch still needs to be checked against
+    /// [`CompressedOp::reach`].
+    pub fn compress(&self) -> Option<Instruction> {
+        use Register::*;
+        let canonical = match self.clone() {
+            Instruction::Compressed { .. } => return None,
+            Instruction::LoadImm {
+                rd,
+                imm,
+                op: LoadImmOp::Li,
+            } => Instruction::RegImm {
+                rd,
+                r1: x0,
+                imm: i32::try_from(imm).ok()?,
+                op: RegImmOp::Addi,
+            },
+            Instruction::Unary {
+                rd,
+                r1: x0,
+                op: UnaryOp::Mv,
+            } => Instruction::RegImm {
+                rd,
+                r1: x0,
+                imm: 0,
+                op: RegImmOp::Addi,
+            },
+            Instruction::Unary {
+                rd,
+                r1,
+                op: UnaryOp::Mv,
+            }
+            | Instruction::RegImm {
+                rd,
+                r1,
+                imm: 0,
+                op: RegImmOp::Addi,
+            } if r1 != x0 => Instruction::RegReg {
+                rd,
+                r1: x0,
+                r2: r1,
+                op: RegRegOp::Add,
+            },
+            Instruction::BranchZero {
+                r1,
+                label,
+                op: op @ (BranchZeroOp::Beqz | BranchZeroOp::Bnez),
+            } => Instruction::Branch {
+                r1,
+                r2: x0,
+                label,
+                op: match op {
+                    BranchZeroOp::Beqz => BranchOp::Beq,
+                    _ => BranchOp::Bne,
+                },
+            },
+            Instruction::Branch {
+                r1: x0,
+                r2,
+                label,
+                op: op @ (BranchOp::Beq | BranchOp::Bne),
+            } => Instruction::Branch {
+                r1: r2,
+                r2: x0,
+                label,
+                op,
+            },
+            Instruction::call { label } => Instruction::jal { rd: ra, label },
+            Instruction::jalr {
+                rd: x0,
+                offset: 0,
+                r1: ra,
+            } => Instruction::ret {},
+            Instruction::jalr {
+                rd: x0,
+                offset: 0,
+                r1,
+            } => Instruction::jr { rs: r1 },
+            instr => instr,
+        };
+        let op = CompressedOp::ALL
+            .iter()
+            .copied()
+            .find(|op| op.fits(&canonical))?;
+        Some(Instruction::Compressed {
+            op,
+            instr: Box::new(canonical),
+        })
+    }
+}
+
+impl Program {
+    /// Compress every instruction that has a compressed form and, for jumps and
+    /// branches, can still reach its label. Labels move with the instructions
+    /// they point to. The labels must be addresses from pc 0.
+    pub fn compress(&mut self) {
+        // Compressing moves labels but not which instruction they point to
+        let addresses: Vec<_> = self.addresses().collect();
+        let indices: HashMap<_, _> = self
+            .labels
+            .iter()
+            .map(|(name, &pc)| {
+                let index = addresses.iter().position(|&addr| addr == pc as i64);
+                (name.clone(), index.unwrap_or(self.asm.len()))
+            })
+            .collect();
+
+        // Shrinking only brings labels closer, so anything that reaches its
+        // label in one pass still does after it. Shrinking can bring others in
+        // range though, so keep going until nothing changes.
+        loop {
+            let addresses: Vec<_> = self.addresses().collect();
+            let mut changed = false;
+            for (instr, pc) in self.asm.iter_mut().zip(addresses) {
+                let Some(compressed) = instr.compress() else {
+                    continue;
+                };
+                let Instruction::Compressed { op, .. } = &compressed else {
+                    unreachable!("compress returns compressed instructions");
+                };
+                if let (Some(reach), Some(label)) = (op.reach(), instr.label()) {
+                    let target = self.labels.get(label).map(|&target| target as i64);
+                    if !target.is_some_and(|target| reach.contains(&(target - pc))) {
+                        continue;
+                    }
+                }
+                *instr = compressed;
+                changed = true;
+            }
+            if !changed {
+                break;
+            }
+            let addresses: Vec<_> = self.addresses().collect();
+            let end = self.asm.iter().map(Instruction::size).sum();
+            for (name, index) in &indices {
+                let pc = addresses.get(*index).copied().unwrap_or(end);
+                self.labels.insert(name.clone(), pc as usize);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use indoc::indoc;
+
+    use super::*;
+
+    #[test]
+    fn layouts() {
+        for layout in [CIW, CL, CI, CI_LUI, CI_SP, CI_LWSP, CSS, CB, CJ] {
+            let mask = gather(0x1ffc, layout);
+            assert_eq!(scatter(mask, layout), scatter(u32::MAX, layout));
+            assert_eq!(gather(scatter(mask, layout), layout), mask);
+        }
+    }
+
+    #[test]
+    fn parse() {
+        let program = indoc! {"
+            c.addi4spn a0, sp, 16
+            c.lw a1, 4(a0)
+            c.sw a1, 124(s0)
+            c.nop
+            c.addi a0, -1
+            c.li t0, 31
+            c.addi16sp sp, -64
+            c.lui a0, -1
+            c.srli a0, 3
+            c.sub a0, a1
+            c.mv a0, t6
+            c.add a0, t6
+            loop:
+            c.beqz a0, loop
+            c.j loop
+            c.jal loop
+            c.lwsp ra, 252(sp)
+            c.swsp ra, 0(sp)
+            c.jr ra
+            c.jalr t0
+        "}
+        .parse::<Program>()
+        .unwrap();
+        assert_eq!(program.labels["loop"], 2 * 12);
+        assert_eq!(
+            program.asm[5].expanded(),
+            &"addi t0, x0, 31".parse::<Program>().unwrap().asm[0]
+        );
+        assert_eq!(program.asm[17].expanded(), &Instruction::ret {});
+        let text: Vec<_> = program.asm.iter().map(ToString::to_string).collect();
+        assert_eq!(text[7], "c.lui a0, 1048575");
+        assert_eq!(text[17], "c.jr ra");
+        for (line, instr) in text.iter().zip(&program.asm) {
+            let reparsed = format!("loop:\n{line}").parse::<Program>().unwrap();
+            assert_eq!(&reparsed.asm[0], instr);
+        }
+
+        for source in [
+            "c.addi4spn a0, sp, 0",
+            "c.addi4spn ra, sp, 16",
+            "c.lw a0, 2(a1)",
+            "c.addi a0, 32",
+            "c.addi zero, 1",
+            "c.li a0, -33",
+            "c.addi16sp sp, 8",
+            "c.lui sp, 1",
+            "c.lui a0, 0",
+            "c.srli a0, 0",
+            "c.slli zero, 1",
+            "c.sub ra, a1",
+            "c.lwsp a0, 256(sp)",
+            "c.mv a0, zero",
+            "c.jr zero",
+        ] {
+            assert!(source.parse::<Program>().is_err(), "{source}");
+        }
+    }
+
+    #[test]
+    fn compress() {
+        let source = indoc! {"
+            li a0, 10
+            li a1, 0
+            mv s0, sp
+            addi sp, sp, -16
+            loop:
+            add a1, a1, a0
+            addi a0, a0, -1
+            sw a1, 0(sp)
+            bnez a0, loop
+            lw a2, 0(sp)
+            addi sp, sp, 16
+            call double
+            j end
+            double:
+            slli a2, a2, 1
+            lui a3, 0x12345
+            ret
+            end:
+        "};
+        let mut program = source.parse::<Program>().unwrap();
+        program.compress();
+        let sizes: Vec<_> = program.asm.iter().map(Instruction::size).collect();
+        assert_eq!(sizes, [2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 4, 2]);
+        assert_eq!(program.labels["loop"], 8);
+        assert_eq!(program.labels["double"], 24);
+        assert_eq!(program.labels["end"], 32);
+        assert_eq!(program.asm[7].to_string(), "c.bnez a0, loop");
+        assert_eq!(program.asm[10].to_string(), "c.jal double");
+
+        // Branches that would be too far stay as they are
+        let far = format!("beqz a0, end\n{}end:\nret", "lui a0, 0x100\n".repeat(64));
+        let mut program = far.parse::<Program>().unwrap();
+        program.compress();
+        assert_eq!(program.asm[0].size(), 4);
+        assert_eq!(program.labels["end"], 4 * 65);
+        assert_eq!(program.asm[65].size(), 2);
+    }
+}
@@ -0,0 +1,1645 @@
+//! Decoding machine code back into [`Instruction`]s, the inverse of
+//! [`encode`](crate::encode).
+//!
+//! Branch and jump targets become synthetic labels named after their address,
+//! like `label_1c`, so decoded instructions display and parse like any other.
+//! Jumps that don't link come back as `j`, `jr` and `ret`, which the executor
+//! uses to track calls. Everything else decodes to the base instruction, so
+//! `mv a0, a1` comes back as `addi a0, a1, 0`.
+//!
+//! Instructions whose low two bits aren't `11` are 16-bit compressed ones, and
+//! decode to [`Instruction::Compressed`].
+
+use std::collections::HashMap;
+
+use thiserror::Error;
+
+use crate::{
+    compress,
+    executor::custom::CustomInstructions,
+    parse::{
+        AmoOp, BranchOp, CompressedOp, Csr, CsrImmOp, CsrRegOp, CustomEncoding, CustomFormat,
+        CustomOpcode, CustomOperands, ElementWidth, FCompareOp, FFusedOp, FRegRegOp, FRegister,
+        FToIntOp, Instruction, IntToFOp, LoadImmOp, LoadOp, Program, RegImmOp, RegRegOp, Register,
+        RoundingMode, StoreOp, UnaryOp, VArithOp, VCompareOp, VMaskOp, VOperand, VReduceOp,
+        VRegister, VType,
+    },
+};
+
+/// An error decoding an instruction.
+#[derive(Debug, Error, PartialEq, Eq)]
+#[error("{error} (pc {pc:#010x})")]
+pub struct DecodeError {
+    /// The pc of the instruction that couldn't be decoded
+    pc: i64,
+
+    error: DecodeErrorInner,
+}
+
+impl DecodeError {
+    /// The pc of the instruction that couldn't be decoded.
+    pub fn pc(&self) -> i64 {
+        self.pc
+    }
+
+    pub fn error(&self) -> &DecodeErrorInner {
+        &self.error
+    }
+}
+
+#[derive(Debug, Error, PartialEq, Eq, Clone)]
+pub enum DecodeErrorInner {
+    #[error("{0:#010x} is not a valid instruction")]
+    Unknown(u32),
+
+    /// A valid instruction that the crate has no [`Instruction`] for.
+    #[error("{mnemonic} ({word:#010x}) is not supported")]
+    Unsupported { word: u32, mnemonic: &'static str },
+
+    /// A branch or jump out of the program being disassembled.
+    #[error("jump to {0:#010x}, outside of the program")]
+    Target(i64),
+
+    /// Bytes left over after the last whole instruction.
+    #[error("{0} trailing bytes")]
+    Trailing(usize),
+}
+
+/// The synthetic label for an address.
+pub fn label_name(addr: i64) -> String {
+    format!("label_{addr:x}")
+}
+
+/// The address a synthetic label from [`label_name`] stands for.
+pub fn label_address(name: &str) -> Option<i64> {
+    let addr = name.strip_prefix("label_")?;
+    u64::from_str_radix(addr, 16).ok().map(|addr| addr as i64)
+}
+
+// Every register field is 5 bits, so the register always exists
+fn x(number: u32) -> Register {
+    format!("x{number}").parse().unwrap()
+}
+
+fn f(number: u32) -> FRegister {
+    format!("f{number}").parse().unwrap()
+}
+
+fn v(number: u32) -> VRegister {
+    format!("v{number}").parse().unwrap()
+}
+
+fn rounding_mode(rm: u32) -> Option<RoundingMode> {
+    match rm {
+        0b111 => Some(RoundingMode::Dyn),
+        other => RoundingMode::from_frm(other),
+    }
+}
+
+fn vector_width(width: u32) -> Option<ElementWidth> {
+    match width {
+        0b000 => Some(ElementWidth::E8),
+        0b101 => Some(ElementWidth::E16),
+        0b110 => Some(ElementWidth::E32),
+        0b111 => Some(ElementWidth::E64),
+        _ => None,
+    }
+}
+
+/// The fields of a 32-bit instruction. Which ones mean something depends on
+/// its format.
+struct Fields {
+    word: u32,
+}
+
+impl Fields {
+    fn opcode(&self) -> u32 {
+        self.word & 0x7f
+    }
+
+    fn rd(&self) -> u32 {
+        self.word >> 7 & 0x1f
+    }
+
+    fn funct3(&self) -> u32 {
+        self.word >> 12 & 0b111
+    }
+
+    fn rs1(&self) -> u32 {
+        self.word >> 15 & 0x1f
+    }
+
+    fn rs2(&self) -> u32 {
+        self.word >> 20 & 0x1f
+    }
+
+    fn funct7(&self) -> u32 {
+        self.word >> 25
+    }
+
+    /// Sign extended, like every immediate
+    fn imm_i(&self) -> i32 {
+        self.word as i32 >> 20
+    }
+
+    fn imm_s(&self) -> i32 {
+        (self.word as i32 >> 25) << 5 | self.rd() as i32
+    }
+
+    fn imm_b(&self) -> i32 {
+        let word = self.word;
+        (word as i32 >> 31) << 12
+            | ((word >> 7 & 1) << 11 | (word >> 25 & 0x3f) << 5 | (word >> 8 & 0xf) << 1) as i32
+    }
+
+    fn imm_j(&self) -> i32 {
+        let word = self.word;
+        (word as i32 >> 31) << 20
+            | ((word >> 12 & 0xff) << 12 | (word >> 20 & 1) << 11 | (word >> 21 & 0x3ff) << 1)
+                as i32
+    }
+}
+
+/// Decodes machine code into instructions, including custom ones.
+#[derive(Debug, Clone, Default)]
+pub struct Decoder {
+    custom: CustomInstructions,
+}
+
+type DecodeResult<T> = Result<T, DecodeErrorInner>;
+
+impl Decoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Also decode the instructions in `custom`.
+    pub fn with_custom(self, custom: CustomInstructions) -> Self {
+        Self { custom }
+    }
+
+    /// Decode a single instruction at `pc`.
+    pub fn decode(&self, word: u32, pc: i64) -> Result<Instruction, DecodeError> {
+        self.decode_inner(word, pc)
+            .map_err(|error| DecodeError { pc, error })
+    }
+
+    /// Decode a little-endian image, starting at pc 0, into a program with a
+    /// label at every branch and jump target.
+    pub fn disassemble(&self, bytes: &[u8]) -> Result<Program, DecodeError> {
+        let end = bytes.len() as i64;
+        let mut labels = HashMap::new();
+        let mut asm = vec![];
+        let mut pc = 0;
+        while pc < end {
+            let rest = &bytes[pc as usize..];
+            // The low two bits of a 4-byte instruction are 11
+            let size = if rest[0] & 0b11 == 0b11 { 4 } else { 2 };
+            if rest.len() < size {
+                return Err(DecodeError {
+                    pc,
+                    error: DecodeErrorInner::Trailing(rest.len()),
+                });
+            }
+            let mut word = [0; 4];
+            word[..size].copy_from_slice(&rest[..size]);
+            let instr = self.decode(u32::from_le_bytes(word), pc)?;
+            if let Some(target) = instr.label().and_then(label_address) {
+                // A label can be just past the last instruction
+                if !(0..=end).contains(&target) {
+                    return Err(DecodeError {
+                        pc,
+                        error: DecodeErrorInner::Target(target),
+                    });
+                }
+                labels.insert(label_name(target), target as usize);
+            }
+            asm.push(instr);
+            pc += size as i64;
+        }
+        Ok(Program { labels, asm })
+    }
+
+    fn decode_inner(&self, word: u32, pc: i64) -> DecodeResult<Instruction> {
+        if word & 0b11 != 0b11 {
+            return self.compressed(word & 0xffff, pc);
+        }
+        let fields = Fields { word };
+        let unknown = || DecodeErrorInner::Unknown(word);
+        let unsupported = |mnemonic| DecodeErrorInner::Unsupported { word, mnemonic };
+        let (rd, rs1, rs2) = (fields.rd(), fields.rs1(), fields.rs2());
+        let (funct3, funct7) = (fields.funct3(), fields.funct7());
+
+        let instr = match fields.opcode() {
+            // LOAD
+            0b0000011 => {
+                let op = match funct3 {
+                    0b000 => LoadOp::Lb,
+                    0b001 => LoadOp::Lh,
+                    0b010 => LoadOp::Lw,
+                    0b100 => LoadOp::Lbu,
+                    0b101 => LoadOp::Lhu,
+                    0b011 => Err(unsupported("ld"))?,
+                    0b110 => Err(unsupported("lwu"))?,
+                    _ => Err(unknown())?,
+                };
+                Instruction::Load {
+                    rd: x(rd),
+                    offset: fields.imm_i(),
+                    r1: x(rs1),
+                    op,
+                }
+            }
+            // LOAD-FP
+            0b0000111 => match funct3 {
+                0b010 => Instruction::FLoad {
+                    rd: f(rd),
+                    offset: fields.imm_i(),
+                    r1: x(rs1),
+                },
+                width => {
+                    let (width, stride, masked) = self.vector_memory(&fields, width)?;
+                    Instruction::VLoad {
+                        vd: v(rd),
+                        r1: x(rs1),
+                        stride,
+                        width,
+                        masked,
+                    }
+                }
+            },
+            0b0001111 => Err(unsupported("fence"))?,
+            // OP-IMM
+            0b0010011 => self.op_imm(&fields)?,
+            // AUIPC
+            0b0010111 => Instruction::LoadImm {
+                rd: x(rd),
+                imm: (word >> 12) as i64,
+                op: LoadImmOp::Auipc,
+            },
+            0b0011011 | 0b0111011 => Err(unsupported("RV64 word operations"))?,
+            // STORE
+            0b0100011 => {
+                let op = match funct3 {
+                    0b000 => StoreOp::Sb,
+                    0b001 => StoreOp::Sh,
+                    0b010 => StoreOp::Sw,
+                    0b011 => Err(unsupported("sd"))?,
+                    _ => Err(unknown())?,
+                };
+                Instruction::Store {
+                    r2: x(rs2),
+                    offset: fields.imm_s(),
+                    r1: x(rs1),
+                    op,
+                }
+            }
+            // STORE-FP
+            0b0100111 => match funct3 {
+                0b010 => Instruction::FStore {
+                    r2: f(rs2),
+                    offset: fields.imm_s(),
+                    r1: x(rs1),
+                },
+                width => {
+                    let (width, stride, masked) = self.vector_memory(&fields, width)?;
+                    Instruction::VStore {
+                        vs3: v(rd),
+                        r1: x(rs1),
+                        stride,
+                        width,
+                        masked,
+                    }
+                }
+            },
+            // AMO. The aq and rl bits only order memory accesses, so they're
+            // ignored
+            0b0101111 if funct3 == 0b010 => {
+                let (rd, r2, r1) = (x(rd), x(rs2), x(rs1));
+                let op = match funct7 >> 2 {
+                    0b00010 if rs2 == 0 => return Ok(Instruction::LoadReserved { rd, r1 }),
+                    0b00011 => return Ok(Instruction::StoreConditional { rd, r2, r1 }),
+                    0b00000 => AmoOp::Add,
+                    0b00001 => AmoOp::Swap,
+                    0b00100 => AmoOp::Xor,
+                    0b01000 => AmoOp::Or,
+                    0b01100 => AmoOp::And,
+                    0b10000 => AmoOp::Min,
+                    0b10100 => AmoOp::Max,
+                    0b11000 => AmoOp::Minu,
+                    0b11100 => AmoOp::Maxu,
+                    _ => Err(unknown())?,
+                };
+                Instruction::Amo { rd, r2, r1, op }
+            }
+            // OP
+            0b0110011 => self.op(&fields)?,
+            // LUI
+            0b0110111 => Instruction::LoadImm {
+                rd: x(rd),
+                imm: (word >> 12) as i64,
+                op: LoadImmOp::Lui,
+            },
+            // MADD, MSUB, NMSUB and NMADD, in single precision
+            opcode @ (0b1000011 | 0b1000111 | 0b1001011 | 0b1001111) if funct7 & 0b11 == 0 => {
+                let op = match opcode {
+                    0b1000011 => FFusedOp::Madd,
+                    0b1000111 => FFusedOp::Msub,
+                    0b1001011 => FFusedOp::Nmsub,
+                    _ => FFusedOp::Nmadd,
+                };
+                Instruction::FFused {
+                    rd: f(rd),
+                    r1: f(rs1),
+                    r2: f(rs2),
+                    r3: f(funct7 >> 2),
+                    op,
+                    rm: rounding_mode(funct3).ok_or_else(unknown)?,
+                }
+            }
+            // OP-FP
+            0b1010011 => self.op_fp(&fields)?,
+            // OP-V
+            0b1010111 => self.op_v(&fields)?,
+            // BRANCH
+            0b1100011 => {
+                let op = match funct3 {
+                    0b000 => BranchOp::Beq,
+                    0b001 => BranchOp::Bne,
+                    0b100 => BranchOp::Blt,
+                    0b101 => BranchOp::Bge,
+                    0b110 => BranchOp::Bltu,
+                    0b111 => BranchOp::Bgeu,
+                    _ => Err(unknown())?,
+                };
+                Instruction::Branch {
+                    r1: x(rs1),
+                    r2: x(rs2),
+                    label: label_name(pc + fields.imm_b() as i64),
+                    op,
+                }
+            }
+            // JALR
+            0b1100111 if funct3 == 0 => match (x(rd), fields.imm_i(), x(rs1)) {
+                (Register::x0, 0, Register::ra) => Instruction::ret {},
+                (Register::x0, 0, rs) => Instruction::jr { rs },
+                (rd, offset, r1) => Instruction::jalr { rd, offset, r1 },
+            },
+            // JAL
+            0b1101111 => {
+                let label = label_name(pc + fields.imm_j() as i64);
+                match x(rd) {
+                    Register::x0 => Instruction::j { label },
+                    rd => Instruction::jal { rd, label },
+                }
+            }
+            // SYSTEM
+            0b1110011 => match funct3 {
+                0b000 => match word {
+                    0x00000073 => Instruction::ecall {},
+                    0x00100073 => Err(unsupported("ebreak"))?,
+                    0x10200073 => Instruction::sret {},
+                    0x30200073 => Instruction::mret {},
+                    0x10500073 => Instruction::wfi {},
+                    _ if funct7 == 0b0001001 && rd == 0 => Instruction::sfence_vma {
+                        vaddr: x(rs1),
+                        asid: x(rs2),
+                    },
+                    _ => Err(unknown())?,
+                },
+                funct3 => {
+                    let csr = Csr::from_number((word >> 20) as u16).ok_or_else(unknown)?;
+                    let rd = x(rd);
+                    match funct3 {
+                        0b001 => Instruction::CsrReg {
+                            rd,
+                            csr,
+                            r1: x(rs1),
+                            op: CsrRegOp::Rw,
+                        },
+                        0b010 => Instruction::CsrReg {
+                            rd,
+                            csr,
+                            r1: x(rs1),
+                            op: CsrRegOp::Rs,
+                        },
+                        0b011 => Instruction::CsrReg {
+                            rd,
+                            csr,
+                            r1: x(rs1),
+                            op: CsrRegOp::Rc,
+                        },
+                        0b101 => Instruction::CsrImm {
+                            rd,
+                            csr,
+                            imm: rs1 as i32,
+                            op: CsrImmOp::Rwi,
+                        },
+                        0b110 => Instruction::CsrImm {
+                            rd,
+                            csr,
+                            imm: rs1 as i32,
+                            op: CsrImmOp::Rsi,
+                        },
+                        0b111 => Instruction::CsrImm {
+                            rd,
+                            csr,
+                            imm: rs1 as i32,
+                            op: CsrImmOp::Rci,
+                        },
+                        _ => Err(unknown())?,
+                    }
+                }
+            },
+            opcode @ (0b0001011 | 0b0101011 | 0b1011011 | 0b1111011) => {
+                let opcode = match opcode {
+                    0b0001011 => CustomOpcode::Custom0,
+                    0b0101011 => CustomOpcode::Custom1,
+                    0b1011011 => CustomOpcode::Custom2,
+                    _ => CustomOpcode::Custom3,
+                };
+                let (mnemonic, custom) = self
+                    .custom
+                    .find(opcode, funct3 as u8, funct7 as u8)
+                    .ok_or_else(unknown)?;
+                let operands = match custom.format {
+                    CustomFormat::R => CustomOperands::R {
+                        rd: x(rd),
+                        r1: x(rs1),
+                        r2: x(rs2),
+                    },
+                    CustomFormat::I => CustomOperands::I {
+                        rd: x(rd),
+                        r1: x(rs1),
+                        imm: fields.imm_i(),
+                    },
+                    CustomFormat::S => CustomOperands::S {
+                        r2: x(rs2),
+                        offset: fields.imm_s(),
+                        r1: x(rs1),
+                    },
+                };
+                Instruction::Custom {
+                    mnemonic: mnemonic.to_string(),
+                    encoding: CustomEncoding { ..custom.encoding },
+                    operands,
+                }
+            }
+            _ => Err(unknown())?,
+        };
+        Ok(instr)
+    }
+
+    fn op_imm(&self, fields: &Fields) -> DecodeResult<Instruction> {
+        use RegImmOp::*;
+        let unknown = || DecodeErrorInner::Unknown(fields.word);
+        let (rd, r1) = (x(fields.rd()), x(fields.rs1()));
+        let shamt = fields.rs2();
+        let unary = |op| Ok(Instruction::Unary { rd, r1, op });
+        let op = match (fields.funct3(), fields.funct7()) {
+            (0b000, _) => Addi,
+            (0b010, _) => Slti,
+            (0b011, _) => Sltiu,
+            (0b100, _) => Xori,
+            (0b110, _) => Ori,
+            (0b111, _) => Andi,
+            (0b001, 0b0000000) => Slli,
+            (0b001, 0b0010100) => Bseti,
+            (0b001, 0b0100100) => Bclri,
+            (0b001, 0b0110100) => Binvi,
+            (0b001, 0b0110000) => match shamt {
+                0b00000 => return unary(UnaryOp::Clz),
+                0b00001 => return unary(UnaryOp::Ctz),
+                0b00010 => return unary(UnaryOp::Cpop),
+                0b00100 => return unary(UnaryOp::SextB),
+                0b00101 => return unary(UnaryOp::SextH),
+                _ => Err(unknown())?,
+            },
+            (0b101, 0b0000000) => Srli,
+            (0b101, 0b0100000) => Srai,
+            (0b101, 0b0110000) => Rori,
+            (0b101, 0b0100100) => Bexti,
+            (0b101, 0b0110100) if shamt == 0b11000 => return unary(UnaryOp::Rev8),
+            (0b101, 0b0010100) if shamt == 0b00111 => return unary(UnaryOp::OrcB),
+            _ => Err(unknown())?,
+        };
+        let imm = match op {
+            Addi | Slti | Sltiu | Xori | Ori | Andi => fields.imm_i(),
+            _ => shamt as i32,
+        };
+        Ok(Instruction::RegImm { rd, r1, imm, op })
+    }
+
+    fn op(&self, fields: &Fields) -> DecodeResult<Instruction> {
+        use RegRegOp::*;
+        let (rd, r1, r2) = (x(fields.rd()), x(fields.rs1()), x(fields.rs2()));
+        let op = match (fields.funct7(), fields.funct3()) {
+            (0b0000000, 0b000) => Add,
+            (0b0100000, 0b000) => Sub,
+            (0b0000000, 0b001) => Sll,
+            (0b0000000, 0b010) => Slt,
+            (0b0000000, 0b011) => Sltu,
+            (0b0000000, 0b100) => Xor,
+            (0b0000000, 0b101) => Srl,
+            (0b0100000, 0b101) => Sra,
+            (0b0000000, 0b110) => Or,
+            (0b0000000, 0b111) => And,
+            (0b0010000, 0b010) => Sh1add,
+            (0b0010000, 0b100) => Sh2add,
+            (0b0010000, 0b110) => Sh3add,
+            (0b0000101, 0b100) => Min,
+            (0b0000101, 0b101) => Minu,
+            (0b0000101, 0b110) => Max,
+            (0b0000101, 0b111) => Maxu,
+            (0b0110000, 0b001) => Rol,
+            (0b0110000, 0b101) => Ror,
+            (0b0100000, 0b111) => Andn,
+            (0b0100000, 0b110) => Orn,
+            (0b0100000, 0b100) => Xnor,
+            (0b0010100, 0b001) => Bset,
+            (0b0100100, 0b001) => Bclr,
+            (0b0110100, 0b001) => Binv,
+            (0b0100100, 0b101) => Bext,
+            (0b0000100, 0b100) if fields.rs2() == 0 => {
+                return Ok(Instruction::Unary {
+                    rd,
+                    r1,
+                    op: UnaryOp::ZextH,
+                })
+            }
+            (0b0000001, _) => Err(DecodeErrorInner::Unsupported {
+                word: fields.word,
+                mnemonic: "the M extension",
+            })?,
+            _ => Err(DecodeErrorInner::Unknown(fields.word))?,
+        };
+        Ok(Instruction::RegReg { rd, r1, r2, op })
+    }
+
+    fn op_fp(&self, fields: &Fields) -> DecodeResult<Instruction> {
+        let unknown = || DecodeErrorInner::Unknown(fields.word);
+        let funct3 = fields.funct3();
+        let rm = || rounding_mode(funct3).ok_or_else(unknown);
+        let (rd, rs1, rs2) = (fields.rd(), fields.rs1(), fields.rs2());
+        let reg_reg = |op, rm| Instruction::FRegReg {
+            rd: f(rd),
+            r1: f(rs1),
+            r2: f(rs2),
+            op,
+            rm,
+        };
+        let instr = match (fields.funct7(), funct3, rs2) {
+            (0b0000000, _, _) => reg_reg(FRegRegOp::Add, rm()?),
+            (0b0000100, _, _) => reg_reg(FRegRegOp::Sub, rm()?),
+            (0b0001000, _, _) => reg_reg(FRegRegOp::Mul, rm()?),
+            (0b0001100, _, _) => reg_reg(FRegRegOp::Div, rm()?),
+            (0b0010000, 0b000, _) => reg_reg(FRegRegOp::Sgnj, RoundingMode::Dyn),
+            (0b0010000, 0b001, _) => reg_reg(FRegRegOp::Sgnjn, RoundingMode::Dyn),
+            (0b0010000, 0b010, _) => reg_reg(FRegRegOp::Sgnjx, RoundingMode::Dyn),
+            (0b0010100, 0b000, _) => reg_reg(FRegRegOp::Min, RoundingMode::Dyn),
+            (0b0010100, 0b001, _) => reg_reg(FRegRegOp::Max, RoundingMode::Dyn),
+            (0b0101100, _, 0) => Instruction::FSqrt {
+                rd: f(rd),
+                r1: f(rs1),
+                rm: rm()?,
+            },
+            (0b1010000, funct3 @ 0b000..=0b010, _) => Instruction::FCompare {
+                rd: x(rd),
+                r1: f(rs1),
+                r2: f(rs2),
+                op: [FCompareOp::Le, FCompareOp::Lt, FCompareOp::Eq][funct3 as usize],
+            },
+            (0b1110000, 0b001, 0) => Instruction::FClass {
+                rd: x(rd),
+                r1: f(rs1),
+            },
+            (0b1110000, 0b000, 0) => Instruction::FToInt {
+                rd: x(rd),
+                r1: f(rs1),
+                op: FToIntOp::MvXW,
+                rm: RoundingMode::Dyn,
+            },
+            (0b1100000, _, rs2 @ (0 | 1)) => Instruction::FToInt {
+                rd: x(rd),
+                r1: f(rs1),
+                op: [FToIntOp::CvtW, FToIntOp::CvtWu][rs2 as usize],
+                rm: rm()?,
+            },
+            (0b1111000, 0b000, 0) => Instruction::IntToF {
+                rd: f(rd),
+                r1: x(rs1),
+                op: IntToFOp::MvWX,
+                rm: RoundingMode::Dyn,
+            },
+            (0b1101000, _, rs2 @ (0 | 1)) => Instruction::IntToF {
+                rd: f(rd),
+                r1: x(rs1),
+                op: [IntToFOp::CvtSW, IntToFOp::CvtSWu][rs2 as usize],
+                rm: rm()?,
+            },
+            _ => Err(unknown())?,
+        };
+        Ok(instr)
+    }
+
+    /// The width, stride register and masking of a vector load or store. Only
+    /// unit-stride and strided accesses of single registers are supported.
+    fn vector_memory(
+        &self,
+        fields: &Fields,
+        width: u32,
+    ) -> DecodeResult<(ElementWidth, Option<Register>, bool)> {
+        let unknown = || DecodeErrorInner::Unknown(fields.word);
+        let width = vector_width(width).ok_or_else(unknown)?;
+        let (nf_mew, mop) = (fields.funct7() >> 3, fields.funct7() >> 1 & 0b11);
+        let stride = match (nf_mew, mop) {
+            (0, 0b00) if fields.rs2() == 0 => None,
+            (0, 0b10) => Some(x(fields.rs2())),
+            _ => Err(unknown())?,
+        };
+        Ok((width, stride, fields.funct7() & 1 == 0))
+    }
+
+    fn op_v(&self, fields: &Fields) -> DecodeResult<Instruction> {
+        let word = fields.word;
+        let unknown = || DecodeErrorInner::Unknown(word);
+        let funct3 = fields.funct3();
+        if funct3 == 0b111 {
+            // Only vsetvli, which has a clear top bit
+            if word >> 31 != 0 {
+                Err(DecodeErrorInner::Unsupported {
+                    word,
+                    mnemonic: "vsetvl and vsetivli",
+                })?
+            }
+            let vtype = VType::from_bits((word >> 20 & 0x7ff) as i64).ok_or_else(unknown)?;
+            return Ok(Instruction::Vsetvli {
+                rd: x(fields.rd()),
+                r1: x(fields.rs1()),
+                vtype,
+            });
+        }
+
+        let funct6 = word >> 26;
+        let masked = word >> 25 & 1 == 0;
+        let (vd, vs2, vs1) = (fields.rd(), fields.rs2(), fields.rs1());
+        let operand = match funct3 {
+            0b000 | 0b010 => VOperand::Vector(v(vs1)),
+            0b100 | 0b110 => VOperand::Scalar(x(vs1)),
+            // Sign extend the 5-bit immediate
+            0b011 => VOperand::Immediate((vs1 as i32) << 27 >> 27),
+            _ => Err(unknown())?,
+        };
+        let arith = |op| Instruction::VArith {
+            vd: v(vd),
+            vs2: v(vs2),
+            operand,
+            op,
+            masked,
+        };
+        let compare = |op| Instruction::VCompare {
+            vd: v(vd),
+            vs2: v(vs2),
+            operand,
+            op,
+            masked,
+        };
+
+        let instr = if funct3 == 0b010 || funct3 == 0b110 {
+            // OPMVV and OPMVX
+            match (funct6, funct3) {
+                (0b000000..=0b000111, 0b010) => Instruction::VReduce {
+                    vd: v(vd),
+                    vs2: v(vs2),
+                    vs1: v(vs1),
+                    op: [
+                        VReduceOp::Sum,
+                        VReduceOp::And,
+                        VReduceOp::Or,
+                        VReduceOp::Xor,
+                        VReduceOp::Minu,
+                        VReduceOp::Min,
+                        VReduceOp::Maxu,
+                        VReduceOp::Max,
+                    ][funct6 as usize],
+                    masked,
+                },
+                (0b011000..=0b011011 | 0b011101, 0b010) if !masked => Instruction::VMask {
+                    vd: v(vd),
+                    vs2: v(vs2),
+                    vs1: v(vs1),
+                    op: match funct6 {
+                        0b011000 => VMaskOp::Andn,
+                        0b011001 => VMaskOp::And,
+                        0b011010 => VMaskOp::Or,
+                        0b011011 => VMaskOp::Xor,
+                        _ => VMaskOp::Nand,
+                    },
+                },
+                (0b010000, 0b010) if vs1 == 0 && !masked => Instruction::VMoveToScalar {
+                    rd: x(vd),
+                    vs2: v(vs2),
+                },
+                (0b010000, 0b010) if vs1 == 0b10000 => Instruction::VCpop {
+                    rd: x(vd),
+                    vs2: v(vs2),
+                    masked,
+                },
+                (0b010100, 0b010) if vs1 == 0b10001 && vs2 == 0 => {
+                    Instruction::Vid { vd: v(vd), masked }
+                }
+                (0b010000, 0b110) if vs2 == 0 && !masked => Instruction::VMoveFromScalar {
+                    vd: v(vd),
+                    r1: x(vs1),
+                },
+                (0b100101, _) => arith(VArithOp::Mul),
+                _ => Err(unknown())?,
+            }
+        } else {
+            // OPIVV, OPIVX and OPIVI
+            let vector = funct3 == 0b000;
+            let immediate = funct3 == 0b011;
+            match funct6 {
+                0b000000 => arith(VArithOp::Add),
+                0b000010 if !immediate => arith(VArithOp::Sub),
+                0b001001 => arith(VArithOp::And),
+                0b001010 => arith(VArithOp::Or),
+                0b001011 => arith(VArithOp::Xor),
+                0b011000 => compare(VCompareOp::Eq),
+                0b011001 => compare(VCompareOp::Ne),
+                0b011010 if !immediate => compare(VCompareOp::Ltu),
+                0b011011 if !immediate => compare(VCompareOp::Lt),
+                0b011100 => compare(VCompareOp::Leu),
+                0b011101 => compare(VCompareOp::Le),
+                0b011110 if !vector => compare(VCompareOp::Gtu),
+                0b011111 if !vector => compare(VCompareOp::Gt),
+                0b010111 if vs2 == 0 && !masked => Instruction::VMove { vd: v(vd), operand },
+                _ => Err(unknown())?,
+            }
+        };
+        Ok(instr)
+    }
+
+    /// A 16-bit compressed instruction.
+    fn compressed(&self, word: u32, pc: i64) -> DecodeResult<Instruction> {
+        use {CompressedOp::*, Register::*};
+        let unknown = || DecodeErrorInner::Unknown(word);
+        let unsupported = |mnemonic| DecodeErrorInner::Unsupported { word, mnemonic };
+        let imm = |layout| compress::gather(word, layout);
+        // Sign extend from `bit`
+        let signed = |imm: u32, bit: u32| (imm << (31 - bit)) as i32 >> (31 - bit);
+        let target = |layout, bit| label_name(pc + signed(imm(layout), bit) as i64);
+        let (rd, rs2) = (x(word >> 7 & 0x1f), x(word >> 2 & 0x1f));
+        // Registers in 3-bit fields are x8-x15
+        let (crs1, crs2) = (x(8 + (word >> 7 & 0b111)), x(8 + (word >> 2 & 0b111)));
+
+        let (op, instr) = match (word & 0b11, word >> 13) {
+            (0b00, 0b000) => (
+                Addi4spn,
+                Instruction::RegImm {
+                    rd: crs2,
+                    r1: sp,
+                    imm: imm(compress::CIW) as i32,
+                    op: RegImmOp::Addi,
+                },
+            ),
+            (0b00, 0b010) => (
+                Lw,
+                Instruction::Load {
+                    rd: crs2,
+                    offset: imm(compress::CL) as i32,
+                    r1: crs1,
+                    op: LoadOp::Lw,
+                },
+            ),
+            (0b00, 0b110) => (
+                Sw,
+                Instruction::Store {
+                    r2: crs2,
+                    offset: imm(compress::CL) as i32,
+                    r1: crs1,
+                    op: StoreOp::Sw,
+                },
+            ),
+            (0b00 | 0b10, 0b011 | 0b111) => Err(unsupported("compressed floating point"))?,
+            (0b00 | 0b10, 0b001 | 0b101) => Err(unsupported("compressed double precision"))?,
+            (0b01, 0b000) => (
+                if rd == x0 { Nop } else { Addi },
+                Instruction::RegImm {
+                    rd,
+                    r1: rd,
+                    imm: signed(imm(compress::CI), 5),
+                    op: RegImmOp::Addi,
+                },
+            ),
+            (0b01, 0b001) => (
+                Jal,
+                Instruction::jal {
+                    rd: ra,
+                    label: target(compress::CJ, 11),
+                },
+            ),
+            (0b01, 0b010) => (
+                Li,
+                Instruction::RegImm {
+                    rd,
+                    r1: x0,
+                    imm: signed(imm(compress::CI), 5),
+                    op: RegImmOp::Addi,
+                },
+            ),
+            (0b01, 0b011) if rd == sp => (
+                Addi16sp,
+                Instruction::RegImm {
+                    rd: sp,
+                    r1: sp,
+                    imm: signed(imm(compress::CI_SP), 9),
+                    op: RegImmOp::Addi,
+                },
+            ),
+            (0b01, 0b011) => (
+                Lui,
+                Instruction::LoadImm {
+                    rd,
+                    imm: (signed(imm(compress::CI_LUI), 17) >> 12 & 0xfffff).into(),
+                    op: LoadImmOp::Lui,
+                },
+            ),
+            (0b01, 0b100) => match word >> 10 & 0b11 {
+                0b11 if word >> 12 & 1 == 1 => Err(unsupported("RV64 word operations"))?,
+                0b11 => {
+                    let (op, base) = match word >> 5 & 0b11 {
+                        0b00 => (Sub, RegRegOp::Sub),
+                        0b01 => (Xor, RegRegOp::Xor),
+                        0b10 => (Or, RegRegOp::Or),
+                        _ => (And, RegRegOp::And),
+                    };
+                    let instr = Instruction::RegReg {
+                        rd: crs1,
+                        r1: crs1,
+                        r2: crs2,
+                        op: base,
+                    };
+                    (op, instr)
+                }
+                funct2 => {
+                    let (op, base, imm) = match funct2 {
+                        0b00 => (Srli, RegImmOp::Srli, imm(compress::CI) as i32),
+                        0b01 => (Srai, RegImmOp::Srai, imm(compress::CI) as i32),
+                        _ => (Andi, RegImmOp::Andi, signed(imm(compress::CI), 5)),
+                    };
+                    let instr = Instruction::RegImm {
+                        rd: crs1,
+                        r1: crs1,
+                        imm,
+                        op: base,
+                    };
+                    (op, instr)
+                }
+            },
+            (0b01, 0b101) => (
+                J,
+                Instruction::j {
+                    label: target(compress::CJ, 11),
+                },
+            ),
+            (0b01, funct3 @ (0b110 | 0b111)) => (
+                if funct3 == 0b110 { Beqz } else { Bnez },
+                Instruction::Branch {
+                    r1: crs1,
+                    r2: x0,
+                    label: target(compress::CB, 8),
+                    op: if funct3 == 0b110 {
+                        BranchOp::Beq
+                    } else {
+                        BranchOp::Bne
+                    },
+                },
+            ),
+            (0b10, 0b000) => (
+                Slli,
+                Instruction::RegImm {
+                    rd,
+                    r1: rd,
+                    imm: imm(compress::CI) as i32,
+                    op: RegImmOp::Slli,
+                },
+            ),
+            (0b10, 0b010) => (
+                Lwsp,
+                Instruction::Load {
+                    rd,
+                    offset: imm(compress::CI_LWSP) as i32,
+                    r1: sp,
+                    op: LoadOp::Lw,
+                },
+            ),
+            (0b10, 0b100) => match (word >> 12 & 1, rd, rs2) {
+                (0, ra, x0) => (Jr, Instruction::ret {}),
+                (0, rs, x0) => (Jr, Instruction::jr { rs }),
+                (0, rd, r2) => (
+                    Mv,
+                    Instruction::RegReg {
+                        rd,
+                        r1: x0,
+                        r2,
+                        op: RegRegOp::Add,
+                    },
+                ),
+                (_, x0, x0) => Err(unsupported("c.ebreak"))?,
+                (_, r1, x0) => (
+                    Jalr,
+                    Instruction::jalr {
+                        rd: ra,
+                        offset: 0,
+                        r1,
+                    },
+                ),
+                (_, rd, r2) => (
+                    Add,
+                    Instruction::RegReg {
+                        rd,
+                        r1: rd,
+                        r2,
+                        op: RegRegOp::Add,
+                    },
+                ),
+            },
+            (0b10, 0b110) => (
+                Swsp,
+                Instruction::Store {
+                    r2: rs2,
+                    offset: imm(compress::CSS) as i32,
+                    r1: sp,
+                    op: StoreOp::Sw,
+                },
+            ),
+            _ => Err(unknown())?,
+        };
+        // Reserved encodings and hints, like a zero immediate where it has to
+        // be nonzero
+        if !op.fits(&instr) {
+            Err(unknown())?
+        }
+        Ok(Instruction::Compressed {
+            op,
+            instr: Box::new(instr),
+        })
+    }
+}
+
+impl Instruction {
+    /// Decode a single built-in instruction at `pc`.
+    pub fn decode(word: u32, pc: i64) -> Result<Instruction, DecodeError> {
+        Decoder::new().decode(word, pc)
+    }
+}
+
+impl Program {
+    /// Decode a little-endian image of built-in instructions, starting at pc 0.
+    pub fn disassemble(bytes: &[u8]) -> Result<Program, DecodeError> {
+        Decoder::new().disassemble(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use indoc::indoc;
+
+    use crate::{
+        encode::EncodeErrorInner,
+        lex::Lexer,
+        parse::{
+            BranchZeroOp, CsrImmOp, CsrRegOp, CustomEncoding, FCompareOp, FFusedOp, FToIntOp,
+            IntToFOp, LoadImmOp, RegImmOp, UnaryOp,
+        },
+    };
+
+    use super::*;
+
+    #[test]
+    fn disassemble() {
+        let source = indoc! {"
+            loop:
+            addi a0, a0, 1
+            bne a0, a1, loop
+            beqz a0, end
+            jal ra, loop
+            mv a1, a0
+            ret
+            end:
+        "};
+        let image = source.parse::<Program>().unwrap().image().unwrap();
+        let program = Program::disassemble(&image).unwrap();
+        let text: Vec<_> = program.asm.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            text,
+            [
+                "addi a0, a0, 1",
+                "bne a0, a1, label_0",
+                "beq a0, x0, label_18",
+                "jal ra, label_0",
+                "addi a1, a0, 0",
+                "ret",
+            ]
+        );
+        assert_eq!(program.label("label_18"), Some(0x18));
+        // Disassembling gives the same image back
+        assert_eq!(program.image().unwrap(), image);
+
+        assert_eq!(
+            Instruction::decode(0xffffffff, 8).unwrap_err(),
+            DecodeError {
+                pc: 8,
+                error: DecodeErrorInner::Unknown(0xffffffff),
+            }
+        );
+        // fence, then a branch backwards out of the program
+        let error = Program::disassemble(&[0x0f, 0, 0, 0]).unwrap_err();
+        assert!(matches!(
+            error.error(),
+            DecodeErrorInner::Unsupported { .. }
+        ));
+        let error = Program::disassemble(&0xfe000ee3u32.to_le_bytes()).unwrap_err();
+        assert_eq!(error.error(), &DecodeErrorInner::Target(-4));
+        let error = Program::disassemble(&[0x13, 0, 0, 0, 0x13]).unwrap_err();
+        assert_eq!(error.pc(), 4);
+        assert_eq!(error.error(), &DecodeErrorInner::Trailing(1));
+    }
+
+    #[test]
+    fn compressed() {
+        let source = indoc! {"
+            loop:
+            c.addi a0, 1
+            bne a0, a1, loop
+            c.beqz a0, end
+            c.jal loop
+            c.mv a1, a0
+            c.jr ra
+            end:
+        "};
+        let image = source.parse::<Program>().unwrap().image().unwrap();
+        assert_eq!(image.len(), 14);
+        let program = Program::disassemble(&image).unwrap();
+        let text: Vec<_> = program.asm.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            text,
+            [
+                "c.addi a0, 1",
+                "bne a0, a1, label_0",
+                "c.beqz a0, label_e",
+                "c.jal label_0",
+                "c.mv a1, a0",
+                "c.jr ra",
+            ]
+        );
+        assert_eq!(program.image().unwrap(), image);
+
+        // Reserved encodings, like all zeros, aren't instructions
+        for word in [0x0000, 0x0081, 0x6001, 0x8002] {
+            assert_eq!(
+                Instruction::decode(word, 0).unwrap_err().error(),
+                &DecodeErrorInner::Unknown(word)
+            );
+        }
+        assert!(matches!(
+            Instruction::decode(0x9002, 0).unwrap_err().error(),
+            DecodeErrorInner::Unsupported {
+                mnemonic: "c.ebreak",
+                ..
+            }
+        ));
+        // Half of a 4-byte instruction
+        let error = Program::disassemble(&[0x05, 0x45, 0x13, 0x05]).unwrap_err();
+        assert_eq!(error.pc(), 2);
+        assert_eq!(error.error(), &DecodeErrorInner::Trailing(2));
+    }
+
+    /// A tiny xorshift generator, so the test is repeatable.
+    struct Random(u64);
+
+    impl Random {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn pick<T: Copy>(&mut self, items: &[T]) -> T {
+            items[self.next() as usize % items.len()]
+        }
+
+        fn x(&mut self) -> Register {
+            x(self.next() as u32 % 32)
+        }
+
+        fn f(&mut self) -> FRegister {
+            f(self.next() as u32 % 32)
+        }
+
+        fn v(&mut self) -> VRegister {
+            v(self.next() as u32 % 32)
+        }
+
+        /// A signed immediate that fits in `bits` bits
+        fn imm(&mut self, bits: u32) -> i32 {
+            (self.next() as i32) << (32 - bits) >> (32 - bits)
+        }
+
+        /// One of the registers 3-bit fields can hold
+        fn compact(&mut self) -> Register {
+            x(8 + self.next() as u32 % 8)
+        }
+
+        fn bool(&mut self) -> bool {
+            self.next() & 1 == 1
+        }
+
+        fn operand(&mut self) -> VOperand {
+            match self.next() % 3 {
+                0 => VOperand::Vector(self.v()),
+                1 => VOperand::Scalar(self.x()),
+                _ => VOperand::Immediate(self.imm(5)),
+            }
+        }
+    }
+
+    /// A random instruction `op` can encode.
+    fn compressed_instruction(random: &mut Random, op: CompressedOp, label: &str) -> Instruction {
+        use {CompressedOp::*, Register::*};
+        loop {
+            let (rd, r2, c1, c2) = (random.x(), random.x(), random.compact(), random.compact());
+            let (imm, shamt) = (random.imm(6), random.next() as i32 % 32);
+            let words = |random: &mut Random, count| 4 * (random.next() % count) as i32;
+            let label = label.to_string();
+            let instr = match op {
+                Addi4spn => Instruction::RegImm {
+                    rd: c1,
+                    r1: sp,
+                    imm: words(random, 256),
+                    op: RegImmOp::Addi,
+                },
+                Lw => Instruction::Load {
+                    rd: c1,
+                    offset: words(random, 32),
+                    r1: c2,
+                    op: LoadOp::Lw,
+                },
+                Sw => Instruction::Store {
+                    r2: c1,
+                    offset: words(random, 32),
+                    r1: c2,
+                    op: StoreOp::Sw,
+                },
+                Nop => Instruction::RegImm {
+                    rd: x0,
+                    r1: x0,
+                    imm: 0,
+                    op: RegImmOp::Addi,
+                },
+                Addi | Li | Addi16sp => Instruction::RegImm {
+                    rd: if op == Addi16sp { sp } else { rd },
+                    r1: match op {
+                        Addi => rd,
+                        Li => x0,
+                        _ => sp,
+                    },
+                    imm: if op == Addi16sp { 16 * imm } else { imm },
+                    op: RegImmOp::Addi,
+                },
+                Lui => Instruction::LoadImm {
+                    rd,
+                    imm: (imm & 0xfffff).into(),
+                    op: LoadImmOp::Lui,
+                },
+                Srli | Srai | Andi | Slli => {
+                    let (rd, imm, op) = match op {
+                        Srli => (c1, shamt, RegImmOp::Srli),
+                        Srai => (c1, shamt, RegImmOp::Srai),
+                        Andi => (c1, imm, RegImmOp::Andi),
+                        _ => (rd, shamt, RegImmOp::Slli),
+                    };
+                    Instruction::RegImm {
+                        rd,
+                        r1: rd,
+                        imm,
+                        op,
+                    }
+                }
+                Sub | Xor | Or | And => Instruction::RegReg {
+                    rd: c1,
+                    r1: c1,
+                    r2: c2,
+                    op: match op {
+                        Sub => RegRegOp::Sub,
+                        Xor => RegRegOp::Xor,
+                        Or => RegRegOp::Or,
+                        _ => RegRegOp::And,
+                    },
+                },
+                Mv | Add => Instruction::RegReg {
+                    rd,
+                    r1: if op == Mv { x0 } else { rd },
+                    r2,
+                    op: RegRegOp::Add,
+                },
+                J => Instruction::j { label },
+                Jal => Instruction::jal { rd: ra, label },
+                Beqz | Bnez => Instruction::Branch {
+                    r1: c1,
+                    r2: x0,
+                    label,
+                    op: if op == Beqz {
+                        BranchOp::Beq
+                    } else {
+                        BranchOp::Bne
+                    },
+                },
+                Lwsp => Instruction::Load {
+                    rd,
+                    offset: words(random, 64),
+                    r1: sp,
+                    op: LoadOp::Lw,
+                },
+                Swsp => Instruction::Store {
+                    r2,
+                    offset: words(random, 64),
+                    r1: sp,
+                    op: StoreOp::Sw,
+                },
+                Jr if rd == ra => Instruction::ret {},
+                Jr => Instruction::jr { rs: rd },
+                Jalr => Instruction::jalr {
+                    rd: ra,
+                    offset: 0,
+                    r1: rd,
+                },
+            };
+            // Retry the registers and immediates that can't be zero
+            if op.fits(&instr) {
+                return instr;
+            }
+        }
+    }
+
+    /// A random instruction for every operation of every instruction set.
+    fn instructions(random: &mut Random) -> Vec<Instruction> {
+        let label = "target".to_string();
+        let mut instrs = vec![];
+        for &op in RegImmOp::ALL {
+            let imm = match op {
+                RegImmOp::Addi
+                | RegImmOp::Slti
+                | RegImmOp::Sltiu
+                | RegImmOp::Xori
+                | RegImmOp::Ori
+                | RegImmOp::Andi => random.imm(12),
+                _ => random.next() as i32 & 0x1f,
+            };
+            instrs.push(Instruction::RegImm {
+                rd: random.x(),
+                r1: random.x(),
+                imm,
+                op,
+            });
+        }
+        for &op in RegRegOp::ALL {
+            instrs.push(Instruction::RegReg {
+                rd: random.x(),
+                r1: random.x(),
+                r2: random.x(),
+                op,
+            });
+        }
+        for &op in LoadOp::ALL {
+            instrs.push(Instruction::Load {
+                rd: random.x(),
+                offset: random.imm(12),
+                r1: random.x(),
+                op,
+            });
+        }
+        for &op in StoreOp::ALL {
+            instrs.push(Instruction::Store {
+                r2: random.x(),
+                offset: random.imm(12),
+                r1: random.x(),
+                op,
+            });
+        }
+        for &op in BranchOp::ALL {
+            instrs.push(Instruction::Branch {
+                r1: random.x(),
+                r2: random.x(),
+                label: label.clone(),
+                op,
+            });
+        }
+        for &op in BranchZeroOp::ALL {
+            instrs.push(Instruction::BranchZero {
+                r1: random.x(),
+                label: label.clone(),
+                op,
+            });
+        }
+        for &op in LoadImmOp::ALL {
+            let imm = match op {
+                LoadImmOp::Lui | LoadImmOp::Auipc => random.next() as i64 & 0xfffff,
+                // Usually too large for one instruction
+                LoadImmOp::Li => random.next() as i32 as i64,
+            };
+            instrs.push(Instruction::LoadImm {
+                rd: random.x(),
+                imm,
+                op,
+            });
+        }
+        for &op in UnaryOp::ALL {
+            instrs.push(Instruction::Unary {
+                rd: random.x(),
+                r1: random.x(),
+                op,
+            });
+        }
+        for &op in AmoOp::ALL {
+            instrs.push(Instruction::Amo {
+                rd: random.x(),
+                r2: random.x(),
+                r1: random.x(),
+                op,
+            });
+        }
+        for &op in FRegRegOp::ALL {
+            let rm = if op.takes_rounding_mode() {
+                random.pick(RoundingMode::ALL)
+            } else {
+                RoundingMode::Dyn
+            };
+            instrs.push(Instruction::FRegReg {
+                rd: random.f(),
+                r1: random.f(),
+                r2: random.f(),
+                op,
+                rm,
+            });
+        }
+        for &op in FFusedOp::ALL {
+            instrs.push(Instruction::FFused {
+                rd: random.f(),
+                r1: random.f(),
+                r2: random.f(),
+                r3: random.f(),
+                op,
+                rm: random.pick(RoundingMode::ALL),
+            });
+        }
+        for &op in FCompareOp::ALL {
+            instrs.push(Instruction::FCompare {
+                rd: random.x(),
+                r1: random.f(),
+                r2: random.f(),
+                op,
+            });
+        }
+        for &op in FToIntOp::ALL {
+            let rm = match op {
+                FToIntOp::MvXW => RoundingMode::Dyn,
+                _ => random.pick(RoundingMode::ALL),
+            };
+            instrs.push(Instruction::FToInt {
+                rd: random.x(),
+                r1: random.f(),
+                op,
+                rm,
+            });
+        }
+        for &op in IntToFOp::ALL {
+            let rm = match op {
+                IntToFOp::MvWX => RoundingMode::Dyn,
+                _ => random.pick(RoundingMode::ALL),
+            };
+            instrs.push(Instruction::IntToF {
+                rd: random.f(),
+                r1: random.x(),
+                op,
+                rm,
+            });
+        }
+        for &op in CsrRegOp::ALL {
+            instrs.push(Instruction::CsrReg {
+                rd: random.x(),
+                csr: random.pick(&[Csr::Mstatus, Csr::Fcsr, Csr::Satp, Csr::Vl]),
+                r1: random.x(),
+                op,
+            });
+        }
+        for &op in CsrImmOp::ALL {
+            instrs.push(Instruction::CsrImm {
+                rd: random.x(),
+                csr: random.pick(&[Csr::Mstatus, Csr::Fcsr, Csr::Satp, Csr::Vl]),
+                imm: random.next() as i32 & 0x1f,
+                op,
+            });
+        }
+        for &sew in ElementWidth::ALL {
+            let vtype = VType {
+                sew,
+                lmul: random.pick(&[1, 2, 4, 8]),
+                tail_agnostic: random.bool(),
+                mask_agnostic: random.bool(),
+            };
+            instrs.push(Instruction::Vsetvli {
+                rd: random.x(),
+                r1: random.x(),
+                vtype,
+            });
+            let stride = random.bool().then(|| random.x());
+            instrs.push(Instruction::VLoad {
+                vd: random.v(),
+                r1: random.x(),
+                stride,
+                width: sew,
+                masked: random.bool(),
+            });
+            instrs.push(Instruction::VStore {
+                vs3: random.v(),
+                r1: random.x(),
+                stride,
+                width: sew,
+                masked: random.bool(),
+            });
+        }
+        for &op in VArithOp::ALL {
+            instrs.push(Instruction::VArith {
+                vd: random.v(),
+                vs2: random.v(),
+                operand: random.operand(),
+                op,
+                masked: random.bool(),
+            });
+        }
+        for &op in VCompareOp::ALL {
+            instrs.push(Instruction::VCompare {
+                vd: random.v(),
+                vs2: random.v(),
+                operand: random.operand(),
+                op,
+                masked: random.bool(),
+            });
+        }
+        for &op in VReduceOp::ALL {
+            instrs.push(Instruction::VReduce {
+                vd: random.v(),
+                vs2: random.v(),
+                vs1: random.v(),
+                op,
+                masked: random.bool(),
+            });
+        }
+        for &op in VMaskOp::ALL {
+            instrs.push(Instruction::VMask {
+                vd: random.v(),
+                vs2: random.v(),
+                vs1: random.v(),
+                op,
+            });
+        }
+        instrs.extend([
+            Instruction::VMove {
+                vd: random.v(),
+                operand: random.operand(),
+            },
+            Instruction::call {
+                label: label.clone(),
+            },
+            Instruction::la {
+                rd: random.x(),
+                label: label.clone(),
+            },
+            Instruction::jalr {
+                rd: random.x(),
+                offset: random.imm(12),
+                r1: random.x(),
+            },
+            Instruction::sfence_vma {
+                vaddr: random.x(),
+                asid: random.x(),
+            },
+            Instruction::mret {},
+            Instruction::sret {},
+            Instruction::ecall {},
+            Instruction::wfi {},
+            Instruction::Custom {
+                mnemonic: "mac".to_string(),
+                encoding: CustomEncoding::new(CustomOpcode::Custom2, 3, 1),
+                operands: CustomOperands::R {
+                    rd: random.x(),
+                    r1: random.x(),
+                    r2: random.x(),
+                },
+            },
+        ]);
+        for &op in CompressedOp::ALL {
+            let instr = Box::new(compressed_instruction(random, op, &label));
+            instrs.push(Instruction::Compressed { op, instr });
+        }
+        instrs
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut custom = CustomInstructions::default();
+        let encoding = CustomEncoding::new(CustomOpcode::Custom2, 3, 1);
+        custom
+            .register("mac", CustomFormat::R, encoding, |_| Ok(vec![]))
+            .unwrap();
+        let decoder = Decoder::new().with_custom(custom.clone());
+
+        let mut random = Random(0x5eed);
+        for _ in 0..50 {
+            let mut asm = instructions(&mut random);
+            // Put the branch target somewhere random, possibly after the end
+            let target = random.next() as usize % (asm.len() + 1);
+            let program = Program {
+                labels: HashMap::from([("target".to_string(), 4 * target)]),
+                asm: std::mem::take(&mut asm),
+            };
+            for (pc, instr) in program.addresses().zip(&program.asm) {
+                // Everything encodes except RV64 instructions, operands with
+                // no encoding, like vmslt.vi, and compressed jumps that can't
+                // reach the target
+                let no_encoding = matches!(
+                    instr,
+                    Instruction::VArith {
+                        op: VArithOp::Sub | VArithOp::Mul,
+                        operand: VOperand::Immediate(_),
+                        ..
+                    } | Instruction::VCompare {
+                        op: VCompareOp::Lt | VCompareOp::Ltu,
+                        operand: VOperand::Immediate(_),
+                        ..
+                    } | Instruction::VCompare {
+                        op: VCompareOp::Gt | VCompareOp::Gtu,
+                        operand: VOperand::Vector(_),
+                        ..
+                    }
+                );
+                let out_of_reach = match instr {
+                    Instruction::Compressed { op, .. } => op
+                        .reach()
+                        .is_some_and(|reach| !reach.contains(&(4 * target as i64 - pc))),
+                    _ => false,
+                };
+                let words = match instr.encode(pc, &program) {
+                    Ok(words) => {
+                        assert!(
+                            !instr.is_rv64_only() && !no_encoding && !out_of_reach,
+                            "{instr} encoded"
+                        );
+                        words
+                    }
+                    Err(error) if instr.is_rv64_only() => {
+                        assert_eq!(error.error(), &EncodeErrorInner::Rv64Only(instr.clone()));
+                        continue;
+                    }
+                    Err(error) if no_encoding => {
+                        assert_eq!(error.error(), &EncodeErrorInner::NoEncoding(instr.clone()));
+                        continue;
+                    }
+                    Err(error) if out_of_reach => {
+                        assert!(matches!(
+                            error.error(),
+                            EncodeErrorInner::OutOfRange {
+                                field: "offset",
+                                ..
+                            }
+                        ));
+                        continue;
+                    }
+                    Err(error) => panic!("{instr} doesn't encode: {error}"),
+                };
+                // Pseudo-instructions that expand decode one word at a time
+                for (word, pc) in words.into_iter().zip((pc..).step_by(4)) {
+                    let decoded = decoder.decode(word, pc).unwrap();
+                    let decoded_program = Program {
+                        labels: HashMap::from([(label_name(4 * target as i64), 4 * target)]),
+                        asm: vec![],
+                    };
+                    assert_eq!(
+                        decoded.encode(pc, &decoded_program),
+                        Ok(vec![word]),
+                        "{instr} decoded as {decoded}"
+                    );
+
+                    // The decoded instruction parses back from its text too
+                    let text = format!("{decoded}\n{}:", label_name(4 * target as i64));
+                    let mut lexer = Lexer::new(&text).with_custom(custom.clone());
+                    let parsed = Program::parse(&mut lexer).unwrap();
+                    assert_eq!(parsed.asm[0], decoded, "{text}");
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,795 @@
+//! Reading and writing RV32 ELF files.
+//!
+//! Only what's needed to run an executable, like one `riscv32-unknown-elf-gcc`
+//! links, is read: the `PT_LOAD` segments, the entry point and the symbol
+//! table. Every offset and size is checked against the file, so malformed input
+//! is an error rather than a panic.
+//!
+//! Programs are written with `.text`, `.data`, `.symtab`, `.strtab` and
+//! `.shstrtab` sections, as either an object file or an executable.
+
+use std::{collections::HashMap, ops::Range};
+
+use thiserror::Error;
+
+use crate::{encode::EncodeError, parse::Program};
+
+const MAGIC: &[u8; 4] = b"\x7fELF";
+const CLASS_32: u8 = 1;
+const DATA_LE: u8 = 1;
+
+const EV_CURRENT: u8 = 1;
+
+const ET_REL: u16 = 1;
+const ET_EXEC: u16 = 2;
+const EM_RISCV: u16 = 243;
+/// The code uses compressed instructions.
+const EF_RISCV_RVC: u32 = 1;
+
+/// The size of the ELF header.
+const EHDR_SIZE: usize = 52;
+/// The size of a program header.
+const PHDR_SIZE: usize = 32;
+/// The size of a section header.
+const SHDR_SIZE: usize = 40;
+/// The size of a symbol table entry.
+const SYM_SIZE: usize = 16;
+/// The largest segment that's loaded. Sizes come from the file, so this keeps
+/// a malformed one from zeroing gigabytes of memory.
+const MAX_SEGMENT_SIZE: usize = 64 << 20;
+
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+
+const SHF_WRITE: u32 = 1;
+const SHF_ALLOC: u32 = 2;
+const SHF_EXECINSTR: u32 = 4;
+
+const STB_LOCAL: u8 = 0;
+const STB_GLOBAL: u8 = 1;
+const STT_NOTYPE: u8 = 0;
+
+const STT_SECTION: u8 = 3;
+const STT_FILE: u8 = 4;
+const SHN_UNDEF: u16 = 0;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum ElfError {
+    #[error("not an ELF file")]
+    Magic,
+
+    #[error("only 32-bit ELF files are supported")]
+    Class,
+
+    #[error("only little-endian ELF files are supported")]
+    Endianness,
+
+    #[error("not a RISC-V ELF file (machine {0})")]
+    Machine(u16),
+
+    /// Relocatable objects and shared libraries need linking first.
+    #[error("not an executable (type {0})")]
+    Type(u16),
+
+    /// A header or table runs past the end of the file.
+    #[error("{0} is out of bounds")]
+    OutOfBounds(&'static str),
+
+    #[error("segment {0} is larger in the file than in memory")]
+    SegmentSize(usize),
+
+    /// The segment runs past the end of the address space, or is too large to
+    /// load.
+    #[error("segment {0} is too large")]
+    SegmentTooLarge(usize),
+
+    #[error("no executable segment")]
+    NoText,
+
+    #[error("symbol name at offset {0} is not terminated or not UTF-8")]
+    SymbolName(u32),
+}
+
+/// A segment to be loaded into memory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    /// The virtual address of the segment.
+    pub addr: i64,
+
+    /// The contents of the segment. Memory past the end of it, up to `size`
+    /// bytes from `addr`, is zeroed (`.bss`).
+    pub data: Vec<u8>,
+    pub size: usize,
+
+    pub executable: bool,
+}
+
+/// The parts of an executable needed to run it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Elf {
+    pub entry: i64,
+    pub segments: Vec<Segment>,
+
+    /// Functions and objects from the symbol table, by name.
+    pub symbols: HashMap<String, usize>,
+}
+
+/// Little-endian reads that fail instead of panicking at the end of the file.
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn slice(&self, offset: usize, len: usize, what: &'static str) -> Result<&'a [u8], ElfError> {
+        offset
+            .checked_add(len)
+            .and_then(|end| self.bytes.get(offset..end))
+            .ok_or(ElfError::OutOfBounds(what))
+    }
+
+    fn u8(&self, offset: usize, what: &'static str) -> Result<u8, ElfError> {
+        Ok(self.slice(offset, 1, what)?[0])
+    }
+
+    fn u16(&self, offset: usize, what: &'static str) -> Result<u16, ElfError> {
+        let bytes = self.slice(offset, 2, what)?;
+        Ok(u16::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn u32(&self, offset: usize, what: &'static str) -> Result<u32, ElfError> {
+        let bytes = self.slice(offset, 4, what)?;
+        Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    /// The offset of the `index`th `size`-byte entry of a table at `offset`.
+    fn entry(
+        &self,
+        offset: usize,
+        size: usize,
+        index: usize,
+        what: &'static str,
+    ) -> Result<usize, ElfError> {
+        let entry = size
+            .checked_mul(index)
+            .and_then(|start| start.checked_add(offset))
+            .ok_or(ElfError::OutOfBounds(what))?;
+        self.slice(entry, size, what)?;
+        Ok(entry)
+    }
+}
+
+impl Elf {
+    pub fn parse(bytes: &[u8]) -> Result<Elf, ElfError> {
+        let reader = Reader { bytes };
+        if !bytes.starts_with(MAGIC) {
+            Err(ElfError::Magic)?
+        }
+        let header = reader.slice(0, EHDR_SIZE, "ELF header")?;
+        if header[4] != CLASS_32 {
+            Err(ElfError::Class)?
+        }
+        if header[5] != DATA_LE {
+            Err(ElfError::Endianness)?
+        }
+        let machine = reader.u16(18, "ELF header")?;
+        if machine != EM_RISCV {
+            Err(ElfError::Machine(machine))?
+        }
+        let kind = reader.u16(16, "ELF header")?;
+        if kind != ET_EXEC {
+            Err(ElfError::Type(kind))?
+        }
+        let entry = reader.u32(24, "ELF header")? as i64;
+
+        let phoff = reader.u32(28, "ELF header")? as usize;
+        let phnum = reader.u16(44, "ELF header")? as usize;
+        let mut segments = vec![];
+        for index in 0..phnum {
+            let phdr = reader.entry(phoff, PHDR_SIZE, index, "program header")?;
+            if reader.u32(phdr, "program header")? != PT_LOAD {
+                continue;
+            }
+            let offset = reader.u32(phdr + 4, "program header")? as usize;
+            let addr = reader.u32(phdr + 8, "program header")? as i64;
+            let file_size = reader.u32(phdr + 16, "program header")? as usize;
+            let size = reader.u32(phdr + 20, "program header")? as usize;
+            let flags = reader.u32(phdr + 24, "program header")?;
+            if file_size > size {
+                Err(ElfError::SegmentSize(index))?
+            }
+            if size > MAX_SEGMENT_SIZE || addr + size as i64 > 1 << 32 {
+                Err(ElfError::SegmentTooLarge(index))?
+            }
+            segments.push(Segment {
+                addr,
+                data: reader.slice(offset, file_size, "segment")?.to_vec(),
+                size,
+                executable: flags & PF_X != 0,
+            });
+        }
+
+        Ok(Elf {
+            entry,
+            segments,
+            symbols: Self::symbols(&reader)?,
+        })
+    }
+
+    /// Named functions, objects and labels from the symbol table, if there is
+    /// one.
+    fn symbols(reader: &Reader) -> Result<HashMap<String, usize>, ElfError> {
+        let mut symbols = HashMap::new();
+        let shoff = reader.u32(32, "ELF header")? as usize;
+        let shnum = reader.u16(48, "ELF header")? as usize;
+        for index in 0..shnum {
+            let shdr = reader.entry(shoff, SHDR_SIZE, index, "section header")?;
+            if reader.u32(shdr + 4, "section header")? != SHT_SYMTAB {
+                continue;
+            }
+            let offset = reader.u32(shdr + 16, "section header")? as usize;
+            let size = reader.u32(shdr + 20, "section header")? as usize;
+            // The names are in the string table section it links to
+            let link = reader.u32(shdr + 24, "section header")? as usize;
+            let strtab = reader.entry(shoff, SHDR_SIZE, link, "section header")?;
+            let strings = reader.slice(
+                reader.u32(strtab + 16, "section header")? as usize,
+                reader.u32(strtab + 20, "section header")? as usize,
+                "string table",
+            )?;
+
+            for sym in 0..size / SYM_SIZE {
+                let sym = reader.entry(offset, SYM_SIZE, sym, "symbol table")?;
+                let name = reader.u32(sym, "symbol table")?;
+                let value = reader.u32(sym + 4, "symbol table")?;
+                let kind = reader.u8(sym + 12, "symbol table")? & 0xf;
+                let section = reader.u16(sym + 14, "symbol table")?;
+                if name == 0 || section == SHN_UNDEF || matches!(kind, STT_SECTION | STT_FILE) {
+                    continue;
+                }
+                let name = strings
+                    .get(name as usize..)
+                    .and_then(|rest| Some(&rest[..rest.iter().position(|&byte| byte == 0)?]))
+                    .and_then(|name| std::str::from_utf8(name).ok())
+                    .ok_or(ElfError::SymbolName(name))?;
+                symbols.insert(name.to_string(), value as usize);
+            }
+        }
+        Ok(symbols)
+    }
+
+    /// The addresses covered by executable segments.
+    pub fn text(&self) -> Result<Range<i64>, ElfError> {
+        let text = self.segments.iter().filter(|segment| segment.executable);
+        let start = text.clone().map(|segment| segment.addr).min();
+        let end = text.map(|segment| segment.addr + segment.size as i64).max();
+        match (start, end) {
+            (Some(start), Some(end)) => Ok(start..end),
+            _ => Err(ElfError::NoText),
+        }
+    }
+}
+
+/// What kind of ELF file to write.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfKind {
+    /// An object file for linking, with its sections at address 0. No
+    /// relocations are written, which works because every label is in `.text`
+    /// and `la` and `call` are relative to the pc.
+    Relocatable,
+
+    /// An executable with `.text` loaded at `text` and `.data` at `data`. It
+    /// starts at `_start`, or the first instruction if there's no such label.
+    Executable { text: i64, data: i64 },
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum WriteError {
+    #[error(transparent)]
+    Encode(#[from] EncodeError),
+
+    #[error("address {0:#x} is not word aligned")]
+    Unaligned(i64),
+}
+
+// Indices of the sections in written files, after the null section
+const TEXT_SECTION: u16 = 1;
+const STRTAB_SECTION: u16 = 4;
+const SHSTRTAB_SECTION: u16 = 5;
+const SECTIONS: usize = 6;
+
+/// A string table under construction.
+struct Strings {
+    bytes: Vec<u8>,
+}
+
+impl Strings {
+    fn new() -> Self {
+        // Every string table starts with the empty string
+        Self { bytes: vec![0] }
+    }
+
+    /// Add a string, returning its offset.
+    fn add(&mut self, string: &str) -> u32 {
+        let offset = self.bytes.len() as u32;
+        self.bytes.extend(string.bytes().chain([0]));
+        offset
+    }
+}
+
+fn words(out: &mut Vec<u8>, words: &[u32]) {
+    out.extend(words.iter().flat_map(|word| word.to_le_bytes()));
+}
+
+fn align(offset: usize) -> usize {
+    offset.next_multiple_of(4)
+}
+
+impl Program {
+    /// Assemble the program into an ELF file, with `data` in `.data`. Every
+    /// label becomes a local symbol in `.text`, except `_start`, which is
+    /// global.
+    pub fn elf(&self, kind: ElfKind, data: &[u8]) -> Result<Vec<u8>, WriteError> {
+        let (text_base, data_base) = match kind {
+            ElfKind::Relocatable => (0, 0),
+            ElfKind::Executable { text, data } => {
+                for base in [text, data] {
+                    if base % 4 != 0 {
+                        Err(WriteError::Unaligned(base))?
+                    }
+                }
+                (text, data)
+            }
+        };
+        let mut program = self.clone();
+        program.rebase(text_base);
+        let code = program.image_at(text_base)?;
+
+        // Locals have to come before globals
+        let mut labels: Vec<_> = program.labels.iter().collect();
+        labels.sort_by_key(|(name, pc)| (*name == "_start", **pc, *name));
+        let mut strtab = Strings::new();
+        let mut symtab = vec![0; SYM_SIZE];
+        let mut first_global = labels.len() + 1;
+        for (index, (name, pc)) in labels.iter().enumerate() {
+            let bind = match name.as_str() {
+                "_start" => {
+                    first_global = index + 1;
+                    STB_GLOBAL
+                }
+                _ => STB_LOCAL,
+            };
+            words(&mut symtab, &[strtab.add(name), **pc as u32, 0]);
+            symtab.extend([bind << 4 | STT_NOTYPE, 0]);
+            symtab.extend(TEXT_SECTION.to_le_bytes());
+        }
+        let mut shstrtab = Strings::new();
+        let names: Vec<_> = [".text", ".data", ".symtab", ".strtab", ".shstrtab"]
+            .into_iter()
+            .map(|name| shstrtab.add(name))
+            .collect();
+
+        let executable = matches!(kind, ElfKind::Executable { .. });
+        let phnum = if executable {
+            1 + !data.is_empty() as usize
+        } else {
+            0
+        };
+        let text_offset = align(EHDR_SIZE + phnum * PHDR_SIZE);
+        let data_offset = text_offset + code.len();
+        let symtab_offset = align(data_offset + data.len());
+        let strtab_offset = symtab_offset + symtab.len();
+        let shstrtab_offset = strtab_offset + strtab.bytes.len();
+        let shoff = align(shstrtab_offset + shstrtab.bytes.len());
+        let entry = program.label("_start").unwrap_or(text_base);
+
+        let mut elf = vec![];
+        elf.extend(MAGIC);
+        elf.extend([CLASS_32, DATA_LE, EV_CURRENT]);
+        elf.resize(16, 0);
+        let kind = if executable { ET_EXEC } else { ET_REL };
+        elf.extend(kind.to_le_bytes());
+        elf.extend(EM_RISCV.to_le_bytes());
+        let phoff = if executable { EHDR_SIZE } else { 0 };
+        words(
+            &mut elf,
+            &[
+                EV_CURRENT as u32,
+                entry as u32,
+                phoff as u32,
+                shoff as u32,
+                if self.asm.iter().any(|instr| instr.size() == 2) {
+                    EF_RISCV_RVC
+                } else {
+                    0
+                },
+            ],
+        );
+        let phentsize = if executable { PHDR_SIZE } else { 0 };
+        for half in [
+            EHDR_SIZE,
+            phentsize,
+            phnum,
+            SHDR_SIZE,
+            SECTIONS,
+            SHSTRTAB_SECTION as usize,
+        ] {
+            elf.extend((half as u16).to_le_bytes());
+        }
+
+        let segments = [
+            (text_offset, text_base, code.len(), PF_R | PF_X),
+            (data_offset, data_base, data.len(), PF_R | PF_W),
+        ];
+        for (offset, addr, size, flags) in segments.into_iter().take(phnum) {
+            let (offset, addr, size) = (offset as u32, addr as u32, size as u32);
+            words(
+                &mut elf,
+                &[PT_LOAD, offset, addr, addr, size, size, flags, 4],
+            );
+        }
+        elf.resize(text_offset, 0);
+        elf.extend(&code);
+        elf.extend(data);
+        elf.resize(symtab_offset, 0);
+        elf.extend(&symtab);
+        elf.extend(&strtab.bytes);
+        elf.extend(&shstrtab.bytes);
+        elf.resize(shoff, 0);
+
+        let (text_addr, data_addr) = if executable {
+            (text_base as u32, data_base as u32)
+        } else {
+            (0, 0)
+        };
+        let sections = [
+            [0; 10],
+            [
+                names[0],
+                SHT_PROGBITS,
+                SHF_ALLOC | SHF_EXECINSTR,
+                text_addr,
+                text_offset as u32,
+                code.len() as u32,
+                0,
+                0,
+                4,
+                0,
+            ],
+            [
+                names[1],
+                SHT_PROGBITS,
+                SHF_ALLOC | SHF_WRITE,
+                data_addr,
+                data_offset as u32,
+                data.len() as u32,
+                0,
+                0,
+                4,
+                0,
+            ],
+            [
+                names[2],
+                SHT_SYMTAB,
+                0,
+                0,
+                symtab_offset as u32,
+                symtab.len() as u32,
+                STRTAB_SECTION as u32,
+                first_global as u32,
+                4,
+                SYM_SIZE as u32,
+            ],
+            [
+                names[3],
+                SHT_STRTAB,
+                0,
+                0,
+                strtab_offset as u32,
+                strtab.bytes.len() as u32,
+                0,
+                0,
+                1,
+                0,
+            ],
+            [
+                names[4],
+                SHT_STRTAB,
+                0,
+                0,
+                shstrtab_offset as u32,
+                shstrtab.bytes.len() as u32,
+                0,
+                0,
+                1,
+                0,
+            ],
+        ];
+        for section in sections {
+            words(&mut elf, &section);
+        }
+        Ok(elf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use indoc::indoc;
+
+    use crate::{
+        executor::Executor,
+        parse::{LoadOp, Program, Register},
+    };
+
+    use super::*;
+
+    const TEXT: i64 = 0x10000;
+    const DATA: i64 = 0x20000;
+
+    /// A minimal executable with `code` at [`TEXT`], a data segment at
+    /// [`DATA`] with 4 bytes in the file and 4 more in `.bss`, and a symbol
+    /// table.
+    fn executable(code: &[u8], symbols: &[(&str, u32)]) -> Vec<u8> {
+        let data = [1, 2, 3, 4];
+        let mut strtab = vec![0];
+        let mut symtab = vec![0; SYM_SIZE];
+        for (name, value) in symbols {
+            symtab.extend((strtab.len() as u32).to_le_bytes());
+            symtab.extend(value.to_le_bytes());
+            symtab.extend([0; 4]);
+            // A global function in section 1
+            symtab.extend([0x12, 0, 1, 0]);
+            strtab.extend(name.bytes().chain([0]));
+        }
+
+        let phoff = EHDR_SIZE;
+        let code_offset = phoff + 2 * PHDR_SIZE;
+        let data_offset = code_offset + code.len();
+        let symtab_offset = data_offset + data.len();
+        let strtab_offset = symtab_offset + symtab.len();
+        let shoff = strtab_offset + strtab.len();
+
+        let mut elf = vec![];
+        elf.extend(MAGIC);
+        elf.extend([CLASS_32, DATA_LE, 1]);
+        elf.resize(16, 0);
+        for half in [ET_EXEC, EM_RISCV] {
+            elf.extend(half.to_le_bytes());
+        }
+        for word in [1, TEXT as u32, phoff as u32, shoff as u32, 0] {
+            elf.extend(word.to_le_bytes());
+        }
+        for half in [EHDR_SIZE, PHDR_SIZE, 2, SHDR_SIZE, 3, 0] {
+            elf.extend((half as u16).to_le_bytes());
+        }
+        let code_len = code.len() as u32;
+        for phdr in [
+            [
+                PT_LOAD,
+                code_offset as u32,
+                TEXT as u32,
+                TEXT as u32,
+                code_len,
+                code_len,
+                5,
+                4,
+            ],
+            [
+                PT_LOAD,
+                data_offset as u32,
+                DATA as u32,
+                DATA as u32,
+                4,
+                8,
+                6,
+                4,
+            ],
+        ] {
+            elf.extend(phdr.iter().flat_map(|word| word.to_le_bytes()));
+        }
+        elf.extend(code);
+        elf.extend(data);
+        elf.extend(&symtab);
+        elf.extend(&strtab);
+        for shdr in [
+            [0; 10],
+            [
+                0,
+                SHT_SYMTAB,
+                0,
+                0,
+                symtab_offset as u32,
+                symtab.len() as u32,
+                2,
+                1,
+                4,
+                16,
+            ],
+            [
+                0,
+                3,
+                0,
+                0,
+                strtab_offset as u32,
+                strtab.len() as u32,
+                0,
+                0,
+                1,
+                0,
+            ],
+        ] {
+            elf.extend(shdr.iter().flat_map(|word| word.to_le_bytes()));
+        }
+        elf
+    }
+
+    #[test]
+    fn load() {
+        let program = indoc! {"
+            lui t0, 0x20
+            lw a1, 0(t0)
+            lw a2, 4(t0)
+            addi a0, x0, 5
+            jal ra, double
+            j end
+            double:
+            add a0, a0, a0
+            ret
+            end:
+        "};
+        let mut program = program.parse::<Program>().unwrap();
+        program.rebase(TEXT);
+        let code = program.image_at(TEXT).unwrap();
+        let elf = executable(&code, &[("_start", TEXT as u32), ("double", 0x10018)]);
+        let parsed = Elf::parse(&elf).unwrap();
+        assert_eq!(parsed.entry, TEXT);
+        assert_eq!(parsed.text(), Ok(TEXT..TEXT + code.len() as i64));
+        assert_eq!(parsed.symbols.get("double"), Some(&0x10018));
+
+        let mut exec = Executor::from_elf(&elf, Default::default()).unwrap();
+        assert_eq!(exec.pc(), TEXT);
+        assert_eq!(exec.program().label("_start"), Some(TEXT));
+        exec.run().unwrap();
+        assert_eq!(exec.regfile[Register::a0], 10);
+        assert_eq!(exec.regfile[Register::a1], 0x04030201);
+        // .bss is zeroed
+        assert_eq!(exec.regfile[Register::a2], 0);
+        assert_eq!(exec.memory.load(DATA + 4, LoadOp::Lw).unwrap(), 0);
+    }
+
+    #[test]
+    fn malformed() {
+        let elf = executable(&[0x13, 0, 0, 0], &[("_start", TEXT as u32)]);
+        let with = |offset: usize, bytes: &[u8]| {
+            let mut elf = elf.clone();
+            elf[offset..offset + bytes.len()].copy_from_slice(bytes);
+            Elf::parse(&elf)
+        };
+        assert_eq!(Elf::parse(b"#!/bin/sh"), Err(ElfError::Magic));
+        assert_eq!(with(4, &[2]), Err(ElfError::Class));
+        assert_eq!(with(5, &[2]), Err(ElfError::Endianness));
+        assert_eq!(with(18, &62u16.to_le_bytes()), Err(ElfError::Machine(62)));
+        assert_eq!(with(16, &1u16.to_le_bytes()), Err(ElfError::Type(1)));
+        // The text segment's offset is past the end of the file
+        assert_eq!(
+            with(EHDR_SIZE + 4, &u32::MAX.to_le_bytes()),
+            Err(ElfError::OutOfBounds("segment"))
+        );
+        // And its size in the file is larger than in memory
+        assert_eq!(
+            with(EHDR_SIZE + 16, &8u32.to_le_bytes()),
+            Err(ElfError::SegmentSize(0))
+        );
+        // Or its size in memory is too large to load
+        assert_eq!(
+            with(EHDR_SIZE + 20, &u32::MAX.to_le_bytes()),
+            Err(ElfError::SegmentTooLarge(0))
+        );
+        assert_eq!(
+            with(EHDR_SIZE + 20, &0x1000_0000u32.to_le_bytes()),
+            Err(ElfError::SegmentTooLarge(0))
+        );
+        // Or runs past the end of the address space
+        assert_eq!(Elf::parse(&elf).unwrap().segments[0].size, 4);
+        assert_eq!(
+            with(EHDR_SIZE + 8, &0xffff_fffeu32.to_le_bytes()),
+            Err(ElfError::SegmentTooLarge(0))
+        );
+
+        // Every truncation fails without panicking
+        for len in 0..elf.len() {
+            assert!(Elf::parse(&elf[..len]).is_err());
+        }
+    }
+
+    #[test]
+    fn pseudo_instructions() {
+        let program = indoc! {"
+            _start:
+            lui t0, 0x20
+            lw a0, 0(t0)
+            call double
+            la t1, double
+            jalr ra, 0(t1)
+            li a1, 0x12345678
+            j end
+            double:
+            add a0, a0, a0
+            ret
+            end:
+        "}
+        .parse::<Program>()
+        .unwrap();
+        let kind = ElfKind::Executable {
+            text: TEXT,
+            data: DATA,
+        };
+        let elf = program.elf(kind, &[5, 0, 0, 0]).unwrap();
+        let parsed = Elf::parse(&elf).unwrap();
+        assert_eq!(parsed.text(), Ok(TEXT..TEXT + 48));
+        assert_eq!(parsed.symbols.get("double"), Some(&(TEXT as usize + 40)));
+
+        let mut exec = Executor::from_elf(&elf, Default::default()).unwrap();
+        exec.run().unwrap();
+        assert_eq!(exec.regfile[Register::a0], 20);
+        assert_eq!(exec.regfile[Register::a1], 0x12345678);
+        assert_eq!(exec.regfile[Register::t1], TEXT + 40);
+
+        // Object files need no relocations for them, since they're relative
+        let object = program.elf(ElfKind::Relocatable, &[]).unwrap();
+        let image = program.image().unwrap();
+        let text = align(EHDR_SIZE);
+        assert_eq!(object[text..text + image.len()], image);
+    }
+
+    #[test]
+    fn write() {
+        let program = indoc! {"
+            lui t0, 0x20
+            lw a0, 0(t0)
+            call double
+            j end
+            _start:
+            addi a0, x0, 3
+            j double
+            double:
+            add a0, a0, a0
+            ret
+            end:
+        "}
+        .parse::<Program>()
+        .unwrap();
+        let kind = ElfKind::Executable {
+            text: TEXT,
+            data: DATA,
+        };
+        let elf = program.elf(kind, &[21, 0, 0, 0]).unwrap();
+        let parsed = Elf::parse(&elf).unwrap();
+        assert_eq!(parsed.entry, TEXT + 20);
+        assert_eq!(parsed.text(), Ok(TEXT..TEXT + 36));
+        assert_eq!(parsed.segments[1].addr, DATA);
+        assert_eq!(parsed.symbols.get("double"), Some(&0x1001c));
+
+        // It runs from _start
+        let mut exec = Executor::from_elf(&elf, Default::default()).unwrap();
+        exec.run().unwrap();
+        assert_eq!(exec.regfile[Register::a0], 6);
+        assert_eq!(exec.memory.load(DATA, LoadOp::Lw).unwrap(), 21);
+
+        // Object files aren't executables, and have no program headers
+        let object = program.elf(ElfKind::Relocatable, &[]).unwrap();
+        assert_eq!(Elf::parse(&object), Err(ElfError::Type(ET_REL)));
+        assert_eq!(object[44..46], [0, 0]);
+
+        let kind = ElfKind::Executable {
+            text: TEXT,
+            data: DATA + 2,
+        };
+        assert_eq!(program.elf(kind, &[]), Err(WriteError::Unaligned(DATA + 2)));
+    }
+}
@@ -0,0 +1,1127 @@
+//! Encoding instructions as 32-bit machine code, or 16-bit for compressed
+//! instructions.
+//!
+//! Most pseudo-instructions are encoded as the single base instruction they
+//! stand for, like `mv` as `addi`. The ones that need two, like `call` as
+//! `auipc` and `jalr`, already take up 8 bytes when the parser lays out the
+//! labels (see [`Instruction::size`]), so they're expanded in place without
+//! moving anything.
+
+use thiserror::Error;
+
+use crate::{
+    compress::{self, ImmediateLayout},
+    parse::{
+        AmoOp, BranchOp, BranchZeroOp, CompressedOp, CsrImmOp, CsrRegOp, CustomOperands,
+        ElementWidth, FCompareOp, FFusedOp, FRegRegOp, FToIntOp, Instruction, IntToFOp, LoadImmOp,
+        LoadOp, Program, RegImmOp, RegRegOp, Register, RoundingMode, StoreOp, UnaryOp, VArithOp,
+        VCompareOp, VMaskOp, VOperand, VReduceOp,
+    },
+};
+
+const LOAD: u32 = 0b0000011;
+const LOAD_FP: u32 = 0b0000111;
+const OP_IMM: u32 = 0b0010011;
+const STORE: u32 = 0b0100011;
+const STORE_FP: u32 = 0b0100111;
+const AMO: u32 = 0b0101111;
+const OP: u32 = 0b0110011;
+const LUI: u32 = 0b0110111;
+const AUIPC: u32 = 0b0010111;
+const OP_FP: u32 = 0b1010011;
+const OP_V: u32 = 0b1010111;
+const BRANCH: u32 = 0b1100011;
+const JALR: u32 = 0b1100111;
+const JAL: u32 = 0b1101111;
+const SYSTEM: u32 = 0b1110011;
+
+/// An error encoding an instruction.
+#[derive(Debug, Error, PartialEq, Eq)]
+#[error("{error} (pc {pc:#010x})")]
+pub struct EncodeError {
+    /// The pc of the instruction that couldn't be encoded
+    pc: i64,
+
+    error: EncodeErrorInner,
+}
+
+impl EncodeError {
+    /// The pc of the instruction that couldn't be encoded.
+    pub fn pc(&self) -> i64 {
+        self.pc
+    }
+
+    pub fn error(&self) -> &EncodeErrorInner {
+        &self.error
+    }
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum EncodeErrorInner {
+    #[error("undefined label <{0}>")]
+    UndefinedLabel(String),
+
+    /// An immediate, offset or label that doesn't fit in its field.
+    #[error("{field} {val} doesn't fit in {instr}")]
+    OutOfRange {
+        instr: Instruction,
+        field: &'static str,
+        val: i64,
+    },
+
+    #[error("{0} only exists in RV64")]
+    Rv64Only(Instruction),
+
+    /// A combination of operands the parser accepts but the ISA has no
+    /// encoding for, like `vmslt.vi`.
+    #[error("{0} has no encoding")]
+    NoEncoding(Instruction),
+}
+
+fn r_type(funct7: u32, rs2: u32, rs1: u32, funct3: u32, rd: u32, opcode: u32) -> u32 {
+    funct7 << 25 | rs2 << 20 | rs1 << 15 | funct3 << 12 | rd << 7 | opcode
+}
+
+fn i_type(imm: u32, rs1: u32, funct3: u32, rd: u32, opcode: u32) -> u32 {
+    (imm & 0xfff) << 20 | rs1 << 15 | funct3 << 12 | rd << 7 | opcode
+}
+
+fn s_type(imm: u32, rs2: u32, rs1: u32, funct3: u32, opcode: u32) -> u32 {
+    let (hi, lo) = (imm >> 5 & 0x7f, imm & 0x1f);
+    hi << 25 | rs2 << 20 | rs1 << 15 | funct3 << 12 | lo << 7 | opcode
+}
+
+fn b_type(offset: u32, rs2: u32, rs1: u32, funct3: u32) -> u32 {
+    let bit = |n: u32| offset >> n & 1;
+    let hi = bit(12) << 6 | (offset >> 5 & 0x3f);
+    let lo = (offset >> 1 & 0xf) << 1 | bit(11);
+    hi << 25 | rs2 << 20 | rs1 << 15 | funct3 << 12 | lo << 7 | BRANCH
+}
+
+fn u_type(imm: u32, rd: u32, opcode: u32) -> u32 {
+    (imm & 0xfffff) << 12 | rd << 7 | opcode
+}
+
+fn j_type(offset: u32, rd: u32) -> u32 {
+    let imm = (offset >> 20 & 1) << 19
+        | (offset >> 1 & 0x3ff) << 9
+        | (offset >> 11 & 1) << 8
+        | (offset >> 12 & 0xff);
+    imm << 12 | rd << 7 | JAL
+}
+
+/// The vector arithmetic formats, as the funct3 field.
+fn vector_funct3(operand: VOperand, mask_unit: bool) -> u32 {
+    match (operand, mask_unit) {
+        // OPIVV, OPIVX and OPIVI
+        (VOperand::Vector(_), false) => 0b000,
+        (VOperand::Immediate(_), false) => 0b011,
+        (VOperand::Scalar(_), false) => 0b100,
+        // OPMVV and OPMVX
+        (VOperand::Vector(_), true) => 0b010,
+        (VOperand::Scalar(_), true) => 0b110,
+        (VOperand::Immediate(_), true) => unreachable!("OPM instructions have no immediate form"),
+    }
+}
+
+/// The width field of vector loads and stores.
+fn vector_width(width: ElementWidth) -> u32 {
+    match width {
+        ElementWidth::E8 => 0b000,
+        ElementWidth::E16 => 0b101,
+        ElementWidth::E32 => 0b110,
+        ElementWidth::E64 => 0b111,
+    }
+}
+
+/// Split `val` into the upper 20 bits for `lui` or `auipc` and the lower 12
+/// for `addi` or `jalr`. The lower bits are sign extended, so the upper ones
+/// are rounded to make up for it.
+fn split_immediate(val: i64) -> (i32, i32) {
+    let lo = (val as i32) << 20 >> 20;
+    let hi = ((val - lo as i64) >> 12) as i32 & 0xfffff;
+    (hi, lo)
+}
+
+/// The base instructions `li` stands for when `imm` doesn't fit in 32 bits,
+/// which only happens in RV64. The upper bits are loaded first, with as few
+/// as possible set, and shifted into place before adding the lower 12 bits.
+pub(crate) fn load_immediate_64(rd: Register, imm: i64) -> Vec<Instruction> {
+    let reg_imm = |r1, imm, op| Instruction::RegImm { rd, r1, imm, op };
+    let lo = imm << 52 >> 52;
+    // The upper bits wrap around at the top, but the shift drops them again
+    let upper = imm.wrapping_sub(lo);
+    let shift = upper.trailing_zeros();
+    let upper = upper >> shift;
+    let mut instrs = match i32::try_from(upper) {
+        Ok(upper) if (-2048..2048).contains(&upper) => {
+            vec![reg_imm(Register::x0, upper, RegImmOp::Addi)]
+        }
+        Ok(upper) => {
+            let (upper_hi, upper_lo) = split_immediate(upper as i64);
+            let lui = Instruction::LoadImm {
+                rd,
+                imm: upper_hi as i64,
+                op: LoadImmOp::Lui,
+            };
+            // addiw keeps the sum sign extended from 32 bits, like lui
+            let mut instrs = vec![lui];
+            if upper_lo != 0 {
+                instrs.push(reg_imm(rd, upper_lo, RegImmOp::Addiw));
+            }
+            instrs
+        }
+        Err(_) => load_immediate_64(rd, upper),
+    };
+    instrs.push(reg_imm(rd, shift as i32, RegImmOp::Slli));
+    if lo != 0 {
+        instrs.push(reg_imm(rd, lo as i32, RegImmOp::Addi));
+    }
+    instrs
+}
+
+fn rounding_mode(rm: RoundingMode) -> u32 {
+    match rm {
+        RoundingMode::Rne => 0b000,
+        RoundingMode::Rtz => 0b001,
+        RoundingMode::Rdn => 0b010,
+        RoundingMode::Rup => 0b011,
+        RoundingMode::Rmm => 0b100,
+        RoundingMode::Dyn => 0b111,
+    }
+}
+
+/// Encodes a single instruction, knowing where it is so labels can be turned
+/// into offsets.
+struct Encoder<'a> {
+    instr: &'a Instruction,
+    pc: i64,
+    program: &'a Program,
+}
+
+type EncodeResult<T> = Result<T, EncodeErrorInner>;
+
+impl Encoder<'_> {
+    /// Check that `val` fits in a `bits`-bit signed field.
+    fn signed(&self, field: &'static str, val: i64, bits: u32) -> EncodeResult<u32> {
+        let half = 1 << (bits - 1);
+        if !(-half..half).contains(&val) {
+            Err(self.out_of_range(field, val))?
+        }
+        Ok(val as u32)
+    }
+
+    /// Check that `val` fits in a `bits`-bit unsigned field.
+    fn unsigned(&self, field: &'static str, val: i64, bits: u32) -> EncodeResult<u32> {
+        if !(0..1 << bits).contains(&val) {
+            Err(self.out_of_range(field, val))?
+        }
+        Ok(val as u32)
+    }
+
+    fn out_of_range(&self, field: &'static str, val: i64) -> EncodeErrorInner {
+        EncodeErrorInner::OutOfRange {
+            instr: self.instr.clone(),
+            field,
+            val,
+        }
+    }
+
+    fn target(&self, label: &str) -> EncodeResult<i64> {
+        self.program
+            .label(label)
+            .ok_or_else(|| EncodeErrorInner::UndefinedLabel(label.to_string()))
+    }
+
+    /// The offset from this instruction to `label`, which must fit in a
+    /// `bits`-bit signed field.
+    fn offset(&self, label: &str, bits: u32) -> EncodeResult<u32> {
+        self.signed("offset", self.target(label)? - self.pc, bits)
+    }
+
+    /// The base instructions a pseudo-instruction that takes up more than 4
+    /// bytes stands for, or `None` for any other instruction.
+    fn expand(&self) -> EncodeResult<Option<Vec<Instruction>>> {
+        use Register::ra;
+
+        if self.instr.size() <= 4 {
+            return Ok(None);
+        }
+        let upper = |rd, imm: i32, op| Instruction::LoadImm {
+            rd,
+            imm: imm.into(),
+            op,
+        };
+        let addi = |rd, imm| Instruction::RegImm {
+            rd,
+            r1: rd,
+            imm,
+            op: RegImmOp::Addi,
+        };
+        let expanded = match self.instr {
+            Instruction::LoadImm { rd, imm, .. } if i32::try_from(*imm).is_err() => {
+                load_immediate_64(*rd, *imm)
+            }
+            Instruction::LoadImm { rd, imm, .. } => {
+                let (hi, lo) = split_immediate(*imm);
+                vec![upper(*rd, hi, LoadImmOp::Lui), addi(*rd, lo)]
+            }
+            Instruction::la { rd, label } => {
+                let (hi, lo) = split_immediate(self.target(label)? - self.pc);
+                vec![upper(*rd, hi, LoadImmOp::Auipc), addi(*rd, lo)]
+            }
+            Instruction::call { label } => {
+                let (hi, lo) = split_immediate(self.target(label)? - self.pc);
+                let jalr = Instruction::jalr {
+                    rd: ra,
+                    offset: lo,
+                    r1: ra,
+                };
+                vec![upper(ra, hi, LoadImmOp::Auipc), jalr]
+            }
+            instr => unreachable!("{instr} takes up {} bytes", instr.size()),
+        };
+        Ok(Some(expanded))
+    }
+
+    fn no_encoding(&self) -> EncodeErrorInner {
+        EncodeErrorInner::NoEncoding(self.instr.clone())
+    }
+
+    fn encode(&self) -> EncodeResult<u32> {
+        use Register::x0;
+
+        let instr = self.instr;
+        if instr.is_rv64_only() {
+            Err(EncodeErrorInner::Rv64Only(instr.clone()))?
+        }
+        let encoded = match instr {
+            Instruction::RegImm { rd, r1, imm, op } => {
+                use RegImmOp::*;
+                let (rd, rs1, imm) = (rd.number(), r1.number(), *imm as i64);
+                let shift = |funct7: u32, funct3| -> EncodeResult<u32> {
+                    let shamt = self.unsigned("shift amount", imm, 5)?;
+                    Ok(r_type(funct7, shamt, rs1, funct3, rd, OP_IMM))
+                };
+                let funct3 = match op {
+                    Addi => 0b000,
+                    Slti => 0b010,
+                    Sltiu => 0b011,
+                    Xori => 0b100,
+                    Ori => 0b110,
+                    Andi => 0b111,
+                    Slli => return shift(0b0000000, 0b001),
+                    Srli => return shift(0b0000000, 0b101),
+                    Srai => return shift(0b0100000, 0b101),
+                    Rori => return shift(0b0110000, 0b101),
+                    Bseti => return shift(0b0010100, 0b001),
+                    Bclri => return shift(0b0100100, 0b001),
+                    Binvi => return shift(0b0110100, 0b001),
+                    Bexti => return shift(0b0100100, 0b101),
+                    Addiw | Slliw | Srliw | Sraiw => unreachable!("RV64-only"),
+                };
+                i_type(self.signed("immediate", imm, 12)?, rs1, funct3, rd, OP_IMM)
+            }
+            Instruction::RegReg { rd, r1, r2, op } => {
+                use RegRegOp::*;
+                let (funct7, funct3) = match op {
+                    Add => (0b0000000, 0b000),
+                    Sub => (0b0100000, 0b000),
+                    Sll => (0b0000000, 0b001),
+                    Slt => (0b0000000, 0b010),
+                    Sltu => (0b0000000, 0b011),
+                    Xor => (0b0000000, 0b100),
+                    Srl => (0b0000000, 0b101),
+                    Sra => (0b0100000, 0b101),
+                    Or => (0b0000000, 0b110),
+                    And => (0b0000000, 0b111),
+                    Sh1add => (0b0010000, 0b010),
+                    Sh2add => (0b0010000, 0b100),
+                    Sh3add => (0b0010000, 0b110),
+                    Min => (0b0000101, 0b100),
+                    Minu => (0b0000101, 0b101),
+                    Max => (0b0000101, 0b110),
+                    Maxu => (0b0000101, 0b111),
+                    Rol => (0b0110000, 0b001),
+                    Ror => (0b0110000, 0b101),
+                    Andn => (0b0100000, 0b111),
+                    Orn => (0b0100000, 0b110),
+                    Xnor => (0b0100000, 0b100),
+                    Bset => (0b0010100, 0b001),
+                    Bclr => (0b0100100, 0b001),
+                    Binv => (0b0110100, 0b001),
+                    Bext => (0b0100100, 0b101),
+                    Addw | Subw | Sllw | Srlw | Sraw => unreachable!("RV64-only"),
+                };
+                r_type(funct7, r2.number(), r1.number(), funct3, rd.number(), OP)
+            }
+            Instruction::Load { rd, offset, r1, op } => {
+                let funct3 = match op {
+                    LoadOp::Lb => 0b000,
+                    LoadOp::Lh => 0b001,
+                    LoadOp::Lw => 0b010,
+                    LoadOp::Lbu => 0b100,
+                    LoadOp::Lhu => 0b101,
+                    LoadOp::Ld | LoadOp::Lwu => unreachable!("RV64-only"),
+                };
+                let offset = self.signed("offset", *offset as i64, 12)?;
+                i_type(offset, r1.number(), funct3, rd.number(), LOAD)
+            }
+            Instruction::Store { r2, offset, r1, op } => {
+                let funct3 = match op {
+                    StoreOp::Sb => 0b000,
+                    StoreOp::Sh => 0b001,
+                    StoreOp::Sw => 0b010,
+                    StoreOp::Sd => unreachable!("RV64-only"),
+                };
+                let offset = self.signed("offset", *offset as i64, 12)?;
+                s_type(offset, r2.number(), r1.number(), funct3, STORE)
+            }
+            Instruction::Branch { r1, r2, label, op } => {
+                use BranchOp::*;
+                // The greater-than branches swap their operands
+                let (r1, r2) = match op {
+                    Bgt | Ble | Bgtu | Bleu => (r2, r1),
+                    _ => (r1, r2),
+                };
+                let funct3 = match op {
+                    Beq => 0b000,
+                    Bne => 0b001,
+                    Blt | Bgt => 0b100,
+                    Bge | Ble => 0b101,
+                    Bltu | Bgtu => 0b110,
+                    Bgeu | Bleu => 0b111,
+                };
+                b_type(self.offset(label, 13)?, r2.number(), r1.number(), funct3)
+            }
+            Instruction::BranchZero { r1, label, op } => {
+                use BranchZeroOp::*;
+                let (r1, r2, funct3) = match op {
+                    Beqz => (*r1, x0, 0b000),
+                    Bnez => (*r1, x0, 0b001),
+                    Bltz => (*r1, x0, 0b100),
+                    Bgez => (*r1, x0, 0b101),
+                    Bgtz => (x0, *r1, 0b100),
+                    Blez => (x0, *r1, 0b101),
+                };
+                b_type(self.offset(label, 13)?, r2.number(), r1.number(), funct3)
+            }
+            Instruction::LoadImm { rd, imm, op } => {
+                let (rd, imm) = (rd.number(), *imm);
+                match op {
+                    LoadImmOp::Lui => u_type(self.unsigned("immediate", imm, 20)?, rd, LUI),
+                    LoadImmOp::Auipc => u_type(self.unsigned("immediate", imm, 20)?, rd, AUIPC),
+                    // One instruction is enough if either the lower or upper
+                    // bits are zero, otherwise it was expanded
+                    LoadImmOp::Li if (-2048..2048).contains(&imm) => {
+                        i_type(imm as u32, 0, 0b000, rd, OP_IMM)
+                    }
+                    LoadImmOp::Li => u_type((imm >> 12) as u32, rd, LUI),
+                }
+            }
+            Instruction::Unary { rd, r1, op } => {
+                use UnaryOp::*;
+                let (rd, rs1) = (rd.number(), r1.number());
+                // The Zbb unary instructions pick the operation with rs2
+                let zbb = |rs2: u32| r_type(0b0110000, rs2, rs1, 0b001, rd, OP_IMM);
+                match op {
+                    Mv => i_type(0, rs1, 0b000, rd, OP_IMM),
+                    Not => i_type(-1i32 as u32, rs1, 0b100, rd, OP_IMM),
+                    Neg => r_type(0b0100000, rs1, 0, 0b000, rd, OP),
+                    Clz => zbb(0b00000),
+                    Ctz => zbb(0b00001),
+                    Cpop => zbb(0b00010),
+                    SextB => zbb(0b00100),
+                    SextH => zbb(0b00101),
+                    ZextH => r_type(0b0000100, 0, rs1, 0b100, rd, OP),
+                    Rev8 => i_type(0b011010011000, rs1, 0b101, rd, OP_IMM),
+                    OrcB => i_type(0b001010000111, rs1, 0b101, rd, OP_IMM),
+                    NegW | SextW => unreachable!("RV64-only"),
+                }
+            }
+            Instruction::LoadReserved { rd, r1 } => {
+                r_type(0b0001000, 0, r1.number(), 0b010, rd.number(), AMO)
+            }
+            Instruction::StoreConditional { rd, r2, r1 } => {
+                r_type(0b0001100, r2.number(), r1.number(), 0b010, rd.number(), AMO)
+            }
+            Instruction::Amo { rd, r2, r1, op } => {
+                let funct5 = match op {
+                    AmoOp::Add => 0b00000,
+                    AmoOp::Swap => 0b00001,
+                    AmoOp::Xor => 0b00100,
+                    AmoOp::Or => 0b01000,
+                    AmoOp::And => 0b01100,
+                    AmoOp::Min => 0b10000,
+                    AmoOp::Max => 0b10100,
+                    AmoOp::Minu => 0b11000,
+                    AmoOp::Maxu => 0b11100,
+                };
+                // The aq and rl bits are always clear
+                r_type(
+                    funct5 << 2,
+                    r2.number(),
+                    r1.number(),
+                    0b010,
+                    rd.number(),
+                    AMO,
+                )
+            }
+            Instruction::FLoad { rd, offset, r1 } => {
+                let offset = self.signed("offset", *offset as i64, 12)?;
+                i_type(offset, r1.number(), 0b010, *rd as u32, LOAD_FP)
+            }
+            Instruction::FStore { r2, offset, r1 } => {
+                let offset = self.signed("offset", *offset as i64, 12)?;
+                s_type(offset, *r2 as u32, r1.number(), 0b010, STORE_FP)
+            }
+            Instruction::FRegReg { rd, r1, r2, op, rm } => {
+                use FRegRegOp::*;
+                let (funct7, funct3) = match op {
+                    Add => (0b0000000, rounding_mode(*rm)),
+                    Sub => (0b0000100, rounding_mode(*rm)),
+                    Mul => (0b0001000, rounding_mode(*rm)),
+                    Div => (0b0001100, rounding_mode(*rm)),
+                    Sgnj => (0b0010000, 0b000),
+                    Sgnjn => (0b0010000, 0b001),
+                    Sgnjx => (0b0010000, 0b010),
+                    Min => (0b0010100, 0b000),
+                    Max => (0b0010100, 0b001),
+                };
+                r_type(funct7, *r2 as u32, *r1 as u32, funct3, *rd as u32, OP_FP)
+            }
+            Instruction::FSqrt { rd, r1, rm } => r_type(
+                0b0101100,
+                0,
+                *r1 as u32,
+                rounding_mode(*rm),
+                *rd as u32,
+                OP_FP,
+            ),
+            Instruction::FFused {
+                rd,
+                r1,
+                r2,
+                r3,
+                op,
+                rm,
+            } => {
+                let opcode = match op {
+                    FFusedOp::Madd => 0b1000011,
+                    FFusedOp::Msub => 0b1000111,
+                    FFusedOp::Nmsub => 0b1001011,
+                    FFusedOp::Nmadd => 0b1001111,
+                };
+                // The low two bits of funct7 are the format, which is 0 for
+                // single precision
+                let funct7 = (*r3 as u32) << 2;
+                r_type(
+                    funct7,
+                    *r2 as u32,
+                    *r1 as u32,
+                    rounding_mode(*rm),
+                    *rd as u32,
+                    opcode,
+                )
+            }
+            Instruction::FCompare { rd, r1, r2, op } => {
+                let funct3 = match op {
+                    FCompareOp::Le => 0b000,
+                    FCompareOp::Lt => 0b001,
+                    FCompareOp::Eq => 0b010,
+                };
+                r_type(
+                    0b1010000,
+                    *r2 as u32,
+                    *r1 as u32,
+                    funct3,
+                    rd.number(),
+                    OP_FP,
+                )
+            }
+            Instruction::FClass { rd, r1 } => {
+                r_type(0b1110000, 0, *r1 as u32, 0b001, rd.number(), OP_FP)
+            }
+            Instruction::FToInt { rd, r1, op, rm } => {
+                let (funct7, rs2, funct3) = match op {
+                    FToIntOp::CvtW => (0b1100000, 0, rounding_mode(*rm)),
+                    FToIntOp::CvtWu => (0b1100000, 1, rounding_mode(*rm)),
+                    FToIntOp::MvXW => (0b1110000, 0, 0b000),
+                };
+                r_type(funct7, rs2, *r1 as u32, funct3, rd.number(), OP_FP)
+            }
+            Instruction::IntToF { rd, r1, op, rm } => {
+                let (funct7, rs2, funct3) = match op {
+                    IntToFOp::CvtSW => (0b1101000, 0, rounding_mode(*rm)),
+                    IntToFOp::CvtSWu => (0b1101000, 1, rounding_mode(*rm)),
+                    IntToFOp::MvWX => (0b1111000, 0, 0b000),
+                };
+                r_type(funct7, rs2, r1.number(), funct3, *rd as u32, OP_FP)
+            }
+            Instruction::CsrReg { rd, csr, r1, op } => {
+                let funct3 = match op {
+                    CsrRegOp::Rw => 0b001,
+                    CsrRegOp::Rs => 0b010,
+                    CsrRegOp::Rc => 0b011,
+                };
+                i_type(
+                    csr.number() as u32,
+                    r1.number(),
+                    funct3,
+                    rd.number(),
+                    SYSTEM,
+                )
+            }
+            Instruction::CsrImm { rd, csr, imm, op } => {
+                let funct3 = match op {
+                    CsrImmOp::Rwi => 0b101,
+                    CsrImmOp::Rsi => 0b110,
+                    CsrImmOp::Rci => 0b111,
+                };
+                let uimm = self.unsigned("immediate", *imm as i64, 5)?;
+                i_type(csr.number() as u32, uimm, funct3, rd.number(), SYSTEM)
+            }
+            Instruction::Vsetvli { rd, r1, vtype } => {
+                let zimm = vtype.bits() as u32;
+                i_type(zimm, r1.number(), 0b111, rd.number(), OP_V)
+            }
+            Instruction::VLoad {
+                vd,
+                r1,
+                stride,
+                width,
+                masked,
+            } => self.vector_memory(*vd as u32, r1, stride, *width, *masked, LOAD_FP),
+            Instruction::VStore {
+                vs3,
+                r1,
+                stride,
+                width,
+                masked,
+            } => self.vector_memory(*vs3 as u32, r1, stride, *width, *masked, STORE_FP),
+            Instruction::VArith {
+                vd,
+                vs2,
+                operand,
+                op,
+                masked,
+            } => {
+                use VArithOp::*;
+                let funct6 = match (op, operand) {
+                    (Add, _) => 0b000000,
+                    (Sub, VOperand::Vector(_) | VOperand::Scalar(_)) => 0b000010,
+                    (Mul, VOperand::Vector(_) | VOperand::Scalar(_)) => 0b100101,
+                    (And, _) => 0b001001,
+                    (Or, _) => 0b001010,
+                    (Xor, _) => 0b001011,
+                    (Sub | Mul, VOperand::Immediate(_)) => Err(self.no_encoding())?,
+                };
+                let funct3 = vector_funct3(*operand, *op == Mul);
+                let vs1 = self.vector_operand(*operand)?;
+                self.vector(funct6, *masked, *vs2 as u32, vs1, funct3, *vd as u32)
+            }
+            Instruction::VCompare {
+                vd,
+                vs2,
+                operand,
+                op,
+                masked,
+            } => {
+                use VCompareOp::*;
+                let vector = matches!(operand, VOperand::Vector(_));
+                let immediate = matches!(operand, VOperand::Immediate(_));
+                let funct6 = match op {
+                    Eq => 0b011000,
+                    Ne => 0b011001,
+                    Ltu if !immediate => 0b011010,
+                    Lt if !immediate => 0b011011,
+                    Leu => 0b011100,
+                    Le => 0b011101,
+                    Gtu if !vector => 0b011110,
+                    Gt if !vector => 0b011111,
+                    _ => Err(self.no_encoding())?,
+                };
+                let funct3 = vector_funct3(*operand, false);
+                let vs1 = self.vector_operand(*operand)?;
+                self.vector(funct6, *masked, *vs2 as u32, vs1, funct3, *vd as u32)
+            }
+            Instruction::VReduce {
+                vd,
+                vs2,
+                vs1,
+                op,
+                masked,
+            } => {
+                use VReduceOp::*;
+                let funct6 = match op {
+                    Sum => 0b000000,
+                    And => 0b000001,
+                    Or => 0b000010,
+                    Xor => 0b000011,
+                    Minu => 0b000100,
+                    Min => 0b000101,
+                    Maxu => 0b000110,
+                    Max => 0b000111,
+                };
+                self.vector(funct6, *masked, *vs2 as u32, *vs1 as u32, 0b010, *vd as u32)
+            }
+            Instruction::VMask { vd, vs2, vs1, op } => {
+                let funct6 = match op {
+                    VMaskOp::Andn => 0b011000,
+                    VMaskOp::And => 0b011001,
+                    VMaskOp::Or => 0b011010,
+                    VMaskOp::Xor => 0b011011,
+                    VMaskOp::Nand => 0b011101,
+                };
+                self.vector(funct6, false, *vs2 as u32, *vs1 as u32, 0b010, *vd as u32)
+            }
+            Instruction::VMove { vd, operand } => {
+                let funct3 = vector_funct3(*operand, false);
+                let vs1 = self.vector_operand(*operand)?;
+                self.vector(0b010111, false, 0, vs1, funct3, *vd as u32)
+            }
+            Instruction::VMoveToScalar { rd, vs2 } => {
+                self.vector(0b010000, false, *vs2 as u32, 0, 0b010, rd.number())
+            }
+            Instruction::VMoveFromScalar { vd, r1 } => {
+                self.vector(0b010000, false, 0, r1.number(), 0b110, *vd as u32)
+            }
+            Instruction::VCpop { rd, vs2, masked } => {
+                self.vector(0b010000, *masked, *vs2 as u32, 0b10000, 0b010, rd.number())
+            }
+            Instruction::Vid { vd, masked } => {
+                self.vector(0b010100, *masked, 0, 0b10001, 0b010, *vd as u32)
+            }
+            Instruction::jal { rd, label } => j_type(self.offset(label, 21)?, rd.number()),
+            Instruction::jalr { rd, offset, r1 } => {
+                let offset = self.signed("offset", *offset as i64, 12)?;
+                i_type(offset, r1.number(), 0b000, rd.number(), JALR)
+            }
+            Instruction::j { label } => j_type(self.offset(label, 21)?, 0),
+            Instruction::jr { rs } => i_type(0, rs.number(), 0b000, 0, JALR),
+            Instruction::ret {} => i_type(0, Register::ra.number(), 0b000, 0, JALR),
+            Instruction::mret {} => i_type(0b001100000010, 0, 0b000, 0, SYSTEM),
+            Instruction::sret {} => i_type(0b000100000010, 0, 0b000, 0, SYSTEM),
+            Instruction::ecall {} => i_type(0, 0, 0b000, 0, SYSTEM),
+            Instruction::sfence_vma { vaddr, asid } => {
+                r_type(0b0001001, asid.number(), vaddr.number(), 0b000, 0, SYSTEM)
+            }
+            Instruction::wfi {} => i_type(0b000100000101, 0, 0b000, 0, SYSTEM),
+            Instruction::Custom {
+                encoding, operands, ..
+            } => {
+                let opcode = encoding.opcode.bits();
+                let funct3 = encoding.funct3 as u32;
+                match *operands {
+                    CustomOperands::R { rd, r1, r2 } => r_type(
+                        encoding.funct7 as u32,
+                        r2.number(),
+                        r1.number(),
+                        funct3,
+                        rd.number(),
+                        opcode,
+                    ),
+                    CustomOperands::I { rd, r1, imm } => {
+                        let imm = self.signed("immediate", imm as i64, 12)?;
+                        i_type(imm, r1.number(), funct3, rd.number(), opcode)
+                    }
+                    CustomOperands::S { r2, offset, r1 } => {
+                        let offset = self.signed("offset", offset as i64, 12)?;
+                        s_type(offset, r2.number(), r1.number(), funct3, opcode)
+                    }
+                }
+            }
+            Instruction::Compressed { op, instr } => self.compressed(*op, instr)?,
+            Instruction::la { .. } | Instruction::call { .. } => {
+                unreachable!("{instr} is expanded")
+            }
+        };
+        Ok(encoded)
+    }
+
+    /// A 16-bit compressed instruction, from the base instruction it expands
+    /// to.
+    fn compressed(&self, op: CompressedOp, instr: &Instruction) -> EncodeResult<u32> {
+        use CompressedOp::*;
+        if !op.fits(instr) {
+            Err(self.no_encoding())?
+        }
+        // Registers in 3-bit fields are x8-x15
+        let creg = |reg: &Register| reg.number() - 8;
+        let c = |funct3: u32, imm: u32, layout: ImmediateLayout, regs: u32, quadrant: u32| {
+            funct3 << 13 | compress::scatter(imm, layout) | regs | quadrant
+        };
+        let none = [None; 11];
+        let encoded = match (op, instr) {
+            (Addi4spn, Instruction::RegImm { rd, imm, .. }) => {
+                c(0b000, *imm as u32, compress::CIW, creg(rd) << 2, 0b00)
+            }
+            (Lw, Instruction::Load { rd, offset, r1, .. }) => c(
+                0b010,
+                *offset as u32,
+                compress::CL,
+                creg(r1) << 7 | creg(rd) << 2,
+                0b00,
+            ),
+            (Sw, Instruction::Store { r2, offset, r1, .. }) => c(
+                0b110,
+                *offset as u32,
+                compress::CL,
+                creg(r1) << 7 | creg(r2) << 2,
+                0b00,
+            ),
+            (Nop, _) => c(0b000, 0, none, 0, 0b01),
+            (Addi | Li, Instruction::RegImm { rd, imm, .. }) => {
+                let funct3 = if op == Addi { 0b000 } else { 0b010 };
+                c(funct3, *imm as u32, compress::CI, rd.number() << 7, 0b01)
+            }
+            (Jal | J, instr) => {
+                let funct3 = if op == Jal { 0b001 } else { 0b101 };
+                let label = instr.label().expect("jumps have labels");
+                c(funct3, self.offset(label, 12)?, compress::CJ, 0, 0b01)
+            }
+            (Addi16sp, Instruction::RegImm { imm, .. }) => {
+                c(0b011, *imm as u32, compress::CI_SP, 2 << 7, 0b01)
+            }
+            (Lui, Instruction::LoadImm { rd, imm, .. }) => c(
+                0b011,
+                (*imm as u32) << 12,
+                compress::CI_LUI,
+                rd.number() << 7,
+                0b01,
+            ),
+            (Srli | Srai | Andi, Instruction::RegImm { rd, imm, .. }) => {
+                let funct2 = match op {
+                    Srli => 0b00,
+                    Srai => 0b01,
+                    _ => 0b10,
+                };
+                let regs = funct2 << 10 | creg(rd) << 7;
+                c(0b100, *imm as u32, compress::CI, regs, 0b01)
+            }
+            (Sub | Xor | Or | And, Instruction::RegReg { rd, r2, .. }) => {
+                let funct2 = match op {
+                    Sub => 0b00,
+                    Xor => 0b01,
+                    Or => 0b10,
+                    _ => 0b11,
+                };
+                let regs = 0b011 << 10 | creg(rd) << 7 | funct2 << 5 | creg(r2) << 2;
+                c(0b100, 0, none, regs, 0b01)
+            }
+            (Beqz | Bnez, Instruction::Branch { r1, label, .. }) => {
+                let funct3 = if op == Beqz { 0b110 } else { 0b111 };
+                let offset = self.offset(label, 9)?;
+                c(funct3, offset, compress::CB, creg(r1) << 7, 0b01)
+            }
+            (Slli, Instruction::RegImm { rd, imm, .. }) => {
+                c(0b000, *imm as u32, compress::CI, rd.number() << 7, 0b10)
+            }
+            (Lwsp, Instruction::Load { rd, offset, .. }) => c(
+                0b010,
+                *offset as u32,
+                compress::CI_LWSP,
+                rd.number() << 7,
+                0b10,
+            ),
+            (Jr, Instruction::jr { rs }) => c(0b100, 0, none, rs.number() << 7, 0b10),
+            (Jr, _) => c(0b100, 0, none, Register::ra.number() << 7, 0b10),
+            (Mv, Instruction::RegReg { rd, r2, .. }) => {
+                c(0b100, 0, none, rd.number() << 7 | r2.number() << 2, 0b10)
+            }
+            (Jalr, Instruction::jalr { r1, .. }) => {
+                c(0b100, 0, none, 1 << 12 | r1.number() << 7, 0b10)
+            }
+            (Add, Instruction::RegReg { rd, r2, .. }) => {
+                let regs = 1 << 12 | rd.number() << 7 | r2.number() << 2;
+                c(0b100, 0, none, regs, 0b10)
+            }
+            (Swsp, Instruction::Store { r2, offset, .. }) => {
+                c(0b110, *offset as u32, compress::CSS, r2.number() << 2, 0b10)
+            }
+            _ => unreachable!("{op} fits {instr}"),
+        };
+        Ok(encoded)
+    }
+
+    /// The vs1 field for each form of a vector instruction.
+    fn vector_operand(&self, operand: VOperand) -> EncodeResult<u32> {
+        match operand {
+            VOperand::Vector(reg) => Ok(reg as u32),
+            VOperand::Scalar(reg) => Ok(reg.number()),
+            VOperand::Immediate(imm) => Ok(self.signed("immediate", imm as i64, 5)? & 0x1f),
+        }
+    }
+
+    /// An OP-V instruction. The vm bit is set when the instruction is not
+    /// masked.
+    fn vector(&self, funct6: u32, masked: bool, vs2: u32, vs1: u32, funct3: u32, vd: u32) -> u32 {
+        r_type(funct6 << 1 | !masked as u32, vs2, vs1, funct3, vd, OP_V)
+    }
+
+    /// A unit-stride or strided vector load or store.
+    fn vector_memory(
+        &self,
+        vd: u32,
+        r1: &Register,
+        stride: &Option<Register>,
+        width: ElementWidth,
+        masked: bool,
+        opcode: u32,
+    ) -> u32 {
+        // mop is 0b10 for strided accesses, where rs2 holds the stride
+        let (mop, rs2) = match stride {
+            Some(stride) => (0b10, stride.number()),
+            None => (0b00, 0),
+        };
+        let funct7 = mop << 1 | !masked as u32;
+        r_type(funct7, rs2, r1.number(), vector_width(width), vd, opcode)
+    }
+}
+
+impl Instruction {
+    /// The base instructions the instruction is encoded as at `pc` in
+    /// `program`: two for pseudo-instructions that take up 8 bytes, like `la`
+    /// as `auipc` and `addi`, more for `li` with a 64-bit immediate, and
+    /// otherwise just the instruction itself.
+    pub fn expand(&self, pc: i64, program: &Program) -> Result<Vec<Instruction>, EncodeError> {
+        let encoder = Encoder {
+            instr: self,
+            pc,
+            program,
+        };
+        match encoder.expand() {
+            Ok(Some(instrs)) => Ok(instrs),
+            Ok(None) => Ok(vec![self.clone()]),
+            Err(error) => Err(EncodeError { pc, error }),
+        }
+    }
+
+    /// Encode the instruction as machine code, as if it were at `pc` in
+    /// `program`. There is a word for each instruction it expands to, see
+    /// [`Instruction::expand`].
+    pub fn encode(&self, pc: i64, program: &Program) -> Result<Vec<u32>, EncodeError> {
+        let addresses = (pc..).step_by(4);
+        self.expand(pc, program)?
+            .iter()
+            .zip(addresses)
+            .map(|(instr, pc)| {
+                let encoder = Encoder { instr, pc, program };
+                encoder.encode().map_err(|error| EncodeError { pc, error })
+            })
+            .collect()
+    }
+}
+
+impl Program {
+    /// Encode every instruction in the program, in order.
+    pub fn encode(&self) -> Result<Vec<u32>, EncodeError> {
+        self.encode_at(0)
+    }
+
+    /// Encode every instruction in the program with the first at `base`. The
+    /// labels must already be addresses from `base`, see [`Program::rebase`].
+    /// Compressed instructions only use the low 16 bits of their word.
+    pub fn encode_at(&self, base: i64) -> Result<Vec<u32>, EncodeError> {
+        let mut words = vec![];
+        for (addr, instr) in self.addresses().zip(&self.asm) {
+            words.extend(instr.encode(base + addr, self)?);
+        }
+        Ok(words)
+    }
+
+    /// The program as a little-endian byte image, starting at pc 0.
+    pub fn image(&self) -> Result<Vec<u8>, EncodeError> {
+        self.image_at(0)
+    }
+
+    /// The program as a little-endian byte image, starting at `base`, like
+    /// [`Program::encode_at`].
+    pub fn image_at(&self, base: i64) -> Result<Vec<u8>, EncodeError> {
+        let mut image = vec![];
+        for (addr, instr) in self.addresses().zip(&self.asm) {
+            let words = instr.encode(base + addr, self)?;
+            let bytes = words.iter().flat_map(|word| word.to_le_bytes());
+            image.extend(bytes.take(instr.size() as usize));
+        }
+        Ok(image)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use indoc::indoc;
+
+    use super::*;
+
+    fn encode(source: &str) -> Result<Vec<u32>, EncodeError> {
+        source.parse::<Program>().unwrap().encode()
+    }
+
+    #[test]
+    fn formats() {
+        // Checked against the GNU assembler
+        for (source, expected) in [
+            ("addi a0, zero, 1", 0x00100513),
+            ("add a0, a1, a2", 0x00c58533),
+            ("sw a1, 4(a0)", 0x00b52223),
+            ("lw a1, -4(sp)", 0xffc12583),
+            ("lui a0, 0x12345", 0x12345537),
+            ("slli a0, a0, 3", 0x00351513),
+            ("srai a0, a0, 3", 0x40355513),
+            ("ret", 0x00008067),
+            ("ecall", 0x00000073),
+            ("mret", 0x30200073),
+            ("csrrw t0, mscratch, t1", 0x340312f3),
+            ("lr.w a0, (a1)", 0x1005a52f),
+            ("fadd.s fa0, fa1, fa2", 0x00c5f553),
+            ("vsetvli a0, t0, e32, m1, ta, ma", 0x0d02f557),
+            ("vlse32.v v1, (a0), a1", 0x0ab56087),
+            ("vadd.vv v1, v2, v3", 0x022180d7),
+            ("mv a0, a1", 0x00058513),
+            ("li t0, -1", 0xfff00293),
+            ("li t0, 0x10000", 0x000102b7),
+        ] {
+            assert_eq!(encode(source).unwrap(), [expected], "{source}");
+        }
+    }
+
+    #[test]
+    fn labels() {
+        let program = indoc! {"
+            loop:
+            addi a0, a0, 1
+            bne a0, a1, loop
+            beqz a0, end
+            jal ra, loop
+            end:
+        "};
+        assert_eq!(
+            encode(program).unwrap(),
+            [0x00150513, 0xfeb51ee3, 0x00050463, 0xff5ff0ef]
+        );
+        let image = program.parse::<Program>().unwrap().image().unwrap();
+        assert_eq!(image[..8], [0x13, 0x05, 0x15, 0x00, 0xe3, 0x1e, 0xb5, 0xfe]);
+
+        // Things that don't fit in an instruction
+        let error = encode("addi a0, a0, 2048").unwrap_err();
+        assert_eq!(error.pc(), 0);
+        assert!(matches!(
+            error.error(),
+            EncodeErrorInner::OutOfRange {
+                field: "immediate",
+                val: 2048,
+                ..
+            }
+        ));
+        let error = encode("li a0, 1\nld a0, 0(a0)").unwrap_err();
+        assert_eq!(error.pc(), 4);
+        assert!(matches!(error.error(), EncodeErrorInner::Rv64Only(_)));
+    }
+
+    #[test]
+    fn expanded() {
+        let source = indoc! {"
+            li a0, 0x12345678
+            la a1, data
+            call func
+            li a2, -2049
+            li a3, 0x12345fff
+            func:
+            ret
+            data:
+        "};
+        let program = source.parse::<Program>().unwrap();
+        assert_eq!(program.label("func"), Some(40));
+        assert_eq!(
+            program.encode().unwrap(),
+            [
+                0x12345537, 0x67850513, // lui, addi
+                0x00000597, 0x02458593, // auipc, addi
+                0x00000097, 0x018080e7, // auipc, jalr
+                0xfffff637, 0x7ff60613, // lui, addi
+                0x123466b7, 0xfff68693, // lui, addi with a negative immediate
+                0x00008067,
+            ]
+        );
+
+        // Decoding gives back the base instructions
+        let decoded = Program::disassemble(&program.image().unwrap()).unwrap();
+        let decoded: Vec<_> = decoded.asm.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            decoded,
+            [
+                "lui a0, 74565",
+                "addi a0, a0, 1656",
+                "auipc a1, 0",
+                "addi a1, a1, 36",
+                "auipc ra, 0",
+                "jalr ra, 24(ra)",
+                "lui a2, 1048575",
+                "addi a2, a2, 2047",
+                "lui a3, 74566",
+                "addi a3, a3, -1",
+                "ret",
+            ]
+        );
+
+        // Far away labels need the upper bits
+        let far = format!("la a0, far\n{}far:", "addi a0, a0, 1\n".repeat(1024));
+        let words = encode(&far).unwrap();
+        assert_eq!(&words[..2], [0x00001517, 0x00850513]);
+    }
+
+    #[test]
+    fn compressed() {
+        let nops = |n| "c.nop\n".repeat(n);
+        // Checked against LLVM's assembler. Jumps are padded out with c.nop
+        // (0x0001) to check their offsets.
+        for (source, expected) in [
+            ("c.addi4spn a0, sp, 16".to_string(), 0x0808),
+            ("c.lw a1, 4(a0)".to_string(), 0x414c),
+            ("c.sw a1, 124(s0)".to_string(), 0xdc6c),
+            ("c.nop".to_string(), 0x0001),
+            ("c.addi a0, -1".to_string(), 0x157d),
+            ("x:\nc.jal x".to_string(), 0x2001),
+            ("c.li t0, 31".to_string(), 0x42fd),
+            ("c.addi16sp sp, -64".to_string(), 0x7139),
+            ("c.lui a0, 0xfffff".to_string(), 0x757d),
+            ("c.srli a0, 3".to_string(), 0x810d),
+            ("c.srai a5, 31".to_string(), 0x87fd),
+            ("c.andi s1, -32".to_string(), 0x9881),
+            ("c.sub a0, a1".to_string(), 0x8d0d),
+            ("c.xor a0, a1".to_string(), 0x8d2d),
+            ("c.or a0, a1".to_string(), 0x8d4d),
+            ("c.and a0, a1".to_string(), 0x8d6d),
+            (format!("x:\n{}c.j x", nops(1)), 0xbffd),
+            (format!("x:\n{}c.beqz a0, x", nops(128)), 0xd101),
+            (format!("c.bnez s1, x\n{}x:", nops(126)), 0xecfd),
+            ("c.slli ra, 31".to_string(), 0x00fe),
+            ("c.lwsp ra, 252(sp)".to_string(), 0x50fe),
+            ("c.jr ra".to_string(), 0x8082),
+            ("c.mv a0, t6".to_string(), 0x857e),
+            ("c.jalr t0".to_string(), 0x9282),
+            ("c.add a0, t6".to_string(), 0x957e),
+            ("c.swsp ra, 8(sp)".to_string(), 0xc406),
+        ] {
+            let words = encode(&source).unwrap();
+            let word = words
+                .iter()
+                .find(|&&word| word != 0x0001)
+                .unwrap_or(&0x0001);
+            assert_eq!(*word, expected, "{source}");
+        }
+
+        // Compressed instructions take up 2 bytes of the image
+        let program = "c.li a0, 1\naddi a0, a0, 1\nc.mv a1, a0".parse::<Program>();
+        assert_eq!(
+            program.unwrap().image().unwrap(),
+            [0x05, 0x45, 0x13, 0x05, 0x15, 0x00, 0xaa, 0x85]
+        );
+        let error = encode(&format!("c.beqz a0, x\n{}x:", nops(127))).unwrap_err();
+        assert!(matches!(
+            error.error(),
+            EncodeErrorInner::OutOfRange { val: 256, .. }
+        ));
+    }
+}
@@ -0,0 +1,137 @@
+//! Bit manipulation (Zba/Zbb/Zbs) operations that care about the register
+//! width.
+//!
+//! Values are passed in and returned sign extended, the same way they live in
+//! the register file. Bit indices and rotation amounts only use the low
+//! `log2(XLEN)` bits, like the hardware.
+
+use super::Xlen;
+
+/// Mask a bit index or rotation amount to the register width.
+fn index(shamt: u32, xlen: Xlen) -> u32 {
+    shamt & (xlen.bits() - 1)
+}
+
+pub fn rotate_left(val: i64, shamt: u32, xlen: Xlen) -> i64 {
+    let shamt = index(shamt, xlen);
+    match xlen {
+        Xlen::Rv32 => (val as u32).rotate_left(shamt) as i32 as i64,
+        Xlen::Rv64 => (val as u64).rotate_left(shamt) as i64,
+    }
+}
+
+pub fn rotate_right(val: i64, shamt: u32, xlen: Xlen) -> i64 {
+    let shamt = index(shamt, xlen);
+    match xlen {
+        Xlen::Rv32 => (val as u32).rotate_right(shamt) as i32 as i64,
+        Xlen::Rv64 => (val as u64).rotate_right(shamt) as i64,
+    }
+}
+
+/// Set a single bit (`bset`).
+pub fn set(val: i64, bit: u32, xlen: Xlen) -> i64 {
+    xlen.sext(val | (1 << index(bit, xlen)))
+}
+
+/// Clear a single bit (`bclr`).
+pub fn clear(val: i64, bit: u32, xlen: Xlen) -> i64 {
+    xlen.sext(val & !(1 << index(bit, xlen)))
+}
+
+/// Invert a single bit (`binv`).
+pub fn invert(val: i64, bit: u32, xlen: Xlen) -> i64 {
+    xlen.sext(val ^ (1 << index(bit, xlen)))
+}
+
+/// Extract a single bit (`bext`).
+pub fn extract(val: i64, bit: u32, xlen: Xlen) -> i64 {
+    (val >> index(bit, xlen)) & 1
+}
+
+pub fn leading_zeros(val: i64, xlen: Xlen) -> i64 {
+    match xlen {
+        Xlen::Rv32 => (val as u32).leading_zeros() as i64,
+        Xlen::Rv64 => (val as u64).leading_zeros() as i64,
+    }
+}
+
+pub fn trailing_zeros(val: i64, xlen: Xlen) -> i64 {
+    match xlen {
+        Xlen::Rv32 => (val as u32).trailing_zeros() as i64,
+        Xlen::Rv64 => (val as u64).trailing_zeros() as i64,
+    }
+}
+
+pub fn count_ones(val: i64, xlen: Xlen) -> i64 {
+    match xlen {
+        Xlen::Rv32 => (val as u32).count_ones() as i64,
+        Xlen::Rv64 => (val as u64).count_ones() as i64,
+    }
+}
+
+/// Reverse the order of the bytes in the register (`rev8`).
+pub fn reverse_bytes(val: i64, xlen: Xlen) -> i64 {
+    match xlen {
+        Xlen::Rv32 => (val as u32).swap_bytes() as i32 as i64,
+        Xlen::Rv64 => val.swap_bytes(),
+    }
+}
+
+/// Set every nonzero byte to `0xff` (`orc.b`).
+pub fn or_combine(val: i64, xlen: Xlen) -> i64 {
+    let bytes = val
+        .to_le_bytes()
+        .map(|byte| if byte == 0 { 0 } else { 0xff });
+    xlen.sext(i64::from_le_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotates() {
+        assert_eq!(rotate_left(0x80000001, 1, Xlen::Rv32), 3);
+        assert_eq!(rotate_right(3, 1, Xlen::Rv32), 0x80000001_u32 as i32 as i64);
+        // Only the low 5 bits of the amount are used
+        assert_eq!(rotate_left(1, 33, Xlen::Rv32), 2);
+        assert_eq!(rotate_right(1, 1, Xlen::Rv64), i64::MIN);
+        assert_eq!(rotate_left(1, 33, Xlen::Rv64), 1 << 33);
+    }
+
+    #[test]
+    fn single_bits() {
+        assert_eq!(set(0, 31, Xlen::Rv32), i32::MIN as i64);
+        assert_eq!(set(0, 31, Xlen::Rv64), 0x80000000);
+        assert_eq!(clear(-1, 0, Xlen::Rv32), -2);
+        assert_eq!(invert(i32::MIN as i64, 31, Xlen::Rv32), 0);
+        assert_eq!(extract(0b100, 2, Xlen::Rv32), 1);
+        assert_eq!(extract(-1, 63, Xlen::Rv64), 1);
+    }
+
+    #[test]
+    fn counts() {
+        assert_eq!(leading_zeros(1, Xlen::Rv32), 31);
+        assert_eq!(leading_zeros(1, Xlen::Rv64), 63);
+        assert_eq!(trailing_zeros(0, Xlen::Rv32), 32);
+        assert_eq!(trailing_zeros(0, Xlen::Rv64), 64);
+        assert_eq!(count_ones(-1, Xlen::Rv32), 32);
+        assert_eq!(count_ones(-1, Xlen::Rv64), 64);
+    }
+
+    #[test]
+    fn bytes() {
+        assert_eq!(reverse_bytes(0x11223344, Xlen::Rv32), 0x44332211);
+        assert_eq!(
+            reverse_bytes(0x11223380, Xlen::Rv32),
+            0x80332211_u32 as i32 as i64
+        );
+        assert_eq!(reverse_bytes(0x11223344, Xlen::Rv64), 0x4433221100000000);
+        assert_eq!(or_combine(0x00010200, Xlen::Rv32), 0x00ffff00);
+        assert_eq!(
+            or_combine(0x01000000, Xlen::Rv32),
+            0xff000000_u32 as i32 as i64
+        );
+        assert_eq!(or_combine(0x0100000000, Xlen::Rv64), 0xff00000000);
+    }
+}
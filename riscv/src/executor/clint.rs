@@ -0,0 +1,200 @@
+//! A CLINT (core-local interruptor) with the memory map used by SiFive cores and
+//! QEMU's `virt` machine.
+//!
+//! `mtime` doesn't follow wall-clock time. Instead, it advances by one every
+//! [`Clint::period`] executed instructions, so programs behave the same way on
+//! every run. It's computed from the number of executed instructions, so time
+//! passing isn't an effect, only writes to the registers are.
+
+use std::borrow::Cow;
+
+use super::device::{Changes, Device};
+
+/// The base address of the CLINT registers.
+pub const CLINT_BASE: i64 = 0x0200_0000;
+
+/// Offset of the `msip` register (4 bytes).
+pub const MSIP: i64 = 0x0;
+/// Offset of the `mtimecmp` register (8 bytes).
+pub const MTIMECMP: i64 = 0x4000;
+/// Offset of the `mtime` register (8 bytes).
+pub const MTIME: i64 = 0xbff8;
+
+/// The size of the CLINT's address range.
+const SIZE: i64 = 0x10000;
+
+/// State registers.
+const MSIP_REG: usize = 0;
+const MTIMECMP_REG: usize = 1;
+const MTIME_OFFSET_REG: usize = 2;
+
+/// Bits of `mip` and `mie`. The CLINT only raises the machine-level ones;
+/// supervisor interrupts are raised by machine-mode software writing `mip`.
+pub mod mip {
+    /// Supervisor software interrupt
+    pub const SSIP: i64 = 1 << 1;
+    /// Supervisor timer interrupt
+    pub const STIP: i64 = 1 << 5;
+    /// Machine software interrupt
+    pub const MSIP: i64 = 1 << 3;
+    /// Machine timer interrupt
+    pub const MTIP: i64 = 1 << 7;
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Clint {
+    /// The number of instructions it takes for `mtime` to advance by one.
+    pub period: u64,
+
+    msip: u32,
+    mtimecmp: u64,
+    /// What `mtime` was at time 0, or would have been if it was written since.
+    mtime_offset: u64,
+
+    /// The number of instructions executed.
+    now: u64,
+}
+
+impl Default for Clint {
+    fn default() -> Self {
+        Self {
+            period: 1,
+            msip: 0,
+            // Don't fire a timer interrupt until the program asks for one
+            mtimecmp: u64::MAX,
+            mtime_offset: 0,
+            now: 0,
+        }
+    }
+}
+
+impl Clint {
+    pub fn mtime(&self) -> u64 {
+        self.mtime_offset.wrapping_add(self.now / self.period)
+    }
+}
+
+impl Device for Clint {
+    fn name(&self) -> &'static str {
+        "clint"
+    }
+
+    fn size(&self) -> i64 {
+        SIZE
+    }
+
+    fn read(&self, offset: i64) -> u8 {
+        let (reg, byte) = match offset {
+            0x0..=0x3 => (self.msip as u64, offset - MSIP),
+            0x4000..=0x4007 => (self.mtimecmp, offset - MTIMECMP),
+            0xbff8..=0xbfff => (self.mtime(), offset - MTIME),
+            _ => return 0,
+        };
+        reg.to_le_bytes()[byte as usize]
+    }
+
+    fn get(&self, reg: usize) -> u64 {
+        match reg {
+            MSIP_REG => self.msip as u64,
+            MTIMECMP_REG => self.mtimecmp,
+            MTIME_OFFSET_REG => self.mtime_offset,
+            _ => unreachable!("no register {reg}"),
+        }
+    }
+
+    fn set(&mut self, reg: usize, val: u64) {
+        match reg {
+            MSIP_REG => self.msip = val as u32,
+            MTIMECMP_REG => self.mtimecmp = val,
+            MTIME_OFFSET_REG => self.mtime_offset = val,
+            _ => unreachable!("no register {reg}"),
+        }
+    }
+
+    fn register_name(&self, reg: usize) -> Cow<'static, str> {
+        match reg {
+            MSIP_REG => "msip",
+            MTIMECMP_REG => "mtimecmp",
+            _ => "mtime offset",
+        }
+        .into()
+    }
+
+    fn write(&self, regs: &mut Changes, offset: i64, val: u8) {
+        let ticks = self.now / self.period;
+        let (reg, byte, old) = match offset {
+            // Only the lowest bit of msip is writable
+            0x0 => return regs.set(MSIP_REG, (val & 1) as u64),
+            0x4000..=0x4007 => (MTIMECMP_REG, offset - MTIMECMP, regs.get(MTIMECMP_REG)),
+            0xbff8..=0xbfff => {
+                let mtime = regs.get(MTIME_OFFSET_REG).wrapping_add(ticks);
+                (MTIME_OFFSET_REG, offset - MTIME, mtime)
+            }
+            _ => return,
+        };
+        let mut bytes = old.to_le_bytes();
+        bytes[byte as usize] = val;
+        let new = u64::from_le_bytes(bytes);
+        match reg {
+            MTIME_OFFSET_REG => regs.set(reg, new.wrapping_sub(ticks)),
+            _ => regs.set(reg, new),
+        }
+    }
+
+    fn set_time(&mut self, now: u64) {
+        self.now = now;
+    }
+
+    /// The interrupts the CLINT is currently raising, as `mip` bits.
+    fn pending(&self) -> i64 {
+        let mut pending = 0;
+        if self.msip & 1 != 0 {
+            pending |= mip::MSIP;
+        }
+        if self.mtime() >= self.mtimecmp {
+            pending |= mip::MTIP;
+        }
+        pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::executor::device::update;
+
+    use super::*;
+
+    #[test]
+    fn registers() {
+        let mut clint = Clint {
+            period: 2,
+            ..Default::default()
+        };
+        assert_eq!(clint.pending(), 0);
+
+        clint.set_time(5);
+        assert_eq!(clint.mtime(), 2);
+        assert_eq!(clint.read(MTIME), 2);
+
+        // mtimecmp can be written a byte at a time
+        for (i, byte) in 3u64.to_le_bytes().into_iter().enumerate() {
+            update(&mut clint, |clint, regs| {
+                clint.write(regs, MTIMECMP + i as i64, byte)
+            });
+        }
+        assert_eq!(clint.pending(), 0);
+        clint.set_time(6);
+        assert_eq!(clint.pending(), mip::MTIP);
+
+        update(&mut clint, |clint, regs| clint.write(regs, MSIP, 0xff));
+        assert_eq!(clint.read(MSIP), 1);
+        assert_eq!(clint.pending(), mip::MSIP | mip::MTIP);
+
+        // Writing mtime moves it, and time keeps passing from there
+        update(&mut clint, |clint, regs| clint.write(regs, MTIME, 1));
+        assert_eq!(clint.mtime(), 1);
+        clint.set_time(8);
+        assert_eq!(clint.mtime(), 2);
+        assert_eq!(clint.pending(), mip::MSIP);
+    }
+}
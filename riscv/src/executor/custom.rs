@@ -0,0 +1,456 @@
+//! Custom instructions in the `custom-0` to `custom-3` opcode spaces.
+//!
+//! A [`CustomInstructions`] set maps mnemonics to an operand format, an
+//! encoding and a closure giving the instruction's semantics. The same set is
+//! handed to the lexer, so the mnemonics parse, and to the executor through
+//! [`Config`](super::Config), so they run.
+//!
+//! Semantics only compute effects from the operands; the executor applies them
+//! like it would for a built-in instruction, so they can be reverted too.
+//!
+//! ```
+//! use riscv::executor::{custom::{CustomEffect, CustomInstructions}, Config, Executor};
+//! use riscv::parse::{CustomEncoding, CustomFormat, CustomOpcode, Register};
+//!
+//! let mut custom = CustomInstructions::default();
+//! custom
+//!     .register(
+//!         "absdiff",
+//!         CustomFormat::R,
+//!         CustomEncoding::new(CustomOpcode::Custom0, 0, 0),
+//!         |ctx| Ok(vec![CustomEffect::Register(ctx.rs1.abs_diff(ctx.rs2) as i64)]),
+//!     )
+//!     .unwrap();
+//! let mut config = Config::default();
+//! config.custom = custom;
+//!
+//! let source = "li a0, 3\nli a1, 10\nabsdiff a2, a0, a1";
+//! let mut exec = Executor::from_source(source, config).unwrap();
+//! exec.run().unwrap();
+//! assert_eq!(exec.regfile[Register::a2], 7);
+//! ```
+
+use std::{collections::HashMap, fmt, sync::Arc};
+
+use thiserror::Error;
+
+use crate::parse::{
+    self, CustomEncoding, CustomFormat, CustomOpcode, CustomOperands, LoadOp, Register, StoreOp,
+};
+
+use super::{memory::MemoryError, ExecErrorInner, Executor, ProcessorUpdate, Xlen};
+
+/// One thing a custom instruction does, computed by its semantics. Semantics
+/// return every effect of the instruction, in order, and an empty list does
+/// nothing but advance the pc.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CustomEffect {
+    /// Write `rd`. The value is sign extended from XLEN. If `rd` is written
+    /// more than once, the last value is kept.
+    Register(i64),
+    /// Store `val` to the (virtual) address `addr`.
+    Store { addr: i64, val: i64, op: StoreOp },
+}
+
+/// Why a custom instruction failed.
+#[derive(Debug, Error)]
+pub enum CustomError {
+    /// A load made by the semantics failed. This traps like a built-in load.
+    #[error(transparent)]
+    Memory(#[from] MemoryError),
+
+    /// The operands aren't valid for the instruction. This raises an illegal
+    /// instruction exception.
+    #[error("{0}")]
+    Illegal(String),
+}
+
+/// Errors registering a custom instruction.
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum RegisterError {
+    #[error("{0} is already registered")]
+    Mnemonic(String),
+
+    #[error("{0} is a built-in instruction")]
+    Builtin(String),
+
+    #[error("{mnemonic} has the same encoding as {other}")]
+    Encoding { mnemonic: String, other: String },
+
+    #[error("{field} is {val}, which doesn't fit in {bits} bits")]
+    Field {
+        field: &'static str,
+        val: u8,
+        bits: u32,
+    },
+}
+
+/// The operands a custom instruction's semantics can see.
+pub struct CustomContext<'a> {
+    /// The value of `rs1`.
+    pub rs1: i64,
+    /// The value of `rs2`, or 0 for the I format.
+    pub rs2: i64,
+    /// The sign extended immediate, or 0 for the R format.
+    pub imm: i64,
+    /// The address of the instruction.
+    pub pc: i64,
+    pub xlen: Xlen,
+    exec: &'a Executor,
+}
+
+impl CustomContext<'_> {
+    /// Load from the (virtual) address `addr`.
+    pub fn load(&self, addr: i64, op: LoadOp) -> Result<i64, MemoryError> {
+        let addr = self.exec.data_address(self.xlen.address(addr));
+        self.exec.memory.load(addr, op)
+    }
+}
+
+type Semantics = dyn Fn(&CustomContext) -> Result<Vec<CustomEffect>, CustomError> + Send + Sync;
+
+/// A registered custom instruction.
+#[derive(Clone)]
+pub struct CustomInstruction {
+    pub format: CustomFormat,
+    pub encoding: CustomEncoding,
+    semantics: Arc<Semantics>,
+}
+
+/// A set of custom instructions. Cloning is cheap, as the instructions are
+/// shared.
+#[derive(Clone, Default)]
+pub struct CustomInstructions {
+    instructions: Arc<HashMap<String, CustomInstruction>>,
+}
+
+impl CustomInstructions {
+    /// Add an instruction. Its mnemonic can't be a built-in instruction's, or
+    /// one that's already registered.
+    pub fn register(
+        &mut self,
+        mnemonic: &str,
+        format: CustomFormat,
+        encoding: CustomEncoding,
+        semantics: impl Fn(&CustomContext) -> Result<Vec<CustomEffect>, CustomError>
+            + Send
+            + Sync
+            + 'static,
+    ) -> Result<(), RegisterError> {
+        for (field, val, bits) in [
+            ("funct3", encoding.funct3, 3),
+            ("funct7", encoding.funct7, 7),
+        ] {
+            if val >> bits != 0 {
+                return Err(RegisterError::Field { field, val, bits });
+            }
+        }
+        if parse::is_builtin(mnemonic) {
+            return Err(RegisterError::Builtin(mnemonic.to_string()));
+        }
+        if self.instructions.contains_key(mnemonic) {
+            return Err(RegisterError::Mnemonic(mnemonic.to_string()));
+        }
+        let clash = self.instructions.iter().find(|(_, other)| {
+            // funct7 only tells R format instructions apart
+            other.encoding.opcode == encoding.opcode
+                && other.encoding.funct3 == encoding.funct3
+                && (other.format != CustomFormat::R
+                    || format != CustomFormat::R
+                    || other.encoding.funct7 == encoding.funct7)
+        });
+        if let Some((other, _)) = clash {
+            return Err(RegisterError::Encoding {
+                mnemonic: mnemonic.to_string(),
+                other: other.clone(),
+            });
+        }
+
+        Arc::make_mut(&mut self.instructions).insert(
+            mnemonic.to_string(),
+            CustomInstruction {
+                format,
+                encoding,
+                semantics: Arc::new(semantics),
+            },
+        );
+        Ok(())
+    }
+
+    pub fn get(&self, mnemonic: &str) -> Option<&CustomInstruction> {
+        self.instructions.get(mnemonic)
+    }
+
+    /// The instruction with an encoding, and its mnemonic. `funct7` is ignored
+    /// unless the instruction is in the R format.
+    pub fn find(
+        &self,
+        opcode: CustomOpcode,
+        funct3: u8,
+        funct7: u8,
+    ) -> Option<(&str, &CustomInstruction)> {
+        self.instructions
+            .iter()
+            .find(|(_, custom)| {
+                custom.encoding.opcode == opcode
+                    && custom.encoding.funct3 == funct3
+                    && (custom.format != CustomFormat::R || custom.encoding.funct7 == funct7)
+            })
+            .map(|(mnemonic, custom)| (mnemonic.as_str(), custom))
+    }
+}
+
+impl fmt::Debug for CustomInstructions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.instructions.keys()).finish()
+    }
+}
+
+impl Executor {
+    /// Compute the update for a custom instruction by running its semantics.
+    pub(super) fn custom_update(
+        &self,
+        mnemonic: &str,
+        operands: &CustomOperands,
+    ) -> Result<ProcessorUpdate, ExecErrorInner> {
+        let illegal = |reason: &str| ExecErrorInner::Custom {
+            mnemonic: mnemonic.to_string(),
+            reason: reason.to_string(),
+        };
+        let Some(custom) = self.config.custom.get(mnemonic) else {
+            Err(illegal("not registered with the executor"))?
+        };
+
+        let regs = &self.regfile;
+        let (rd, rs1, rs2, imm) = match *operands {
+            CustomOperands::R { rd, r1, r2 } => (Some(rd), regs[r1], regs[r2], 0),
+            CustomOperands::I { rd, r1, imm } => (Some(rd), regs[r1], 0, imm as i64),
+            CustomOperands::S { r2, offset, r1 } => (None, regs[r1], regs[r2], offset as i64),
+        };
+        let ctx = CustomContext {
+            rs1,
+            rs2,
+            imm,
+            pc: self.pc,
+            xlen: self.config.xlen,
+            exec: self,
+        };
+        let computed = (custom.semantics)(&ctx).map_err(|error| match error {
+            CustomError::Memory(error) => ExecErrorInner::Memory(error),
+            CustomError::Illegal(reason) => illegal(&reason),
+        })?;
+
+        let mut write = None;
+        let mut stores = vec![];
+        for effect in computed {
+            match effect {
+                CustomEffect::Register(val) => {
+                    let reg: Register =
+                        rd.ok_or_else(|| illegal("the S format has no rd to write"))?;
+                    write = Some(self.write_effect(reg, self.config.xlen.sext(val)));
+                }
+                CustomEffect::Store { addr, val, op } => {
+                    let addr = self.config.xlen.address(addr);
+                    let addr = self.memory.check_store(self.data_address(addr), op)?;
+                    stores.push((addr, val, op));
+                }
+            }
+        }
+        let mut effects: Vec<_> = write.into_iter().collect();
+        effects.extend(self.store_effects(stores));
+        Ok(ProcessorUpdate {
+            nextpc: self.pc + 4,
+            effects,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use indoc::indoc;
+
+    use crate::{executor::Config, parse::CustomOpcode};
+
+    use super::{super::trap::Exception, *};
+
+    #[test]
+    fn registering() {
+        let mut custom = CustomInstructions::default();
+        let nop = |_: &CustomContext| Ok(vec![]);
+        let encoding = CustomEncoding::new(CustomOpcode::Custom1, 2, 5);
+        custom
+            .register("first", CustomFormat::R, encoding, nop)
+            .unwrap();
+        // Other funct7 values are free for R format instructions
+        let other = CustomEncoding::new(CustomOpcode::Custom1, 2, 6);
+        custom
+            .register("second", CustomFormat::R, other, nop)
+            .unwrap();
+
+        assert_eq!(
+            custom.register("first", CustomFormat::I, other, nop),
+            Err(RegisterError::Mnemonic("first".to_string()))
+        );
+        assert!(matches!(
+            custom.register("third", CustomFormat::I, other, nop),
+            Err(RegisterError::Encoding { .. })
+        ));
+        for builtin in ["add", "fmadd.s", "vadd.vv", "c.addi", "ret"] {
+            let encoding = CustomEncoding::new(CustomOpcode::Custom2, 0, 0);
+            assert_eq!(
+                custom.register(builtin, CustomFormat::R, encoding, nop),
+                Err(RegisterError::Builtin(builtin.to_string()))
+            );
+        }
+        let wide = CustomEncoding::new(CustomOpcode::Custom2, 8, 0);
+        assert_eq!(
+            custom
+                .register("fourth", CustomFormat::I, wide, nop)
+                .unwrap_err()
+                .to_string(),
+            "funct3 is 8, which doesn't fit in 3 bits"
+        );
+
+        // Clones share the instructions registered so far
+        let clone = custom.clone();
+        let encoding = CustomEncoding::new(CustomOpcode::Custom3, 0, 0);
+        custom
+            .register("fifth", CustomFormat::S, encoding, nop)
+            .unwrap();
+        assert!(clone.get("first").is_some());
+        assert!(clone.get("fifth").is_none());
+    }
+
+    #[test]
+    fn executing() {
+        let mut custom = CustomInstructions::default();
+        // Multiply-add of the low and high halves of rs1 and rs2
+        custom
+            .register(
+                "dot2.h",
+                CustomFormat::R,
+                CustomEncoding::new(CustomOpcode::Custom0, 1, 0),
+                |ctx| {
+                    let halves = |val: i64| (val as i16 as i64, (val >> 16) as i16 as i64);
+                    let ((a0, a1), (b0, b1)) = (halves(ctx.rs1), halves(ctx.rs2));
+                    Ok(vec![CustomEffect::Register(a0 * b0 + a1 * b1)])
+                },
+            )
+            .unwrap();
+        // Sum `imm` words starting at rs1
+        custom
+            .register(
+                "sumw",
+                CustomFormat::I,
+                CustomEncoding::new(CustomOpcode::Custom0, 2, 0),
+                |ctx| {
+                    if ctx.imm <= 0 {
+                        return Err(CustomError::Illegal("count must be positive".to_string()));
+                    }
+                    let mut sum = 0;
+                    for word in 0..ctx.imm {
+                        sum += ctx.load(ctx.rs1 + word * 4, LoadOp::Lw)?;
+                    }
+                    Ok(vec![CustomEffect::Register(sum)])
+                },
+            )
+            .unwrap();
+        // Store rs2 with its bytes reversed
+        custom
+            .register(
+                "swrev",
+                CustomFormat::S,
+                CustomEncoding::new(CustomOpcode::Custom1, 0, 0),
+                |ctx| {
+                    Ok(vec![CustomEffect::Store {
+                        addr: ctx.rs1 + ctx.imm,
+                        val: (ctx.rs2 as u32).swap_bytes() as i64,
+                        op: StoreOp::Sw,
+                    }])
+                },
+            )
+            .unwrap();
+        // Swap rs2 with the word at rs1, into rd
+        custom
+            .register(
+                "swapw",
+                CustomFormat::R,
+                CustomEncoding::new(CustomOpcode::Custom1, 1, 0),
+                |ctx| {
+                    let old = ctx.load(ctx.rs1, LoadOp::Lw)?;
+                    Ok(vec![
+                        CustomEffect::Register(old),
+                        CustomEffect::Store {
+                            addr: ctx.rs1,
+                            val: ctx.rs2,
+                            op: StoreOp::Sw,
+                        },
+                    ])
+                },
+            )
+            .unwrap();
+        let config = Config {
+            custom,
+            ..Default::default()
+        };
+
+        let source = indoc! {"
+            li a0, 0x00030002
+            li a1, 0x00050004
+            dot2.h a2, a0, a1
+            li s0, 0x100
+            sw a0, 0(s0)
+            sw a1, 4(s0)
+            sumw a3, s0, 2
+            swrev a1, -8(s0)
+            lw a4, -8(s0)
+            li a5, 7
+            swapw a6, s0, a5
+            lw a7, 0(s0)
+        "};
+        let mut exec = Executor::from_source(source, config.clone()).unwrap();
+        assert_eq!(exec.program.asm[6].to_string(), "sumw a3, s0, 2");
+        assert_eq!(exec.program.asm[7].to_string(), "swrev a1, -8(s0)");
+        exec.run().unwrap();
+        assert_eq!(exec.regfile[Register::a2], 2 * 4 + 3 * 5);
+        assert_eq!(exec.regfile[Register::a3], 0x00080006);
+        assert_eq!(exec.regfile[Register::a4], 0x04000500);
+        assert_eq!(exec.regfile[Register::a6], 0x00030002);
+        assert_eq!(exec.regfile[Register::a7], 7);
+        exec.revert();
+        exec.revert();
+        assert_eq!(exec.regfile[Register::a6], 0);
+        assert_eq!(
+            exec.memory.load(0x100, LoadOp::Lw).unwrap(),
+            0x00030002,
+            "swapw's store is reverted with it"
+        );
+
+        // Failing semantics raise exceptions like built-in instructions
+        let source = indoc! {"
+            la t0, handler
+            csrw mtvec, t0
+            sumw a0, zero, 0
+            li s0, 0x1001
+            sumw a1, s0, 1
+            j end
+            handler:
+            csrr t0, mepc
+            addi t0, t0, 4
+            csrw mepc, t0
+            csrr t1, mcause
+            add s1, s1, t1
+            mret
+            end:
+        "};
+        let mut exec = Executor::from_source(source, config).unwrap();
+        exec.run().unwrap();
+        assert_eq!(
+            exec.regfile[Register::s1],
+            Exception::IllegalInstruction as i64 + Exception::LoadMisaligned as i64
+        );
+
+        // Without the instructions, they don't parse
+        assert!(Executor::from_source("sumw a0, zero, 1", Config::default()).is_err());
+    }
+}
@@ -0,0 +1,359 @@
+//! Memory-mapped devices. A [`Device`] attached to [`Memory`] at some base
+//! address handles the loads and stores to its registers, instead of memory.
+//!
+//! A device's state that the program can change is a set of numbered state
+//! registers. Accesses and ticks compute [`Changes`] to them without touching
+//! the device, and each changed register is recorded as an
+//! [`Effect::Device`](super::effect::Effect::Device) with its old and new value,
+//! so reverting restores it exactly. State that comes from outside the program,
+//! like the levels on input pins, isn't in a state register and is never
+//! reverted.
+//!
+//! [`Memory`]: super::memory::Memory
+
+use std::{any::Any, borrow::Cow, fmt, ops::Range};
+
+/// A device with memory-mapped registers.
+///
+/// Registers are accessed a byte at a time, like the CLINT's, with offsets from
+/// the device's base address. Only [`Device::set`] may change the device's
+/// state registers.
+pub trait Device: DeviceState + fmt::Debug + Send + Sync {
+    /// A short name for the device, like `gpio`.
+    fn name(&self) -> &'static str;
+
+    /// The number of bytes of address space the registers take up.
+    fn size(&self) -> i64;
+
+    /// Read a byte of a register. Unmapped offsets should read as zero.
+    fn read(&self, offset: i64) -> u8;
+
+    /// The value of the state register `reg`.
+    fn get(&self, reg: usize) -> u64;
+
+    /// Set the state register `reg`.
+    fn set(&mut self, reg: usize, val: u64);
+
+    /// The name of the state register `reg`, like `mtime`.
+    fn register_name(&self, reg: usize) -> Cow<'static, str>;
+
+    /// Write a byte of a register. Writes to unmapped offsets should be ignored.
+    fn write(&self, regs: &mut Changes, offset: i64, val: u8);
+
+    /// Called after an integer load of `len` bytes at `offset`, for devices
+    /// whose registers change when they're read, like a receive buffer.
+    fn after_read(&self, _regs: &mut Changes, _offset: i64, _len: usize) {}
+
+    /// Account for one executed instruction.
+    fn tick(&self, _regs: &mut Changes) {}
+
+    /// Called with the number of instructions executed so far, after every
+    /// step and revert. Time isn't a state register, so devices that only
+    /// depend on it, like a timer, don't record effects as it passes.
+    fn set_time(&mut self, _now: u64) {}
+
+    /// The interrupts the device is raising, as `mip` bits.
+    fn pending(&self) -> i64 {
+        0
+    }
+}
+
+/// A state register changed from `old` to `new`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Change {
+    pub reg: usize,
+    pub old: u64,
+    pub new: u64,
+}
+
+/// Changes to a device's state registers, made on top of its current state.
+pub struct Changes<'a> {
+    device: &'a dyn Device,
+    changes: Vec<Change>,
+    /// Where the changes made through this start, after those already made
+    start: usize,
+}
+
+impl<'a> Changes<'a> {
+    /// Start from `device`'s state after `made`, changes that are already
+    /// recorded elsewhere.
+    pub fn new(device: &'a dyn Device, made: Vec<Change>) -> Self {
+        Self {
+            device,
+            start: made.len(),
+            changes: made,
+        }
+    }
+
+    /// The value of a state register, with the changes so far.
+    pub fn get(&self, reg: usize) -> u64 {
+        self.changes
+            .iter()
+            .rev()
+            .find(|change| change.reg == reg)
+            .map_or_else(|| self.device.get(reg), |change| change.new)
+    }
+
+    pub fn set(&mut self, reg: usize, val: u64) {
+        let made = &mut self.changes[self.start..];
+        match made.iter_mut().find(|change| change.reg == reg) {
+            Some(change) => change.new = val,
+            None => self.changes.push(Change {
+                reg,
+                old: self.get(reg),
+                new: val,
+            }),
+        }
+    }
+
+    /// The registers changed through this, with their values before and after.
+    pub fn into_changes(mut self) -> Vec<Change> {
+        let mut changes = self.changes.split_off(self.start);
+        changes.retain(|change| change.old != change.new);
+        changes
+    }
+}
+
+/// Make the changes `change` computes to `device` right away, like a store from
+/// outside the program.
+pub fn update(device: &mut dyn Device, change: impl FnOnce(&dyn Device, &mut Changes)) {
+    let mut changes = Changes::new(device, vec![]);
+    change(device, &mut changes);
+    for Change { reg, new, .. } in changes.into_changes() {
+        device.set(reg, new);
+    }
+}
+
+/// Cloning, comparing and downcasting for `dyn Device`. Implemented for every
+/// device that is `Clone + PartialEq`.
+pub trait DeviceState {
+    fn clone_box(&self) -> Box<dyn Device>;
+    fn eq_device(&self, other: &dyn Device) -> bool;
+    fn as_any(&self) -> &dyn Any;
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+impl<T: Device + Clone + PartialEq + 'static> DeviceState for T {
+    fn clone_box(&self) -> Box<dyn Device> {
+        Box::new(self.clone())
+    }
+
+    fn eq_device(&self, other: &dyn Device) -> bool {
+        other.as_any().downcast_ref::<T>() == Some(self)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+impl Clone for Box<dyn Device> {
+    fn clone(&self) -> Self {
+        self.clone_box()
+    }
+}
+
+impl PartialEq for dyn Device {
+    fn eq(&self, other: &Self) -> bool {
+        self.eq_device(other)
+    }
+}
+
+impl Eq for dyn Device {}
+
+/// A device attached at an address.
+#[derive(Debug, Clone)]
+pub struct Mapped {
+    /// The address of the device's first register.
+    pub base: i64,
+    pub device: Box<dyn Device>,
+}
+
+impl PartialEq for Mapped {
+    fn eq(&self, other: &Self) -> bool {
+        self.base == other.base && *self.device == *other.device
+    }
+}
+
+impl Eq for Mapped {}
+
+impl Mapped {
+    /// The addresses of the device's registers.
+    pub fn range(&self) -> Range<i64> {
+        self.base..self.base + self.device.size()
+    }
+
+    pub fn contains(&self, addr: i64) -> bool {
+        self.range().contains(&addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        executor::{effect::Effect, Executor},
+        parse::{LoadOp, Register},
+    };
+
+    use super::*;
+
+    const COUNT: usize = 0;
+    const WRITTEN: usize = 1;
+
+    /// A counter that counts instructions, and is cleared by reading it.
+    #[derive(Debug, Clone, Default, PartialEq, Eq)]
+    struct Counter {
+        count: u32,
+        /// Written to the second register, which reads as zero
+        written: u32,
+    }
+
+    impl Device for Counter {
+        fn name(&self) -> &'static str {
+            "counter"
+        }
+
+        fn size(&self) -> i64 {
+            8
+        }
+
+        fn read(&self, offset: i64) -> u8 {
+            match offset {
+                0..=3 => self.count.to_le_bytes()[offset as usize],
+                _ => 0,
+            }
+        }
+
+        fn get(&self, reg: usize) -> u64 {
+            match reg {
+                COUNT => self.count as u64,
+                WRITTEN => self.written as u64,
+                _ => unreachable!("no register {reg}"),
+            }
+        }
+
+        fn set(&mut self, reg: usize, val: u64) {
+            match reg {
+                COUNT => self.count = val as u32,
+                WRITTEN => self.written = val as u32,
+                _ => unreachable!("no register {reg}"),
+            }
+        }
+
+        fn register_name(&self, reg: usize) -> Cow<'static, str> {
+            match reg {
+                COUNT => "count".into(),
+                _ => "written".into(),
+            }
+        }
+
+        fn write(&self, regs: &mut Changes, offset: i64, val: u8) {
+            if (4..8).contains(&offset) {
+                let mut bytes = (regs.get(WRITTEN) as u32).to_le_bytes();
+                bytes[offset as usize - 4] = val;
+                regs.set(WRITTEN, u32::from_le_bytes(bytes) as u64);
+            }
+        }
+
+        fn after_read(&self, regs: &mut Changes, offset: i64, _len: usize) {
+            if offset == 0 {
+                regs.set(COUNT, 0);
+            }
+        }
+
+        fn tick(&self, regs: &mut Changes) {
+            regs.set(COUNT, regs.get(COUNT) + 1);
+        }
+    }
+
+    #[test]
+    fn devices() {
+        let mut exec: Executor = "
+            lui t0, 0x60000
+            lw a0, 0(t0)
+            li a1, 0x4948
+            sh a1, 4(t0)
+            lw a2, 0(t0)
+            sw a1, 8(t0)
+        "
+        .parse()
+        .unwrap();
+        let index = exec.memory.attach(0x60000000, Counter::default());
+        assert_eq!(
+            exec.memory.attach(0x60000100, Counter::default()),
+            index + 1
+        );
+        exec.run().unwrap();
+
+        let counter = exec.memory.device::<Counter>().unwrap();
+        // Two instructions ticked it after the last read cleared it
+        assert_eq!(counter.count, 2);
+        assert_eq!(counter.written, 0x4948);
+        assert_eq!(exec.regfile[Register::a0], 1);
+        assert_eq!(exec.regfile[Register::a2], 3);
+        // Past the end of the device is plain memory
+        assert_eq!(exec.memory.load(0x60000008, LoadOp::Lw).unwrap(), 0x4948);
+        assert_eq!(exec.memory.load(0x60000004, LoadOp::Lw).unwrap(), 0);
+
+        // Reverting restores the device exactly
+        exec.revert();
+        exec.revert();
+        let counter = exec.memory.device::<Counter>().unwrap();
+        assert_eq!(counter.count, 3);
+        assert_eq!(counter.written, 0x4948);
+        exec.revert();
+        assert_eq!(exec.memory.device::<Counter>().unwrap().written, 0);
+        while exec.revert() {}
+        assert_eq!(exec.memory.devices()[index].device.read(0), 0);
+
+        // Each changed register is an effect of its own, even when a store
+        // writes it a byte at a time
+        let update = exec.execute().unwrap();
+        let counted = |effect: &&Effect| matches!(effect, Effect::Device { reg: COUNT, .. });
+        let counts: Vec<_> = update.effects().iter().filter(counted).collect();
+        assert_eq!(counts.len(), 2);
+        assert_eq!(counts[0].to_string(), "count: 0x0 -> 0x1");
+        exec.execute().unwrap();
+        exec.execute().unwrap();
+        let update = exec.execute().unwrap();
+        assert!(update.effects().contains(&Effect::Device {
+            index,
+            reg: WRITTEN,
+            name: "written".into(),
+            old: 0,
+            new: 0x4948,
+        }));
+    }
+
+    #[test]
+    fn changes() {
+        let counter = Counter {
+            count: 5,
+            written: 0,
+        };
+        let made = vec![Change {
+            reg: COUNT,
+            old: 5,
+            new: 6,
+        }];
+        let mut regs = Changes::new(&counter, made);
+        assert_eq!(regs.get(COUNT), 6);
+        regs.set(COUNT, 7);
+        regs.set(COUNT, 8);
+        // Setting a register back to its value isn't a change
+        regs.set(WRITTEN, 1);
+        regs.set(WRITTEN, 0);
+        assert_eq!(
+            regs.into_changes(),
+            [Change {
+                reg: COUNT,
+                old: 6,
+                new: 8,
+            }]
+        );
+    }
+}
@@ -0,0 +1,424 @@
+//! The changes an instruction makes, each with the value before and after.
+//!
+//! Executing an instruction computes an ordered list of [`Effect`]s without
+//! touching the executor, then applies them in order. Reverting undoes them in
+//! the opposite order, restoring every old value.
+
+use std::{borrow::Cow, fmt};
+
+use crate::parse::{Csr, ElementWidth, FRegister, Register, StoreOp, VRegister};
+
+use super::{
+    device::{Change, Changes, Device},
+    trap::Privilege,
+    Executor, RegisterSnapshot,
+};
+
+/// CSRs that hold state of their own. The rest are views of these, like
+/// `sstatus` of `mstatus` and `fflags` of `fcsr`, and are never recorded.
+const STATE_CSRS: [Csr; 19] = [
+    Csr::Fcsr,
+    Csr::Vl,
+    Csr::Vtype,
+    Csr::Mstatus,
+    Csr::Medeleg,
+    Csr::Mideleg,
+    Csr::Mie,
+    Csr::Mtvec,
+    Csr::Mscratch,
+    Csr::Mepc,
+    Csr::Mcause,
+    Csr::Mtval,
+    Csr::Mip,
+    Csr::Stvec,
+    Csr::Sscratch,
+    Csr::Sepc,
+    Csr::Scause,
+    Csr::Stval,
+    Csr::Satp,
+];
+
+/// One change made by an instruction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Effect {
+    Register {
+        reg: Register,
+        old: i64,
+        new: i64,
+    },
+    FRegister {
+        reg: FRegister,
+        old: u32,
+        new: u32,
+    },
+    /// The vector register group starting at `reg`, holding elements of width
+    /// `sew`.
+    VRegister {
+        reg: VRegister,
+        sew: ElementWidth,
+        old: Vec<u8>,
+        new: Vec<u8>,
+    },
+    Csr {
+        csr: Csr,
+        old: i64,
+        new: i64,
+    },
+    Privilege {
+        old: Privilege,
+        new: Privilege,
+    },
+    /// Physical memory starting at `addr`. Bytes that were uninitialized are
+    /// `None`.
+    Memory {
+        addr: i64,
+        old: Vec<Option<u8>>,
+        new: Vec<u8>,
+    },
+    /// The word reserved by `lr.w`.
+    Reservation {
+        old: Option<i64>,
+        new: Option<i64>,
+    },
+    /// The state register `reg`, called `name`, of the device at `index` in
+    /// [`Memory::devices`](super::memory::Memory::devices).
+    Device {
+        index: usize,
+        reg: usize,
+        name: Cow<'static, str>,
+        old: u64,
+        new: u64,
+    },
+}
+
+/// Interpret little-endian bytes as a sign extended integer.
+fn le_value(bytes: &[u8]) -> i64 {
+    let mut buf = [0; 8];
+    buf[..bytes.len()].copy_from_slice(bytes);
+    let shift = 64 - 8 * bytes.len() as u32;
+    (i64::from_le_bytes(buf) << shift) >> shift
+}
+
+fn elements(bytes: &[u8], sew: ElementWidth) -> String {
+    let elements: Vec<_> = bytes
+        .chunks(sew.bytes())
+        .map(|element| le_value(element).to_string())
+        .collect();
+    format!("[{}]", elements.join(", "))
+}
+
+fn reservation(addr: &Option<i64>) -> String {
+    match addr {
+        Some(addr) => format!("{addr:#010x}"),
+        None => "none".to_string(),
+    }
+}
+
+impl fmt::Display for Effect {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Effect::Register { reg, old, new } => write!(f, "{reg}: {old} -> {new}"),
+            Effect::FRegister { reg, old, new } => write!(
+                f,
+                "{reg}: {} -> {}",
+                f32::from_bits(*old),
+                f32::from_bits(*new)
+            ),
+            Effect::VRegister { reg, sew, old, new } => {
+                write!(
+                    f,
+                    "{reg}: {} -> {}",
+                    elements(old, *sew),
+                    elements(new, *sew)
+                )
+            }
+            Effect::Csr { csr, old, new } => write!(f, "{csr}: {old:#x} -> {new:#x}"),
+            Effect::Privilege { old, new } => write!(f, "privilege: {old} -> {new}"),
+            Effect::Memory { addr, old, new } => {
+                let old: Option<Vec<u8>> = old.iter().copied().collect();
+                match old {
+                    Some(old) => write!(f, "{addr:#010x}: {} -> ", le_value(&old))?,
+                    None => write!(f, "{addr:#010x}: uninitialized -> ")?,
+                }
+                write!(f, "{}", le_value(new))
+            }
+            Effect::Reservation { old, new } => write!(
+                f,
+                "reservation: {} -> {}",
+                reservation(old),
+                reservation(new)
+            ),
+            Effect::Device { name, old, new, .. } => write!(f, "{name}: {old:#x} -> {new:#x}"),
+        }
+    }
+}
+
+impl RegisterSnapshot {
+    /// `len` bytes of the vector registers, starting at `reg`.
+    fn vector_group(&self, reg: VRegister, len: usize) -> &[u8] {
+        let start = reg as usize * self.vregs.len() / 32;
+        &self.vregs[start..start + len]
+    }
+
+    /// Set the state behind a CSR directly, bypassing the rules for which bits
+    /// are writable. The values in effects already follow them.
+    fn set_csr_raw(&mut self, csr: Csr, val: i64) {
+        match csr {
+            Csr::Fcsr => self.fcsr = val as u32,
+            Csr::Vl => self.vl = val,
+            Csr::Vtype => self.vtype = val,
+            Csr::Mstatus => self.mstatus = val,
+            Csr::Medeleg => self.medeleg = val,
+            Csr::Mideleg => self.mideleg = val,
+            Csr::Mie => self.mie = val,
+            Csr::Mtvec => self.mtvec = val,
+            Csr::Mscratch => self.mscratch = val,
+            Csr::Mepc => self.mepc = val,
+            Csr::Mcause => self.mcause = val,
+            Csr::Mtval => self.mtval = val,
+            Csr::Mip => self.mip = val,
+            Csr::Stvec => self.stvec = val,
+            Csr::Sscratch => self.sscratch = val,
+            Csr::Sepc => self.sepc = val,
+            Csr::Scause => self.scause = val,
+            Csr::Stval => self.stval = val,
+            Csr::Satp => self.satp = val,
+            Csr::Fflags | Csr::Frm | Csr::Vlenb | Csr::Sstatus | Csr::Sie | Csr::Sip => {
+                unreachable!("{csr} is a view of another CSR")
+            }
+        }
+    }
+}
+
+fn store_bytes(val: i64, op: StoreOp) -> Vec<u8> {
+    let len = match op {
+        StoreOp::Sb => 1,
+        StoreOp::Sh => 2,
+        StoreOp::Sw => 4,
+        StoreOp::Sd => 8,
+    };
+    val.to_le_bytes()[..len].to_vec()
+}
+
+impl Executor {
+    /// Write `val` to an integer register.
+    pub(super) fn write_effect(&self, reg: Register, val: i64) -> Effect {
+        Effect::Register {
+            reg,
+            old: self.regfile[reg],
+            new: val,
+        }
+    }
+
+    /// Write `val` to a floating point register, accruing exception flags.
+    pub(super) fn float_effects(&self, reg: FRegister, val: u32, fflags: u8) -> Vec<Effect> {
+        let mut effects = vec![Effect::FRegister {
+            reg,
+            old: self.regfile[reg],
+            new: val,
+        }];
+        effects.extend(self.flag_effect(fflags));
+        effects
+    }
+
+    /// Accrue floating point exception flags in `fcsr`.
+    pub(super) fn flag_effect(&self, fflags: u8) -> Option<Effect> {
+        let old = self.regfile.csr(Csr::Fcsr);
+        let new = old | fflags as i64;
+        (new != old).then_some(Effect::Csr {
+            csr: Csr::Fcsr,
+            old,
+            new,
+        })
+    }
+
+    /// Overwrite the vector register group starting at `reg` with `val`.
+    pub(super) fn vector_effect(&self, reg: VRegister, val: Vec<u8>, sew: ElementWidth) -> Effect {
+        Effect::VRegister {
+            reg,
+            sew,
+            old: self.regfile.vector_group(reg, val.len()).to_vec(),
+            new: val,
+        }
+    }
+
+    /// Store each `(addr, val, op)` to physical memory in order. A store that
+    /// touches the reserved word clears the reservation.
+    pub(super) fn store_effects(
+        &self,
+        stores: impl IntoIterator<Item = (i64, i64, StoreOp)>,
+    ) -> Vec<Effect> {
+        let mut effects = vec![];
+        let mut reservation = self.memory.reservation();
+        for (addr, val, op) in stores {
+            let new = store_bytes(val, op);
+            if let Some(reserved) = reservation {
+                if addr < reserved + 4 && reserved < addr + new.len() as i64 {
+                    reservation = None;
+                }
+            }
+            // A store can straddle a device's range, so each run of bytes goes
+            // to the device or memory it lands in
+            let target = |i: usize| self.memory.device_at(addr + i as i64);
+            let mut start = 0;
+            while start < new.len() {
+                let end = (start..new.len())
+                    .find(|&i| target(i) != target(start))
+                    .unwrap_or(new.len());
+                let (addr, bytes) = (addr + start as i64, &new[start..end]);
+                if let Some(index) = target(start) {
+                    let offset = addr - self.memory.devices()[index].base;
+                    let written = self.device_effects(&effects, index, |device, regs| {
+                        for (i, byte) in (offset..).zip(bytes) {
+                            device.write(regs, i, *byte);
+                        }
+                    });
+                    effects.extend(written);
+                } else {
+                    effects.push(Effect::Memory {
+                        addr,
+                        old: self.memory.bytes(addr, bytes.len()),
+                        new: bytes.to_vec(),
+                    });
+                }
+                start = end;
+            }
+        }
+        if reservation != self.memory.reservation() {
+            effects.push(Effect::Reservation {
+                old: self.memory.reservation(),
+                new: reservation,
+            });
+        }
+        effects
+    }
+
+    /// Change the device at `index`, starting from its state after `effects`.
+    /// Each state register that changes is an effect.
+    pub(super) fn device_effects(
+        &self,
+        effects: &[Effect],
+        index: usize,
+        change: impl FnOnce(&dyn Device, &mut Changes),
+    ) -> Vec<Effect> {
+        let device = &*self.memory.devices()[index].device;
+        let made = effects
+            .iter()
+            .filter_map(|effect| match *effect {
+                Effect::Device {
+                    index: i,
+                    reg,
+                    old,
+                    new,
+                    ..
+                } if i == index => Some(Change { reg, old, new }),
+                _ => None,
+            })
+            .collect();
+        let mut regs = Changes::new(device, made);
+        change(device, &mut regs);
+        regs.into_changes()
+            .into_iter()
+            .map(|Change { reg, old, new }| Effect::Device {
+                index,
+                reg,
+                name: device.register_name(reg),
+                old,
+                new,
+            })
+            .collect()
+    }
+
+    /// The changes a load of `len` bytes at the physical address `addr` makes
+    /// to the device it reads from, if any.
+    pub(super) fn read_effects(&self, addr: i64, len: usize) -> Vec<Effect> {
+        let Some(index) = self.memory.device_at(addr) else {
+            return vec![];
+        };
+        let offset = addr - self.memory.devices()[index].base;
+        self.device_effects(&[], index, |device, regs| {
+            device.after_read(regs, offset, len)
+        })
+    }
+
+    /// The effects that change the CSRs and privilege mode to those in `after`.
+    /// Instructions that change a lot of state at once, like traps, compute it
+    /// on a copy of the registers and record the difference.
+    pub(super) fn state_effects(&self, after: &RegisterSnapshot) -> Vec<Effect> {
+        let before = &self.regfile;
+        let mut effects = vec![];
+        if before.privilege != after.privilege {
+            effects.push(Effect::Privilege {
+                old: before.privilege,
+                new: after.privilege,
+            });
+        }
+        for csr in STATE_CSRS {
+            let (old, new) = (before.csr(csr), after.csr(csr));
+            if old != new {
+                effects.push(Effect::Csr { csr, old, new });
+            }
+        }
+        effects
+    }
+
+    /// Apply an effect, or undo it by restoring its old value.
+    pub(super) fn apply(&mut self, effect: &Effect, undo: bool) {
+        fn pick<'a, T: ?Sized>(undo: bool, old: &'a T, new: &'a T) -> &'a T {
+            if undo {
+                old
+            } else {
+                new
+            }
+        }
+        match effect {
+            Effect::Register { reg, old, new } => self.regfile[reg] = *pick(undo, old, new),
+            Effect::FRegister { reg, old, new } => self.regfile[*reg] = *pick(undo, old, new),
+            Effect::VRegister { reg, old, new, .. } => {
+                self.regfile.set_vector(*reg, pick(undo, old, new))
+            }
+            Effect::Csr { csr, old, new } => self.regfile.set_csr_raw(*csr, *pick(undo, old, new)),
+            Effect::Privilege { old, new } => self.regfile.privilege = *pick(undo, old, new),
+            Effect::Memory { addr, old, new } => {
+                if undo {
+                    self.memory.set_bytes(*addr, old);
+                } else {
+                    let new: Vec<_> = new.iter().copied().map(Some).collect();
+                    self.memory.set_bytes(*addr, &new);
+                }
+            }
+            Effect::Reservation { old, new } => self.memory.set_reservation(*pick(undo, old, new)),
+            Effect::Device {
+                index,
+                reg,
+                old,
+                new,
+                ..
+            } => self
+                .memory
+                .set_device_register(*index, *reg, *pick(undo, old, new)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn display() {
+        let effect = Effect::Memory {
+            addr: 0x100,
+            old: vec![Some(0xff), None],
+            new: vec![0xfe, 0xff],
+        };
+        assert_eq!(effect.to_string(), "0x00000100: uninitialized -> -2");
+        let effect = Effect::VRegister {
+            reg: VRegister::v1,
+            sew: ElementWidth::E16,
+            old: vec![0; 4],
+            new: vec![1, 0, 0xff, 0xff],
+        };
+        assert_eq!(effect.to_string(), "v1: [0, 0] -> [1, -1]");
+    }
+}
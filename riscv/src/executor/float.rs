@@ -0,0 +1,420 @@
+//! Single-precision (RV32F) arithmetic.
+//!
+//! Floats are passed around as their raw bits, since that is how they live in
+//! the register file. Every operation returns the result along with the
+//! exception flags it raised, which the executor accrues into `fflags`.
+//!
+//! Results are computed in double precision and then rounded to single precision
+//! with the requested rounding mode. Double precision has more than twice the
+//! bits of single precision, so for `+ - * /` and `sqrt` the double rounding
+//! never changes the result. Fused multiply-adds can (very rarely) be off by one
+//! ulp in the last place.
+
+use crate::parse::{FCompareOp, FFusedOp, FRegRegOp, RoundingMode};
+
+/// The canonical NaN that all operations producing a NaN return.
+pub const CANONICAL_NAN: u32 = 0x7fc00000;
+
+/// Exception flags, as laid out in `fflags`.
+pub mod flags {
+    /// Invalid operation
+    pub const NV: u8 = 0b10000;
+    /// Divide by zero
+    pub const DZ: u8 = 0b01000;
+    /// Overflow
+    pub const OF: u8 = 0b00100;
+    /// Underflow
+    pub const UF: u8 = 0b00010;
+    /// Inexact
+    pub const NX: u8 = 0b00001;
+}
+
+/// A result along with the exception flags raised while computing it.
+pub type Flagged<T> = (T, u8);
+
+fn is_nan(bits: u32) -> bool {
+    f32::from_bits(bits).is_nan()
+}
+
+/// Signaling NaNs have the most significant bit of the mantissa clear.
+fn is_signaling(bits: u32) -> bool {
+    is_nan(bits) && bits & 0x0040_0000 == 0
+}
+
+/// The next float towards positive infinity.
+fn next_up(x: f32) -> f32 {
+    let bits = x.to_bits();
+    if x.is_nan() || x == f32::INFINITY {
+        x
+    } else if x == 0.0 {
+        f32::from_bits(1)
+    } else if x > 0.0 {
+        f32::from_bits(bits + 1)
+    } else {
+        f32::from_bits(bits - 1)
+    }
+}
+
+/// The next float towards negative infinity.
+fn next_down(x: f32) -> f32 {
+    -next_up(-x)
+}
+
+/// Round a double to a single with the given rounding mode.
+///
+/// `rm` must not be [`RoundingMode::Dyn`]; the caller resolves it using `frm`.
+pub fn round(x: f64, rm: RoundingMode) -> Flagged<u32> {
+    if x.is_nan() {
+        return (CANONICAL_NAN, 0);
+    }
+    // Casting rounds to nearest, ties to even
+    let nearest = x as f32;
+    if x.is_infinite() || nearest as f64 == x {
+        return (nearest.to_bits(), 0);
+    }
+
+    // The two floats on either side of x
+    let (below, above) = if (nearest as f64) < x {
+        (nearest, next_up(nearest))
+    } else {
+        (next_down(nearest), nearest)
+    };
+    let rounded = match rm {
+        RoundingMode::Rne => nearest,
+        RoundingMode::Rtz if x > 0.0 => below,
+        RoundingMode::Rtz => above,
+        RoundingMode::Rdn => below,
+        RoundingMode::Rup => above,
+        RoundingMode::Rmm => {
+            let tie = above.is_finite() && x - below as f64 == above as f64 - x;
+            match (tie, x > 0.0) {
+                (true, true) => above,
+                (true, false) => below,
+                (false, _) => nearest,
+            }
+        }
+        RoundingMode::Dyn => unreachable!("dynamic rounding mode should be resolved"),
+    };
+
+    let mut raised = flags::NX;
+    if rounded.is_infinite() || x.abs() >= 2f64.powi(128) {
+        raised |= flags::OF;
+    }
+    if x.abs() < f32::MIN_POSITIVE as f64 && rounded.abs() < f32::MIN_POSITIVE {
+        raised |= flags::UF;
+    }
+    (rounded.to_bits(), raised)
+}
+
+/// Flags raised just by looking at the inputs: NaN inputs are invalid if they
+/// are signaling.
+fn nan_flags(inputs: &[u32]) -> u8 {
+    if inputs.iter().any(|bits| is_signaling(*bits)) {
+        flags::NV
+    } else {
+        0
+    }
+}
+
+/// Round `x`, the result of an operation on `inputs`. If the operation produced a
+/// NaN out of non-NaN inputs, it was invalid.
+fn finish(x: f64, inputs: &[u32], rm: RoundingMode) -> Flagged<u32> {
+    if x.is_nan() {
+        let invalid = if inputs.iter().any(|bits| is_nan(*bits)) {
+            nan_flags(inputs)
+        } else {
+            flags::NV
+        };
+        return (CANONICAL_NAN, invalid);
+    }
+    round(x, rm)
+}
+
+/// Register-register operations: arithmetic, min/max and sign injection.
+pub fn reg_reg(op: FRegRegOp, a: u32, b: u32, rm: RoundingMode) -> Flagged<u32> {
+    let (x, y) = (f32::from_bits(a) as f64, f32::from_bits(b) as f64);
+    match op {
+        FRegRegOp::Add => finish(x + y, &[a, b], rm),
+        FRegRegOp::Sub => finish(x - y, &[a, b], rm),
+        FRegRegOp::Mul => finish(x * y, &[a, b], rm),
+        FRegRegOp::Div => {
+            let (bits, mut raised) = finish(x / y, &[a, b], rm);
+            if y == 0.0 && x.is_finite() && x != 0.0 {
+                raised |= flags::DZ;
+            }
+            (bits, raised)
+        }
+        FRegRegOp::Min | FRegRegOp::Max => {
+            let raised = nan_flags(&[a, b]);
+            let bits = match (is_nan(a), is_nan(b)) {
+                (true, true) => CANONICAL_NAN,
+                (true, false) => b,
+                (false, true) => a,
+                // -0.0 is considered less than +0.0, which comparing as floats
+                // doesn't do
+                _ if x == y => {
+                    let negative = (a | b) & 0x8000_0000 != 0;
+                    let positive = (a & b) & 0x8000_0000 == 0;
+                    match op {
+                        FRegRegOp::Min if negative => a | b,
+                        FRegRegOp::Max if positive => a & b,
+                        _ => a,
+                    }
+                }
+                _ if (x < y) == (op == FRegRegOp::Min) => a,
+                _ => b,
+            };
+            (bits, raised)
+        }
+        FRegRegOp::Sgnj => ((a & 0x7fff_ffff) | (b & 0x8000_0000), 0),
+        FRegRegOp::Sgnjn => ((a & 0x7fff_ffff) | (!b & 0x8000_0000), 0),
+        FRegRegOp::Sgnjx => (a ^ (b & 0x8000_0000), 0),
+    }
+}
+
+pub fn sqrt(a: u32, rm: RoundingMode) -> Flagged<u32> {
+    finish((f32::from_bits(a) as f64).sqrt(), &[a], rm)
+}
+
+/// Fused multiply-adds, which only round once.
+pub fn fused(op: FFusedOp, a: u32, b: u32, c: u32, rm: RoundingMode) -> Flagged<u32> {
+    let (x, y, z) = (
+        f32::from_bits(a) as f64,
+        f32::from_bits(b) as f64,
+        f32::from_bits(c) as f64,
+    );
+    let result = match op {
+        FFusedOp::Madd => x.mul_add(y, z),
+        FFusedOp::Msub => x.mul_add(y, -z),
+        FFusedOp::Nmsub => (-x).mul_add(y, z),
+        FFusedOp::Nmadd => (-x).mul_add(y, -z),
+    };
+    let (bits, mut raised) = finish(result, &[a, b, c], rm);
+    // inf * 0 is invalid even if the addend is a quiet NaN
+    if (x.is_infinite() && y == 0.0) || (x == 0.0 && y.is_infinite()) {
+        raised |= flags::NV;
+    }
+    (bits, raised)
+}
+
+/// Comparisons. `feq.s` is quiet and only complains about signaling NaNs, while
+/// `flt.s` and `fle.s` complain about any NaN.
+pub fn compare(op: FCompareOp, a: u32, b: u32) -> Flagged<bool> {
+    let (x, y) = (f32::from_bits(a), f32::from_bits(b));
+    let raised = match op {
+        FCompareOp::Eq => nan_flags(&[a, b]),
+        _ if x.is_nan() || y.is_nan() => flags::NV,
+        _ => 0,
+    };
+    let result = match op {
+        FCompareOp::Eq => x == y,
+        FCompareOp::Lt => x < y,
+        FCompareOp::Le => x <= y,
+    };
+    (result, raised)
+}
+
+/// The 10-bit mask produced by `fclass.s`. Exactly one bit is set.
+pub fn classify(a: u32) -> u32 {
+    let x = f32::from_bits(a);
+    let negative = a & 0x8000_0000 != 0;
+    let bit = if is_signaling(a) {
+        8
+    } else if x.is_nan() {
+        9
+    } else if x.is_infinite() {
+        if negative {
+            0
+        } else {
+            7
+        }
+    } else if x == 0.0 {
+        if negative {
+            3
+        } else {
+            4
+        }
+    } else if x.is_subnormal() {
+        if negative {
+            2
+        } else {
+            5
+        }
+    } else if negative {
+        1
+    } else {
+        6
+    };
+    1 << bit
+}
+
+/// Round to an integral value with the given rounding mode.
+fn round_integral(x: f64, rm: RoundingMode) -> f64 {
+    match rm {
+        RoundingMode::Rne => x.round_ties_even(),
+        RoundingMode::Rtz => x.trunc(),
+        RoundingMode::Rdn => x.floor(),
+        RoundingMode::Rup => x.ceil(),
+        RoundingMode::Rmm => x.round(),
+        RoundingMode::Dyn => unreachable!("dynamic rounding mode should be resolved"),
+    }
+}
+
+/// Convert to an integer, saturating if the value is out of range. `fcvt.w.s`
+/// if `signed`, otherwise `fcvt.wu.s`.
+pub fn to_int(a: u32, signed: bool, rm: RoundingMode) -> Flagged<i32> {
+    let x = f32::from_bits(a) as f64;
+    let (min, max) = if signed {
+        (i32::MIN as f64, i32::MAX as f64)
+    } else {
+        (0.0, u32::MAX as f64)
+    };
+    let saturated = |high: bool| {
+        let val = match (signed, high) {
+            (true, true) => i32::MAX,
+            (true, false) => i32::MIN,
+            (false, true) => u32::MAX as i32,
+            (false, false) => 0,
+        };
+        (val, flags::NV)
+    };
+    if x.is_nan() {
+        return saturated(true);
+    }
+    let rounded = round_integral(x, rm);
+    if rounded > max {
+        saturated(true)
+    } else if rounded < min {
+        saturated(false)
+    } else {
+        let val = if signed {
+            rounded as i32
+        } else {
+            rounded as u32 as i32
+        };
+        (val, if rounded == x { 0 } else { flags::NX })
+    }
+}
+
+/// Convert an integer to a float. `fcvt.s.w` if `signed`, otherwise `fcvt.s.wu`.
+pub fn from_int(val: i32, signed: bool, rm: RoundingMode) -> Flagged<u32> {
+    let x = if signed {
+        val as f64
+    } else {
+        val as u32 as f64
+    };
+    round(x, rm)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bits(x: f32) -> u32 {
+        x.to_bits()
+    }
+
+    #[test]
+    fn rounding_modes() {
+        use RoundingMode::*;
+        // 1 + 2^-24 is exactly halfway between 1 and the next float
+        let halfway = 1.0 + 2f64.powi(-24);
+        assert_eq!(round(halfway, Rne), (bits(1.0), flags::NX));
+        assert_eq!(round(halfway, Rtz), (bits(1.0), flags::NX));
+        assert_eq!(round(halfway, Rdn), (bits(1.0), flags::NX));
+        assert_eq!(round(halfway, Rup), (bits(1.0) + 1, flags::NX));
+        assert_eq!(round(halfway, Rmm), (bits(1.0) + 1, flags::NX));
+        assert_eq!(round(-halfway, Rtz), (bits(-1.0), flags::NX));
+        assert_eq!(round(-halfway, Rdn), (bits(-1.0) + 1, flags::NX));
+
+        // Exact values don't raise anything
+        assert_eq!(round(0.5, Rup), (bits(0.5), 0));
+
+        // Overflow
+        assert_eq!(
+            round(1e39, Rne),
+            (bits(f32::INFINITY), flags::OF | flags::NX)
+        );
+        assert_eq!(round(1e39, Rtz), (bits(f32::MAX), flags::OF | flags::NX));
+        assert_eq!(round(-1e39, Rup), (bits(f32::MIN), flags::OF | flags::NX));
+
+        // Underflow
+        assert_eq!(round(1e-46, Rne), (bits(0.0), flags::UF | flags::NX));
+    }
+
+    #[test]
+    fn arithmetic() {
+        use RoundingMode::Rne;
+        assert_eq!(
+            reg_reg(FRegRegOp::Add, bits(1.5), bits(2.25), Rne),
+            (bits(3.75), 0)
+        );
+        assert_eq!(
+            reg_reg(FRegRegOp::Div, bits(1.0), bits(3.0), Rne),
+            (bits(1.0 / 3.0), flags::NX)
+        );
+        assert_eq!(
+            reg_reg(FRegRegOp::Div, bits(1.0), bits(0.0), Rne),
+            (bits(f32::INFINITY), flags::DZ)
+        );
+        assert_eq!(
+            reg_reg(
+                FRegRegOp::Sub,
+                bits(f32::INFINITY),
+                bits(f32::INFINITY),
+                Rne
+            ),
+            (CANONICAL_NAN, flags::NV)
+        );
+        assert_eq!(sqrt(bits(-1.0), Rne), (CANONICAL_NAN, flags::NV));
+        assert_eq!(
+            fused(FFusedOp::Nmsub, bits(2.0), bits(3.0), bits(10.0), Rne),
+            (bits(4.0), 0)
+        );
+    }
+
+    #[test]
+    fn min_max() {
+        let snan = 0x7f80_0001;
+        assert_eq!(
+            reg_reg(FRegRegOp::Min, bits(-0.0), bits(0.0), RoundingMode::Rne),
+            (bits(-0.0), 0)
+        );
+        assert_eq!(
+            reg_reg(FRegRegOp::Max, bits(-0.0), bits(0.0), RoundingMode::Rne),
+            (bits(0.0), 0)
+        );
+        assert_eq!(
+            reg_reg(FRegRegOp::Max, CANONICAL_NAN, bits(2.0), RoundingMode::Rne),
+            (bits(2.0), 0)
+        );
+        assert_eq!(
+            reg_reg(FRegRegOp::Min, snan, bits(2.0), RoundingMode::Rne),
+            (bits(2.0), flags::NV)
+        );
+    }
+
+    #[test]
+    fn conversions() {
+        use RoundingMode::*;
+        assert_eq!(to_int(bits(-2.5), true, Rne), (-2, flags::NX));
+        assert_eq!(to_int(bits(-2.5), true, Rmm), (-3, flags::NX));
+        assert_eq!(to_int(bits(-2.5), true, Rup), (-2, flags::NX));
+        assert_eq!(to_int(bits(3e9), true, Rne), (i32::MAX, flags::NV));
+        assert_eq!(to_int(bits(3e9), false, Rne), (3_000_000_000u32 as i32, 0));
+        assert_eq!(to_int(bits(-1.0), false, Rne), (0, flags::NV));
+        assert_eq!(to_int(CANONICAL_NAN, true, Rne), (i32::MAX, flags::NV));
+        assert_eq!(from_int(-7, true, Rne), (bits(-7.0), 0));
+        assert_eq!(from_int(-1, false, Rtz), (bits(4294967040.0), flags::NX));
+    }
+
+    #[test]
+    fn classes() {
+        assert_eq!(classify(bits(f32::NEG_INFINITY)), 1 << 0);
+        assert_eq!(classify(bits(-0.0)), 1 << 3);
+        assert_eq!(classify(bits(1.0)), 1 << 6);
+        assert_eq!(classify(1), 1 << 5);
+        assert_eq!(classify(0x7f80_0001), 1 << 8);
+        assert_eq!(classify(CANONICAL_NAN), 1 << 9);
+    }
+}
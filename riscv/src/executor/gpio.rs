@@ -0,0 +1,320 @@
+//! GPIO pins with the ESP32-C3's register layout, so embedded lab programs run
+//! unmodified.
+//!
+//! There are two devices: [`Gpio`] holds the pin levels and output enables, and
+//! [`IoMux`] holds each pin's configuration. Input levels are driven from
+//! outside the program with [`Gpio::set_input`], and don't depend on the IO MUX
+//! configuration, and aren't reverted with the program's changes. A pin whose
+//! output is enabled reads back its output level.
+
+use std::borrow::Cow;
+
+use super::device::{Changes, Device};
+
+/// The base address of the GPIO registers.
+pub const GPIO_BASE: i64 = 0x6000_4000;
+/// The base address of the IO MUX registers.
+pub const IO_MUX_BASE: i64 = 0x6000_9000;
+
+/// The number of pins, GPIO0 to GPIO21.
+pub const PINS: usize = 22;
+const PIN_MASK: u32 = (1 << PINS) - 1;
+
+/// Offset of `GPIO_OUT_REG`, the output levels.
+pub const OUT: i64 = 0x04;
+/// Offset of `GPIO_OUT_W1TS_REG`: writing a 1 bit sets that output.
+pub const OUT_W1TS: i64 = 0x08;
+/// Offset of `GPIO_OUT_W1TC_REG`: writing a 1 bit clears that output.
+pub const OUT_W1TC: i64 = 0x0c;
+/// Offset of `GPIO_ENABLE_REG`, which pins are outputs.
+pub const ENABLE: i64 = 0x20;
+/// Offset of `GPIO_ENABLE_W1TS_REG`: writing a 1 bit enables that output.
+pub const ENABLE_W1TS: i64 = 0x24;
+/// Offset of `GPIO_ENABLE_W1TC_REG`: writing a 1 bit disables that output.
+pub const ENABLE_W1TC: i64 = 0x28;
+/// Offset of `GPIO_IN_REG`, the levels on the pins.
+pub const IN: i64 = 0x3c;
+
+/// The `FUN_IE` (input enable) bit of an `IO_MUX_GPIOn_REG`.
+pub const FUN_IE: u32 = 1 << 9;
+/// The `FUN_WPU` (pull-up) bit of an `IO_MUX_GPIOn_REG`.
+pub const FUN_WPU: u32 = 1 << 8;
+
+/// State registers of the GPIO.
+const OUT_REG: usize = 0;
+const ENABLE_REG: usize = 1;
+
+/// Panic if there's no such pin.
+fn check_pin(pin: usize) {
+    assert!(
+        pin < PINS,
+        "GPIO{pin} doesn't exist, there are only {PINS} pins"
+    );
+}
+
+/// The bit of a pin in the GPIO registers.
+fn pin_bit(pin: usize) -> u32 {
+    check_pin(pin);
+    1 << pin
+}
+
+/// The GPIO registers, each a bit per pin.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Gpio {
+    out: u32,
+    enable: u32,
+    /// The levels driven onto the pins from outside
+    inputs: u32,
+}
+
+impl Gpio {
+    /// Drive an input pin high or low, like pressing a button. Panics if `pin`
+    /// isn't below [`PINS`], like the other pin accessors.
+    pub fn set_input(&mut self, pin: usize, high: bool) {
+        let bit = pin_bit(pin);
+        if high {
+            self.inputs |= bit;
+        } else {
+            self.inputs &= !bit;
+        }
+    }
+
+    /// The level driven onto a pin from outside.
+    pub fn input(&self, pin: usize) -> bool {
+        self.inputs & pin_bit(pin) != 0
+    }
+
+    /// The level the program outputs on a pin, or `None` if its output isn't
+    /// enabled, like an LED.
+    pub fn output(&self, pin: usize) -> Option<bool> {
+        let bit = pin_bit(pin);
+        (self.enable & bit != 0).then_some(self.out & bit != 0)
+    }
+
+    /// The levels on the pins, as `GPIO_IN_REG` reads them.
+    pub fn levels(&self) -> u32 {
+        (self.inputs & !self.enable | self.out & self.enable) & PIN_MASK
+    }
+
+    /// The register at `offset`, if it can be read.
+    fn register(&self, offset: i64) -> Option<u32> {
+        match offset {
+            OUT => Some(self.out),
+            ENABLE => Some(self.enable),
+            IN => Some(self.levels()),
+            _ => None,
+        }
+    }
+}
+
+impl Device for Gpio {
+    fn name(&self) -> &'static str {
+        "gpio"
+    }
+
+    fn size(&self) -> i64 {
+        0x100
+    }
+
+    fn read(&self, offset: i64) -> u8 {
+        let reg = offset & !0b11;
+        let shift = 8 * (offset & 0b11);
+        self.register(reg).map_or(0, |val| (val >> shift) as u8)
+    }
+
+    fn get(&self, reg: usize) -> u64 {
+        match reg {
+            OUT_REG => self.out as u64,
+            ENABLE_REG => self.enable as u64,
+            _ => unreachable!("no register {reg}"),
+        }
+    }
+
+    fn set(&mut self, reg: usize, val: u64) {
+        match reg {
+            OUT_REG => self.out = val as u32,
+            ENABLE_REG => self.enable = val as u32,
+            _ => unreachable!("no register {reg}"),
+        }
+    }
+
+    fn register_name(&self, reg: usize) -> Cow<'static, str> {
+        match reg {
+            OUT_REG => "GPIO_OUT_REG",
+            _ => "GPIO_ENABLE_REG",
+        }
+        .into()
+    }
+
+    fn write(&self, regs: &mut Changes, offset: i64, val: u8) {
+        let reg = offset & !0b11;
+        let shift = 8 * (offset & 0b11);
+        let bits = ((val as u32) << shift) & PIN_MASK;
+        let byte = (0xff << shift) & PIN_MASK;
+        let (state, old) = match reg {
+            OUT | OUT_W1TS | OUT_W1TC => (OUT_REG, regs.get(OUT_REG) as u32),
+            ENABLE | ENABLE_W1TS | ENABLE_W1TC => (ENABLE_REG, regs.get(ENABLE_REG) as u32),
+            _ => return,
+        };
+        let new = match reg {
+            OUT | ENABLE => old & !byte | bits,
+            OUT_W1TS | ENABLE_W1TS => old | bits,
+            _ => old & !bits,
+        };
+        regs.set(state, new as u64);
+    }
+}
+
+/// The IO MUX registers: `IO_MUX_PIN_CTRL`, then `IO_MUX_GPIOn_REG` for each
+/// pin. They read back what was written, and reset to 0.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IoMux {
+    regs: [u32; PINS + 1],
+}
+
+impl IoMux {
+    /// The `IO_MUX_GPIOn_REG` of a pin. Panics if `pin` isn't below [`PINS`].
+    pub fn pin(&self, pin: usize) -> u32 {
+        check_pin(pin);
+        self.regs[pin + 1]
+    }
+
+    /// Whether a pin's input is enabled.
+    pub fn input_enabled(&self, pin: usize) -> bool {
+        self.pin(pin) & FUN_IE != 0
+    }
+}
+
+impl Device for IoMux {
+    fn name(&self) -> &'static str {
+        "io_mux"
+    }
+
+    fn size(&self) -> i64 {
+        4 * self.regs.len() as i64
+    }
+
+    fn read(&self, offset: i64) -> u8 {
+        self.regs[offset as usize / 4].to_le_bytes()[offset as usize % 4]
+    }
+
+    fn get(&self, reg: usize) -> u64 {
+        self.regs[reg] as u64
+    }
+
+    fn set(&mut self, reg: usize, val: u64) {
+        self.regs[reg] = val as u32;
+    }
+
+    fn register_name(&self, reg: usize) -> Cow<'static, str> {
+        match reg {
+            0 => "IO_MUX_PIN_CTRL".into(),
+            _ => format!("IO_MUX_GPIO{}_REG", reg - 1).into(),
+        }
+    }
+
+    fn write(&self, regs: &mut Changes, offset: i64, val: u8) {
+        let reg = offset as usize / 4;
+        let mut bytes = (regs.get(reg) as u32).to_le_bytes();
+        bytes[offset as usize % 4] = val;
+        regs.set(reg, u32::from_le_bytes(bytes) as u64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use indoc::indoc;
+
+    use crate::{
+        executor::{device::update, Config, Executor},
+        parse::Register,
+    };
+
+    use super::*;
+
+    fn write(gpio: &mut Gpio, offset: i64, val: u8) {
+        update(gpio, |gpio, regs| gpio.write(regs, offset, val));
+    }
+
+    #[test]
+    fn registers() {
+        let mut gpio = Gpio::default();
+        write(&mut gpio, ENABLE, 0b1100);
+        write(&mut gpio, OUT_W1TS, 0b0110);
+        assert_eq!(gpio.output(2), Some(true));
+        assert_eq!(gpio.output(3), Some(false));
+        // Not an output
+        assert_eq!(gpio.output(1), None);
+
+        gpio.set_input(0, true);
+        gpio.set_input(3, true);
+        // Outputs read back their own level
+        assert_eq!(gpio.read(IN), 0b0101);
+
+        write(&mut gpio, OUT_W1TC, 0b0100);
+        write(&mut gpio, ENABLE_W1TS + 2, 0xff);
+        assert_eq!(gpio.read(OUT), 0b0010);
+        // Only 22 pins
+        assert_eq!(gpio.read(ENABLE + 2), 0x3f);
+        assert_eq!(gpio.read(OUT_W1TS), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "GPIO22 doesn't exist, there are only 22 pins")]
+    fn missing_pin() {
+        Gpio::default().set_input(22, true);
+    }
+
+    #[test]
+    #[should_panic(expected = "GPIO40 doesn't exist")]
+    fn missing_io_mux_pin() {
+        IoMux::default().pin(40);
+    }
+
+    #[test]
+    fn program() {
+        // Like pinSetup, pinWrite and pinRead in tests/test.s
+        let program = indoc! {"
+            # Input on pin 3, with its pull-up
+            li a2, 0x60009004   # IO_MUX_GPIOn_ADDR
+            addi a2, a2, 12
+            lw a5, 0(a2)
+            ori a5, a5, 0x300
+            sw a5, 0(a2)
+            # Output on pin 5
+            li a3, 0x60004020   # GPIO_ENABLE_ADDR
+            lw a6, 0(a3)
+            ori a6, a6, 0x20
+            sw a6, 0(a3)
+            # Write 1 to it
+            li a2, 0x60004004   # GPIO_OUT_ADDR
+            lw a4, 0(a2)
+            ori a4, a4, 0x20
+            sw a4, 0(a2)
+            # Read pin 3
+            li a2, 0x6000403C   # GPIO_IN_ADDR
+            lw a1, 0(a2)
+            srli a1, a1, 3
+            andi a0, a1, 1
+        "};
+        let config = Config {
+            gpio: true,
+            ..Default::default()
+        };
+        let mut exec = Executor::from_source(program, config).unwrap();
+        exec.memory.device_mut::<Gpio>().unwrap().set_input(3, true);
+        exec.run().unwrap();
+
+        assert_eq!(exec.regfile[Register::a0], 1);
+        let gpio = exec.memory.device::<Gpio>().unwrap();
+        assert_eq!(gpio.output(5), Some(true));
+        assert!(exec.memory.device::<IoMux>().unwrap().input_enabled(3));
+
+        // Reverting takes back the program's changes, but not the inputs
+        exec.memory.device_mut::<Gpio>().unwrap().set_input(4, true);
+        while exec.revert() {}
+        let gpio = exec.memory.device::<Gpio>().unwrap();
+        assert_eq!(gpio.output(5), None);
+        assert!(gpio.input(3) && gpio.input(4));
+    }
+}
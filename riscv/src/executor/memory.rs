@@ -1,7 +1,18 @@
-use std::collections::HashMap;
+use std::{cell::RefCell, collections::HashMap, fmt, ops::Range, sync::Arc};
+
+use thiserror::Error;
 
 use crate::parse::{LoadOp, StoreOp};
 
+use super::{
+    clint::CLINT_BASE,
+    device::{self, Device, Mapped},
+    gpio::{GPIO_BASE, IO_MUX_BASE},
+    paging::{Access, Translation},
+    uart::UART_BASE,
+    ConfigLevel, STACK_TOP,
+};
+
 #[derive(Debug, PartialEq, Eq, Default, Clone, Copy)]
 pub struct Config {
     /// The default value for unitialized memory locations. `None` means there is
@@ -22,71 +33,552 @@ impl Config {
     }
 }
 
+const PAGE_BITS: u32 = 12;
+
+/// The size of the pages memory is allocated in, 4 KiB.
+pub const PAGE_SIZE: usize = 1 << PAGE_BITS;
+
+/// A page of memory, which remembers which of its bytes are initialized.
+#[derive(Clone, PartialEq, Eq)]
+struct Page {
+    data: [u8; PAGE_SIZE],
+    /// Bit `i % 64` of `init[i / 64]` is set if byte `i` is initialized
+    init: [u64; PAGE_SIZE / 64],
+}
+
+impl Page {
+    fn new() -> Self {
+        Self {
+            data: [0; PAGE_SIZE],
+            init: [0; PAGE_SIZE / 64],
+        }
+    }
+
+    fn get(&self, offset: usize) -> Option<u8> {
+        let initialized = self.init[offset / 64] & (1 << (offset % 64)) != 0;
+        initialized.then_some(self.data[offset])
+    }
+
+    fn set(&mut self, offset: usize, byte: Option<u8>) {
+        let bit = 1 << (offset % 64);
+        match byte {
+            Some(byte) => {
+                self.data[offset] = byte;
+                self.init[offset / 64] |= bit;
+            }
+            None => {
+                self.data[offset] = 0;
+                self.init[offset / 64] &= !bit;
+            }
+        }
+    }
+
+    /// The `N` bytes starting at `offset`, if they are all initialized.
+    fn get_all<const N: usize>(&self, offset: usize) -> Option<[u8; N]> {
+        let initialized = (offset..offset + N).all(|offset| self.get(offset).is_some());
+        initialized.then(|| self.data[offset..offset + N].try_into().unwrap())
+    }
+
+    fn set_all(&mut self, offset: usize, bytes: &[u8]) {
+        self.data[offset..offset + bytes.len()].copy_from_slice(bytes);
+        for offset in offset..offset + bytes.len() {
+            self.init[offset / 64] |= 1 << (offset % 64);
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.init.iter().all(|&bits| bits == 0)
+    }
+}
+
+impl fmt::Debug for Page {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let initialized: u32 = self.init.iter().map(|bits| bits.count_ones()).sum();
+        write!(f, "Page({initialized} bytes initialized)")
+    }
+}
+
+/// What a [`Region`] of memory can be accessed for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Permissions {
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool,
+}
+
+impl Permissions {
+    /// Read only, like constants.
+    pub const R: Self = Self::new(true, false, false);
+    /// Read and write, like data and the stack.
+    pub const RW: Self = Self::new(true, true, false);
+    /// Read and execute, like code.
+    pub const RX: Self = Self::new(true, false, true);
+    /// Anything goes.
+    pub const RWX: Self = Self::new(true, true, true);
+
+    pub const fn new(read: bool, write: bool, execute: bool) -> Self {
+        Self {
+            read,
+            write,
+            execute,
+        }
+    }
+
+    pub fn allows(self, access: Access) -> bool {
+        match access {
+            Access::Read => self.read,
+            Access::Write => self.write,
+            Access::Execute => self.execute,
+        }
+    }
+}
+
+/// Like `ls -l`: `r-x` is readable and executable, but not writable.
+impl fmt::Display for Permissions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let flag = |set, c| if set { c } else { '-' };
+        write!(
+            f,
+            "{}{}{}",
+            flag(self.read, 'r'),
+            flag(self.write, 'w'),
+            flag(self.execute, 'x')
+        )
+    }
+}
+
+/// A named range of physical addresses in a [`MemoryMap`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Region {
+    pub name: String,
+    pub range: Range<i64>,
+    pub permissions: Permissions,
+
+    /// The value of uninitialized bytes in the region, instead of
+    /// [`Config::default_value`]. Defaults to `None`.
+    pub default_value: Option<u8>,
+}
+
+impl Region {
+    pub fn new(name: impl Into<String>, range: Range<i64>, permissions: Permissions) -> Self {
+        Self {
+            name: name.into(),
+            range,
+            permissions,
+            default_value: None,
+        }
+    }
+
+    pub fn with_default(mut self, default_value: Option<u8>) -> Self {
+        self.default_value = default_value;
+        self
+    }
+}
+
+/// The regions of memory a program may access. Accesses outside every region,
+/// or that the region doesn't permit, are reported at the map's level.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemoryMap {
+    pub regions: Vec<Region>,
+    pub level: ConfigLevel,
+}
+
+impl MemoryMap {
+    /// A map with no regions, so every access is reported at `level`.
+    pub fn new(level: ConfigLevel) -> Self {
+        Self {
+            regions: vec![],
+            level,
+        }
+    }
+
+    /// The usual layout: text at `0x00400000` and data at `0x10000000` like
+    /// in RARS, the heap after the data, the stack below where `sp` starts at
+    /// `0x40000000`, and the registers of the CLINT, the GPIO devices and the
+    /// UART as MMIO.
+    pub fn standard(level: ConfigLevel) -> Self {
+        Self::new(level)
+            .with(Region::new(
+                "text",
+                0x0040_0000..0x1000_0000,
+                Permissions::RX,
+            ))
+            .with(Region::new(
+                "data",
+                0x1000_0000..0x1004_0000,
+                Permissions::RW,
+            ))
+            .with(Region::new(
+                "heap",
+                0x1004_0000..0x3000_0000,
+                Permissions::RW,
+            ))
+            .with(Region::new(
+                "stack",
+                0x3000_0000..STACK_TOP,
+                Permissions::RW,
+            ))
+            .with(Region::new(
+                "mmio",
+                CLINT_BASE..CLINT_BASE + 0x10000,
+                Permissions::RW,
+            ))
+            .with(Region::new(
+                "uart",
+                UART_BASE..UART_BASE + 8,
+                Permissions::RW,
+            ))
+            .with(Region::new(
+                "gpio",
+                GPIO_BASE..GPIO_BASE + 0x100,
+                Permissions::RW,
+            ))
+            .with(Region::new(
+                "io_mux",
+                IO_MUX_BASE..IO_MUX_BASE + 0x100,
+                Permissions::RW,
+            ))
+    }
+
+    pub fn with(mut self, region: Region) -> Self {
+        self.regions.push(region);
+        self
+    }
+
+    /// The region `addr` is in. The first one wins if regions overlap.
+    pub fn region(&self, addr: i64) -> Option<&Region> {
+        self.regions
+            .iter()
+            .find(|region| region.range.contains(&addr))
+    }
+
+    /// Check an access of `len` bytes at the physical address `addr`. It may
+    /// span several regions, as long as they all permit it.
+    pub fn check(&self, addr: i64, len: usize, access: Access) -> MemoryResult<()> {
+        for addr in addr..addr + len as i64 {
+            let Some(region) = self.region(addr) else {
+                Err(MemoryError::Unmapped { addr, access })?
+            };
+            if !region.permissions.allows(access) {
+                Err(MemoryError::Forbidden {
+                    addr,
+                    access,
+                    region: region.name.clone(),
+                    permissions: region.permissions,
+                })?
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The page an address is in, and its offset in that page.
+fn split(addr: i64) -> (i64, usize) {
+    (addr >> PAGE_BITS, (addr & (PAGE_SIZE as i64 - 1)) as usize)
+}
+
 /// Byte addressible memory that handles unitialized values and unaligned access.
 ///
+/// Memory is allocated a page at a time, the first time one of its bytes is
+/// written. Pages are shared between clones until one of them writes to it, so
+/// cloning is cheap.
+///
 /// See [`Config`] for more details.
 #[derive(Debug, Default, PartialEq, Eq, Clone)]
 pub struct Memory {
     pub config: Config,
 
-    // Bytes are stored in little-endian order
-    pub mem: HashMap<i32, u8>,
+    // Bytes are stored in little-endian order, keyed by page number. Pages that
+    // have no initialized bytes are never kept, so equal memories have equal
+    // pages.
+    pages: HashMap<i64, Arc<Page>>,
+
+    /// The word reserved by the last `lr.w`, if any. The executor clears it when
+    /// a store touches the reserved word, see `Executor::store_effects`.
+    reservation: Option<i64>,
+
+    /// Devices handle the accesses to their own registers, like the CLINT.
+    devices: Vec<Mapped>,
+
+    /// The regions accesses are checked against, if any.
+    pub map: Option<MemoryMap>,
+
+    /// Violations of the map at [`ConfigLevel::Warn`] since they were last
+    /// taken, as the access went ahead anyway.
+    violations: RefCell<Vec<MemoryError>>,
+}
+
+/// An address given to [`Memory`]. Plain integers convert to physical
+/// addresses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Address {
+    Physical(i64),
+    /// Translated through the page tables before it is accessed.
+    Virtual(i64, Translation),
+}
+
+impl Address {
+    /// The address before any translation.
+    pub fn raw(self) -> i64 {
+        match self {
+            Address::Physical(addr) | Address::Virtual(addr, _) => addr,
+        }
+    }
+}
+
+impl From<i64> for Address {
+    fn from(addr: i64) -> Self {
+        Address::Physical(addr)
+    }
 }
 
 type MemoryResult<T> = Result<T, MemoryError>;
 
-#[derive(Debug)]
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
 pub enum MemoryError {
-    UnalignedAccess(i32),
-    UnitializedAccess(i32),
+    #[error("unaligned access at {0:#010x}")]
+    UnalignedAccess(i64),
+    #[error("access to uninitialized memory at {0:#010x}")]
+    UnitializedAccess(i64),
+    #[error("{access} page fault at {addr:#010x}")]
+    PageFault { addr: i64, access: Access },
+    #[error("{access} at {addr:#010x} is outside every memory region")]
+    Unmapped { addr: i64, access: Access },
+    #[error("{access} at {addr:#010x} not permitted in {region} ({permissions})")]
+    Forbidden {
+        addr: i64,
+        access: Access,
+        region: String,
+        permissions: Permissions,
+    },
 }
 
 impl Memory {
-    pub fn load(&self, addr: i32, op: LoadOp) -> MemoryResult<i32> {
+    /// Load a value. Virtual addresses are checked for alignment before they are
+    /// translated.
+    pub fn load(&self, addr: impl Into<Address>, op: LoadOp) -> MemoryResult<i64> {
+        let addr = addr.into();
+        let mask = match op {
+            LoadOp::Ld => 0b111,
+            LoadOp::Lw | LoadOp::Lwu => 0b11,
+            LoadOp::Lh | LoadOp::Lhu => 0b1,
+            LoadOp::Lb | LoadOp::Lbu => 0,
+        };
+        if !self.config.allow_unaligned && addr.raw() & mask != 0 {
+            Err(MemoryError::UnalignedAccess(addr.raw()))?
+        }
+        let addr = self.translate(addr, Access::Read)?;
+        self.check_map(addr, mask as usize + 1, Access::Read)?;
         match op {
+            LoadOp::Ld => {
+                let data = self.load_bytes::<8>(addr)?;
+                Ok(i64::from_le_bytes(data))
+            }
             LoadOp::Lw => {
-                if !self.config.allow_unaligned && !(addr & 0b11 == 0) {
-                    Err(MemoryError::UnalignedAccess(addr))?
-                }
                 let data = self.load_bytes::<4>(addr)?;
-                Ok(i32::from_le_bytes(data))
+                // Sign extends
+                Ok(i32::from_le_bytes(data) as i64)
+            }
+            LoadOp::Lwu => {
+                let data = self.load_bytes::<4>(addr)?;
+                // First cast to u32 to zero extend, then cast to i64
+                Ok(u32::from_le_bytes(data) as i64)
             }
             LoadOp::Lh => {
-                if !self.config.allow_unaligned && !(addr & 0b1 == 0) {
-                    Err(MemoryError::UnalignedAccess(addr))?
-                }
                 let data = self.load_bytes::<2>(addr)?;
                 // Sign extends
-                Ok(i16::from_le_bytes(data) as i32)
+                Ok(i16::from_le_bytes(data) as i64)
             }
             LoadOp::Lhu => {
-                if !self.config.allow_unaligned && !(addr & 0b1 == 0) {
-                    Err(MemoryError::UnalignedAccess(addr))?
-                }
                 let data = self.load_bytes::<2>(addr)?;
-                // First cast to u32 to zero extend, then cast to i32
-                Ok((u16::from_le_bytes(data) as u32) as i32)
+                // First cast to u16 to zero extend, then cast to i64
+                Ok(u16::from_le_bytes(data) as i64)
             }
             LoadOp::Lb => {
                 let data = self.load_bytes::<1>(addr)?;
                 // Sign extends
-                Ok(i8::from_le_bytes(data) as i32)
+                Ok(i8::from_le_bytes(data) as i64)
             }
             LoadOp::Lbu => {
                 let data = self.load_bytes::<1>(addr)?;
-                // First cast to u32 to zero extend, then cast to i32
-                Ok((u8::from_le_bytes(data) as u32) as i32)
+                // First cast to u8 to zero extend, then cast to i64
+                Ok(u8::from_le_bytes(data) as i64)
+            }
+        }
+    }
+
+    /// Check a physical access against the memory map, if there is one.
+    fn check_map(&self, addr: i64, len: usize, access: Access) -> MemoryResult<()> {
+        let Some(map) = &self.map else {
+            return Ok(());
+        };
+        match (map.check(addr, len, access), map.level) {
+            (Ok(()), _) | (Err(_), ConfigLevel::Allow) => Ok(()),
+            (Err(error), ConfigLevel::Warn) => {
+                self.violations.borrow_mut().push(error);
+                Ok(())
+            }
+            (Err(error), ConfigLevel::Deny) => Err(error),
+        }
+    }
+
+    /// Take the violations of the map that were let through with a warning.
+    pub fn take_violations(&self) -> Vec<MemoryError> {
+        self.violations.take()
+    }
+
+    /// The value of an uninitialized byte at `addr`.
+    fn default_value(&self, addr: i64) -> Option<u8> {
+        match self.map.as_ref().and_then(|map| map.region(addr)) {
+            Some(region) => region.default_value,
+            None => self.config.default_value,
+        }
+    }
+
+    /// Fetch the 2-byte instruction parcel at the physical address `addr`.
+    pub fn fetch(&self, addr: i64) -> MemoryResult<u16> {
+        if addr & 1 != 0 {
+            Err(MemoryError::UnalignedAccess(addr))?
+        }
+        self.check_map(addr, 2, Access::Execute)?;
+        Ok(u16::from_le_bytes(self.load_bytes::<2>(addr)?))
+    }
+
+    /// Whether the `len` bytes starting at `addr` are all in one page, and
+    /// none are a device's registers.
+    fn in_one_page(&self, addr: i64, len: usize) -> bool {
+        let last = addr + len as i64 - 1;
+        let range = addr..last + 1;
+        split(addr).0 == split(last).0
+            && self.devices.iter().all(|mapped| {
+                let device = mapped.range();
+                device.end <= range.start || range.end <= device.start
+            })
+    }
+
+    /// Attach a device with its first register at `base`. Returns its index in
+    /// [`Memory::devices`].
+    pub fn attach(&mut self, base: i64, device: impl Device + 'static) -> usize {
+        self.devices.push(Mapped {
+            base,
+            device: Box::new(device),
+        });
+        self.devices.len() - 1
+    }
+
+    pub fn devices(&self) -> &[Mapped] {
+        &self.devices
+    }
+
+    /// The first attached device of type `T`.
+    pub fn device<T: Device + 'static>(&self) -> Option<&T> {
+        self.devices
+            .iter()
+            .find_map(|mapped| mapped.device.as_any().downcast_ref())
+    }
+
+    /// The first attached device of type `T`, to change from outside the
+    /// program, like pressing a button. Changes made this way aren't effects,
+    /// so they can't be reverted.
+    pub fn device_mut<T: Device + 'static>(&mut self) -> Option<&mut T> {
+        self.devices
+            .iter_mut()
+            .find_map(|mapped| mapped.device.as_any_mut().downcast_mut())
+    }
+
+    /// The index of the device whose registers include `addr`, if any.
+    pub fn device_at(&self, addr: i64) -> Option<usize> {
+        self.devices.iter().position(|mapped| mapped.contains(addr))
+    }
+
+    /// Tell every device how many instructions have been executed.
+    pub(super) fn set_time(&mut self, now: u64) {
+        for mapped in &mut self.devices {
+            mapped.device.set_time(now);
+        }
+    }
+
+    /// Set a state register of the device at `index`.
+    pub(super) fn set_device_register(&mut self, index: usize, reg: usize, val: u64) {
+        self.devices[index].device.set(reg, val);
+    }
+
+    /// The byte at the physical address `addr`, or `None` if it's uninitialized.
+    fn byte(&self, addr: i64) -> Option<u8> {
+        if let Some(mapped) = self.devices.iter().find(|mapped| mapped.contains(addr)) {
+            return Some(mapped.device.read(addr - mapped.base));
+        }
+        let (number, offset) = split(addr);
+        self.pages.get(&number)?.get(offset)
+    }
+
+    /// Set the byte at the physical address `addr`, or make it uninitialized.
+    fn set_byte(&mut self, addr: i64, byte: Option<u8>) {
+        if let Some(mapped) = self.devices.iter_mut().find(|mapped| mapped.contains(addr)) {
+            let offset = addr - mapped.base;
+            device::update(&mut *mapped.device, |device, regs| {
+                device.write(regs, offset, byte.unwrap_or(0))
+            });
+            return;
+        }
+        let (number, offset) = split(addr);
+        match byte {
+            Some(_) => {
+                let page = self
+                    .pages
+                    .entry(number)
+                    .or_insert_with(|| Arc::new(Page::new()));
+                Arc::make_mut(page).set(offset, byte);
+            }
+            None => {
+                let Some(page) = self.pages.get_mut(&number) else {
+                    return;
+                };
+                let page = Arc::make_mut(page);
+                page.set(offset, None);
+                if page.is_empty() {
+                    self.pages.remove(&number);
+                }
             }
         }
     }
 
+    /// Every initialized byte, by address. Device registers aren't included.
+    pub fn contents(&self) -> HashMap<i64, u8> {
+        let mut contents = HashMap::new();
+        for (&number, page) in &self.pages {
+            for offset in 0..PAGE_SIZE {
+                if let Some(byte) = page.get(offset) {
+                    contents.insert((number << PAGE_BITS) + offset as i64, byte);
+                }
+            }
+        }
+        contents
+    }
+
+    /// The number of pages that have been allocated.
+    pub fn pages(&self) -> usize {
+        self.pages.len()
+    }
+
     /// Load `N` bytes, starting at the base address. Returns an error if any of
     /// then is unitialized.
-    fn load_bytes<const N: usize>(&self, base_addr: i32) -> MemoryResult<[u8; N]> {
+    fn load_bytes<const N: usize>(&self, base_addr: i64) -> MemoryResult<[u8; N]> {
+        // Fast path: an initialized access within a page needs one lookup
+        if self.in_one_page(base_addr, N) {
+            let (number, offset) = split(base_addr);
+            if let Some(data) = self
+                .pages
+                .get(&number)
+                .and_then(|page| page.get_all(offset))
+            {
+                return Ok(data);
+            }
+        }
         let mut data = [0u8; N];
         for (offset, spot) in data.iter_mut().enumerate() {
-            let addr = base_addr + (offset as i32);
-            let Some(byte) = self.mem.get(&addr).copied().or(self.config.default_value) else {
+            let addr = base_addr + (offset as i64);
+            let Some(byte) = self.byte(addr).or_else(|| self.default_value(addr)) else {
                 Err(MemoryError::UnitializedAccess(addr))?
             };
             *spot = byte;
@@ -95,36 +587,100 @@ impl Memory {
     }
 
     /// Store `N` bytes, starting at the base address
-    fn store_bytes<const N: usize>(&mut self, base_addr: i32, bytes: [u8; N]) {
+    fn store_bytes<const N: usize>(&mut self, base_addr: i64, bytes: [u8; N]) {
+        if self.in_one_page(base_addr, N) {
+            let (number, offset) = split(base_addr);
+            let page = self
+                .pages
+                .entry(number)
+                .or_insert_with(|| Arc::new(Page::new()));
+            Arc::make_mut(page).set_all(offset, &bytes);
+            return;
+        }
         for (offset, byte) in bytes.iter().enumerate() {
-            let addr = base_addr + (offset as i32);
-            self.mem.insert(addr, *byte);
+            self.set_byte(base_addr + (offset as i64), Some(*byte));
         }
     }
 
-    /// Store a value at a certain address, returning the value that was previously
-    /// there, if any.
-    pub fn store(&mut self, addr: i32, val: i32, op: StoreOp) -> MemoryResult<()> {
+    /// Check that a store to `addr` would succeed, without performing it.
+    /// Returns the physical address to store to.
+    pub fn check_store(&self, addr: impl Into<Address>, op: StoreOp) -> MemoryResult<i64> {
+        let addr = addr.into();
+        let mask = match op {
+            StoreOp::Sd => 0b111,
+            StoreOp::Sw => 0b11,
+            StoreOp::Sh => 0b1,
+            StoreOp::Sb => 0,
+        };
+        if !self.config.allow_unaligned && addr.raw() & mask != 0 {
+            Err(MemoryError::UnalignedAccess(addr.raw()))?;
+        }
+        let addr = self.translate(addr, Access::Write)?;
+        self.check_map(addr, mask as usize + 1, Access::Write)?;
+        Ok(addr)
+    }
+
+    /// Store a value at a certain address.
+    pub fn store(&mut self, addr: impl Into<Address>, val: i64, op: StoreOp) -> MemoryResult<()> {
+        let addr = self.check_store(addr, op)?;
         // Note: casting to a smaller integer type truncates, which is what we want
         match op {
-            StoreOp::Sw => {
-                if !self.config.allow_unaligned && !(addr & 0b11 == 0) {
-                    Err(MemoryError::UnalignedAccess(addr))?;
-                }
-                self.store_bytes(addr, val.to_le_bytes());
-            }
-            StoreOp::Sh => {
-                if !self.config.allow_unaligned && !(addr & 0b1 == 0) {
-                    Err(MemoryError::UnalignedAccess(addr))?;
-                }
-                self.store_bytes(addr, (val as i16).to_le_bytes());
-            }
-            StoreOp::Sb => {
-                self.store_bytes(addr, (val as i8).to_le_bytes());
-            }
+            StoreOp::Sd => self.store_bytes(addr, val.to_le_bytes()),
+            StoreOp::Sw => self.store_bytes(addr, (val as i32).to_le_bytes()),
+            StoreOp::Sh => self.store_bytes(addr, (val as i16).to_le_bytes()),
+            StoreOp::Sb => self.store_bytes(addr, (val as i8).to_le_bytes()),
         }
         Ok(())
     }
+
+    /// Load the word used by an atomic instruction, sign extended. Unlike regular
+    /// loads, atomics must always be naturally aligned, regardless of [`Config`].
+    pub fn load_atomic(&self, addr: impl Into<Address>) -> MemoryResult<i64> {
+        let addr = addr.into();
+        if addr.raw() & 0b11 != 0 {
+            Err(MemoryError::UnalignedAccess(addr.raw()))?
+        }
+        let addr = self.translate(addr, Access::Read)?;
+        self.check_map(addr, 4, Access::Read)?;
+        Ok(i32::from_le_bytes(self.load_bytes::<4>(addr)?) as i64)
+    }
+
+    /// Check that `addr` can be the target of an atomic store. Returns the
+    /// physical address to store to.
+    pub fn check_atomic(&self, addr: impl Into<Address>) -> MemoryResult<i64> {
+        let addr = addr.into();
+        if addr.raw() & 0b11 != 0 {
+            Err(MemoryError::UnalignedAccess(addr.raw()))?
+        }
+        let addr = self.translate(addr, Access::Write)?;
+        self.check_map(addr, 4, Access::Write)?;
+        Ok(addr)
+    }
+
+    /// The address currently reserved by `lr.w`, if any.
+    pub fn reservation(&self) -> Option<i64> {
+        self.reservation
+    }
+
+    pub fn set_reservation(&mut self, reservation: Option<i64>) {
+        self.reservation = reservation;
+    }
+
+    /// The `len` bytes starting at the physical address `addr`, with `None` for
+    /// uninitialized ones. Unlike loads, this ignores the default value.
+    pub fn bytes(&self, addr: i64, len: usize) -> Vec<Option<u8>> {
+        (addr..addr + len as i64)
+            .map(|addr| self.byte(addr))
+            .collect()
+    }
+
+    /// Overwrite the bytes starting at the physical address `addr`, making the
+    /// `None` ones uninitialized. The reservation is left alone.
+    pub fn set_bytes(&mut self, addr: i64, bytes: &[Option<u8>]) {
+        for (addr, byte) in (addr..).zip(bytes) {
+            self.set_byte(addr, *byte);
+        }
+    }
 }
 
 #[cfg(test)]
@@ -145,7 +701,11 @@ mod tests {
                     default_value: None,
                     allow_unaligned: false
                 },
-                mem: map![]
+                pages: map![],
+                reservation: None,
+                devices: vec![],
+                map: None,
+                violations: Default::default(),
             },
             mem
         );
@@ -161,16 +721,34 @@ mod tests {
         // out of bounds
         assert!(mem.load(0x41, LoadOp::Lw).is_err());
 
-        assert_eq!(mem.load(0x40, LoadOp::Lh).unwrap(), 0xffffabcd_u32 as i32);
+        assert_eq!(
+            mem.load(0x40, LoadOp::Lh).unwrap(),
+            0xffffabcd_u32 as i32 as i64
+        );
         assert!(mem.load(0x41, LoadOp::Lh).is_err());
-        assert_eq!(mem.load(0x42, LoadOp::Lh).unwrap(), 0x00001234_u32 as i32);
+        assert_eq!(
+            mem.load(0x42, LoadOp::Lh).unwrap(),
+            0x00001234_u32 as i32 as i64
+        );
         // out of bounds
         assert!(mem.load(0x43, LoadOp::Lh).is_err());
 
-        assert_eq!(mem.load(0x40, LoadOp::Lb).unwrap(), 0xffffffcd_u32 as i32);
-        assert_eq!(mem.load(0x41, LoadOp::Lb).unwrap(), 0xffffffab_u32 as i32);
-        assert_eq!(mem.load(0x42, LoadOp::Lb).unwrap(), 0x00000034_u32 as i32);
-        assert_eq!(mem.load(0x43, LoadOp::Lb).unwrap(), 0x00000012_u32 as i32);
+        assert_eq!(
+            mem.load(0x40, LoadOp::Lb).unwrap(),
+            0xffffffcd_u32 as i32 as i64
+        );
+        assert_eq!(
+            mem.load(0x41, LoadOp::Lb).unwrap(),
+            0xffffffab_u32 as i32 as i64
+        );
+        assert_eq!(
+            mem.load(0x42, LoadOp::Lb).unwrap(),
+            0x00000034_u32 as i32 as i64
+        );
+        assert_eq!(
+            mem.load(0x43, LoadOp::Lb).unwrap(),
+            0x00000012_u32 as i32 as i64
+        );
         // out of bounds
         assert!(mem.load(0x44, LoadOp::Lb).is_err());
 
@@ -190,16 +768,31 @@ mod tests {
         // Unaligned access
         mem.config.allow_unaligned = true;
         mem.store(0x44, 0x1234abcd, StoreOp::Sw).unwrap();
-        assert_eq!(mem.load(0x41, LoadOp::Lw).unwrap(), 0xcd1234ab_u32 as i32);
-        assert_eq!(mem.load(0x41, LoadOp::Lh).unwrap(), 0x000034ab_u32 as i32);
+        assert_eq!(
+            mem.load(0x41, LoadOp::Lw).unwrap(),
+            0xcd1234ab_u32 as i32 as i64
+        );
+        assert_eq!(
+            mem.load(0x41, LoadOp::Lh).unwrap(),
+            0x000034ab_u32 as i32 as i64
+        );
         assert_eq!(mem.load(0x41, LoadOp::Lhu).unwrap(), 0x000034ab);
 
         // Default values
         mem.config.default_value = Some(0xaa);
-        assert_eq!(mem.load(0x48, LoadOp::Lw).unwrap(), 0xaaaaaaaa_u32 as i32);
-        assert_eq!(mem.load(0x48, LoadOp::Lh).unwrap(), 0xffffaaaa_u32 as i32);
+        assert_eq!(
+            mem.load(0x48, LoadOp::Lw).unwrap(),
+            0xaaaaaaaa_u32 as i32 as i64
+        );
+        assert_eq!(
+            mem.load(0x48, LoadOp::Lh).unwrap(),
+            0xffffaaaa_u32 as i32 as i64
+        );
         assert_eq!(mem.load(0x48, LoadOp::Lhu).unwrap(), 0xaaaa);
-        assert_eq!(mem.load(0x48, LoadOp::Lb).unwrap(), 0xffffffaa_u32 as i32);
+        assert_eq!(
+            mem.load(0x48, LoadOp::Lb).unwrap(),
+            0xffffffaa_u32 as i32 as i64
+        );
         assert_eq!(mem.load(0x48, LoadOp::Lbu).unwrap(), 0xaa);
     }
 
@@ -207,11 +800,11 @@ mod tests {
     fn stores() {
         let mut mem: Memory = Default::default();
 
-        assert_eq!(mem.mem, map![]);
+        assert_eq!(mem.contents(), map![]);
 
         mem.store(0x20, 0x1234abcd, StoreOp::Sw).unwrap();
         assert_eq!(
-            mem.mem,
+            mem.contents(),
             map![
                 0x20 => 0xcd,
                 0x21 => 0xab,
@@ -222,7 +815,7 @@ mod tests {
         // Truncates
         mem.store(0x22, 0x1234abcd, StoreOp::Sh).unwrap();
         assert_eq!(
-            mem.mem,
+            mem.contents(),
             map![
                 0x20 => 0xcd,
                 0x21 => 0xab,
@@ -232,7 +825,7 @@ mod tests {
         );
         mem.store(0x23, 0x1234abcd, StoreOp::Sb).unwrap();
         assert_eq!(
-            mem.mem,
+            mem.contents(),
             map![
                 0x20 => 0xcd,
                 0x21 => 0xab,
@@ -247,7 +840,7 @@ mod tests {
         mem.config.allow_unaligned = true;
         mem.store(0x21, 0x1234abcd, StoreOp::Sw).unwrap();
         assert_eq!(
-            mem.mem,
+            mem.contents(),
             map![
                 0x20 => 0xcd,
                 0x21 => 0xcd,
@@ -259,7 +852,7 @@ mod tests {
         );
         mem.store(0x23, 0x1234abcd, StoreOp::Sh).unwrap();
         assert_eq!(
-            mem.mem,
+            mem.contents(),
             map![
                 0x20 => 0xcd,
                 0x21 => 0xcd,
@@ -269,4 +862,130 @@ mod tests {
             ]
         );
     }
+
+    #[test]
+    fn doublewords() {
+        let mut mem: Memory = Default::default();
+        mem.store(0x40, 0x1122334455667788, StoreOp::Sd).unwrap();
+        assert_eq!(mem.load(0x40, LoadOp::Ld).unwrap(), 0x1122334455667788);
+        assert_eq!(mem.load(0x44, LoadOp::Lw).unwrap(), 0x11223344);
+        assert!(mem.load(0x44, LoadOp::Ld).is_err());
+        assert!(mem.store(0x44, 0, StoreOp::Sd).is_err());
+
+        // lw sign extends, lwu zero extends
+        mem.store(0x48, -2, StoreOp::Sw).unwrap();
+        assert_eq!(mem.load(0x48, LoadOp::Lw).unwrap(), -2);
+        assert_eq!(mem.load(0x48, LoadOp::Lwu).unwrap(), 0xfffffffe);
+    }
+
+    #[test]
+    fn pages() {
+        let mut mem: Memory = Default::default();
+        mem.config.allow_unaligned = true;
+        assert_eq!(mem.pages(), 0);
+
+        // A word that straddles two pages
+        let boundary = PAGE_SIZE as i64;
+        mem.store(boundary - 2, 0x1234abcd, StoreOp::Sw).unwrap();
+        assert_eq!(mem.pages(), 2);
+        assert_eq!(mem.load(boundary - 2, LoadOp::Lw).unwrap(), 0x1234abcd);
+        assert_eq!(mem.load(boundary, LoadOp::Lhu).unwrap(), 0x1234);
+        // Partly initialized, within a page and across pages
+        assert!(mem.load(boundary - 4, LoadOp::Lw).is_err());
+        assert!(mem.load(boundary + 1, LoadOp::Lw).is_err());
+        mem.config.default_value = Some(0);
+        assert_eq!(mem.load(boundary + 1, LoadOp::Lw).unwrap(), 0x12);
+
+        // Negative addresses are in their own pages
+        mem.store(-4, -1, StoreOp::Sw).unwrap();
+        assert_eq!(mem.load(-4, LoadOp::Lw).unwrap(), -1);
+        assert_eq!(mem.pages(), 3);
+
+        // Clones share pages until they're written to
+        let clone = mem.clone();
+        mem.store(boundary, 0, StoreOp::Sh).unwrap();
+        assert_eq!(clone.load(boundary, LoadOp::Lhu).unwrap(), 0x1234);
+        assert_ne!(clone, mem);
+
+        // Pages with nothing initialized are freed
+        mem.set_bytes(-4, &[None; 4]);
+        assert_eq!(mem.pages(), 2);
+        assert_eq!(mem.bytes(boundary - 1, 2), [Some(0xab), Some(0)]);
+        assert_eq!(mem.contents().len(), 4);
+    }
+
+    #[test]
+    fn regions() {
+        let map = MemoryMap::new(ConfigLevel::Deny)
+            .with(Region::new("rom", 0x0..0x100, Permissions::R).with_default(Some(0xff)))
+            .with(Region::new("ram", 0x100..0x200, Permissions::RW));
+        let mut mem = Memory {
+            map: Some(map),
+            ..Default::default()
+        };
+        mem.config.default_value = Some(0);
+
+        // Each region has its own default value
+        assert_eq!(mem.load(0x10, LoadOp::Lbu).unwrap(), 0xff);
+        assert!(matches!(
+            mem.load(0x110, LoadOp::Lbu),
+            Err(MemoryError::UnitializedAccess(0x110))
+        ));
+        assert!(matches!(
+            mem.store(0x10, 0, StoreOp::Sw),
+            Err(MemoryError::Forbidden {
+                addr: 0x10,
+                access: Access::Write,
+                ..
+            })
+        ));
+        assert!(matches!(
+            mem.fetch(0x10),
+            Err(MemoryError::Forbidden {
+                access: Access::Execute,
+                ..
+            })
+        ));
+        assert!(matches!(
+            mem.load(0x200, LoadOp::Lw),
+            Err(MemoryError::Unmapped {
+                addr: 0x200,
+                access: Access::Read
+            })
+        ));
+        // An access can't spill out of the map
+        mem.config.allow_unaligned = true;
+        mem.store(0x1fc, 0, StoreOp::Sw).unwrap();
+        assert!(matches!(
+            mem.store(0x1fe, 0, StoreOp::Sw),
+            Err(MemoryError::Unmapped { addr: 0x200, .. })
+        ));
+
+        // Warnings are collected instead
+        mem.map.as_mut().unwrap().level = ConfigLevel::Warn;
+        mem.store(0x10, 0x1234, StoreOp::Sh).unwrap();
+        assert_eq!(mem.load(0x10, LoadOp::Lhu).unwrap(), 0x1234);
+        assert_eq!(mem.take_violations().len(), 1);
+        assert!(mem.take_violations().is_empty());
+        assert_eq!(Permissions::RX.to_string(), "r-x");
+    }
+
+    #[test]
+    fn atomics() {
+        let mut mem: Memory = Default::default();
+        mem.store(0x40, -1, StoreOp::Sw).unwrap();
+        assert_eq!(mem.load_atomic(0x40).unwrap(), -1);
+        assert_eq!(mem.check_atomic(0x40).unwrap(), 0x40);
+
+        // Atomics are always aligned
+        mem.config.allow_unaligned = true;
+        assert!(matches!(
+            mem.load_atomic(0x41),
+            Err(MemoryError::UnalignedAccess(0x41))
+        ));
+        assert!(matches!(
+            mem.check_atomic(0x42),
+            Err(MemoryError::UnalignedAccess(0x42))
+        ));
+    }
 }
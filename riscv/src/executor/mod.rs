@@ -1,26 +1,49 @@
+pub mod bitmanip;
+pub mod clint;
+pub mod custom;
+pub mod device;
+pub mod effect;
+pub mod float;
+pub mod gpio;
 pub mod memory;
+pub mod paging;
+pub mod trap;
+pub mod uart;
+pub mod vector;
 
 // TODO: change all printing to hex
 use std::{
-    collections::HashMap,
-    ops::{Index, IndexMut},
+    collections::VecDeque,
+    fmt,
+    ops::{Index, IndexMut, Range},
     str::FromStr,
 };
 
-use anyhow::{anyhow, Context};
+use anyhow::Context;
+use thiserror::Error;
 
 use crate::{
-    map,
+    decode::{self, DecodeErrorInner, Decoder},
+    elf::{Elf, ElfError},
+    encode::EncodeError,
+    lex::Lexer,
     parse::{
-        BranchOp, BranchZeroOp, Instruction, LoadImmOp, Program, RegImmOp, RegRegOp, Register,
-        StoreOp, UnaryOp,
+        AmoOp, Base, BranchOp, BranchZeroOp, Csr, CsrImmOp, CsrRegOp, ElementWidth, Extension,
+        FRegister, FToIntOp, Instruction, IntToFOp, LoadImmOp, LoadOp, Program, RegImmOp, RegRegOp,
+        Register, RoundingMode, StoreOp, UnaryOp, VRegister, VType,
     },
 };
 
-use self::memory::MemoryError;
-
-/// Take a snapshot of the registers every `SNAPSHOT_INTERVAL` instructions.
-pub const SNAPSHOT_INTERVAL: usize = 1000;
+use self::{
+    clint::{mip, Clint, CLINT_BASE},
+    custom::CustomInstructions,
+    effect::Effect,
+    gpio::{Gpio, IoMux, GPIO_BASE, IO_MUX_BASE},
+    memory::{Address, MemoryError, MemoryMap},
+    paging::{Access, Translation, PAGE_SIZE},
+    trap::{mstatus, Cause, Exception, Interrupt, Privilege},
+    uart::{Uart, UART_BASE},
+};
 
 #[rustfmt::skip]
 pub const REGISTERS: [Register; 32] = {
@@ -33,36 +56,283 @@ pub const REGISTERS: [Register; 32] = {
     ]
 };
 
+#[rustfmt::skip]
+pub const FREGISTERS: [FRegister; 32] = {
+    use FRegister::*;
+    [
+        ft0, ft1, ft2, ft3, ft4, ft5, ft6, ft7, ft8, ft9, ft10, ft11,
+        fa0, fa1, fa2, fa3, fa4, fa5, fa6, fa7,
+        fs0, fs1, fs2, fs3, fs4, fs5, fs6, fs7, fs8, fs9, fs10, fs11,
+    ]
+};
+
+#[rustfmt::skip]
+pub const VREGISTERS: [VRegister; 32] = {
+    use VRegister::*;
+    [
+        v0, v1, v2, v3, v4, v5, v6, v7, v8, v9, v10, v11, v12, v13, v14, v15,
+        v16, v17, v18, v19, v20, v21, v22, v23, v24, v25, v26, v27, v28, v29, v30, v31,
+    ]
+};
+
+/// Where `sp` starts, halfway up in the address space. The stack grows down
+/// from here.
+pub const STACK_TOP: i64 = 0x40000000;
+
 /// A snapshot of the registers at one point in time.
 #[rustfmt::skip]
 #[derive(Default, Clone, PartialEq, Eq, Debug)]
 pub struct RegisterSnapshot {
-    pc: i32,
+    pc: i64,
     
-    x0: i32, ra: i32, sp: i32, gp: i32, tp: i32,
+    x0: i64, ra: i64, sp: i64, gp: i64, tp: i64,
     
-    t0: i32, t1: i32, t2: i32, t3: i32,
-    t4: i32, t5: i32, t6: i32,
+    t0: i64, t1: i64, t2: i64, t3: i64,
+    t4: i64, t5: i64, t6: i64,
     
-    a0: i32, a1: i32, a2: i32, a3: i32,
-    a4: i32, a5: i32, a6: i32, a7: i32,
+    a0: i64, a1: i64, a2: i64, a3: i64,
+    a4: i64, a5: i64, a6: i64, a7: i64,
     
-    s0: i32, s1: i32, s2: i32, s3: i32, s4: i32,
-    s5: i32, s6: i32, s7: i32, s8: i32, s9: i32,
-    s10: i32, s11: i32,
+    s0: i64, s1: i64, s2: i64, s3: i64, s4: i64,
+    s5: i64, s6: i64, s7: i64, s8: i64, s9: i64,
+    s10: i64, s11: i64,
+
+    /// Floating point registers, stored as raw bits. Indexed by `FRegister as
+    /// usize`.
+    fregs: [u32; 32],
+
+    /// The floating point control and status register: exception flags in the
+    /// low 5 bits, and the rounding mode in the next 3.
+    fcsr: u32,
+
+    /// The current privilege mode.
+    privilege: Privilege,
+
+    // Machine-mode trap CSRs
+    mstatus: i64,
+    medeleg: i64,
+    mideleg: i64,
+    mtvec: i64,
+    mscratch: i64,
+    mepc: i64,
+    mcause: i64,
+    mtval: i64,
+    mie: i64,
+
+    /// Pending interrupts. The machine-level bits are raised by devices, the
+    /// supervisor-level ones by machine-mode software.
+    mip: i64,
+
+    // Supervisor-mode trap CSRs. `sstatus`, `sie` and `sip` are views of the
+    // machine-mode registers.
+    stvec: i64,
+    sscratch: i64,
+    sepc: i64,
+    scause: i64,
+    stval: i64,
+
+    /// Address translation: the mode in the top bit and the physical page
+    /// number of the root page table in the low 22 bits.
+    satp: i64,
+
+    /// Vector registers, `vlenb` bytes each, stored back to back so register
+    /// groups are contiguous.
+    vregs: Vec<u8>,
+    /// The number of elements vector instructions operate on.
+    vl: i64,
+    /// The vector configuration, as set by `vsetvli`.
+    vtype: i64,
 }
 
 impl RegisterSnapshot {
-    pub fn pc(&self) -> i32 {
+    pub fn pc(&self) -> i64 {
         self.pc
     }
 
-    /// Compare two [`RegisterSnapshot`] to see if their caller-saved registers
-    /// are equal.
+    pub fn privilege(&self) -> Privilege {
+        self.privilege
+    }
+
+    /// The bytes of a vector register.
+    pub fn vector(&self, reg: VRegister) -> &[u8] {
+        let vlenb = self.vregs.len() / 32;
+        &self.vregs[reg as usize * vlenb..(reg as usize + 1) * vlenb]
+    }
+
+    /// The elements of a vector register, at the given width.
+    pub fn vector_elements(&self, reg: VRegister, sew: ElementWidth) -> Vec<i64> {
+        let len = self.vregs.len() / 32 / sew.bytes();
+        (0..len)
+            .map(|index| vector::element(&self.vregs, reg, index, sew))
+            .collect()
+    }
+
+    /// The current vector configuration, or `None` if it is invalid.
+    pub fn vtype(&self) -> Option<VType> {
+        VType::from_bits(self.vtype)
+    }
+
+    /// Overwrite the register group starting at `reg`.
+    fn set_vector(&mut self, reg: VRegister, bytes: &[u8]) {
+        let start = reg as usize * self.vregs.len() / 32;
+        self.vregs[start..start + bytes.len()].copy_from_slice(bytes);
+    }
+
+    /// The value of a floating point register as a float.
+    pub fn float(&self, reg: FRegister) -> f32 {
+        f32::from_bits(self[reg])
+    }
+
+    /// Read a control and status register.
+    pub fn csr(&self, csr: Csr) -> i64 {
+        match csr {
+            Csr::Fflags => (self.fcsr & 0x1f) as i64,
+            Csr::Frm => ((self.fcsr >> 5) & 0b111) as i64,
+            Csr::Fcsr => (self.fcsr & 0xff) as i64,
+            Csr::Vl => self.vl,
+            Csr::Vtype => self.vtype,
+            Csr::Vlenb => (self.vregs.len() / 32) as i64,
+            Csr::Sstatus => self.mstatus & mstatus::SSTATUS,
+            Csr::Sie => self.mie & self.mideleg,
+            Csr::Stvec => self.stvec,
+            Csr::Sscratch => self.sscratch,
+            Csr::Sepc => self.sepc,
+            Csr::Scause => self.scause,
+            Csr::Stval => self.stval,
+            Csr::Sip => self.mip & self.mideleg,
+            Csr::Satp => self.satp,
+            Csr::Mstatus => self.mstatus,
+            Csr::Medeleg => self.medeleg,
+            Csr::Mideleg => self.mideleg,
+            Csr::Mtvec => self.mtvec,
+            Csr::Mscratch => self.mscratch,
+            Csr::Mepc => self.mepc,
+            Csr::Mcause => self.mcause,
+            Csr::Mtval => self.mtval,
+            Csr::Mie => self.mie,
+            Csr::Mip => self.mip,
+        }
+    }
+
+    /// Write a control and status register. Bits that aren't part of the CSR
+    /// are ignored. `c` is whether the C extension is enabled, which lets
+    /// `mepc` and `sepc` hold addresses that are only 2-byte aligned.
+    pub fn set_csr(&mut self, csr: Csr, val: i64, c: bool) {
+        // Instructions are 4-byte aligned, or 2-byte aligned with compressed ones
+        let epc_mask = if c { !0b1 } else { !0b11 };
+        let bits = val as u32;
+        match csr {
+            Csr::Fflags => self.fcsr = (self.fcsr & !0x1f) | (bits & 0x1f),
+            Csr::Frm => self.fcsr = (self.fcsr & !0xe0) | ((bits & 0b111) << 5),
+            Csr::Fcsr => self.fcsr = bits & 0xff,
+            // Read-only, so writes are rejected before getting here
+            Csr::Vl | Csr::Vtype | Csr::Vlenb => (),
+            Csr::Sstatus => {
+                self.mstatus = (self.mstatus & !mstatus::SSTATUS) | (val & mstatus::SSTATUS)
+            }
+            Csr::Sie => self.mie = (self.mie & !self.mideleg) | (val & self.mideleg),
+            Csr::Stvec => self.stvec = val & !0b10,
+            Csr::Sscratch => self.sscratch = val,
+            Csr::Sepc => self.sepc = val & epc_mask,
+            Csr::Scause => self.scause = val,
+            Csr::Stval => self.stval = val,
+            // Only the supervisor software interrupt can be raised from
+            // supervisor mode
+            Csr::Sip => {
+                let writable = mip::SSIP & self.mideleg;
+                self.mip = (self.mip & !writable) | (val & writable)
+            }
+            Csr::Satp => self.satp = val,
+            Csr::Mstatus => {
+                let writable = mstatus::SSTATUS | mstatus::MIE | mstatus::MPIE | mstatus::MPP;
+                self.mstatus = val & writable;
+                // The reserved privilege mode isn't a legal value of MPP
+                if (val >> 11) & 0b11 == 0b10 {
+                    self.mstatus &= !mstatus::MPP;
+                }
+            }
+            // Environment calls from machine mode can't be delegated
+            Csr::Medeleg => self.medeleg = val & 0xffff & !(1 << Exception::MachineEcall as i64),
+            // Only supervisor interrupts can be delegated
+            Csr::Mideleg => self.mideleg = val & (mip::SSIP | mip::STIP),
+            // Only the direct (0) and vectored (1) modes exist
+            Csr::Mtvec => self.mtvec = val & !0b10,
+            Csr::Mscratch => self.mscratch = val,
+            Csr::Mepc => self.mepc = val & epc_mask,
+            Csr::Mcause => self.mcause = val,
+            Csr::Mtval => self.mtval = val,
+            Csr::Mie => self.mie = val & (mip::MSIP | mip::MTIP | mip::SSIP | mip::STIP),
+            // The machine-level pending bits are raised by the CLINT
+            Csr::Mip => {
+                let writable = mip::SSIP | mip::STIP;
+                self.mip = (self.mip & !writable) | (val & writable)
+            }
+        }
+    }
+
+    /// Set or clear the bits of `mask` in `mstatus`.
+    fn set_mstatus(&mut self, mask: i64, set: bool) {
+        if set {
+            self.mstatus |= mask;
+        } else {
+            self.mstatus &= !mask;
+        }
+    }
+
+    /// Take a trap into `target` mode: save the pc, cause and previous privilege
+    /// mode, and disable interrupts.
+    fn trap(&mut self, target: Privilege, cause: i64, epc: i64, tval: i64) {
+        match target {
+            Privilege::Machine => {
+                self.mepc = epc;
+                self.mcause = cause;
+                self.mtval = tval;
+                self.set_mstatus(mstatus::MPIE, self.mstatus & mstatus::MIE != 0);
+                self.set_mstatus(mstatus::MIE, false);
+                self.mstatus = (self.mstatus & !mstatus::MPP) | (self.privilege as i64) << 11;
+            }
+            // Traps never go to user mode
+            Privilege::Supervisor | Privilege::User => {
+                self.sepc = epc;
+                self.scause = cause;
+                self.stval = tval;
+                self.set_mstatus(mstatus::SPIE, self.mstatus & mstatus::SIE != 0);
+                self.set_mstatus(mstatus::SIE, false);
+                self.set_mstatus(mstatus::SPP, self.privilege != Privilege::User);
+            }
+        }
+        self.privilege = target;
+    }
+
+    /// Return from a trap handled in `from` mode (`mret` or `sret`), restoring
+    /// the interrupt enable bit and privilege mode from before the trap.
+    fn trap_return(&mut self, from: Privilege) {
+        match from {
+            Privilege::Machine => {
+                self.privilege = Privilege::from_bits(self.mstatus >> 11);
+                self.set_mstatus(mstatus::MIE, self.mstatus & mstatus::MPIE != 0);
+                self.set_mstatus(mstatus::MPIE, true);
+                self.set_mstatus(mstatus::MPP, false);
+            }
+            Privilege::Supervisor | Privilege::User => {
+                self.privilege = if self.mstatus & mstatus::SPP != 0 {
+                    Privilege::Supervisor
+                } else {
+                    Privilege::User
+                };
+                self.set_mstatus(mstatus::SIE, self.mstatus & mstatus::SPIE != 0);
+                self.set_mstatus(mstatus::SPIE, true);
+                self.set_mstatus(mstatus::SPP, false);
+            }
+        }
+    }
+
+    /// Compare two [`RegisterSnapshot`] to see if their callee-saved registers
+    /// are equal. RV32E only has `sp`, `s0` and `s1` as callee-saved registers.
     ///
     /// If not, returns [`Some`] with the registers that are different. Otherwise,
     /// returns [`None`].
-    pub fn check(&self, other: &RegisterSnapshot) -> Option<Vec<Register>> {
+    pub fn check(&self, other: &RegisterSnapshot, base: Base) -> Option<Vec<Register>> {
         use Register::*;
         macro_rules! check {
             ($($reg:expr),+ $(,)?) => {
@@ -77,7 +347,10 @@ impl RegisterSnapshot {
                 }
             };
         }
-        let different = check!(sp, s0, s1, s2, s3, s4, s5, s6, s7, s8, s9, s10, s11);
+        let different = match base {
+            Base::I => check!(sp, s0, s1, s2, s3, s4, s5, s6, s7, s8, s9, s10, s11),
+            Base::E => check!(sp, s0, s1),
+        };
         if different.is_empty() {
             None
         } else {
@@ -90,7 +363,7 @@ impl RegisterSnapshot {
 macro_rules! register_index_impl {
     ($( $reg:ident )*) => {
         impl Index<Register> for RegisterSnapshot {
-            type Output = i32;
+            type Output = i64;
             fn index(&self, index: Register) -> &Self::Output {
                 match index {
                     $(
@@ -111,7 +384,7 @@ macro_rules! register_index_impl {
             }
         }
         impl Index<&Register> for RegisterSnapshot {
-            type Output = i32;
+            type Output = i64;
             fn index(&self, index: &Register) -> &Self::Output {
                 match index {
                     $(
@@ -141,6 +414,32 @@ register_index_impl! {
     s0 s1 s2 s3 s4 s5 s6 s7 s8 s9 s10 s11
 }
 
+impl Index<FRegister> for RegisterSnapshot {
+    type Output = u32;
+    fn index(&self, index: FRegister) -> &Self::Output {
+        &self.fregs[index as usize]
+    }
+}
+
+impl IndexMut<FRegister> for RegisterSnapshot {
+    fn index_mut(&mut self, index: FRegister) -> &mut Self::Output {
+        &mut self.fregs[index as usize]
+    }
+}
+
+impl Index<&FRegister> for RegisterSnapshot {
+    type Output = u32;
+    fn index(&self, index: &FRegister) -> &Self::Output {
+        &self.fregs[*index as usize]
+    }
+}
+
+impl IndexMut<&FRegister> for RegisterSnapshot {
+    fn index_mut(&mut self, index: &FRegister) -> &mut Self::Output {
+        &mut self.fregs[*index as usize]
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum OverflowBehaviour {
     Wrap,
@@ -151,7 +450,7 @@ pub enum OverflowBehaviour {
 
 /// Configuration levels for a setting indicating whether we should allow it, warn,
 /// or deny it.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ConfigLevel {
     Allow,
     Warn,
@@ -172,11 +471,164 @@ impl ConfigLevel {
     }
 }
 
+/// The width of the integer registers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Xlen {
+    #[default]
+    Rv32,
+    Rv64,
+}
+
+impl Xlen {
+    /// Sign extend the low `XLEN` bits of `val`. Registers always hold their
+    /// values in this form, so in RV32 mode the upper 32 bits are copies of
+    /// bit 31.
+    pub fn sext(self, val: i64) -> i64 {
+        match self {
+            Xlen::Rv32 => val as i32 as i64,
+            Xlen::Rv64 => val,
+        }
+    }
+
+    /// Interpret `val` as an address. Addresses are unsigned, so in RV32 mode
+    /// this zero extends the low 32 bits.
+    pub fn address(self, val: i64) -> i64 {
+        match self {
+            Xlen::Rv32 => val as u32 as i64,
+            Xlen::Rv64 => val,
+        }
+    }
+
+    /// The number of bits in a register.
+    pub fn bits(self) -> u32 {
+        match self {
+            Xlen::Rv32 => 32,
+            Xlen::Rv64 => 64,
+        }
+    }
+
+    /// The largest (`positive`) or smallest signed value representable.
+    fn saturate(self, positive: bool) -> i64 {
+        match (self, positive) {
+            (Xlen::Rv32, true) => i32::MAX as i64,
+            (Xlen::Rv32, false) => i32::MIN as i64,
+            (Xlen::Rv64, true) => i64::MAX,
+            (Xlen::Rv64, false) => i64::MIN,
+        }
+    }
+}
+
+impl fmt::Display for Xlen {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Xlen::Rv32 => write!(f, "RV32"),
+            Xlen::Rv64 => write!(f, "RV64"),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Config {
     /// What to do when overflow happens.
     overflow_mode: OverflowBehaviour,
     write_to_x0: ConfigLevel,
+
+    /// Whether to run as an RV32 or RV64 processor.
+    pub xlen: Xlen,
+
+    /// The base ISA, which determines how many integer registers there are.
+    pub base: Base,
+
+    /// Whether the Zba (address generation) extension is enabled.
+    pub zba: bool,
+    /// Whether the Zbb (basic bit manipulation) extension is enabled.
+    pub zbb: bool,
+    /// Whether the Zbs (single-bit instructions) extension is enabled.
+    pub zbs: bool,
+    /// Whether the V (vector) extension is enabled.
+    pub v: bool,
+    /// Whether the C (compressed instructions) extension is enabled. Programs
+    /// parsed by [`Executor::from_source`] are compressed where possible, and
+    /// jumps only need to be 2-byte aligned.
+    pub c: bool,
+
+    /// The number of bits in each vector register. Must be a power of two, and
+    /// at least 64.
+    pub vlen: usize,
+
+    /// Instructions added in the custom opcode spaces.
+    pub custom: CustomInstructions,
+
+    /// Where to load the encoded program in memory, like `0x00400000` in RARS.
+    /// Instructions are then fetched through memory, so loads from the text
+    /// read real encodings and stores to it change the code that runs. `None`
+    /// runs the parsed instructions directly, starting at pc 0.
+    pub text_base: Option<i64>,
+
+    /// The regions of memory the program may access, like
+    /// [`MemoryMap::standard`]. `None` allows every access.
+    pub memory_map: Option<MemoryMap>,
+
+    /// How far the stack can grow down from [`STACK_TOP`], in bytes. Storing
+    /// below that relative to the stack is a stack overflow. `None` is
+    /// unlimited.
+    ///
+    /// Loads and stores are relative to the stack when their base register
+    /// points between `sp` and [`STACK_TOP`], like `sp` itself or a frame
+    /// pointer in `s0`.
+    pub stack_size: Option<i64>,
+
+    /// What to do when a load or store relative to the stack reaches past the
+    /// current function's frame, into its caller's or above [`STACK_TOP`].
+    pub frame_access: ConfigLevel,
+
+    /// Whether to attach the ESP32-C3's [`Gpio`] and [`IoMux`] registers.
+    pub gpio: bool,
+
+    /// Whether to attach a [`Uart`] at [`UART_BASE`].
+    pub uart: bool,
+
+    /// How many instructions can be reverted. The oldest are forgotten past
+    /// that, so long runs don't keep every effect. `None` is unlimited.
+    pub history_limit: Option<usize>,
+}
+
+impl Config {
+    /// Whether instructions from `extension` can be executed.
+    pub fn enabled(&self, extension: Extension) -> bool {
+        match extension {
+            Extension::Zba => self.zba,
+            Extension::Zbb => self.zbb,
+            Extension::Zbs => self.zbs,
+            Extension::V => self.v,
+            Extension::C => self.c,
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            overflow_mode: OverflowBehaviour::Trap,
+            write_to_x0: ConfigLevel::Warn,
+            xlen: Xlen::Rv32,
+            base: Base::I,
+            zba: false,
+            zbb: false,
+            zbs: false,
+            v: false,
+            c: false,
+            vlen: 128,
+            custom: CustomInstructions::default(),
+            text_base: None,
+            memory_map: None,
+            stack_size: None,
+            frame_access: ConfigLevel::Allow,
+            gpio: false,
+            uart: false,
+            history_limit: None,
+        }
+    }
 }
 
 /// Specialized snapshot of the executor for saving some data before entering a
@@ -185,12 +637,6 @@ pub struct Config {
 pub struct FnCallEnter {
     snapshot: RegisterSnapshot,
 
-    /// [`FnCallEnter`]'s need to store the `executed` at which they were taken so
-    /// we can reset to a previous state properly. `executed` represents the
-    /// number of instructions executed _before_ the executing the instruction
-    /// that performs the call.
-    executed: usize,
-
     /// The [`Register`] in which the return address of the function we are calling
     /// is stored.
     ra_register: Register,
@@ -202,7 +648,7 @@ pub struct Executor {
     pub config: Config,
 
     /// The PC of the next instruction to execute
-    pc: i32,
+    pc: i64,
 
     /// The number of instructions executed - used to uniquely identify points
     /// in program execution.
@@ -210,12 +656,18 @@ pub struct Executor {
     program: Program,
     pub regfile: RegisterSnapshot,
 
-    /// Used to store snapshots of the programs state at a certain point in time
-    /// for ttd (time-travel debugging). The index is the number of instructions
-    /// executed _before_ taking the snapshot.
-    snapshots: HashMap<usize, RegisterSnapshot>,
+    /// Every committed update, for ttd (time-travel debugging), up to
+    /// [`Config::history_limit`]. Reverting undoes the last one.
+    history: VecDeque<Step>,
     pub memory: memory::Memory,
 
+    /// The addresses instructions are fetched from, if the program was loaded
+    /// into memory.
+    text: Option<Range<i64>>,
+
+    /// Decodes instructions fetched from the text segment, when there is one.
+    decoder: Decoder,
+
     /// This is not quite a stack. Rather, each [`FnCallEnter`] stores the state
     /// of the processor right _before_ the call was made. This way, when the
     /// call finishes, we can compare the before and after states.
@@ -226,11 +678,11 @@ impl FromStr for Executor {
     type Err = anyhow::Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        Ok(Self::new(s.parse().context("failed to parse program")?))
+        Self::from_source(s, Config::default())
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy)]
 enum StackOp {
     /// Push a new stack frame. `Register` is the [`Register`] in which the return
     /// address is stored.
@@ -241,47 +693,70 @@ enum StackOp {
     PopStack(Register),
 }
 
-impl StackOp {
-    /// Generate the reverse stack operation.
-    ///
-    /// A push becomes a pop and a pop becomes a push. The same register is used.
-    pub fn reverse(&self) -> StackOp {
-        match self {
-            StackOp::PushStack(reg) => StackOp::PopStack(*reg),
-            StackOp::PopStack(reg) => StackOp::PushStack(*reg),
-        }
-    }
-}
-
 /// An update that should be applied to the Executor after executing an instruction
 #[derive(Debug, Clone)]
 pub struct ProcessorUpdate {
-    pub nextpc: i32,
-    pub diff: Option<Diff>,
+    pub nextpc: i64,
+    /// The changes to make, in order.
+    pub effects: Vec<Effect>,
 }
 
 #[derive(Debug)]
 pub struct ExecUpdate {
-    pc: i32,
+    pc: i64,
     processor_update: ProcessorUpdate,
     stackop: Option<StackOp>,
 
+    /// The trap taken instead of executing the instruction, if any.
+    trap: Option<Cause>,
+
     /// These generally get
     warnings: Vec<ExecError>,
 }
 
+impl ExecUpdate {
+    /// The pc of the instruction that produced this update.
+    pub fn pc(&self) -> i64 {
+        self.pc
+    }
+
+    pub fn processor_update(&self) -> &ProcessorUpdate {
+        &self.processor_update
+    }
+
+    /// Everything the instruction changed, in the order the changes were made.
+    pub fn effects(&self) -> &[Effect] {
+        &self.processor_update.effects
+    }
+
+    pub fn trap(&self) -> Option<Cause> {
+        self.trap
+    }
+
+    /// Problems that didn't stop the instruction, like writing to x0.
+    pub fn warnings(&self) -> &[ExecError] {
+        &self.warnings
+    }
+}
+
 impl ProcessorUpdate {
     /// Don't change a register, just jump to the given `pc`
-    fn jump(nextpc: i32) -> Self {
-        ProcessorUpdate { nextpc, diff: None }
+    fn jump(nextpc: i64) -> Self {
+        ProcessorUpdate {
+            nextpc,
+            effects: vec![],
+        }
     }
 }
 
-/// A diff to apply to the registers or pc
-#[derive(Debug, Clone, Copy)]
-pub enum Diff {
-    Memory { addr: i32, val: i32, op: StoreOp },
-    Register { reg: Register, val: i32 },
+/// A committed update, kept so it can be reverted.
+#[derive(Debug, Clone)]
+struct Step {
+    pc: i64,
+    effects: Vec<Effect>,
+    stackop: Option<StackOp>,
+    /// The frame popped by a return, to push back when reverting it.
+    popped: Option<FnCallEnter>,
 }
 
 pub type ExecResult<T> = Result<T, ExecError>;
@@ -290,39 +765,106 @@ pub type ExecResult<T> = Result<T, ExecError>;
 ///
 /// Generally we produce [`ExecErrorInner`] during execution and turn it into an
 /// [`ExecError`] only in the last step of executiong (commiting).
-#[derive(Debug)]
+#[derive(Debug, Error)]
+#[error("{error} (pc {pc:#010x})")]
 pub struct ExecError {
     /// The pc where the error happened
-    pc: i32,
+    pc: i64,
 
     error: ExecErrorInner,
 }
 
-#[derive(Debug)]
+impl ExecError {
+    /// The pc of the instruction that caused the error.
+    pub fn pc(&self) -> i64 {
+        self.pc
+    }
+
+    pub fn error(&self) -> &ExecErrorInner {
+        &self.error
+    }
+}
+
+#[derive(Debug, Error)]
 pub enum ExecErrorInner {
-    // #[error("attempt to write {val} to x0 (hardwired zero)")]
-    WriteToX0 {
-        val: i32,
-    },
+    #[error("attempt to write {val} to x0 (hardwired zero)")]
+    WriteToX0 { val: i64 },
 
     /// An error due to the memory system
-    // #[error(transparent)]
     // Other(anyhow::Error),
+    #[error(transparent)]
     Memory(MemoryError),
 
+    /// The word fetched from the text segment isn't an instruction.
+    #[error(transparent)]
+    Decode(DecodeErrorInner),
+
     /// Returned when we've hit a breakpoint. It is safe to continue after this.
-    // #[error("breakpoint hit")]
+    #[error("breakpoint hit")]
     BreakPoint,
 
-    // #[error("execution finished")]
+    #[error("execution finished")]
     Finished,
 
-    // #[error("reverted back to start state")]
+    #[error("reverted back to start state")]
     StartReached,
 
+    #[error(transparent)]
     Overflow(OverflowError),
 
-    // #[error("calling convention violated: {0:?}")]
+    /// A floating point instruction used the dynamic rounding mode, but `frm`
+    /// holds one of the reserved rounding modes.
+    #[error("dynamic rounding mode used, but frm holds reserved mode {0:#05b}")]
+    InvalidRoundingMode(u32),
+
+    /// A jump or branch to an address that isn't 4-byte aligned.
+    #[error("jump to misaligned address {target:#010x}")]
+    MisalignedJump { target: i64 },
+
+    /// The instruction belongs to an extension the executor isn't configured
+    /// to support.
+    #[error("{instr} requires {requires}, which is not enabled")]
+    UnsupportedInstruction {
+        instr: Instruction,
+        requires: &'static str,
+    },
+
+    /// An instruction that needs a more privileged mode, like accessing a
+    /// machine-mode CSR from supervisor mode.
+    #[error("{instr} can't be executed in {privilege} mode")]
+    Privileged {
+        instr: Instruction,
+        privilege: Privilege,
+    },
+
+    /// A CSR that can only be read was written.
+    #[error("{0} is read-only")]
+    ReadOnlyCsr(Csr),
+
+    /// A custom instruction's semantics rejected it.
+    #[error("illegal custom instruction {mnemonic}: {reason}")]
+    Custom { mnemonic: String, reason: String },
+
+    /// A vector instruction that can't run with the current configuration.
+    #[error("illegal vector instruction: {0}")]
+    IllegalVector(&'static str),
+
+    /// `ecall` with no trap handler to take it.
+    #[error("environment call from {0} mode")]
+    Ecall(Privilege),
+
+    /// A store relative to the stack below the stack limit.
+    #[error("stack overflow: store to {addr:#010x} is below the stack limit {limit:#010x}")]
+    StackOverflow { addr: i64, limit: i64 },
+
+    /// A load or store relative to the stack past the end of the current
+    /// function's frame.
+    #[error(
+        "access to {addr:#010x} is outside the current stack frame, which ends at {end:#010x}"
+    )]
+    OutOfFrame { addr: i64, end: i64 },
+
+    #[error("calling convention violated: {0:?}")]
     CallingConventionViolation(Vec<CallingConventionError>),
 }
 
@@ -338,12 +880,16 @@ impl From<OverflowError> for ExecErrorInner {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Error)]
 pub enum OverflowError {
-    Add { base: i32, adding: i32 },
-    Sub { base: i32, adding: i32 },
-    ShiftLeft { base: i32, shamt: u32 },
-    ShiftRight { base: i32, shamt: u32 },
+    #[error("overflow adding {adding} to {base}")]
+    Add { base: i64, adding: i64 },
+    #[error("overflow subtracting {adding} from {base}")]
+    Sub { base: i64, adding: i64 },
+    #[error("overflow shifting {base} left by {shamt}")]
+    ShiftLeft { base: i64, shamt: u32 },
+    #[error("overflow shifting {base} right by {shamt}")]
+    ShiftRight { base: i64, shamt: u32 },
 }
 
 impl From<CallingConventionError> for ExecErrorInner {
@@ -358,15 +904,15 @@ impl From<Vec<CallingConventionError>> for ExecErrorInner {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Error)]
 pub enum CallingConventionError {
     /// When a callee saved register is modified and not restored during a call
-    // #[error("{reg} was {pre} before pre-call, {post} after returning")]
-    ModifiedRegister { reg: Register, pre: i32, post: i32 },
+    #[error("{reg} was {pre} before pre-call, {post} after returning")]
+    ModifiedRegister { reg: Register, pre: i64, post: i64 },
 
     /// When the return address is saved in one register, but we return to a
     /// return address stored in a different register.
-    // #[error("last return address was stored in {save} but returning to address in {other}")]
+    #[error("last return address was stored in {save} but returning to address in {other}")]
     ReturnViaOtherReg {
         /// The register our last return address was saved in
         save: Register,
@@ -378,35 +924,191 @@ pub enum CallingConventionError {
 
 impl Executor {
     pub fn new(program: Program) -> Self {
+        Self::with_config(program, Config::default())
+    }
+
+    /// Parse a program for the base ISA in `config` and create an executor for
+    /// it. With [`Config::c`], the program is compressed first.
+    pub fn from_source(source: &str, config: Config) -> anyhow::Result<Self> {
+        let mut lexer = Lexer::with_base(source, config.base)
+            .with_xlen(config.xlen)
+            .with_custom(config.custom.clone());
+        let mut program = Program::parse(&mut lexer).context("failed to parse program")?;
+        if config.c {
+            program.compress();
+        }
+        Self::load(program, config).context("failed to load program")
+    }
+
+    /// Create an executor. Panics if the program can't be loaded, see
+    /// [`Executor::load`].
+    pub fn with_config(program: Program, config: Config) -> Self {
+        Self::load(program, config).expect("failed to load program")
+    }
+
+    /// Create an executor, loading the program into memory if there is a
+    /// [`Config::text_base`]. Fails if it can't be encoded, like when a branch
+    /// can't reach its label.
+    pub fn load(mut program: Program, config: Config) -> Result<Self, EncodeError> {
+        let mut memory = memory::Memory::default();
+        let (pc, text) = match config.text_base {
+            Some(base) => {
+                // Labels become addresses in the text segment
+                program.rebase(base);
+                let image: Vec<_> = program.image_at(base)?.into_iter().map(Some).collect();
+                memory.set_bytes(base, &image);
+                (base, Some(base..base + image.len() as i64))
+            }
+            None => (0, None),
+        };
+        Ok(Self::build(program, config, memory, pc, text))
+    }
+
+    /// Create an executor for an RV32 ELF executable, with its segments loaded
+    /// into memory and its symbols as labels. The pc starts at the entry point,
+    /// and [`Config::text_base`] is ignored.
+    pub fn from_elf(bytes: &[u8], config: Config) -> Result<Self, ElfError> {
+        let elf = Elf::parse(bytes)?;
+        let text = elf.text()?;
+        let mut memory = memory::Memory::default();
+        for segment in &elf.segments {
+            // Zero everything past the data in the file
+            let mut bytes: Vec<_> = segment.data.iter().copied().map(Some).collect();
+            bytes.resize(segment.size, Some(0));
+            memory.set_bytes(segment.addr, &bytes);
+        }
+        let program = Program {
+            labels: elf.symbols,
+            asm: vec![],
+        };
+        Ok(Self::build(program, config, memory, elf.entry, Some(text)))
+    }
+
+    fn build(
+        program: Program,
+        config: Config,
+        mut memory: memory::Memory,
+        pc: i64,
+        text: Option<Range<i64>>,
+    ) -> Self {
+        assert!(
+            config.vlen.is_power_of_two() && config.vlen >= 64,
+            "VLEN must be a power of two, at least 64"
+        );
+        memory.map = config.memory_map.clone();
+        memory.attach(CLINT_BASE, Clint::default());
+        if config.gpio {
+            memory.attach(GPIO_BASE, Gpio::default());
+            memory.attach(IO_MUX_BASE, IoMux::default());
+        }
+        if config.uart {
+            memory.attach(UART_BASE, Uart::default());
+        }
         let regfile: RegisterSnapshot = RegisterSnapshot {
-            sp: 0x40000000, // Halfway up in the address space
+            sp: STACK_TOP,
+            vregs: vec![0; 32 * config.vlen / 8],
+            // vill is set until the first vsetvli
+            vtype: config.xlen.sext(1 << (config.xlen.bits() - 1)),
             ..Default::default()
         };
         Self {
-            config: Config {
-                overflow_mode: OverflowBehaviour::Trap,
-                write_to_x0: ConfigLevel::Warn,
-            },
-            pc: 0,
+            text,
+            decoder: Decoder::new().with_custom(config.custom.clone()),
+            config,
+            pc,
             executed: 0,
             program,
             regfile: regfile.clone(),
-            // Start with one snapshot/frame so that we have a state to reset to
-            // before we have even executed an instruction. We have to do this
-            // because we take snapshots in self.commit
-            snapshots: map!(0 => regfile.clone()),
+            history: VecDeque::new(),
+            // Start with one frame so that we have a state to compare to
+            // before we have even executed an instruction.
             stack: vec![FnCallEnter {
                 snapshot: regfile,
-                executed: 0,
                 ra_register: Register::ra,
             }],
-            memory: Default::default(),
+            memory,
         }
     }
 
+    /// The pc of the next instruction to execute.
+    pub fn pc(&self) -> i64 {
+        self.pc
+    }
+
+    pub fn program(&self) -> &Program {
+        &self.program
+    }
+
     /// The instruction the executor is about to execute.
     pub fn current(&self) -> Option<Instruction> {
-        self.program.at(self.pc).cloned()
+        match self.text {
+            Some(_) => self.fetch().ok().flatten(),
+            None => self.program.at(self.pc).cloned(),
+        }
+    }
+
+    /// The address of a label in the program, or of one made up when decoding
+    /// an instruction fetched from memory.
+    fn label(&self, label: &str) -> i64 {
+        self.program
+            .label(label)
+            .or_else(|| decode::label_address(label))
+            .unwrap()
+    }
+
+    /// With paging on, the pc is a virtual address too.
+    fn physical_pc(&self, pc: i64) -> Result<i64, MemoryError> {
+        match self.translation() {
+            Some(translation) => {
+                let pc = Address::Virtual(pc, translation);
+                self.memory.translate(pc, Access::Execute)
+            }
+            None => Ok(pc),
+        }
+    }
+
+    /// Fetch the machine code of the instruction at `pc`.
+    fn fetch_word(&self, pc: i64) -> Result<u32, MemoryError> {
+        // Instructions are fetched in 2-byte parcels. The low two bits of the
+        // first are 11 for a 4-byte instruction, whose second half can be on
+        // another page.
+        let mut word = self.memory.fetch(self.physical_pc(pc)?)? as u32;
+        if word & 0b11 == 0b11 {
+            let next = self.physical_pc(pc + 2)?;
+            word |= (self.memory.fetch(next)? as u32) << 16;
+        }
+        Ok(word)
+    }
+
+    /// Fetch the instruction at the pc, or `None` if the pc is outside the
+    /// program.
+    fn fetch(&self) -> Result<Option<Instruction>, ExecErrorInner> {
+        let pc = self.physical_pc(self.pc)?;
+        let Some(text) = &self.text else {
+            return Ok(self.program.at(pc).cloned());
+        };
+        if !text.contains(&pc) {
+            return Ok(None);
+        }
+        let offset = pc - text.start;
+        let word = self.fetch_word(self.pc)?;
+        // Keep the instruction as it was written, with its labels, unless the
+        // code was changed. A pseudo-instruction that expands to two is only
+        // kept if both are still there, otherwise they run one at a time.
+        let unchanged = |asm: &&Instruction| {
+            let addresses = (self.pc..).step_by(4);
+            asm.encode(self.pc, &self.program).is_ok_and(|words| {
+                (words.iter().zip(addresses))
+                    .all(|(&expected, pc)| self.fetch_word(pc) == Ok(expected))
+            })
+        };
+        match self.program.at(offset).filter(unchanged) {
+            Some(asm) => Ok(Some(asm.clone())),
+            None => match self.decoder.decode(word, self.pc) {
+                Ok(asm) => Ok(Some(asm)),
+                Err(error) => Err(ExecErrorInner::Decode(error.error().clone())),
+            },
+        }
     }
 
     pub fn stack(&self) -> &Vec<FnCallEnter> {
@@ -418,15 +1120,15 @@ impl Executor {
             match self.execute() {
                 Ok(_) => continue,
                 Err(ExecError {
-                    pc,
                     error: ExecErrorInner::Finished,
+                    ..
                 }) => return Ok(()),
                 other => return other.map(|_| ()),
             }
         }
     }
 
-    pub fn set(&mut self, reg: Register, val: i32, pc: i32) -> ExecResult<()> {
+    pub fn set(&mut self, reg: Register, val: i64, pc: i64) -> ExecResult<()> {
         if reg == Register::x0 {
             Err(ExecError {
                 pc,
@@ -439,22 +1141,64 @@ impl Executor {
     }
 
     /// Adds two numbers while respecting the configuration for overflow behaviour.
-    fn add(&self, fst: i32, snd: i32) -> Result<i32, ExecErrorInner> {
+    fn add(&self, fst: i64, snd: i64) -> Result<i64, ExecErrorInner> {
+        self.add_width(fst, snd, self.config.xlen)
+    }
+
+    /// Adds two `width`-bit numbers while respecting the configuration for
+    /// overflow behaviour. The result is sign extended.
+    fn add_width(&self, fst: i64, snd: i64, width: Xlen) -> Result<i64, ExecErrorInner> {
+        let (res, overflowed) = match width {
+            Xlen::Rv32 => {
+                let (res, overflowed) = (fst as i32).overflowing_add(snd as i32);
+                (res as i64, overflowed)
+            }
+            Xlen::Rv64 => fst.overflowing_add(snd),
+        };
         match self.config.overflow_mode {
-            OverflowBehaviour::Wrap => Ok(fst.wrapping_add(snd)),
-            OverflowBehaviour::Saturate => Ok(fst.saturating_add(snd)),
-            OverflowBehaviour::Trap => fst.checked_add(snd).ok_or_else(|| {
-                ExecErrorInner::Overflow(OverflowError::Add {
+            OverflowBehaviour::Saturate if overflowed => Ok(width.saturate(snd > 0)),
+            OverflowBehaviour::Trap if overflowed => {
+                Err(ExecErrorInner::Overflow(OverflowError::Add {
                     base: fst,
                     adding: snd,
-                })
-            }),
+                }))
+            }
+            _ => Ok(res),
+        }
+    }
+
+    /// Subtracts two `width`-bit numbers while respecting the configuration for
+    /// overflow behaviour. The result is sign extended.
+    fn sub_width(&self, fst: i64, snd: i64, width: Xlen) -> Result<i64, ExecErrorInner> {
+        let (res, overflowed) = match width {
+            Xlen::Rv32 => {
+                let (res, overflowed) = (fst as i32).overflowing_sub(snd as i32);
+                (res as i64, overflowed)
+            }
+            Xlen::Rv64 => fst.overflowing_sub(snd),
+        };
+        match self.config.overflow_mode {
+            OverflowBehaviour::Saturate if overflowed => Ok(width.saturate(snd < 0)),
+            OverflowBehaviour::Trap if overflowed => {
+                Err(ExecErrorInner::Overflow(OverflowError::Sub {
+                    base: fst,
+                    adding: snd,
+                }))
+            }
+            _ => Ok(res),
         }
     }
 
-    /// Left shifts while respecting the configuration for overflow behaviour.
-    fn shift_left(&self, fst: i32, shamt: u32) -> Result<i32, ExecErrorInner> {
-        let (res, overflowed) = fst.overflowing_shl(shamt);
+    /// Left shifts a `width`-bit number while respecting the configuration for
+    /// overflow behaviour.
+    fn shift_left(&self, fst: i64, shamt: u32, width: Xlen) -> Result<i64, ExecErrorInner> {
+        let (res, overflowed) = match width {
+            Xlen::Rv32 => {
+                let (res, overflowed) = (fst as i32).overflowing_shl(shamt);
+                (res as i64, overflowed)
+            }
+            Xlen::Rv64 => fst.overflowing_shl(shamt),
+        };
         match self.config.overflow_mode {
             OverflowBehaviour::Trap if overflowed => {
                 Err(ExecErrorInner::Overflow(OverflowError::ShiftLeft {
@@ -466,10 +1210,25 @@ impl Executor {
         }
     }
 
-    /// Logical right shifts while respecting the configuration for overflow behaviour.
-    fn shift_right_logical(&self, fst: i32, shamt: u32) -> Result<i32, ExecErrorInner> {
+    /// Logical right shifts a `width`-bit number while respecting the configuration
+    /// for overflow behaviour.
+    fn shift_right_logical(
+        &self,
+        fst: i64,
+        shamt: u32,
+        width: Xlen,
+    ) -> Result<i64, ExecErrorInner> {
         // right shifts are arithmetic on signed integers and logical on unsigned integers
-        let (res, overflowed) = (fst as u32).overflowing_shr(shamt);
+        let (res, overflowed) = match width {
+            Xlen::Rv32 => {
+                let (res, overflowed) = (fst as u32).overflowing_shr(shamt);
+                (res as i32 as i64, overflowed)
+            }
+            Xlen::Rv64 => {
+                let (res, overflowed) = (fst as u64).overflowing_shr(shamt);
+                (res as i64, overflowed)
+            }
+        };
         match self.config.overflow_mode {
             OverflowBehaviour::Trap if overflowed => {
                 Err(ExecErrorInner::Overflow(OverflowError::ShiftRight {
@@ -477,14 +1236,26 @@ impl Executor {
                     shamt,
                 }))
             }
-            _ => Ok(res as i32),
+            _ => Ok(res),
         }
     }
 
-    /// Arithmetic right shifts while respecting the configuration for overflow behaviour.
-    fn shift_right_arithmetic(&self, fst: i32, shamt: u32) -> Result<i32, ExecErrorInner> {
+    /// Arithmetic right shifts a `width`-bit number while respecting the
+    /// configuration for overflow behaviour.
+    fn shift_right_arithmetic(
+        &self,
+        fst: i64,
+        shamt: u32,
+        width: Xlen,
+    ) -> Result<i64, ExecErrorInner> {
         // right shifts are arithmetic on signed integers and logical on unsigned integers
-        let (res, overflowed) = fst.overflowing_shr(shamt);
+        let (res, overflowed) = match width {
+            Xlen::Rv32 => {
+                let (res, overflowed) = (fst as i32).overflowing_shr(shamt);
+                (res as i64, overflowed)
+            }
+            Xlen::Rv64 => fst.overflowing_shr(shamt),
+        };
         match self.config.overflow_mode {
             OverflowBehaviour::Trap if overflowed => {
                 Err(ExecErrorInner::Overflow(OverflowError::ShiftRight {
@@ -496,11 +1267,23 @@ impl Executor {
         }
     }
 
+    /// Resolve the dynamic rounding mode using `frm`.
+    fn rounding_mode(&self, rm: RoundingMode) -> Result<RoundingMode, ExecErrorInner> {
+        match rm {
+            RoundingMode::Dyn => {
+                let frm = self.regfile.csr(Csr::Frm) as u32;
+                RoundingMode::from_frm(frm).ok_or(ExecErrorInner::InvalidRoundingMode(frm))
+            }
+            other => Ok(other),
+        }
+    }
+
     /// Takes an [`Update`] and applies it to the [`Executor`].
     ///
     /// If the commit fails (for example, due to a memory error), the executor's
     /// state will not be changed.
-    fn commit(&mut self, update: &ExecUpdate) -> ExecResult<()> {
+    fn commit(&mut self, update: &mut ExecUpdate) -> ExecResult<()> {
+        let mut popped = None;
         // If we are returning, all caller-saved registers should be the same
         if let Some(StackOp::PopStack(reg)) = update.stackop {
             let mut violations = vec![];
@@ -514,7 +1297,7 @@ impl Executor {
                 });
             }
 
-            let diff = self.regfile.check(&frame.snapshot);
+            let diff = self.regfile.check(&frame.snapshot, self.config.base);
             if let Some(diff) = diff {
                 violations.extend(
                     diff.iter()
@@ -533,51 +1316,25 @@ impl Executor {
                 })?
             }
 
-            self.stack.pop().unwrap();
+            popped = self.stack.pop();
         }
 
-        // Since we don't want to modify the executor if applying the diff fails,
-        // save some state now, and we'll use it after if everything goes well.
-        let executed = self.executed;
+        // Save the state from before the call to compare against when it returns
         let snapshot = self.regfile.clone();
 
-        // Apply the change
-        if let Some(diff) = update.processor_update.diff {
-            match diff {
-                Diff::Memory { addr, val, op } => {
-                    if let Err(error) = self.memory.store(addr, val, op) {
-                        Err(ExecError {
-                            pc: self.pc,
-                            error: error.into(),
-                        })?
-                    }
-                }
-                Diff::Register { reg, val } => {
-                    // Checking for writes to x0 is handled in calculate_update
-                    self.regfile[reg] = val;
-                }
-            }
-        };
-
-        // Retroactively take the snapshot if need be
-        if executed % SNAPSHOT_INTERVAL == 0 {
-            if let Some(prev) = self.snapshots.insert(executed, snapshot.clone()) {
-                // Sanity check that that we're still computing the same result
-                // this time around
-                assert_eq!(prev, snapshot);
-            }
+        for effect in &update.processor_update.effects {
+            self.apply(effect, false);
         }
 
-        // Retroactively record the state of the processor from *before* making
-        // the call (aka applying the change)
+        // Record the state of the processor from *before* making the call (aka
+        // applying the change)
         if let Some(StackOp::PushStack(ra_register)) = update.stackop {
-            let mut snapshot = snapshot.clone();
+            let mut snapshot = snapshot;
             // We actually do want to save the modified register storing the return
             // address since this is the last thing to happen before the call.
             snapshot[ra_register] = self.regfile[ra_register];
             self.stack.push(FnCallEnter {
                 snapshot,
-                executed,
                 ra_register,
             })
         }
@@ -585,96 +1342,444 @@ impl Executor {
         self.pc = update.processor_update.nextpc;
         self.executed += 1;
 
+        // Time passes after every instruction, which is part of its effects too
+        self.memory.set_time(self.executed as u64);
+        let mut pending = 0;
+        for index in 0..self.memory.devices().len() {
+            let ticked = self.device_effects(&[], index, |device, regs| device.tick(regs));
+            for effect in &ticked {
+                self.apply(effect, false);
+            }
+            update.processor_update.effects.extend(ticked);
+            pending |= self.memory.devices()[index].device.pending();
+        }
+        let raised = mip::MSIP | mip::MTIP;
+        let mip = (self.regfile.mip & !raised) | pending;
+        if mip != self.regfile.mip {
+            update.processor_update.effects.push(Effect::Csr {
+                csr: Csr::Mip,
+                old: self.regfile.mip,
+                new: mip,
+            });
+            self.regfile.mip = mip;
+        }
+
+        let limit = self.config.history_limit.unwrap_or(usize::MAX);
+        while !self.history.is_empty() && self.history.len() >= limit {
+            self.history.pop_front();
+        }
+        if limit > 0 {
+            self.history.push_back(Step {
+                pc: update.pc,
+                effects: update.processor_update.effects.clone(),
+                stackop: update.stackop,
+                popped,
+            });
+        }
+
         Ok(())
     }
 
     pub fn execute(&mut self) -> ExecResult<ExecUpdate> {
-        let Some(asm) = self.program.at(self.pc) else {
-            return Err(ExecError { pc: self.pc, error: ExecErrorInner::Finished });
+        // Forget accesses made outside of execution, like by the debugger
+        self.memory.take_violations();
+        let asm = match self.fetch() {
+            Ok(Some(asm)) => Ok(asm),
+            Ok(None) => {
+                return Err(ExecError {
+                    pc: self.pc,
+                    error: ExecErrorInner::Finished,
+                })
+            }
+            Err(error) => Err(error),
         };
-        let update = self
-            .calculate_update(asm)
-            .map_err(|error| ExecError { pc: self.pc, error })?;
-        self.commit(&update)?;
+        let mut update = if let Some(interrupt) = self.pending_interrupt() {
+            self.trap_update(Cause::Interrupt(interrupt), 0)
+        } else {
+            match asm {
+                Ok(asm) => match self.calculate_update(&asm) {
+                    Ok(update) => update,
+                    Err(error) => match Exception::from_error(&asm, &error) {
+                        Some((exception, tval)) if self.handles(Cause::Exception(exception)) => {
+                            self.trap_update(Cause::Exception(exception), tval)
+                        }
+                        _ => Err(ExecError { pc: self.pc, error })?,
+                    },
+                },
+                // The pc couldn't be translated, or its word decoded
+                Err(error) => {
+                    let (exception, tval) = match &error {
+                        ExecErrorInner::Memory(error) => {
+                            (Exception::from_fetch_error(error), self.pc)
+                        }
+                        _ => (Exception::IllegalInstruction, 0),
+                    };
+                    let cause = Cause::Exception(exception);
+                    if !self.handles(cause) {
+                        Err(ExecError { pc: self.pc, error })?
+                    }
+                    self.trap_update(cause, tval)
+                }
+            }
+        };
+        for error in self.memory.take_violations() {
+            update.warnings.push(ExecError {
+                pc: self.pc,
+                error: error.into(),
+            });
+        }
+        self.commit(&mut update)?;
         Ok(update)
     }
 
+    /// The translation applied to addresses in the current privilege mode, or
+    /// `None` if addresses are physical. Machine mode never translates, and only
+    /// RV32 has Sv32.
+    pub fn translation(&self) -> Option<Translation> {
+        let regs = &self.regfile;
+        if regs.privilege == Privilege::Machine {
+            return None;
+        }
+        self.page_table(regs.satp).map(|root| Translation {
+            root,
+            user: regs.privilege == Privilege::User,
+            sum: regs.mstatus & mstatus::SUM != 0,
+            mxr: regs.mstatus & mstatus::MXR != 0,
+        })
+    }
+
+    /// The physical address of the root page table selected by `satp`, or `None`
+    /// if it selects bare (untranslated) mode.
+    pub fn page_table(&self, satp: i64) -> Option<i64> {
+        let sv32 = self.config.xlen == Xlen::Rv32 && satp & (1 << 31) != 0;
+        sv32.then_some((satp & 0x3fffff) * PAGE_SIZE)
+    }
+
+    /// The address a load or store to `addr` should use in the current privilege
+    /// mode.
+    fn data_address(&self, addr: i64) -> Address {
+        match self.translation() {
+            Some(translation) => Address::Virtual(addr, translation),
+            None => Address::Physical(addr),
+        }
+    }
+
+    /// Check a load or store of `size` bytes at `addr`, through a base register
+    /// holding `base`. If it's relative to the stack, stores can't go below the
+    /// stack limit, and neither can reach past the frame of the current
+    /// function, which ends where `sp` was when it was called.
+    fn check_stack(
+        &self,
+        base: i64,
+        addr: i64,
+        size: i64,
+        store: bool,
+        warnings: &mut Vec<ExecError>,
+    ) -> Result<(), ExecErrorInner> {
+        if !(self.regfile.sp..=STACK_TOP).contains(&base) {
+            return Ok(());
+        }
+        if let Some(limit) = self.config.stack_size.map(|size| STACK_TOP - size) {
+            if store && addr < limit {
+                Err(ExecErrorInner::StackOverflow { addr, limit })?
+            }
+        }
+        let end = self
+            .stack
+            .last()
+            .map_or(STACK_TOP, |frame| frame.snapshot.sp);
+        if addr + size > end {
+            let error = ExecErrorInner::OutOfFrame { addr, end };
+            match self.config.frame_access {
+                ConfigLevel::Allow => (),
+                ConfigLevel::Warn => warnings.push(ExecError { pc: self.pc, error }),
+                ConfigLevel::Deny => Err(error)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether a handler for traps in `privilege` mode is installed, by writing
+    /// its (nonzero) address to `mtvec` or `stvec`.
+    pub fn has_trap_handler(&self, privilege: Privilege) -> bool {
+        let tvec = match privilege {
+            Privilege::Machine => self.regfile.mtvec,
+            Privilege::Supervisor | Privilege::User => self.regfile.stvec,
+        };
+        tvec & !0b11 != 0
+    }
+
+    /// The privilege mode that handles a trap with the given cause.
+    fn trap_target(&self, cause: Cause) -> Privilege {
+        let regs = &self.regfile;
+        let delegated = match cause {
+            Cause::Exception(exception) => regs.medeleg >> exception as i64,
+            Cause::Interrupt(interrupt) => regs.mideleg >> interrupt as i64,
+        } & 1
+            != 0;
+        // Traps never go to a less privileged mode
+        if delegated && regs.privilege != Privilege::Machine {
+            Privilege::Supervisor
+        } else {
+            Privilege::Machine
+        }
+    }
+
+    /// Whether a trap with the given cause would be taken by a handler, rather
+    /// than stopping execution.
+    fn handles(&self, cause: Cause) -> bool {
+        self.has_trap_handler(self.trap_target(cause))
+    }
+
+    /// The interrupt to take before the next instruction, if any. Interrupts
+    /// stay pending while no handler is installed.
+    fn pending_interrupt(&self) -> Option<Interrupt> {
+        let regs = &self.regfile;
+        let pending = regs.mip & regs.mie;
+        // Interrupts for a more privileged mode are always enabled, and ones
+        // for a less privileged mode never are
+        let enabled = |privilege: Privilege, ie: i64| {
+            regs.privilege < privilege || (regs.privilege == privilege && regs.mstatus & ie != 0)
+        };
+        let mut enabled_pending = 0;
+        if enabled(Privilege::Machine, mstatus::MIE) {
+            enabled_pending |= pending & !regs.mideleg;
+        }
+        if enabled(Privilege::Supervisor, mstatus::SIE) {
+            enabled_pending |= pending & regs.mideleg;
+        }
+        Interrupt::highest(enabled_pending)
+            .filter(|interrupt| self.handles(Cause::Interrupt(*interrupt)))
+    }
+
+    /// The update that enters the trap handler instead of executing the current
+    /// instruction.
+    fn trap_update(&self, cause: Cause, tval: i64) -> ExecUpdate {
+        let target = self.trap_target(cause);
+        let tvec = match target {
+            Privilege::Machine => self.regfile.mtvec,
+            Privilege::Supervisor | Privilege::User => self.regfile.stvec,
+        };
+        let base = tvec & !0b11;
+        // Exceptions always go to the base address, but in vectored mode
+        // interrupts get their own entry
+        let handler = match cause {
+            Cause::Interrupt(interrupt) if tvec & 1 == 1 => base + 4 * interrupt as i64,
+            _ => base,
+        };
+        let mut after = self.regfile.clone();
+        after.trap(target, cause.mcause(self.config.xlen), self.pc, tval);
+        ExecUpdate {
+            pc: self.pc,
+            processor_update: ProcessorUpdate {
+                nextpc: self.config.xlen.address(handler),
+                effects: self.state_effects(&after),
+            },
+            stackop: None,
+            trap: Some(cause),
+            warnings: vec![],
+        }
+    }
+
     /// Stateless function that returns an [`ExecUpdate`] to produce the next
     /// [`Executor`] state.
     fn calculate_update(&self, asm: &Instruction) -> Result<ExecUpdate, ExecErrorInner> {
         let regs = &self.regfile;
         let pc = self.pc;
+        let xlen = self.config.xlen;
+
+        if xlen == Xlen::Rv32 && asm.is_rv64_only() {
+            Err(ExecErrorInner::UnsupportedInstruction {
+                instr: asm.clone(),
+                requires: "RV64I",
+            })?
+        }
+        if let Some(extension) = asm.extension().filter(|ext| !self.config.enabled(*ext)) {
+            Err(ExecErrorInner::UnsupportedInstruction {
+                instr: asm.clone(),
+                requires: extension.name(),
+            })?
+        }
+        // Compressed instructions run as the instruction they expand to
+        let (asm, size) = match asm {
+            Instruction::Compressed { instr, .. } => (&**instr, 2),
+            _ => (asm, asm.size()),
+        };
+
+        // CSRs encode the least privileged mode that can access them in bits 8
+        // and 9 of their number
+        let required = match asm {
+            Instruction::CsrReg { csr, .. } | Instruction::CsrImm { csr, .. } => {
+                Privilege::from_bits((csr.number() >> 8) as i64)
+            }
+            Instruction::mret {} => Privilege::Machine,
+            Instruction::sret {} | Instruction::sfence_vma { .. } => Privilege::Supervisor,
+            _ => Privilege::User,
+        };
+        if regs.privilege < required {
+            Err(ExecErrorInner::Privileged {
+                instr: asm.clone(),
+                privilege: regs.privilege,
+            })?
+        }
 
         let mut update = ExecUpdate {
             pc,
             processor_update: ProcessorUpdate {
                 nextpc: -1,
-                diff: None,
+                effects: vec![],
             },
             stackop: None,
+            trap: None,
             warnings: vec![],
         };
 
         // Helper functions for setting the correct update
-        // Advance the pc by 4 and change the appropriate register
+        // Advance the pc past the instruction and change the appropriate register
         let next_with = |reg, val| ProcessorUpdate {
-            nextpc: pc + 4,
-            diff: Some(Diff::Register { reg, val }),
+            nextpc: pc + size,
+            effects: vec![self.write_effect(reg, val)],
         };
 
-        // Advance the pc by 4 and change the appropriate memory location
+        // Advance the pc past the instruction and change the appropriate memory location
         let next_mem = |addr, val, op| ProcessorUpdate {
-            nextpc: pc + 4,
-            diff: Some(Diff::Memory { addr, val, op }),
+            nextpc: pc + size,
+            effects: self.store_effects([(addr, val, op)]),
+        };
+
+        // Advance the pc past the instruction and change a floating point register
+        let next_float = |reg, (val, fflags)| ProcessorUpdate {
+            nextpc: pc + size,
+            effects: self.float_effects(reg, val, fflags),
+        };
+
+        // Advance the pc past the instruction and write the result of a floating point
+        // operation to an integer register
+        let next_flags = |reg, (val, fflags)| {
+            let mut effects = vec![self.write_effect(reg, val)];
+            effects.extend(self.flag_effect(fflags));
+            ProcessorUpdate {
+                nextpc: pc + size,
+                effects,
+            }
+        };
+
+        // Advance the pc past the instruction and swap a CSR. Writing to x0 means the old value
+        // isn't read.
+        let next_csr = |rd, val, csr, csr_val: Option<i64>| {
+            let mut after = regs.clone();
+            if let Some(csr_val) = csr_val {
+                after.set_csr(csr, csr_val, self.config.c);
+            }
+            let mut effects = self.state_effects(&after);
+            if rd != Register::x0 {
+                effects.push(self.write_effect(rd, val));
+            }
+            ProcessorUpdate {
+                nextpc: pc + size,
+                effects,
+            }
+        };
+
+        // CSRs with the top two bits of their number set are read-only
+        let check_csr_write = |csr: Csr, write| {
+            if write && csr.number() >> 10 == 0b11 {
+                Err(ExecErrorInner::ReadOnlyCsr(csr))
+            } else {
+                Ok(())
+            }
         };
 
         // Just advance the pc
         let next = ProcessorUpdate {
-            nextpc: pc + 4,
-            diff: None,
+            nextpc: pc + size,
+            effects: vec![],
         };
 
         let processor_update = match asm {
             Instruction::RegImm { rd, r1, imm, op } => {
-                let imm = *imm;
+                let imm = *imm as i64;
                 let r1val = regs[r1];
+                let shamt = imm as u32;
                 let val = match op {
                     RegImmOp::Addi => self.add(r1val, imm)?,
-                    RegImmOp::Sltiu => ((r1val as u32) < (imm as u32)) as i32,
-                    RegImmOp::Slli => self.shift_left(r1val, imm as u32)?,
-                    RegImmOp::Srli => self.shift_right_logical(r1val, imm as u32)?,
-                    RegImmOp::Srai => self.shift_right_arithmetic(r1val, imm as u32)?,
-                    RegImmOp::Slti => (regs[r1] < imm) as i32,
-                    RegImmOp::Xori => regs[r1] ^ imm,
-                    RegImmOp::Ori => regs[r1] | imm,
-                    RegImmOp::Andi => regs[r1] & imm,
+                    // Registers are always sign extended, so comparing all 64
+                    // bits as unsigned works in RV32 mode too
+                    RegImmOp::Sltiu => ((r1val as u64) < (imm as u64)) as i64,
+                    RegImmOp::Slli => self.shift_left(r1val, shamt, xlen)?,
+                    RegImmOp::Srli => self.shift_right_logical(r1val, shamt, xlen)?,
+                    RegImmOp::Srai => self.shift_right_arithmetic(r1val, shamt, xlen)?,
+                    RegImmOp::Slti => (r1val < imm) as i64,
+                    RegImmOp::Xori => r1val ^ imm,
+                    RegImmOp::Ori => r1val | imm,
+                    RegImmOp::Andi => r1val & imm,
+                    RegImmOp::Addiw => self.add_width(r1val, imm, Xlen::Rv32)?,
+                    RegImmOp::Slliw => self.shift_left(r1val, shamt, Xlen::Rv32)?,
+                    RegImmOp::Srliw => self.shift_right_logical(r1val, shamt, Xlen::Rv32)?,
+                    RegImmOp::Sraiw => self.shift_right_arithmetic(r1val, shamt, Xlen::Rv32)?,
+                    RegImmOp::Rori => bitmanip::rotate_right(r1val, shamt, xlen),
+                    RegImmOp::Bseti => bitmanip::set(r1val, shamt, xlen),
+                    RegImmOp::Bclri => bitmanip::clear(r1val, shamt, xlen),
+                    RegImmOp::Binvi => bitmanip::invert(r1val, shamt, xlen),
+                    RegImmOp::Bexti => bitmanip::extract(r1val, shamt, xlen),
                 };
                 next_with(*rd, val)
             }
             Instruction::RegReg { rd, r1, r2, op } => {
                 let r1val = regs[r1];
                 let r2val = regs[r2];
+                let shamt = r2val as u32;
                 let val = match op {
                     RegRegOp::Add => self.add(r1val, r2val)?,
-                    RegRegOp::Sub => self.add(r1val, -r2val)?,
-                    RegRegOp::Sll => self.shift_left(r1val, r2val as u32)?,
-                    RegRegOp::Srl => self.shift_right_logical(r1val, r2val as u32)?,
-                    RegRegOp::Sra => self.shift_right_arithmetic(r1val, r2val as u32)?,
-                    RegRegOp::Sltu => ((r1val as u32) + (r2val as u32)) as i32,
-                    RegRegOp::Slt => (r1val < r2val) as i32,
+                    RegRegOp::Sub => self.sub_width(r1val, r2val, xlen)?,
+                    RegRegOp::Sll => self.shift_left(r1val, shamt, xlen)?,
+                    RegRegOp::Srl => self.shift_right_logical(r1val, shamt, xlen)?,
+                    RegRegOp::Sra => self.shift_right_arithmetic(r1val, shamt, xlen)?,
+                    RegRegOp::Sltu => ((r1val as u64) < (r2val as u64)) as i64,
+                    RegRegOp::Slt => (r1val < r2val) as i64,
                     RegRegOp::Xor => r1val ^ r2val,
                     RegRegOp::Or => r1val | r2val,
                     RegRegOp::And => r1val & r2val,
+                    RegRegOp::Addw => self.add_width(r1val, r2val, Xlen::Rv32)?,
+                    RegRegOp::Subw => self.sub_width(r1val, r2val, Xlen::Rv32)?,
+                    RegRegOp::Sllw => self.shift_left(r1val, shamt, Xlen::Rv32)?,
+                    RegRegOp::Srlw => self.shift_right_logical(r1val, shamt, Xlen::Rv32)?,
+                    RegRegOp::Sraw => self.shift_right_arithmetic(r1val, shamt, Xlen::Rv32)?,
+                    RegRegOp::Sh1add => self.add(xlen.sext(r1val << 1), r2val)?,
+                    RegRegOp::Sh2add => self.add(xlen.sext(r1val << 2), r2val)?,
+                    RegRegOp::Sh3add => self.add(xlen.sext(r1val << 3), r2val)?,
+                    RegRegOp::Min => r1val.min(r2val),
+                    RegRegOp::Max => r1val.max(r2val),
+                    RegRegOp::Minu => (r1val as u64).min(r2val as u64) as i64,
+                    RegRegOp::Maxu => (r1val as u64).max(r2val as u64) as i64,
+                    RegRegOp::Rol => bitmanip::rotate_left(r1val, shamt, xlen),
+                    RegRegOp::Ror => bitmanip::rotate_right(r1val, shamt, xlen),
+                    RegRegOp::Andn => r1val & !r2val,
+                    RegRegOp::Orn => r1val | !r2val,
+                    RegRegOp::Xnor => !(r1val ^ r2val),
+                    RegRegOp::Bset => bitmanip::set(r1val, shamt, xlen),
+                    RegRegOp::Bclr => bitmanip::clear(r1val, shamt, xlen),
+                    RegRegOp::Binv => bitmanip::invert(r1val, shamt, xlen),
+                    RegRegOp::Bext => bitmanip::extract(r1val, shamt, xlen),
                 };
                 next_with(*rd, val)
             }
             Instruction::Load { rd, offset, r1, op } => {
-                let addr = self.add(*offset, regs[r1])?;
+                let addr = xlen.address(self.add(*offset as i64, regs[r1])?);
+                self.check_stack(regs[r1], addr, op.size(), false, &mut update.warnings)?;
+                let addr = self.data_address(addr);
                 let val = self.memory.load(addr, *op)?;
-                next_with(*rd, val)
+                let mut next = next_with(*rd, val);
+                // Reading a device's registers can change it
+                let addr = self.memory.translate(addr, Access::Read)?;
+                next.effects
+                    .extend(self.read_effects(addr, op.size() as usize));
+                next
             }
             Instruction::Store { r2, offset, r1, op } => {
-                let addr = self.add(*offset, regs[r1])?;
+                let addr = xlen.address(self.add(*offset as i64, regs[r1])?);
+                self.check_stack(regs[r1], addr, op.size(), true, &mut update.warnings)?;
+                let addr = self.memory.check_store(self.data_address(addr), *op)?;
                 next_mem(addr, regs[r2], *op)
             }
             Instruction::Branch { r1, r2, label, op } => {
@@ -683,15 +1788,15 @@ impl Executor {
                     BranchOp::Bne => regs[r1] != regs[r2],
                     BranchOp::Blt => regs[r1] < regs[r2],
                     BranchOp::Bge => regs[r1] >= regs[r2],
-                    BranchOp::Bltu => (regs[r1] as u32) < (regs[r2] as u32),
-                    BranchOp::Bgeu => (regs[r1] as u32) >= (regs[r2] as u32),
+                    BranchOp::Bltu => (regs[r1] as u64) < (regs[r2] as u64),
+                    BranchOp::Bgeu => (regs[r1] as u64) >= (regs[r2] as u64),
                     BranchOp::Bgt => regs[r1] > regs[r2],
                     BranchOp::Ble => regs[r1] <= regs[r2],
-                    BranchOp::Bgtu => (regs[r1] as u32) > (regs[r2] as u32),
-                    BranchOp::Bleu => (regs[r1] as u32) <= (regs[r2] as u32),
+                    BranchOp::Bgtu => (regs[r1] as u64) > (regs[r2] as u64),
+                    BranchOp::Bleu => (regs[r1] as u64) <= (regs[r2] as u64),
                 };
                 if jump {
-                    ProcessorUpdate::jump(self.program.label(label).unwrap())
+                    ProcessorUpdate::jump(self.label(label))
                 } else {
                     next
                 }
@@ -706,14 +1811,16 @@ impl Executor {
                     BranchZeroOp::Blez => regs[r1] <= 0,
                 };
                 if jump {
-                    ProcessorUpdate::jump(self.program.label(label).unwrap())
+                    ProcessorUpdate::jump(self.label(label))
                 } else {
                     next
                 }
             }
             Instruction::LoadImm { rd, imm, op } => {
                 let val = match op {
-                    LoadImmOp::Lui => imm << 12,
+                    // The shift happens on 32 bits, so the result is sign extended
+                    LoadImmOp::Lui => ((*imm as i32) << 12) as i64,
+                    LoadImmOp::Auipc => xlen.sext(pc + ((*imm as i32) << 12) as i64),
                     LoadImmOp::Li => *imm,
                 };
                 next_with(*rd, val)
@@ -723,65 +1830,260 @@ impl Executor {
                 let val = match op {
                     UnaryOp::Mv => r1val,
                     UnaryOp::Not => !r1val,
-                    UnaryOp::Neg => -r1val,
+                    UnaryOp::Neg => self.sub_width(0, r1val, xlen)?,
+                    UnaryOp::NegW => self.sub_width(0, r1val, Xlen::Rv32)?,
+                    UnaryOp::SextW => r1val as i32 as i64,
+                    UnaryOp::Clz => bitmanip::leading_zeros(r1val, xlen),
+                    UnaryOp::Ctz => bitmanip::trailing_zeros(r1val, xlen),
+                    UnaryOp::Cpop => bitmanip::count_ones(r1val, xlen),
+                    UnaryOp::SextB => r1val as i8 as i64,
+                    UnaryOp::SextH => r1val as i16 as i64,
+                    UnaryOp::ZextH => r1val as u16 as i64,
+                    UnaryOp::Rev8 => bitmanip::reverse_bytes(r1val, xlen),
+                    UnaryOp::OrcB => bitmanip::or_combine(r1val, xlen),
                 };
                 next_with(*rd, val)
             }
+            Instruction::LoadReserved { rd, r1 } => {
+                let addr = self.data_address(xlen.address(regs[r1]));
+                let val = self.memory.load_atomic(addr)?;
+                // Reservations are on physical addresses
+                let addr = self.memory.translate(addr, Access::Read)?;
+                ProcessorUpdate {
+                    nextpc: pc + size,
+                    effects: vec![
+                        self.write_effect(*rd, val),
+                        Effect::Reservation {
+                            old: self.memory.reservation(),
+                            new: Some(addr),
+                        },
+                    ],
+                }
+            }
+            Instruction::StoreConditional { rd, r2, r1 } => {
+                let addr = xlen.address(regs[r1]);
+                let addr = self.memory.check_atomic(self.data_address(addr))?;
+                let reservation = self.memory.reservation();
+                let success = reservation == Some(addr);
+                // The reservation is cleared either way
+                let mut effects = if success {
+                    self.store_effects([(addr, regs[r2], StoreOp::Sw)])
+                } else if reservation.is_some() {
+                    vec![Effect::Reservation {
+                        old: reservation,
+                        new: None,
+                    }]
+                } else {
+                    vec![]
+                };
+                effects.push(self.write_effect(*rd, !success as i64));
+                ProcessorUpdate {
+                    nextpc: pc + size,
+                    effects,
+                }
+            }
+            Instruction::Amo { rd, r2, r1, op } => {
+                let addr = xlen.address(regs[r1]);
+                // AMOs fault as stores, so translate for writing
+                let addr = self.memory.check_atomic(self.data_address(addr))?;
+                let val = self.memory.load_atomic(addr)? as i32;
+                let r2val = regs[r2] as i32;
+                let stored = match op {
+                    AmoOp::Swap => r2val,
+                    // AMOs always wrap, like the hardware does
+                    AmoOp::Add => val.wrapping_add(r2val),
+                    AmoOp::And => val & r2val,
+                    AmoOp::Or => val | r2val,
+                    AmoOp::Xor => val ^ r2val,
+                    AmoOp::Min => val.min(r2val),
+                    AmoOp::Max => val.max(r2val),
+                    AmoOp::Minu => (val as u32).min(r2val as u32) as i32,
+                    AmoOp::Maxu => (val as u32).max(r2val as u32) as i32,
+                } as i64;
+                let mut effects = self.store_effects([(addr, stored, StoreOp::Sw)]);
+                effects.push(self.write_effect(*rd, val as i64));
+                ProcessorUpdate {
+                    nextpc: pc + size,
+                    effects,
+                }
+            }
+            Instruction::FLoad { rd, offset, r1 } => {
+                let addr = xlen.address(self.add(*offset as i64, regs[r1])?);
+                self.check_stack(regs[r1], addr, 4, false, &mut update.warnings)?;
+                let val = self.memory.load(self.data_address(addr), LoadOp::Lw)? as u32;
+                next_float(*rd, (val, 0))
+            }
+            Instruction::FStore { r2, offset, r1 } => {
+                let addr = xlen.address(self.add(*offset as i64, regs[r1])?);
+                self.check_stack(regs[r1], addr, 4, true, &mut update.warnings)?;
+                let addr = self
+                    .memory
+                    .check_store(self.data_address(addr), StoreOp::Sw)?;
+                next_mem(addr, regs[r2] as i64, StoreOp::Sw)
+            }
+            Instruction::FRegReg { rd, r1, r2, op, rm } => {
+                let rm = if op.takes_rounding_mode() {
+                    self.rounding_mode(*rm)?
+                } else {
+                    RoundingMode::Rne
+                };
+                next_float(*rd, float::reg_reg(*op, regs[r1], regs[r2], rm))
+            }
+            Instruction::FSqrt { rd, r1, rm } => {
+                next_float(*rd, float::sqrt(regs[r1], self.rounding_mode(*rm)?))
+            }
+            Instruction::FFused {
+                rd,
+                r1,
+                r2,
+                r3,
+                op,
+                rm,
+            } => {
+                let rm = self.rounding_mode(*rm)?;
+                next_float(*rd, float::fused(*op, regs[r1], regs[r2], regs[r3], rm))
+            }
+            Instruction::FCompare { rd, r1, r2, op } => {
+                let (val, fflags) = float::compare(*op, regs[r1], regs[r2]);
+                next_flags(*rd, (val as i64, fflags))
+            }
+            Instruction::FClass { rd, r1 } => next_with(*rd, float::classify(regs[r1]) as i64),
+            Instruction::FToInt { rd, r1, op, rm } => {
+                // Word results are sign extended, even the unsigned ones
+                let (val, fflags) = match op {
+                    FToIntOp::CvtW => float::to_int(regs[r1], true, self.rounding_mode(*rm)?),
+                    FToIntOp::CvtWu => float::to_int(regs[r1], false, self.rounding_mode(*rm)?),
+                    FToIntOp::MvXW => (regs[r1] as i32, 0),
+                };
+                if *op == FToIntOp::MvXW {
+                    next_with(*rd, val as i64)
+                } else {
+                    next_flags(*rd, (val as i64, fflags))
+                }
+            }
+            Instruction::IntToF { rd, r1, op, rm } => match op {
+                IntToFOp::CvtSW => next_float(
+                    *rd,
+                    float::from_int(regs[r1] as i32, true, self.rounding_mode(*rm)?),
+                ),
+                IntToFOp::CvtSWu => next_float(
+                    *rd,
+                    float::from_int(regs[r1] as i32, false, self.rounding_mode(*rm)?),
+                ),
+                IntToFOp::MvWX => next_float(*rd, (regs[r1] as u32, 0)),
+            },
+            Instruction::CsrReg { rd, csr, r1, op } => {
+                let old = regs.csr(*csr);
+                let src = regs[r1];
+                let new = match op {
+                    CsrRegOp::Rw => src,
+                    CsrRegOp::Rs => old | src,
+                    CsrRegOp::Rc => old & !src,
+                };
+                // Setting or clearing bits with x0 doesn't write the CSR at all
+                let write = *op == CsrRegOp::Rw || *r1 != Register::x0;
+                check_csr_write(*csr, write)?;
+                next_csr(*rd, old, *csr, write.then_some(new))
+            }
+            Instruction::CsrImm { rd, csr, imm, op } => {
+                let old = regs.csr(*csr);
+                let imm = *imm as i64;
+                let new = match op {
+                    CsrImmOp::Rwi => imm,
+                    CsrImmOp::Rsi => old | imm,
+                    CsrImmOp::Rci => old & !imm,
+                };
+                let write = *op == CsrImmOp::Rwi || imm != 0;
+                check_csr_write(*csr, write)?;
+                next_csr(*rd, old, *csr, write.then_some(new))
+            }
             Instruction::call { label } => {
                 update.stackop = Some(StackOp::PushStack(Register::ra));
                 ProcessorUpdate {
-                    nextpc: self.program.label(label).unwrap(),
-                    diff: Some(Diff::Register {
-                        reg: Register::ra,
-                        val: self.pc + 4,
-                    }),
+                    nextpc: self.label(label),
+                    effects: vec![self.write_effect(Register::ra, pc + size)],
                 }
             }
             Instruction::jal { rd, label } => {
                 update.stackop = Some(StackOp::PushStack(*rd));
                 ProcessorUpdate {
-                    nextpc: self.program.label(label).unwrap(),
-                    diff: Some(Diff::Register {
-                        reg: *rd,
-                        val: self.pc + 4,
-                    }),
+                    nextpc: self.label(label),
+                    effects: vec![self.write_effect(*rd, pc + size)],
                 }
             }
             Instruction::jalr { rd, offset, r1 } => {
                 update.stackop = Some(StackOp::PushStack(*rd));
-                let nextpc = self.add(regs[r1], *offset)?;
+                // The lowest bit of the target is always cleared
+                let nextpc = xlen.address(self.add(regs[r1], *offset as i64)? & !1);
                 ProcessorUpdate {
                     nextpc,
-                    diff: Some(Diff::Register {
-                        reg: *rd,
-                        val: self.pc + 4,
-                    }),
+                    effects: vec![self.write_effect(*rd, pc + size)],
                 }
             }
-            Instruction::la { rd, label } => next_with(*rd, self.program.label(label).unwrap()),
-            Instruction::j { label } => ProcessorUpdate {
-                nextpc: self.program.label(label).unwrap(),
-                diff: None,
-            },
+            Instruction::la { rd, label } => next_with(*rd, self.label(label)),
+            Instruction::j { label } => ProcessorUpdate::jump(self.label(label)),
             Instruction::jr { rs } => {
                 update.stackop = Some(StackOp::PopStack(*rs));
-                ProcessorUpdate {
-                    nextpc: regs[rs],
-                    diff: None,
-                }
+                ProcessorUpdate::jump(xlen.address(regs[rs]))
             }
             Instruction::ret {} => {
                 update.stackop = Some(StackOp::PopStack(Register::ra));
+                ProcessorUpdate::jump(xlen.address(regs[Register::ra]))
+            }
+            Instruction::wfi {} => next,
+            Instruction::mret {} => {
+                let mut after = regs.clone();
+                after.trap_return(Privilege::Machine);
+                ProcessorUpdate {
+                    nextpc: xlen.address(regs.csr(Csr::Mepc)),
+                    effects: self.state_effects(&after),
+                }
+            }
+            Instruction::sret {} => {
+                let mut after = regs.clone();
+                after.trap_return(Privilege::Supervisor);
                 ProcessorUpdate {
-                    nextpc: regs[Register::ra],
-                    diff: None,
+                    nextpc: xlen.address(regs.csr(Csr::Sepc)),
+                    effects: self.state_effects(&after),
                 }
             }
+            Instruction::ecall {} => Err(ExecErrorInner::Ecall(regs.privilege))?,
+            // Every access walks the page tables, so there is nothing to flush
+            Instruction::sfence_vma { .. } => next,
+            Instruction::Vsetvli { .. }
+            | Instruction::VLoad { .. }
+            | Instruction::VStore { .. }
+            | Instruction::VArith { .. }
+            | Instruction::VCompare { .. }
+            | Instruction::VReduce { .. }
+            | Instruction::VMask { .. }
+            | Instruction::VMove { .. }
+            | Instruction::VMoveToScalar { .. }
+            | Instruction::VMoveFromScalar { .. }
+            | Instruction::VCpop { .. }
+            | Instruction::Vid { .. } => self.vector_update(asm)?,
+            Instruction::Custom {
+                mnemonic, operands, ..
+            } => self.custom_update(mnemonic, operands)?,
+            Instruction::Compressed { .. } => unreachable!("compressed instructions are expanded"),
         };
 
+        // With compressed instructions, jumps only need to be 2-byte aligned
+        let alignment = if self.config.c { 2 } else { 4 };
+        if processor_update.nextpc % alignment != 0 {
+            Err(ExecErrorInner::MisalignedJump {
+                target: processor_update.nextpc,
+            })?
+        }
+
         // Make sure writing to x0 follows the config
-        if let Some(Diff::Register { reg, val }) = processor_update.diff {
-            if reg == Register::x0 {
+        for effect in &processor_update.effects {
+            if let Effect::Register {
+                reg: Register::x0,
+                new: val,
+                ..
+            } = *effect
+            {
                 match self.config.write_to_x0 {
                     ConfigLevel::Allow => (),
                     ConfigLevel::Warn => update.warnings.push(ExecError {
@@ -799,63 +2101,34 @@ impl Executor {
 
     /// Revert one instruction, returning false if we are already at the start.
     pub fn revert(&mut self) -> bool {
-        // A key observation is that if we reached some state, at all points
-        // leading up to that state, we had a valid execution. Therefore, if
-        // we _correctly_ revert to a certain execution point, we can unwrap all
-        // the execution results leading up to the original point because we know
-        // they didn't causes errors.
-
-        if self.executed == 0 {
+        let Some(step) = self.history.pop_back() else {
             return false;
+        };
+        for effect in step.effects.iter().rev() {
+            self.apply(effect, true);
         }
-
-        let executed = self.executed;
-        let target = executed - 1;
-        let offset_from_snapshot = executed.rem_euclid(SNAPSHOT_INTERVAL);
-        let base = executed - offset_from_snapshot;
-
-        // Every `SNAPSHOT_INTERVAL` we take store a snapshot so it should exist.
-        let regs = self.snapshots.get(&base).unwrap_or_else(|| {
-            panic!("no register snapshot for {base} even though we reached {executed}")
-        });
-
-        // Reset the executor to the base state
-        self.regfile = regs.clone();
-        self.pc = self.regfile.pc;
-        self.executed = base;
-
-        // Reset to the correct stack frame too
-        let frames = self.stack.partition_point(|frame| frame.executed <= target);
-        self.stack.truncate(frames);
-
-        // Execute to the state we want to revert to. We know executed cannot be
-        // 0 because we check first thing
-        while self.executed < target {
-            self.execute().unwrap();
-        }
-
-        // Generate the next update so we can reverse it to figure out the diff
-        // that reverts from the start state
-        let asm = self.program.at(self.pc).unwrap();
-        let forward = self.calculate_update(asm).unwrap();
-        let diff = forward.processor_update.diff.map(|diff| match diff {
-            Diff::Memory { addr, val, op } => todo!(),
-            Diff::Register { reg, .. } => Diff::Register {
-                reg,
-                val: self.regfile[reg],
-            },
-        });
-
-        let stackop = forward.stackop.map(|op| StackOp::reverse(&op));
-
-        false
+        match step.stackop {
+            Some(StackOp::PushStack(_)) => {
+                self.stack.pop();
+            }
+            Some(StackOp::PopStack(_)) => self.stack.extend(step.popped),
+            None => (),
+        }
+        self.pc = step.pc;
+        self.executed -= 1;
+        self.memory.set_time(self.executed as u64);
+        true
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::*;
-    use indoc::indoc;
+    use super::{
+        memory::{Permissions, Region},
+        *,
+    };
+    use crate::map;
+    use indoc::{formatdoc, indoc};
 
     #[test]
     fn mem() {
@@ -868,7 +2141,7 @@ mod tests {
         .unwrap();
         exec.run().unwrap();
         assert_eq!(
-            exec.memory.mem,
+            exec.memory.contents(),
             map! {
                 0x100 => 0xdd,
                 0x101 => 0xcc,
@@ -997,4 +2270,1271 @@ mod tests {
         program.run().unwrap();
         println!("{:#?}", program.memory)
     }
+
+    #[test]
+    fn atomics() {
+        let mut exec = indoc! {"
+            li a0, 0x100
+            li t0, 5
+            sw t0, 0(a0)
+
+            # Atomically increment with lr/sc
+            retry:
+            lr.w t1, (a0)
+            addi t1, t1, 1
+            sc.w t2, t1, (a0)
+            bnez t2, retry
+
+            li t0, -3
+            amoadd.w a1, t0, (a0)
+            amomin.w a2, t0, (a0)
+            amomaxu.w a3, t0, (a0)
+            amoswap.w a4, zero, (a0)
+            lw a5, 0(a0)
+        "}
+        .parse::<Executor>()
+        .unwrap();
+        exec.run().unwrap();
+        assert_eq!(exec.regfile[Register::t2], 0);
+        assert_eq!(exec.regfile[Register::a1], 6);
+        assert_eq!(exec.regfile[Register::a2], 3);
+        assert_eq!(exec.regfile[Register::a3], -3);
+        assert_eq!(exec.regfile[Register::a4], -3);
+        assert_eq!(exec.regfile[Register::a5], 0);
+
+        // A store between lr and sc kills the reservation
+        let mut exec = indoc! {"
+            li a0, 0x100
+            sw zero, 0(a0)
+            lr.w t1, (a0)
+            sb t1, 1(a0)
+            sc.w t2, t1, (a0)
+        "}
+        .parse::<Executor>()
+        .unwrap();
+        exec.run().unwrap();
+        assert_eq!(exec.regfile[Register::t2], 1);
+
+        // But stores to the neighbouring words don't
+        let mut exec = indoc! {"
+            li a0, 0x100
+            sw zero, 0(a0)
+            lr.w t1, (a0)
+            sw t1, 4(a0)
+            sw t1, -4(a0)
+            sc.w t2, t1, (a0)
+        "}
+        .parse::<Executor>()
+        .unwrap();
+        exec.run().unwrap();
+        assert_eq!(exec.regfile[Register::t2], 0);
+
+        // Misaligned atomics fault
+        let mut exec = indoc! {"
+            li a0, 0x102
+            amoadd.w t0, t0, (a0)
+        "}
+        .parse::<Executor>()
+        .unwrap();
+        exec.memory.config.default_value = Some(0);
+        assert!(matches!(
+            exec.run(),
+            Err(ExecError {
+                error: ExecErrorInner::Memory(MemoryError::UnalignedAccess(0x102)),
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn floats() {
+        let mut exec = indoc! {"
+            li a0, 0x100
+            li t0, 0x40490fdb # pi
+            sw t0, 0(a0)
+            flw fa0, 0(a0)
+
+            li t0, 2
+            fcvt.s.w fa1, t0
+            fmul.s fa2, fa0, fa1
+            fsw fa2, 4(a0)
+            fmadd.s fa3, fa1, fa1, fa1 # 2 * 2 + 2
+            fcvt.w.s a1, fa3
+            fneg.s fa4, fa3
+            flt.s a2, fa4, fa3
+            fclass.s a3, fa4
+            fsqrt.s fa5, fa4
+            frflags a4
+
+            # Round 2.5 with each rounding mode
+            li t0, 5
+            fcvt.s.w fs0, t0
+            fdiv.s fs0, fs0, fa1
+            fcvt.w.s s1, fs0, rne
+            fcvt.w.s s2, fs0, rup
+            fsrm t1, t0 # reserved rounding mode
+        "}
+        .parse::<Executor>()
+        .unwrap();
+        exec.memory.config.default_value = Some(0);
+        exec.run().unwrap();
+        let regs = &exec.regfile;
+        assert_eq!(regs.float(FRegister::fa2), std::f32::consts::PI * 2.0);
+        assert_eq!(
+            exec.memory.load(0x104, LoadOp::Lw).unwrap(),
+            (std::f32::consts::PI * 2.0).to_bits() as i32 as i64
+        );
+        assert_eq!(regs.float(FRegister::fa3), 6.0);
+        assert_eq!(regs[Register::a1], 6);
+        assert_eq!(regs[Register::a2], 1);
+        // Negative normal number
+        assert_eq!(regs[Register::a3], 1 << 1);
+        assert!(regs.float(FRegister::fa5).is_nan());
+        assert_eq!(regs[Register::a4], float::flags::NV as i64);
+        assert_eq!(regs[Register::s1], 2);
+        assert_eq!(regs[Register::s2], 3);
+        assert_eq!(regs[Register::t1], 0);
+        assert_eq!(regs.csr(Csr::Frm), 5);
+        // NV from the sqrt and NX from rounding 2.5
+        assert_eq!(
+            regs.csr(Csr::Fflags),
+            (float::flags::NV | float::flags::NX) as i64
+        );
+
+        // Using the dynamic rounding mode with a reserved mode fails
+        let mut exec = indoc! {"
+            li t0, 7
+            fsrm t0
+            fadd.s fa0, fa0, fa0
+        "}
+        .parse::<Executor>()
+        .unwrap();
+        assert!(matches!(
+            exec.run(),
+            Err(ExecError {
+                error: ExecErrorInner::InvalidRoundingMode(7),
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn rv64() {
+        let program = indoc! {"
+            li a0, -1
+            srli a1, a0, 32
+            addw a2, a1, zero
+            li t0, 1
+            slli t1, t0, 40
+            add t2, t1, t1
+            sraiw a3, t1, 1
+            lui a4, 0x80000
+            srliw a5, a4, 4
+            sext.w a6, a1
+            negw a7, a1
+
+            li s0, 0x100
+            sd t2, 0(s0)
+            ld s1, 0(s0)
+            sw a0, 8(s0)
+            lwu s2, 8(s0)
+            lw s3, 8(s0)
+            sltu s4, a0, a1
+            sltu s5, a1, a0
+        "}
+        .parse()
+        .unwrap();
+        let config = Config {
+            xlen: Xlen::Rv64,
+            ..Default::default()
+        };
+        let mut exec = Executor::with_config(program, config);
+        exec.run().unwrap();
+        let regs = &exec.regfile;
+        assert_eq!(regs[Register::a1], 0xffffffff);
+        assert_eq!(regs[Register::a2], -1);
+        assert_eq!(regs[Register::t2], 1 << 41);
+        assert_eq!(regs[Register::a3], 0);
+        assert_eq!(regs[Register::a4], -0x80000000);
+        assert_eq!(regs[Register::a5], 0x08000000);
+        assert_eq!(regs[Register::a6], -1);
+        assert_eq!(regs[Register::a7], 1);
+        assert_eq!(regs[Register::s1], 1 << 41);
+        assert_eq!(regs[Register::s2], 0xffffffff);
+        assert_eq!(regs[Register::s3], -1);
+        assert_eq!(regs[Register::s4], 0);
+        assert_eq!(regs[Register::s5], 1);
+
+        // RV32 keeps everything sign extended from 32 bits
+        let mut exec = indoc! {"
+            li a0, -1
+            srli a1, a0, 16
+            li t0, 1
+            li t1, 2
+            sltu a2, t1, t0
+            sltu a3, a0, t0
+            lui a4, 0x80000
+            sltu a5, a4, a0
+        "}
+        .parse::<Executor>()
+        .unwrap();
+        exec.run().unwrap();
+        assert_eq!(exec.regfile[Register::a1], 0xffff);
+        assert_eq!(exec.regfile[Register::a2], 0);
+        assert_eq!(exec.regfile[Register::a3], 0);
+        assert_eq!(exec.regfile[Register::a5], 1);
+
+        // Hex immediates are zero-extended in RV64, and li expands to load
+        // them with as many instructions as it takes
+        let config = Config {
+            xlen: Xlen::Rv64,
+            ..Default::default()
+        };
+        let source = indoc! {"
+            li a0, 0xffffffff
+            li a1, 0x80000000
+            li a2, 0x123456789
+            li a3, -0x123456789
+            li a4, 0x7fffffffffffffff
+        "};
+        let mut exec = Executor::from_source(source, config.clone()).unwrap();
+        exec.run().unwrap();
+        assert_eq!(exec.regfile[Register::a0], 0x00000000ffffffff);
+        assert_eq!(exec.regfile[Register::a1], 0x0000000080000000);
+        assert_eq!(exec.regfile[Register::a2], 0x123456789);
+        assert_eq!(exec.regfile[Register::a3], -0x123456789);
+        assert_eq!(exec.regfile[Register::a4], i64::MAX);
+        let sizes: Vec<_> = exec.program.asm.iter().map(Instruction::size).collect();
+        assert_eq!(sizes, [12, 8, 16, 16, 12]);
+        // Running the instructions they expand to gives the same values
+        let expanded = Program {
+            labels: Default::default(),
+            asm: (exec.program.asm.iter())
+                .flat_map(|instr| instr.expand(0, &exec.program).unwrap())
+                .collect(),
+        };
+        // Loading i64::MAX wraps around on purpose
+        let config = Config {
+            overflow_mode: OverflowBehaviour::Wrap,
+            ..config
+        };
+        let mut expanded = Executor::with_config(expanded, config);
+        expanded.run().unwrap();
+        for reg in [
+            Register::a0,
+            Register::a1,
+            Register::a2,
+            Register::a3,
+            Register::a4,
+        ] {
+            assert_eq!(expanded.regfile[reg], exec.regfile[reg], "{reg}");
+        }
+
+        // They still wrap around to 32 bits in RV32
+        let mut exec = "li a0, 0xffffffff".parse::<Executor>().unwrap();
+        exec.run().unwrap();
+        assert_eq!(exec.regfile[Register::a0], -1);
+
+        // RV64-only instructions are rejected in RV32 mode
+        let mut exec = "ld a0, 0(sp)".parse::<Executor>().unwrap();
+        assert!(matches!(
+            exec.run(),
+            Err(ExecError {
+                error: ExecErrorInner::UnsupportedInstruction {
+                    requires: "RV64I",
+                    ..
+                },
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn bitmanip() {
+        let program = indoc! {"
+            li a0, 3
+            li a1, 0x100
+            sh2add a2, a0, a1
+            li t0, -5
+            min a3, t0, a0
+            minu a4, t0, a0
+            andn a5, a1, a1
+            xnor a6, a0, a0
+            clz a7, a1
+            cpop s0, t0
+            li t1, 0xff80
+            sext.b s1, t1
+            zext.h s2, t0
+            rev8 s3, a1
+            orc.b s4, a0
+            bset s5, zero, a0
+            bexti s6, a1, 8
+            rori s7, a0, 1
+        "}
+        .parse::<Program>()
+        .unwrap();
+        let config = Config {
+            zba: true,
+            zbb: true,
+            zbs: true,
+            ..Default::default()
+        };
+        let mut exec = Executor::with_config(program.clone(), config);
+        exec.run().unwrap();
+        let regs = &exec.regfile;
+        assert_eq!(regs[Register::a2], 0x10c);
+        assert_eq!(regs[Register::a3], -5);
+        assert_eq!(regs[Register::a4], 3);
+        assert_eq!(regs[Register::a5], 0);
+        assert_eq!(regs[Register::a6], -1);
+        assert_eq!(regs[Register::a7], 23);
+        assert_eq!(regs[Register::s0], 31);
+        assert_eq!(regs[Register::s1], -128);
+        assert_eq!(regs[Register::s2], 0xfffb);
+        assert_eq!(regs[Register::s3], 0x00010000);
+        assert_eq!(regs[Register::s4], 0xff);
+        assert_eq!(regs[Register::s5], 0b1000);
+        assert_eq!(regs[Register::s6], 1);
+        assert_eq!(regs[Register::s7], 0x80000001_u32 as i32 as i64);
+
+        // Each extension is enabled separately
+        let config = Config {
+            zbb: true,
+            zbs: true,
+            ..Default::default()
+        };
+        let mut exec = Executor::with_config(program, config);
+        let Err(err) = exec.run() else {
+            panic!("sh2add ran without Zba");
+        };
+        assert_eq!(
+            err.to_string(),
+            "sh2add a2, a0, a1 requires Zba, which is not enabled (pc 0x00000008)"
+        );
+    }
+
+    #[test]
+    fn embedded() {
+        // RV32E only has sp, s0 and s1 as callee-saved registers
+        let before = RegisterSnapshot::default();
+        let mut after = before.clone();
+        after[Register::s2] = 1;
+        assert_eq!(after.check(&before, Base::I), Some(vec![Register::s2]));
+        assert_eq!(after.check(&before, Base::E), None);
+        after[Register::s1] = 1;
+        assert_eq!(after.check(&before, Base::E), Some(vec![Register::s1]));
+
+        let config = Config {
+            base: Base::E,
+            ..Default::default()
+        };
+        let source = indoc! {"
+            call f
+            j end
+            f:
+            li s1, 1
+            ret
+            end:
+        "};
+        let mut exec = Executor::from_source(source, config.clone()).unwrap();
+        assert!(matches!(
+            exec.run(),
+            Err(ExecError {
+                error: ExecErrorInner::CallingConventionViolation(_),
+                ..
+            })
+        ));
+
+        // Registers outside x0-x15 don't parse at all
+        assert!(Executor::from_source("li s2, 1", config).is_err());
+    }
+
+    #[test]
+    fn traps() {
+        let mut exec = indoc! {"
+            la t0, handler
+            csrw mtvec, t0
+            csrsi mstatus, 8
+            li a0, 0x101
+            lw a1, 0(a0)
+            sh1add a2, a0, a0
+            csrr s3, mstatus
+            j end
+
+            handler:
+            csrr s0, mcause
+            csrr s1, mtval
+            csrr s2, mstatus
+            csrr t1, mepc
+            addi t1, t1, 4
+            csrw mepc, t1
+            mret
+            end:
+        "}
+        .parse::<Executor>()
+        .unwrap();
+
+        // Misaligned load, run until the handler returns to the next instruction
+        while exec.pc != 24 {
+            exec.execute().unwrap();
+        }
+        assert_eq!(exec.regfile[Register::s0], Exception::LoadMisaligned as i64);
+        assert_eq!(exec.regfile[Register::s1], 0x101);
+        // Interrupts are disabled in the handler
+        assert_eq!(exec.regfile[Register::s2], mstatus::MPIE | mstatus::MPP);
+
+        // Illegal instruction (Zba isn't enabled)
+        exec.run().unwrap();
+        assert_eq!(
+            exec.regfile[Register::s0],
+            Exception::IllegalInstruction as i64
+        );
+        assert_eq!(exec.regfile[Register::s1], 0);
+        // mret restores them, and resets MPP to user mode
+        assert_eq!(exec.regfile[Register::s3], mstatus::MIE | mstatus::MPIE);
+
+        // Without a handler, faults are still errors
+        let mut exec = indoc! {"
+            li t0, 6
+            jr t0
+        "}
+        .parse::<Executor>()
+        .unwrap();
+        assert!(matches!(
+            exec.run(),
+            Err(ExecError {
+                error: ExecErrorInner::MisalignedJump { target: 6 },
+                pc: 4,
+            })
+        ));
+
+        // jalr clears the lowest bit of the target
+        let mut exec = indoc! {"
+            li t0, 13
+            jalr zero, 0(t0)
+            li a0, 1
+            li a1, 1
+        "}
+        .parse::<Executor>()
+        .unwrap();
+        exec.run().unwrap();
+        assert_eq!(exec.regfile[Register::a0], 0);
+        assert_eq!(exec.regfile[Register::a1], 1);
+    }
+
+    #[test]
+    fn history_limit() {
+        let source = "li a0, 1\nli a0, 2\nli a0, 3\nli a0, 4";
+        let config = |history_limit| Config {
+            history_limit,
+            ..Default::default()
+        };
+        let mut exec = Executor::from_source(source, config(Some(2))).unwrap();
+        exec.run().unwrap();
+        assert!(exec.revert());
+        assert!(exec.revert());
+        // The first two were forgotten
+        assert!(!exec.revert());
+        assert_eq!(exec.regfile[Register::a0], 2);
+        assert_eq!(exec.pc, 8);
+
+        let mut exec = Executor::from_source(source, config(Some(0))).unwrap();
+        exec.run().unwrap();
+        assert!(!exec.revert());
+        assert_eq!(exec.regfile[Register::a0], 4);
+    }
+
+    #[test]
+    fn interrupts() {
+        // mtime counts executed instructions
+        let mut exec = indoc! {"
+            li t0, 0x200bff8
+            lw a0, 0(t0)
+        "}
+        .parse::<Executor>()
+        .unwrap();
+        exec.run().unwrap();
+        assert_eq!(exec.regfile[Register::a0], 1);
+
+        // Periodic timer interrupt every 20 instructions
+        let mut exec = indoc! {"
+            la t0, handler
+            csrw mtvec, t0
+            li t1, 0x2004000
+            li t2, 20
+            sw t2, 0(t1)
+            sw zero, 4(t1)
+            li t0, 0x80
+            csrw mie, t0
+            csrsi mstatus, 8
+            loop:
+            addi s1, s1, 1
+            j loop
+
+            handler:
+            addi s0, s0, 1
+            csrr s2, mcause
+            lw t2, 0(t1)
+            addi t2, t2, 20
+            sw t2, 0(t1)
+            mret
+        "}
+        .parse::<Executor>()
+        .unwrap();
+        for _ in 0..200 {
+            exec.execute().unwrap();
+        }
+        assert_eq!(exec.regfile[Register::s0], 9);
+        assert_eq!(
+            exec.regfile[Register::s2],
+            Cause::Interrupt(Interrupt::MachineTimer).mcause(Xlen::Rv32)
+        );
+        // Time passing isn't an effect, but reverting turns back the clock
+        let update = exec.execute().unwrap();
+        assert!(!update
+            .effects()
+            .iter()
+            .any(|effect| matches!(effect, Effect::Device { .. })));
+        while exec.revert() {}
+        assert_eq!(exec.memory.device::<Clint>().unwrap().mtime(), 0);
+
+        // Software interrupts are raised by writing msip
+        let mut exec = indoc! {"
+            la t0, handler
+            csrw mtvec, t0
+            li t0, 8
+            csrw mie, t0
+            csrsi mstatus, 8
+            li t1, 0x2000000
+            li t2, 1
+            sw t2, 0(t1)
+            li a0, 1
+            j end
+
+            handler:
+            csrr s0, mcause
+            csrr s1, mepc
+            sw zero, 0(t1)
+            mret
+            end:
+        "}
+        .parse::<Executor>()
+        .unwrap();
+        exec.run().unwrap();
+        assert_eq!(
+            exec.regfile[Register::s0],
+            Cause::Interrupt(Interrupt::MachineSoftware).mcause(Xlen::Rv32)
+        );
+        assert_eq!(exec.regfile[Register::s1], 36);
+        assert_eq!(exec.regfile[Register::a0], 1);
+
+        // Stores across either end of the CLINT are split between it and memory
+        let mut exec = indoc! {"
+            li t1, 0x1abcd
+            li t0, 0x1fffffe
+            sw t1, 0(t0)
+            lhu a0, 0(t0)
+            lw a1, 2(t0)
+            li t0, 0x200fffe
+            sw t1, 0(t0)
+            lhu a2, 2(t0)
+        "}
+        .parse::<Executor>()
+        .unwrap();
+        exec.memory.config = memory::Config::new(None, true);
+        exec.run().unwrap();
+        assert_eq!(exec.regfile[Register::a0], 0xabcd);
+        assert_eq!(exec.regfile[Register::a1], 1);
+        assert_eq!(exec.regfile[Register::a2], 1);
+    }
+
+    #[test]
+    fn privilege() {
+        // User-mode ecalls are delegated to supervisor mode, but touching a
+        // machine-mode CSR from user mode goes to the machine handler
+        let mut exec = indoc! {"
+            la t0, mhandler
+            csrw mtvec, t0
+            la t0, shandler
+            csrw stvec, t0
+            li t0, 0x100
+            csrw medeleg, t0
+            la t0, user
+            csrw mepc, t0
+            mret
+
+            user:
+            ecall
+            csrr a0, mstatus
+            li a1, 1
+
+            shandler:
+            csrr s0, scause
+            csrr s1, sstatus
+            csrr t0, sepc
+            addi t0, t0, 4
+            csrw sepc, t0
+            sret
+
+            mhandler:
+            csrr s2, mcause
+            csrr s3, mstatus
+            la t0, end
+            csrw mepc, t0
+            mret
+            end:
+        "}
+        .parse::<Executor>()
+        .unwrap();
+        exec.run().unwrap();
+        assert_eq!(exec.regfile[Register::s0], Exception::UserEcall as i64);
+        // SPP records that the trap came from user mode
+        assert_eq!(exec.regfile[Register::s1] & mstatus::SPP, 0);
+        assert_eq!(
+            exec.regfile[Register::s2],
+            Exception::IllegalInstruction as i64
+        );
+        assert_eq!(exec.regfile[Register::s3] & mstatus::MPP, 0);
+        assert_eq!(exec.regfile[Register::a1], 0);
+        assert_eq!(exec.regfile.privilege(), Privilege::User);
+
+        // Without a handler, ecall stops execution
+        let mut exec = "ecall".parse::<Executor>().unwrap();
+        assert!(matches!(
+            exec.run(),
+            Err(ExecError {
+                error: ExecErrorInner::Ecall(Privilege::Machine),
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn paging() {
+        // Identity map the first 4 MiB with a megapage, and map the page at
+        // 0x400000 to physical page 0x20
+        let mut exec = indoc! {"
+            li t0, 0x10000
+            li t1, 0xcf
+            sw t1, 0(t0)
+            li t1, 0x4401
+            sw t1, 4(t0)
+            li t0, 0x11000
+            li t1, 0x80c7
+            sw t1, 0(t0)
+            li t0, 0x80000010
+            csrw satp, t0
+            la t0, handler
+            csrw mtvec, t0
+            li t0, 0x800
+            csrs mstatus, t0
+            la t0, supervisor
+            csrw mepc, t0
+            mret
+
+            supervisor:
+            li a0, 0x400000
+            li t1, 42
+            sw t1, 8(a0)
+            lw a1, 8(a0)
+            li a2, 0x800000
+            lw a3, 0(a2)
+            sfence.vma
+            ecall
+            li a4, 1
+            j end
+
+            handler:
+            mv s3, s0
+            mv s4, s1
+            csrr s0, mcause
+            csrr s1, mtval
+            csrr s2, mstatus
+            csrr t0, mepc
+            addi t0, t0, 4
+            csrw mepc, t0
+            mret
+            end:
+        "}
+        .parse::<Executor>()
+        .unwrap();
+        // Unmapped page table entries read as invalid
+        exec.memory.config.default_value = Some(0);
+        exec.run().unwrap();
+
+        assert_eq!(exec.regfile[Register::a1], 42);
+        assert_eq!(exec.memory.load(0x20008, LoadOp::Lw).unwrap(), 42);
+        // The page fault, then the ecall, both from supervisor mode
+        assert_eq!(exec.regfile[Register::s3], Exception::LoadPageFault as i64);
+        assert_eq!(exec.regfile[Register::s4], 0x800000);
+        assert_eq!(
+            exec.regfile[Register::s0],
+            Exception::SupervisorEcall as i64
+        );
+        assert_eq!(exec.regfile[Register::s2] & mstatus::MPP, 0x800);
+        assert_eq!(exec.regfile[Register::a4], 1);
+        assert_eq!(exec.regfile.privilege(), Privilege::Supervisor);
+
+        // The debugger can look at the walk for any virtual address
+        let translation = exec.translation().unwrap();
+        let walk = exec.memory.walk(0x400008, translation, Access::Read);
+        assert_eq!(walk.steps.len(), 2);
+        assert_eq!(walk.steps[0].addr, 0x10004);
+        assert_eq!(walk.result.unwrap(), 0x20008);
+
+        // Machine mode never translates
+        let mut exec = indoc! {"
+            li t0, 0x80000010
+            csrw satp, t0
+            li t0, 0x400000
+            li t1, 1
+            sw t1, 0(t0)
+        "}
+        .parse::<Executor>()
+        .unwrap();
+        exec.run().unwrap();
+        assert_eq!(exec.memory.load(0x400000, LoadOp::Lw).unwrap(), 1);
+    }
+
+    #[test]
+    fn vectors() {
+        let program = indoc! {"
+            li s0, 0x1000
+            li t0, 1
+            sw t0, 0(s0)
+            li t0, 2
+            sw t0, 4(s0)
+            li t0, -3
+            sw t0, 8(s0)
+            li t0, 4
+            sw t0, 12(s0)
+
+            # Asks for 6 elements but only 4 fit
+            li t0, 6
+            vsetvli a0, t0, e32, m1, ta, ma
+            vle32.v v1, (s0)
+            vadd.vi v2, v1, 10
+            vmul.vv v3, v1, v1
+            li s1, 0x2000
+            vse32.v v3, (s1)
+
+            # Zero the negative element
+            vmslt.vx v0, v1, zero
+            vsub.vv v3, v3, v3, v0.t
+            vmv.v.i v4, 0
+            vredsum.vs v4, v3, v4
+            vmv.x.s a1, v4
+            vcpop.m a2, v0
+
+            li t1, 8
+            vlse32.v v5, (s0), t1
+            vid.v v6
+
+            vsetvli a3, zero, e8, m2, ta, ma
+            csrr a4, vlenb
+            csrr a5, vl
+        "}
+        .parse::<Program>()
+        .unwrap();
+        let config = Config {
+            v: true,
+            ..Default::default()
+        };
+        let mut exec = Executor::with_config(program.clone(), config);
+        exec.memory.config.default_value = Some(0);
+        exec.run().unwrap();
+        let regs = &exec.regfile;
+        let elements = |reg| regs.vector_elements(reg, ElementWidth::E32);
+        assert_eq!(regs[Register::a0], 4);
+        assert_eq!(elements(VRegister::v2), [11, 12, 7, 14]);
+        assert_eq!(elements(VRegister::v3), [1, 4, 0, 16]);
+        assert_eq!(exec.memory.load(0x2008, LoadOp::Lw).unwrap(), 9);
+        assert_eq!(regs.vector(VRegister::v0)[0], 0b0100);
+        assert_eq!(regs[Register::a1], 21);
+        assert_eq!(regs[Register::a2], 1);
+        assert_eq!(elements(VRegister::v5), [1, -3, 0, 0]);
+        assert_eq!(elements(VRegister::v6), [0, 1, 2, 3]);
+        // LMUL = 2 groups two registers
+        assert_eq!(regs[Register::a3], 32);
+        assert_eq!(regs[Register::a4], 16);
+        assert_eq!(regs[Register::a5], 32);
+        assert_eq!(regs.vtype().unwrap().to_string(), "e8, m2, ta, ma");
+
+        // A wider VLEN fits more elements
+        let config = Config {
+            v: true,
+            vlen: 256,
+            ..Default::default()
+        };
+        let mut exec = Executor::with_config(program.clone(), config);
+        exec.memory.config.default_value = Some(0);
+        exec.run().unwrap();
+        assert_eq!(exec.regfile[Register::a0], 6);
+        assert_eq!(exec.regfile[Register::a1], 21);
+
+        let mut exec = Executor::new(program);
+        let Err(err) = exec.run() else {
+            panic!("vsetvli ran without V");
+        };
+        assert!(err
+            .to_string()
+            .starts_with("vsetvli a0, t0, e32, m1, ta, ma requires V"));
+
+        // Vector instructions need a valid vtype, and register groups must be
+        // aligned
+        let config = Config {
+            v: true,
+            ..Default::default()
+        };
+        for (program, error) in [
+            ("vadd.vv v1, v2, v3", "vtype is invalid"),
+            (
+                "vsetvli t0, zero, e32, m2\nvadd.vv v1, v2, v4",
+                "register groups must start at a multiple of LMUL",
+            ),
+            ("csrw vl, zero", "vl is read-only"),
+        ] {
+            let mut exec = Executor::with_config(program.parse().unwrap(), config.clone());
+            let Err(err) = exec.run() else {
+                panic!("{program} ran");
+            };
+            assert!(err.to_string().contains(error), "{err}");
+        }
+    }
+
+    #[test]
+    fn revert() {
+        let mut exec = indoc! {"
+            la t0, handler
+            csrw mtvec, t0
+            li a0, 0x100
+            li a1, 5
+            sw a1, 0(a0)
+            amoadd.w a2, a1, (a0)
+            lr.w a3, (a0)
+            sc.w a4, a1, (a0)
+            csrrw s0, mscratch, a1
+            lw a5, 1(a0)
+            call double
+            j end
+
+            handler:
+            csrr t1, mepc
+            addi t1, t1, 4
+            csrw mepc, t1
+            mret
+
+            double:
+            add a0, a0, a0
+            sb a0, 8(a0)
+            ret
+            end:
+        "}
+        .parse::<Executor>()
+        .unwrap();
+        assert!(!exec.revert());
+
+        let mut states = vec![];
+        while exec.pc != exec.program.label("end").unwrap() {
+            states.push((exec.pc, exec.regfile.clone(), exec.memory.clone()));
+            let update = exec.execute().unwrap();
+            if update.trap().is_some() {
+                assert!(update
+                    .effects()
+                    .iter()
+                    .any(|effect| matches!(effect, Effect::Csr { csr: Csr::Mepc, .. })));
+            }
+        }
+        assert_eq!(exec.memory.load(0x100, LoadOp::Lw).unwrap(), 5);
+
+        // Reverting goes back through exactly the same states
+        while let Some((pc, regfile, memory)) = states.pop() {
+            assert!(exec.revert());
+            assert_eq!(exec.pc, pc);
+            assert_eq!(exec.regfile, regfile);
+            assert_eq!(exec.memory, memory);
+            assert_eq!(exec.executed, states.len());
+        }
+        assert!(!exec.revert());
+
+        // And executing again after reverting gets the same result
+        exec.run().unwrap();
+        assert_eq!(exec.memory.load(0x100, LoadOp::Lw).unwrap(), 5);
+        assert_eq!(exec.regfile[Register::s0], 0);
+        assert_eq!(exec.regfile.csr(Csr::Mscratch), 5);
+    }
+
+    #[test]
+    fn memory_map() {
+        let program = indoc! {"
+            lui t0, 0x10000
+            sw zero, 0(t0)
+            lw a0, 0(t0)
+            lui t1, 0x400
+            sw zero, 0(t1)
+        "};
+        let config = |level| Config {
+            text_base: Some(0x00400000),
+            memory_map: Some(MemoryMap::standard(level)),
+            ..Default::default()
+        };
+
+        // The text segment can't be written
+        let mut exec = Executor::from_source(program, config(ConfigLevel::Deny)).unwrap();
+        let error = exec.run().unwrap_err();
+        assert_eq!(error.pc(), 0x00400010);
+        assert!(matches!(
+            error.error(),
+            ExecErrorInner::Memory(MemoryError::Forbidden {
+                addr: 0x00400000,
+                access: Access::Write,
+                ..
+            })
+        ));
+        assert_eq!(
+            error.error().to_string(),
+            "store at 0x00400000 not permitted in text (r-x)"
+        );
+
+        // Warnings let it through
+        let mut exec = Executor::from_source(program, config(ConfigLevel::Warn)).unwrap();
+        for _ in 0..4 {
+            assert!(exec.execute().unwrap().warnings().is_empty());
+        }
+        let update = exec.execute().unwrap();
+        assert_eq!(update.warnings().len(), 1);
+        assert_eq!(exec.memory.load(0x00400000, LoadOp::Lw).unwrap(), 0);
+
+        // Wild pointers
+        let mut exec = Executor::from_source("lw a0, 0(zero)", config(ConfigLevel::Deny)).unwrap();
+        assert!(matches!(
+            exec.run().unwrap_err().error(),
+            ExecErrorInner::Memory(MemoryError::Unmapped {
+                addr: 0,
+                access: Access::Read
+            })
+        ));
+        // Code has to be in an executable region
+        let mut config = config(ConfigLevel::Deny);
+        let text = Region::new("text", 0x00400000..0x00401000, Permissions::RW);
+        config.memory_map = Some(MemoryMap::new(ConfigLevel::Deny).with(text));
+        let mut exec = Executor::from_source("addi a0, a0, 1", config).unwrap();
+        assert!(matches!(
+            exec.run().unwrap_err().error(),
+            ExecErrorInner::Memory(MemoryError::Forbidden {
+                access: Access::Execute,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn stack_checks() {
+        let program = indoc! {"
+            main:
+            addi sp, sp, -16
+            sw ra, 12(sp)
+            sw zero, 0(sp)
+            call f
+            lw ra, 12(sp)
+            addi sp, sp, 16
+            j end
+            f:
+            addi sp, sp, -8
+            sw a0, 4(sp)
+            # In main's frame
+            lw t0, 8(sp)
+            addi sp, sp, 8
+            ret
+            end:
+        "};
+        let config = |frame_access| Config {
+            frame_access,
+            ..Default::default()
+        };
+        Executor::from_source(program, config(ConfigLevel::Allow))
+            .unwrap()
+            .run()
+            .unwrap();
+
+        let mut exec = Executor::from_source(program, config(ConfigLevel::Deny)).unwrap();
+        let error = exec.run().unwrap_err();
+        assert!(matches!(
+            error.error(),
+            ExecErrorInner::OutOfFrame {
+                addr: 0x3ffffff0,
+                end: 0x3ffffff0
+            }
+        ));
+        assert_eq!(error.pc(), 40);
+
+        let mut exec = Executor::from_source(program, config(ConfigLevel::Warn)).unwrap();
+        let mut warnings = vec![];
+        while let Ok(update) = exec.execute() {
+            warnings.extend(update.warnings().iter().map(ExecError::pc));
+        }
+        assert_eq!(exec.pc, exec.program.label("end").unwrap());
+        assert_eq!(warnings, [40]);
+
+        // Nothing is above the stack top
+        let mut exec = Executor::from_source("lw a0, 0(sp)", config(ConfigLevel::Deny)).unwrap();
+        assert!(matches!(
+            exec.run().unwrap_err().error(),
+            ExecErrorInner::OutOfFrame {
+                addr: STACK_TOP,
+                end: STACK_TOP
+            }
+        ));
+
+        let program = indoc! {"
+            addi sp, sp, -16
+            sw zero, 0(sp)
+            addi sp, sp, -4
+            sw zero, 0(sp)
+        "};
+        let config = Config {
+            stack_size: Some(16),
+            ..Default::default()
+        };
+        let mut exec = Executor::from_source(program, config.clone()).unwrap();
+        let error = exec.run().unwrap_err();
+        assert_eq!(error.pc(), 12);
+        assert!(matches!(
+            error.error(),
+            ExecErrorInner::StackOverflow {
+                addr: 0x3fffffec,
+                limit: 0x3ffffff0
+            }
+        ));
+
+        // Through a frame pointer too
+        let program = indoc! {"
+            addi sp, sp, -16
+            addi s0, sp, 16
+            sw zero, -16(s0)
+            sw zero, -20(s0)
+        "};
+        let mut exec = Executor::from_source(program, config).unwrap();
+        let error = exec.run().unwrap_err();
+        assert_eq!(error.pc(), 12);
+        assert!(matches!(
+            error.error(),
+            ExecErrorInner::StackOverflow {
+                addr: 0x3fffffec,
+                limit: 0x3ffffff0
+            }
+        ));
+
+        // And with float stores
+        let config = Config {
+            stack_size: Some(16),
+            ..Default::default()
+        };
+        let program = indoc! {"
+            addi sp, sp, -16
+            fsw fa0, -4(sp)
+        "};
+        let mut exec = Executor::from_source(program, config).unwrap();
+        assert!(matches!(
+            exec.run().unwrap_err().error(),
+            ExecErrorInner::StackOverflow {
+                addr: 0x3fffffec,
+                limit: 0x3ffffff0
+            }
+        ));
+
+        for load in ["lw t0, 0(s0)", "flw ft0, 0(s0)"] {
+            let program = formatdoc! {"
+                addi sp, sp, -16
+                mv s0, sp
+                call f
+                j end
+                f:
+                # Reaches into the caller's frame
+                {load}
+                ret
+                end:
+            "};
+            let config = Config {
+                frame_access: ConfigLevel::Deny,
+                ..Default::default()
+            };
+            let mut exec = Executor::from_source(&program, config).unwrap();
+            assert!(matches!(
+                exec.run().unwrap_err().error(),
+                ExecErrorInner::OutOfFrame {
+                    addr: 0x3ffffff0,
+                    end: 0x3ffffff0
+                }
+            ));
+        }
+    }
+
+    #[test]
+    fn text_base() {
+        let config = Config {
+            text_base: Some(0x00400000),
+            ..Default::default()
+        };
+        let program = indoc! {"
+            lui t1, 0x400
+            lw a1, 0(t1)
+            # Patch the addi below into addi a0, a0, 5
+            lui t0, 0x550
+            addi t0, t0, 0x513
+            sw t0, 28(t1)
+            # Call through a function pointer
+            addi a2, t1, 36
+            jalr ra, 0(a2)
+            addi a0, a0, 1
+            j end
+            func:
+            addi a3, x0, 7
+            ret
+            end:
+        "};
+        let mut exec = Executor::from_source(program, config.clone()).unwrap();
+        assert_eq!(exec.pc, 0x00400000);
+        assert_eq!(exec.program.label("end"), Some(0x0040002c));
+
+        while exec.pc != 0x0040001c {
+            exec.execute().unwrap();
+        }
+        // The patched instruction is decoded from memory
+        assert_eq!(exec.current().unwrap().to_string(), "addi a0, a0, 5");
+        exec.run().unwrap();
+        assert_eq!(exec.pc, 0x0040002c);
+        // lw read the encoding of lui t1, 0x400
+        assert_eq!(exec.regfile[Register::a1], 0x00400337);
+        assert_eq!(exec.regfile[Register::a0], 5);
+        assert_eq!(exec.regfile[Register::a3], 7);
+
+        // Reverting restores the original code
+        while exec.revert() {}
+        assert_eq!(exec.pc, 0x00400000);
+        assert_eq!(
+            exec.memory.load(0x0040001c, LoadOp::Lw).unwrap(),
+            0x00150513
+        );
+
+        // Pseudo-instructions that need two instructions are loaded as both,
+        // and run as they were written
+        let program = indoc! {"
+            li s0, 0x12345678
+            la s1, func
+            call func
+            jalr ra, 0(s1)
+            j end
+            func:
+            addi a0, a0, 1
+            ret
+            end:
+        "};
+        let mut exec = Executor::from_source(program, config).unwrap();
+        exec.execute().unwrap();
+        exec.execute().unwrap();
+        assert_eq!(exec.current().unwrap().to_string(), "call func");
+        exec.execute().unwrap();
+        assert_eq!(exec.pc, 0x00400020);
+        assert_eq!(exec.regfile[Register::ra], 0x00400018);
+        assert_eq!(exec.stack().len(), 2);
+        exec.run().unwrap();
+        assert_eq!(exec.pc, 0x00400028);
+        assert_eq!(exec.regfile[Register::s0], 0x12345678);
+        assert_eq!(exec.regfile[Register::s1], 0x00400020);
+        assert_eq!(exec.regfile[Register::a0], 2);
+        assert_eq!(exec.stack().len(), 1);
+    }
+
+    #[test]
+    fn compressed() {
+        let program = indoc! {"
+            li a0, 10
+            li a1, 0
+            mv s0, sp
+            addi sp, sp, -16
+            loop:
+            add a1, a1, a0
+            addi a0, a0, -1
+            sw a1, 0(sp)
+            bnez a0, loop
+            lw a2, 0(sp)
+            addi sp, sp, 16
+            call double
+            j end
+            double:
+            slli a2, a2, 1
+            lui a3, 0x12345
+            ret
+            end:
+        "};
+        // Compressing doesn't change what the program does, whether it runs
+        // from memory or not
+        for (c, text_base) in [(false, None), (true, None), (true, Some(0x00400000))] {
+            let config = Config {
+                c,
+                text_base,
+                ..Default::default()
+            };
+            let mut exec = Executor::from_source(program, config).unwrap();
+            exec.run().unwrap();
+            let size = if c { 32 } else { 64 };
+            assert_eq!(exec.pc, text_base.unwrap_or(0) + size);
+            assert_eq!(exec.regfile[Register::a2], 110);
+            assert_eq!(exec.regfile[Register::a3], 0x12345000);
+            assert_eq!(exec.regfile[Register::sp], exec.regfile[Register::s0]);
+            // Calls are still tracked
+            assert_eq!(exec.stack().len(), 1);
+        }
+
+        // Compressed instructions need the C extension, which also allows
+        // jumps to 2-byte boundaries
+        let mut exec = "c.li a0, 1".parse::<Executor>().unwrap();
+        assert!(matches!(
+            exec.run(),
+            Err(ExecError {
+                error: ExecErrorInner::UnsupportedInstruction { requires: "C", .. },
+                ..
+            })
+        ));
+        let config = Config {
+            c: true,
+            ..Default::default()
+        };
+        let source = "la t0, x\njalr ra, 0(t0)\nx:\nli a0, 1";
+        let mut exec = Executor::from_source(source, config.clone()).unwrap();
+        exec.run().unwrap();
+        assert_eq!(exec.pc, 12);
+        assert_eq!(exec.regfile[Register::a0], 1);
+
+        // A handler can skip a faulting compressed instruction
+        let source = indoc! {"
+            la t0, handler
+            csrw mtvec, t0
+            li a0, 0x101
+            c.lw a1, 0(a0)
+            li a2, 1
+            j end
+            # mtvec is 4-byte aligned
+            c.nop
+
+            handler:
+            csrr t1, mepc
+            addi t1, t1, 2
+            csrw mepc, t1
+            mret
+            end:
+        "};
+        let mut exec = Executor::from_source(source, config).unwrap();
+        let end = exec.program.label("end").unwrap();
+        // Returning to the faulting instruction would loop forever, so step
+        for _ in 0..20 {
+            if exec.pc == end {
+                break;
+            }
+            exec.execute().unwrap();
+        }
+        assert_eq!(exec.pc, end);
+        assert_eq!(exec.regfile[Register::a2], 1);
+    }
 }
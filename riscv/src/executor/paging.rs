@@ -0,0 +1,271 @@
+//! Sv32 virtual memory.
+//!
+//! Virtual addresses are translated with a two-level walk of the page tables
+//! rooted at `satp`, reading page table entries straight from physical memory.
+//! There is no TLB, so every access walks the tables again and `sfence.vma` has
+//! nothing to flush.
+//!
+//! Accessed and dirty bits are never set by the walk. A page whose `A` bit is
+//! clear, or a page being written whose `D` bit is clear, raises a page fault so
+//! that the kernel can update the entry itself, which the spec allows.
+
+use std::fmt;
+
+use super::memory::{Address, Memory, MemoryError};
+
+/// Bits of a page table entry.
+pub mod pte {
+    /// Valid
+    pub const V: u32 = 1 << 0;
+    /// Readable
+    pub const R: u32 = 1 << 1;
+    /// Writable
+    pub const W: u32 = 1 << 2;
+    /// Executable
+    pub const X: u32 = 1 << 3;
+    /// Accessible from user mode
+    pub const U: u32 = 1 << 4;
+    /// Global mapping
+    pub const G: u32 = 1 << 5;
+    /// Accessed
+    pub const A: u32 = 1 << 6;
+    /// Dirty
+    pub const D: u32 = 1 << 7;
+}
+
+/// The size of a page, and of a page table.
+pub const PAGE_SIZE: i64 = 4096;
+
+/// The kind of memory access being translated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Access::Read => write!(f, "load"),
+            Access::Write => write!(f, "store"),
+            Access::Execute => write!(f, "instruction"),
+        }
+    }
+}
+
+/// Everything needed to translate a virtual address: where the page tables are,
+/// and what the current privilege mode is allowed to touch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Translation {
+    /// The physical address of the root page table.
+    pub root: i64,
+    /// Whether the access comes from user mode.
+    pub user: bool,
+    /// `mstatus.SUM`: supervisor mode may load and store to user pages.
+    pub sum: bool,
+    /// `mstatus.MXR`: executable pages are also readable.
+    pub mxr: bool,
+}
+
+/// One page table entry read during a walk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WalkStep {
+    /// 1 for the root table, 0 for the leaf table.
+    pub level: u32,
+    /// The physical address of the entry.
+    pub addr: i64,
+    pub pte: u32,
+}
+
+/// The result of walking the page tables for one virtual address.
+#[derive(Debug)]
+pub struct PageWalk {
+    /// The entries read, starting from the root table. If a step faulted, it is
+    /// the last one.
+    pub steps: Vec<WalkStep>,
+    /// The physical address, or why the walk failed.
+    pub result: Result<i64, MemoryError>,
+}
+
+impl Memory {
+    /// Walk the page tables to translate `vaddr`, keeping every entry read along
+    /// the way.
+    pub fn walk(&self, vaddr: i64, translation: Translation, access: Access) -> PageWalk {
+        let mut steps = vec![];
+        let result = self.walk_inner(vaddr, translation, access, &mut steps);
+        PageWalk { steps, result }
+    }
+
+    fn walk_inner(
+        &self,
+        vaddr: i64,
+        translation: Translation,
+        access: Access,
+        steps: &mut Vec<WalkStep>,
+    ) -> Result<i64, MemoryError> {
+        let vaddr = vaddr as u32;
+        let fault = || MemoryError::PageFault {
+            addr: vaddr as i64,
+            access,
+        };
+        let vpn = [(vaddr >> 12) & 0x3ff, vaddr >> 22];
+        let mut table = translation.root;
+        for level in [1, 0] {
+            let addr = table + vpn[level as usize] as i64 * 4;
+            let pte = self.load(addr, crate::parse::LoadOp::Lw)? as u32;
+            steps.push(WalkStep { level, addr, pte });
+
+            if pte & pte::V == 0 || (pte & pte::R == 0 && pte & pte::W != 0) {
+                return Err(fault());
+            }
+            let ppn = (pte >> 10) as i64;
+            if pte & (pte::R | pte::X) == 0 {
+                // Pointer to the next level
+                table = ppn * PAGE_SIZE;
+                continue;
+            }
+
+            let allowed = match access {
+                Access::Read => pte & pte::R != 0 || (translation.mxr && pte & pte::X != 0),
+                Access::Write => pte & pte::W != 0,
+                Access::Execute => pte & pte::X != 0,
+            };
+            let user_page = pte & pte::U != 0;
+            let privileged = if translation.user {
+                user_page
+            } else {
+                // Supervisor mode can never execute user pages
+                !user_page || (translation.sum && access != Access::Execute)
+            };
+            let updated = pte & pte::A != 0 && (access != Access::Write || pte & pte::D != 0);
+            if !allowed || !privileged || !updated {
+                return Err(fault());
+            }
+
+            let offset = (vaddr & 0xfff) as i64;
+            return if level == 1 {
+                // Megapages must be aligned to 4 MiB
+                if ppn & 0x3ff != 0 {
+                    return Err(fault());
+                }
+                Ok((ppn >> 10) << 22 | (vpn[0] as i64) << 12 | offset)
+            } else {
+                Ok(ppn * PAGE_SIZE + offset)
+            };
+        }
+        // The leaf table pointed to another table
+        Err(fault())
+    }
+
+    /// Turn an address into a physical one, walking the page tables if it's
+    /// virtual.
+    pub fn translate(&self, addr: impl Into<Address>, access: Access) -> Result<i64, MemoryError> {
+        match addr.into() {
+            Address::Physical(addr) => Ok(addr),
+            Address::Virtual(addr, translation) => self.walk(addr, translation, access).result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parse::{LoadOp, StoreOp};
+
+    use super::*;
+
+    const ROOT: i64 = 0x10000;
+    const LEAF: i64 = 0x11000;
+
+    fn supervisor() -> Translation {
+        Translation {
+            root: ROOT,
+            user: false,
+            sum: false,
+            mxr: false,
+        }
+    }
+
+    fn tables() -> Memory {
+        let mut mem = Memory::default();
+        mem.config.default_value = Some(0);
+        let rwx = pte::V | pte::R | pte::W | pte::X | pte::A | pte::D;
+        // 0x0000_0000..0x0040_0000 is an identity-mapped megapage
+        mem.store(ROOT, rwx as i64, StoreOp::Sw).unwrap();
+        // 0x0040_0000 points to the leaf table
+        mem.store(ROOT + 4, (LEAF >> 12 << 10) | pte::V as i64, StoreOp::Sw)
+            .unwrap();
+        // 0x0040_0000 -> 0x2000_0000, read only user page
+        let user = pte::V | pte::R | pte::U | pte::A;
+        mem.store(LEAF, (0x20000 << 10) | user as i64, StoreOp::Sw)
+            .unwrap();
+        // 0x0040_1000 -> 0x3000_0000, clean
+        let clean = pte::V | pte::R | pte::W | pte::A;
+        mem.store(LEAF + 4, (0x30000 << 10) | clean as i64, StoreOp::Sw)
+            .unwrap();
+        mem
+    }
+
+    #[test]
+    fn walks() {
+        let mut mem = tables();
+        let translate = |mem: &Memory, addr, translation, access| {
+            mem.translate(Address::Virtual(addr, translation), access)
+        };
+
+        assert_eq!(
+            translate(&mem, 0x1234, supervisor(), Access::Execute).unwrap(),
+            0x1234
+        );
+        let walk = mem.walk(0x400abc, supervisor(), Access::Read);
+        assert_eq!(walk.steps.len(), 2);
+        assert_eq!(walk.steps[1].addr, LEAF);
+        assert!(matches!(walk.result, Err(MemoryError::PageFault { .. })));
+
+        // Supervisor mode needs SUM to read user pages
+        let sum = Translation {
+            sum: true,
+            ..supervisor()
+        };
+        assert_eq!(
+            translate(&mem, 0x400abc, sum, Access::Read).unwrap(),
+            0x2000_0abc
+        );
+        let user = Translation {
+            user: true,
+            ..supervisor()
+        };
+        assert_eq!(
+            translate(&mem, 0x400abc, user, Access::Read).unwrap(),
+            0x2000_0abc
+        );
+        assert!(translate(&mem, 0x400abc, user, Access::Write).is_err());
+        // User mode can't touch supervisor pages
+        assert!(translate(&mem, 0x1234, user, Access::Read).is_err());
+
+        // Writes to clean pages fault until the kernel sets D
+        assert!(matches!(
+            translate(&mem, 0x401000, supervisor(), Access::Write),
+            Err(MemoryError::PageFault {
+                addr: 0x401000,
+                access: Access::Write
+            })
+        ));
+        assert!(translate(&mem, 0x401000, supervisor(), Access::Read).is_ok());
+
+        // Unmapped
+        let walk = mem.walk(0x800000, supervisor(), Access::Read);
+        assert_eq!(walk.steps.len(), 1);
+        assert!(walk.result.is_err());
+
+        // Loads and stores take virtual addresses too
+        mem.store(Address::Virtual(0x100, supervisor()), 7, StoreOp::Sw)
+            .unwrap();
+        assert_eq!(mem.load(0x100, LoadOp::Lw).unwrap(), 7);
+        assert_eq!(
+            mem.load(Address::Virtual(0x100, supervisor()), LoadOp::Lw)
+                .unwrap(),
+            7
+        );
+    }
+}
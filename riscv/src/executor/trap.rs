@@ -0,0 +1,258 @@
+//! Traps and privilege modes.
+//!
+//! When an instruction raises an exception and a handler is installed in
+//! `mtvec`, the executor jumps to the handler instead of stopping with an
+//! [`ExecError`](super::ExecError). The handler can inspect `mepc`, `mcause` and
+//! `mtval`, and return with `mret`.
+//!
+//! Exceptions raised below machine mode whose bit is set in `medeleg` go to the
+//! supervisor handler in `stvec` instead, which uses `sepc`, `scause`, `stval`
+//! and `sret`. Interrupts are delegated the same way through `mideleg`.
+//!
+//! Interrupts are taken between instructions when they are both pending in `mip`
+//! and enabled in `mie`, and the mode handling them has interrupts enabled. A
+//! mode always takes interrupts meant for a more privileged mode.
+
+use std::fmt;
+
+use crate::parse::{CustomOperands, Instruction};
+
+use super::{clint::mip, memory::MemoryError, paging::Access, ExecErrorInner, Xlen};
+
+/// Bits of `mstatus`.
+pub mod mstatus {
+    /// Supervisor interrupt enable
+    pub const SIE: i64 = 1 << 1;
+    /// Machine interrupt enable
+    pub const MIE: i64 = 1 << 3;
+    /// The value of `SIE` before the last supervisor trap
+    pub const SPIE: i64 = 1 << 5;
+    /// The value of `MIE` before the last machine trap
+    pub const MPIE: i64 = 1 << 7;
+    /// The privilege mode before the last supervisor trap: 0 for user, 1 for
+    /// supervisor
+    pub const SPP: i64 = 1 << 8;
+    /// The privilege mode before the last machine trap
+    pub const MPP: i64 = 0b11 << 11;
+    /// Permit supervisor mode to load and store to user pages
+    pub const SUM: i64 = 1 << 18;
+    /// Make executable pages readable
+    pub const MXR: i64 = 1 << 19;
+
+    /// The bits visible through `sstatus`.
+    pub const SSTATUS: i64 = SIE | SPIE | SPP | SUM | MXR;
+}
+
+/// A privilege mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum Privilege {
+    User = 0,
+    Supervisor = 1,
+    #[default]
+    Machine = 3,
+}
+
+impl Privilege {
+    /// Decode a 2-bit privilege field, like `mstatus.MPP`. The reserved value 2
+    /// decodes as user mode.
+    pub fn from_bits(bits: i64) -> Privilege {
+        match bits & 0b11 {
+            3 => Privilege::Machine,
+            1 => Privilege::Supervisor,
+            _ => Privilege::User,
+        }
+    }
+}
+
+impl fmt::Display for Privilege {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Privilege::User => write!(f, "user"),
+            Privilege::Supervisor => write!(f, "supervisor"),
+            Privilege::Machine => write!(f, "machine"),
+        }
+    }
+}
+
+/// Synchronous exceptions, numbered by the code written to `mcause`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exception {
+    InstructionMisaligned = 0,
+    InstructionAccessFault = 1,
+    IllegalInstruction = 2,
+    LoadMisaligned = 4,
+    LoadAccessFault = 5,
+    StoreMisaligned = 6,
+    StoreAccessFault = 7,
+    UserEcall = 8,
+    SupervisorEcall = 9,
+    MachineEcall = 11,
+    InstructionPageFault = 12,
+    LoadPageFault = 13,
+    StorePageFault = 15,
+}
+
+impl Exception {
+    /// The exception raised when `instr` fails with `error`, along with the value
+    /// for `mtval`. Returns `None` if the error isn't an architectural
+    /// exception, like a calling convention violation.
+    pub fn from_error(instr: &Instruction, error: &ExecErrorInner) -> Option<(Exception, i64)> {
+        // Atomics that write memory fault as stores, even if they read first
+        let is_store = matches!(
+            instr.expanded(),
+            Instruction::Store { .. }
+                | Instruction::FStore { .. }
+                | Instruction::VStore { .. }
+                | Instruction::Custom {
+                    operands: CustomOperands::S { .. },
+                    ..
+                }
+                | Instruction::StoreConditional { .. }
+                | Instruction::Amo { .. }
+        );
+        match error {
+            ExecErrorInner::UnsupportedInstruction { .. }
+            | ExecErrorInner::InvalidRoundingMode(_)
+            | ExecErrorInner::Privileged { .. }
+            | ExecErrorInner::ReadOnlyCsr(_)
+            | ExecErrorInner::IllegalVector(_)
+            | ExecErrorInner::Custom { .. } => Some((Exception::IllegalInstruction, 0)),
+            ExecErrorInner::Ecall(privilege) => {
+                let exception = match privilege {
+                    Privilege::User => Exception::UserEcall,
+                    Privilege::Supervisor => Exception::SupervisorEcall,
+                    Privilege::Machine => Exception::MachineEcall,
+                };
+                Some((exception, 0))
+            }
+            ExecErrorInner::MisalignedJump { target } => {
+                Some((Exception::InstructionMisaligned, *target))
+            }
+            ExecErrorInner::Memory(MemoryError::UnalignedAccess(addr)) if is_store => {
+                Some((Exception::StoreMisaligned, *addr))
+            }
+            ExecErrorInner::Memory(MemoryError::UnalignedAccess(addr)) => {
+                Some((Exception::LoadMisaligned, *addr))
+            }
+            ExecErrorInner::Memory(MemoryError::UnitializedAccess(addr)) if is_store => {
+                Some((Exception::StoreAccessFault, *addr))
+            }
+            ExecErrorInner::Memory(MemoryError::UnitializedAccess(addr)) => {
+                Some((Exception::LoadAccessFault, *addr))
+            }
+            ExecErrorInner::Memory(
+                MemoryError::Unmapped { addr, access }
+                | MemoryError::Forbidden { addr, access, .. },
+            ) => {
+                let exception = match access {
+                    Access::Read => Exception::LoadAccessFault,
+                    Access::Write => Exception::StoreAccessFault,
+                    Access::Execute => Exception::InstructionAccessFault,
+                };
+                Some((exception, *addr))
+            }
+            ExecErrorInner::Memory(MemoryError::PageFault { addr, access }) => {
+                let exception = match access {
+                    Access::Read => Exception::LoadPageFault,
+                    Access::Write => Exception::StorePageFault,
+                    Access::Execute => Exception::InstructionPageFault,
+                };
+                Some((exception, *addr))
+            }
+            _ => None,
+        }
+    }
+
+    /// The exception raised when translating the pc fails with `error`. `mtval`
+    /// is always the pc.
+    pub fn from_fetch_error(error: &MemoryError) -> Exception {
+        match error {
+            MemoryError::PageFault { .. } => Exception::InstructionPageFault,
+            // The page tables themselves couldn't be read
+            _ => Exception::InstructionAccessFault,
+        }
+    }
+}
+
+/// Interrupts, numbered by the code written to `mcause`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interrupt {
+    SupervisorSoftware = 1,
+    MachineSoftware = 3,
+    SupervisorTimer = 5,
+    MachineTimer = 7,
+}
+
+impl Interrupt {
+    /// The highest priority interrupt out of the `mip` bits in `pending`.
+    pub fn highest(pending: i64) -> Option<Interrupt> {
+        [
+            (mip::MSIP, Interrupt::MachineSoftware),
+            (mip::MTIP, Interrupt::MachineTimer),
+            (mip::SSIP, Interrupt::SupervisorSoftware),
+            (mip::STIP, Interrupt::SupervisorTimer),
+        ]
+        .into_iter()
+        .find(|(bit, _)| pending & bit != 0)
+        .map(|(_, interrupt)| interrupt)
+    }
+}
+
+impl fmt::Display for Interrupt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Interrupt::SupervisorSoftware => write!(f, "supervisor software interrupt"),
+            Interrupt::MachineSoftware => write!(f, "machine software interrupt"),
+            Interrupt::SupervisorTimer => write!(f, "supervisor timer interrupt"),
+            Interrupt::MachineTimer => write!(f, "machine timer interrupt"),
+        }
+    }
+}
+
+/// The reason for taking a trap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cause {
+    Exception(Exception),
+    Interrupt(Interrupt),
+}
+
+impl Cause {
+    /// The value written to `mcause` or `scause`. Interrupts set the highest
+    /// bit.
+    pub fn mcause(self, xlen: Xlen) -> i64 {
+        match self {
+            Cause::Exception(exception) => exception as i64,
+            Cause::Interrupt(interrupt) => xlen.sext(1 << (xlen.bits() - 1)) | interrupt as i64,
+        }
+    }
+}
+
+impl fmt::Display for Cause {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Cause::Exception(exception) => write!(f, "{exception}"),
+            Cause::Interrupt(interrupt) => write!(f, "{interrupt}"),
+        }
+    }
+}
+
+impl fmt::Display for Exception {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Exception::InstructionMisaligned => "instruction address misaligned",
+            Exception::InstructionAccessFault => "instruction access fault",
+            Exception::IllegalInstruction => "illegal instruction",
+            Exception::LoadMisaligned => "load address misaligned",
+            Exception::LoadAccessFault => "load access fault",
+            Exception::StoreMisaligned => "store/AMO address misaligned",
+            Exception::StoreAccessFault => "store/AMO access fault",
+            Exception::UserEcall => "environment call from user mode",
+            Exception::SupervisorEcall => "environment call from supervisor mode",
+            Exception::MachineEcall => "environment call from machine mode",
+            Exception::InstructionPageFault => "instruction page fault",
+            Exception::LoadPageFault => "load page fault",
+            Exception::StorePageFault => "store/AMO page fault",
+        };
+        write!(f, "{name}")
+    }
+}
@@ -0,0 +1,227 @@
+//! A UART with the registers of an NS16550 that matter for polled console I/O.
+//!
+//! Transmitting is instant, and received bytes come from a queue filled with
+//! [`Uart::push_input`] rather than from a terminal, so runs are repeatable.
+//! The queue is input from outside the program, so reverting never takes bytes
+//! out of it, but reverting a read puts the byte back.
+//!
+//! Registers are a byte apart: the receive buffer (`RBR`) and transmit holding
+//! register (`THR`) at offset 0, the interrupt enable register (`IER`) at 1,
+//! which reads back but never raises interrupts, and the line status register
+//! (`LSR`) at 5.
+
+use std::borrow::Cow;
+
+use super::device::{Changes, Device};
+
+/// The base address of the UART registers, where the ESP32-C3 has UART0.
+pub const UART_BASE: i64 = 0x6000_0000;
+
+/// Offset of `RBR` when read, and `THR` when written.
+pub const DATA: i64 = 0;
+/// Offset of `IER`.
+pub const IER: i64 = 1;
+/// Offset of `LSR`.
+pub const LSR: i64 = 5;
+
+/// Bits of `LSR`.
+pub mod lsr {
+    /// Data ready: there is a byte to receive
+    pub const DR: u8 = 1 << 0;
+    /// The transmit holding register is empty
+    pub const THRE: u8 = 1 << 5;
+    /// The transmitter is empty
+    pub const TEMT: u8 = 1 << 6;
+}
+
+/// State registers. Each transmitted byte is a register of its own, after
+/// these.
+const IER_REG: usize = 0;
+const RECEIVED_REG: usize = 1;
+const TRANSMITTED_REG: usize = 2;
+const OUTPUT_REG: usize = 3;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Uart {
+    /// Every byte queued to be received
+    input: Vec<u8>,
+    /// How many of them the program has received
+    received: usize,
+    /// The bytes the program has transmitted. Only the first `transmitted`
+    /// are current, the rest were taken back by reverting.
+    output: Vec<u8>,
+    transmitted: usize,
+    ier: u8,
+}
+
+impl Uart {
+    /// Queue bytes for the program to receive.
+    pub fn push_input(&mut self, bytes: &[u8]) {
+        self.input.extend_from_slice(bytes);
+    }
+
+    /// The queued bytes the program hasn't received yet.
+    pub fn pending(&self) -> &[u8] {
+        &self.input[self.received..]
+    }
+
+    /// Everything the program has transmitted.
+    pub fn output(&self) -> &[u8] {
+        &self.output[..self.transmitted]
+    }
+}
+
+impl Device for Uart {
+    fn name(&self) -> &'static str {
+        "uart"
+    }
+
+    fn size(&self) -> i64 {
+        8
+    }
+
+    fn read(&self, offset: i64) -> u8 {
+        match offset {
+            DATA => self.pending().first().copied().unwrap_or(0),
+            IER => self.ier,
+            LSR => {
+                let ready = if self.pending().is_empty() {
+                    0
+                } else {
+                    lsr::DR
+                };
+                ready | lsr::THRE | lsr::TEMT
+            }
+            _ => 0,
+        }
+    }
+
+    fn get(&self, reg: usize) -> u64 {
+        match reg {
+            IER_REG => self.ier as u64,
+            RECEIVED_REG => self.received as u64,
+            TRANSMITTED_REG => self.transmitted as u64,
+            _ => self.output.get(reg - OUTPUT_REG).copied().unwrap_or(0) as u64,
+        }
+    }
+
+    fn set(&mut self, reg: usize, val: u64) {
+        match reg {
+            IER_REG => self.ier = val as u8,
+            RECEIVED_REG => self.received = val as usize,
+            TRANSMITTED_REG => self.transmitted = val as usize,
+            _ => {
+                let i = reg - OUTPUT_REG;
+                if i >= self.output.len() {
+                    self.output.resize(i + 1, 0);
+                }
+                self.output[i] = val as u8;
+            }
+        }
+    }
+
+    fn register_name(&self, reg: usize) -> Cow<'static, str> {
+        match reg {
+            IER_REG => "IER".into(),
+            RECEIVED_REG => "received".into(),
+            TRANSMITTED_REG => "transmitted".into(),
+            _ => format!("output[{}]", reg - OUTPUT_REG).into(),
+        }
+    }
+
+    fn write(&self, regs: &mut Changes, offset: i64, val: u8) {
+        match offset {
+            DATA => {
+                let transmitted = regs.get(TRANSMITTED_REG);
+                regs.set(OUTPUT_REG + transmitted as usize, val as u64);
+                regs.set(TRANSMITTED_REG, transmitted + 1);
+            }
+            IER => regs.set(IER_REG, (val & 0xf) as u64),
+            _ => (),
+        }
+    }
+
+    fn after_read(&self, regs: &mut Changes, offset: i64, _len: usize) {
+        let received = regs.get(RECEIVED_REG);
+        if offset == DATA && (received as usize) < self.input.len() {
+            regs.set(RECEIVED_REG, received + 1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use indoc::indoc;
+
+    use crate::executor::{device::update, Config, Executor};
+
+    use super::*;
+
+    #[test]
+    fn echo() {
+        // Echo bytes in upper case until a newline
+        let program = indoc! {"
+            lui s0, 0x60000
+            wait:
+            lbu t0, 5(s0)
+            andi t0, t0, 1
+            beqz t0, wait
+            lbu a0, 0(s0)
+            li t1, 10
+            beq a0, t1, done
+            addi a0, a0, -32
+            sb a0, 0(s0)
+            j wait
+            done:
+        "};
+        let config = Config {
+            uart: true,
+            ..Default::default()
+        };
+        let mut exec = Executor::from_source(program, config).unwrap();
+        exec.memory
+            .device_mut::<Uart>()
+            .unwrap()
+            .push_input(b"hi\nthere");
+        exec.run().unwrap();
+        let uart = exec.memory.device::<Uart>().unwrap();
+        assert_eq!(uart.output(), b"HI");
+        assert_eq!(uart.pending(), b"there");
+
+        // Reverting gives back the input and takes back the output
+        while exec.revert() {}
+        let uart = exec.memory.device::<Uart>().unwrap();
+        assert_eq!(uart.output(), b"");
+        assert_eq!(uart.pending(), b"hi\nthere");
+
+        // Input queued while running isn't lost by reverting
+        exec.run().unwrap();
+        exec.memory.device_mut::<Uart>().unwrap().push_input(b"!");
+        while exec.revert() {}
+        exec.run().unwrap();
+        let uart = exec.memory.device::<Uart>().unwrap();
+        assert_eq!(uart.output(), b"HI");
+        assert_eq!(uart.pending(), b"there!");
+    }
+
+    #[test]
+    fn registers() {
+        let mut uart = Uart::default();
+        let receive = |uart: &mut Uart| update(uart, |uart, regs| uart.after_read(regs, DATA, 1));
+        assert_eq!(uart.read(LSR), lsr::THRE | lsr::TEMT);
+        uart.push_input(b"a");
+        assert_eq!(uart.read(LSR), lsr::DR | lsr::THRE | lsr::TEMT);
+        assert_eq!(uart.read(DATA), b'a');
+        receive(&mut uart);
+        assert_eq!(uart.read(DATA), 0);
+        // Nothing left to receive
+        receive(&mut uart);
+        uart.push_input(b"b");
+        assert_eq!(uart.read(DATA), b'b');
+
+        update(&mut uart, |uart, regs| uart.write(regs, IER, 0xff));
+        assert_eq!(uart.read(IER), 0xf);
+        update(&mut uart, |uart, regs| uart.write(regs, DATA, b'x'));
+        assert_eq!(uart.output(), b"x");
+    }
+}
@@ -0,0 +1,413 @@
+//! A teaching subset of the vector extension (RVV 1.0).
+//!
+//! Vector registers live back to back in one byte array, so a register group
+//! (LMUL > 1) is just a longer slice starting at its first register. Elements
+//! are stored little-endian, like memory.
+//!
+//! Inactive elements (masked off by `v0.t`) and tail elements (past `vl`) are
+//! always left undisturbed, which the spec allows even for the agnostic
+//! policies. `vstart` isn't modelled: a fault partway through an instruction
+//! leaves the registers untouched, and the whole instruction runs again.
+
+use crate::parse::{
+    ElementWidth, Instruction, LoadOp, Register, StoreOp, VArithOp, VCompareOp, VMaskOp, VOperand,
+    VReduceOp, VRegister, VType,
+};
+
+use super::{ExecErrorInner, Executor, ProcessorUpdate};
+
+/// Sign extend the low `sew` bits of `val`.
+pub fn truncate(val: i64, sew: ElementWidth) -> i64 {
+    let shift = 64 - sew.bits();
+    (val << shift) >> shift
+}
+
+/// Zero extend the low `sew` bits of `val`.
+fn unsigned(val: i64, sew: ElementWidth) -> u64 {
+    (val as u64) & (u64::MAX >> (64 - sew.bits()))
+}
+
+/// Read element `index` of the register group starting at `reg`, sign extended.
+pub fn element(vregs: &[u8], reg: VRegister, index: usize, sew: ElementWidth) -> i64 {
+    let start = reg as usize * vregs.len() / 32 + index * sew.bytes();
+    let mut bytes = [0; 8];
+    bytes[..sew.bytes()].copy_from_slice(&vregs[start..start + sew.bytes()]);
+    truncate(i64::from_le_bytes(bytes), sew)
+}
+
+/// Write element `index` of the register group starting at `reg`, truncating
+/// `val` to the element width.
+pub fn set_element(vregs: &mut [u8], reg: VRegister, index: usize, sew: ElementWidth, val: i64) {
+    let start = reg as usize * vregs.len() / 32 + index * sew.bytes();
+    vregs[start..start + sew.bytes()].copy_from_slice(&val.to_le_bytes()[..sew.bytes()]);
+}
+
+/// Bit `index` of a mask register.
+pub fn mask_bit(vregs: &[u8], reg: VRegister, index: usize) -> bool {
+    let byte = vregs[reg as usize * vregs.len() / 32 + index / 8];
+    (byte >> (index % 8)) & 1 != 0
+}
+
+fn set_mask_bit(vregs: &mut [u8], reg: VRegister, index: usize, bit: bool) {
+    let byte = &mut vregs[reg as usize * vregs.len() / 32 + index / 8];
+    if bit {
+        *byte |= 1 << (index % 8);
+    } else {
+        *byte &= !(1 << (index % 8));
+    }
+}
+
+/// A copy of the `registers` registers starting at `reg`.
+fn group(vregs: &[u8], reg: VRegister, registers: usize) -> Vec<u8> {
+    let vlenb = vregs.len() / 32;
+    vregs[reg as usize * vlenb..(reg as usize + registers) * vlenb].to_vec()
+}
+
+pub fn arith(op: VArithOp, fst: i64, snd: i64, sew: ElementWidth) -> i64 {
+    let val = match op {
+        VArithOp::Add => fst.wrapping_add(snd),
+        VArithOp::Sub => fst.wrapping_sub(snd),
+        VArithOp::Mul => fst.wrapping_mul(snd),
+        VArithOp::And => fst & snd,
+        VArithOp::Or => fst | snd,
+        VArithOp::Xor => fst ^ snd,
+    };
+    truncate(val, sew)
+}
+
+pub fn compare(op: VCompareOp, fst: i64, snd: i64, sew: ElementWidth) -> bool {
+    let (ufst, usnd) = (unsigned(fst, sew), unsigned(snd, sew));
+    match op {
+        VCompareOp::Eq => fst == snd,
+        VCompareOp::Ne => fst != snd,
+        VCompareOp::Lt => fst < snd,
+        VCompareOp::Ltu => ufst < usnd,
+        VCompareOp::Le => fst <= snd,
+        VCompareOp::Leu => ufst <= usnd,
+        VCompareOp::Gt => fst > snd,
+        VCompareOp::Gtu => ufst > usnd,
+    }
+}
+
+/// Fold one more element into a reduction.
+pub fn reduce(op: VReduceOp, acc: i64, val: i64, sew: ElementWidth) -> i64 {
+    let (uacc, uval) = (unsigned(acc, sew), unsigned(val, sew));
+    let result = match op {
+        VReduceOp::Sum => acc.wrapping_add(val),
+        VReduceOp::And => acc & val,
+        VReduceOp::Or => acc | val,
+        VReduceOp::Xor => acc ^ val,
+        VReduceOp::Min => acc.min(val),
+        VReduceOp::Max => acc.max(val),
+        VReduceOp::Minu => uacc.min(uval) as i64,
+        VReduceOp::Maxu => uacc.max(uval) as i64,
+    };
+    truncate(result, sew)
+}
+
+/// Make sure a register group of `registers` registers can start at `reg`.
+fn check_group(reg: VRegister, registers: usize) -> Result<(), ExecErrorInner> {
+    if !(reg as usize).is_multiple_of(registers) {
+        Err(ExecErrorInner::IllegalVector(
+            "register groups must start at a multiple of LMUL",
+        ))?
+    }
+    Ok(())
+}
+
+impl Executor {
+    /// The number of bits in a vector register.
+    fn vlen(&self) -> usize {
+        self.regfile.vregs.len() / 32 * 8
+    }
+
+    /// Compute the update for a vector instruction.
+    pub(super) fn vector_update(
+        &self,
+        asm: &Instruction,
+    ) -> Result<ProcessorUpdate, ExecErrorInner> {
+        let regs = &self.regfile;
+        let pc = self.pc;
+
+        if let Instruction::Vsetvli { rd, r1, vtype } = asm {
+            let vlmax = (vtype.lmul * self.vlen() / vtype.sew.bits() as usize) as u64;
+            let avl = if *r1 != Register::x0 {
+                regs[r1] as u64
+            } else if *rd != Register::x0 {
+                vlmax
+            } else {
+                // Only change vtype, keeping vl
+                regs.vl as u64
+            };
+            let vl = avl.min(vlmax) as i64;
+            let mut after = regs.clone();
+            after.vl = vl;
+            after.vtype = vtype.bits();
+            let mut effects = self.state_effects(&after);
+            effects.push(self.write_effect(*rd, vl));
+            return Ok(ProcessorUpdate {
+                nextpc: pc + 4,
+                effects,
+            });
+        }
+
+        let Some(VType { sew, lmul, .. }) = VType::from_bits(regs.vtype) else {
+            Err(ExecErrorInner::IllegalVector("vtype is invalid"))?
+        };
+        let vl = regs.vl as usize;
+        let vregs = &regs.vregs;
+        let mut new = vregs.clone();
+        let active = |masked: bool, index| !masked || mask_bit(vregs, VRegister::v0, index);
+        let operand = |operand: &VOperand, index| match operand {
+            VOperand::Vector(reg) => element(vregs, *reg, index, sew),
+            VOperand::Scalar(reg) => truncate(regs[reg], sew),
+            VOperand::Immediate(imm) => *imm as i64,
+        };
+        // Advance the pc by 4 and write a vector register group
+        let next_vector = |reg, val, sew| ProcessorUpdate {
+            nextpc: pc + 4,
+            effects: vec![self.vector_effect(reg, val, sew)],
+        };
+        let next_with = |reg, val| ProcessorUpdate {
+            nextpc: pc + 4,
+            effects: vec![self.write_effect(reg, val)],
+        };
+
+        let update = match asm {
+            Instruction::VLoad {
+                vd,
+                r1,
+                stride,
+                width,
+                masked,
+            } => {
+                let emul = self.emul(*width, sew, lmul)?;
+                check_group(*vd, emul)?;
+                let op = match width {
+                    ElementWidth::E8 => LoadOp::Lb,
+                    ElementWidth::E16 => LoadOp::Lh,
+                    ElementWidth::E32 => LoadOp::Lw,
+                    ElementWidth::E64 => LoadOp::Ld,
+                };
+                for index in (0..vl).filter(|index| active(*masked, *index)) {
+                    let addr = self.element_address(*r1, *stride, *width, index);
+                    let val = self.memory.load(self.data_address(addr), op)?;
+                    set_element(&mut new, *vd, index, *width, val);
+                }
+                next_vector(*vd, group(&new, *vd, emul), *width)
+            }
+            Instruction::VStore {
+                vs3,
+                r1,
+                stride,
+                width,
+                masked,
+            } => {
+                let emul = self.emul(*width, sew, lmul)?;
+                check_group(*vs3, emul)?;
+                let op = match width {
+                    ElementWidth::E8 => StoreOp::Sb,
+                    ElementWidth::E16 => StoreOp::Sh,
+                    ElementWidth::E32 => StoreOp::Sw,
+                    ElementWidth::E64 => StoreOp::Sd,
+                };
+                let mut writes = vec![];
+                for index in (0..vl).filter(|index| active(*masked, *index)) {
+                    let addr = self.element_address(*r1, *stride, *width, index);
+                    let addr = self.memory.check_store(self.data_address(addr), op)?;
+                    writes.push((addr, element(vregs, *vs3, index, *width), op));
+                }
+                ProcessorUpdate {
+                    nextpc: pc + 4,
+                    effects: self.store_effects(writes),
+                }
+            }
+            Instruction::VArith {
+                vd,
+                vs2,
+                operand: rhs,
+                op,
+                masked,
+            } => {
+                check_group(*vd, lmul)?;
+                check_group(*vs2, lmul)?;
+                if let VOperand::Vector(vs1) = rhs {
+                    check_group(*vs1, lmul)?;
+                }
+                if *masked && *vd == VRegister::v0 {
+                    Err(ExecErrorInner::IllegalVector(
+                        "masked instructions can't overwrite the mask in v0",
+                    ))?
+                }
+                for index in (0..vl).filter(|index| active(*masked, *index)) {
+                    let val = arith(
+                        *op,
+                        element(vregs, *vs2, index, sew),
+                        operand(rhs, index),
+                        sew,
+                    );
+                    set_element(&mut new, *vd, index, sew, val);
+                }
+                next_vector(*vd, group(&new, *vd, lmul), sew)
+            }
+            Instruction::VCompare {
+                vd,
+                vs2,
+                operand: rhs,
+                op,
+                masked,
+            } => {
+                check_group(*vs2, lmul)?;
+                if let VOperand::Vector(vs1) = rhs {
+                    check_group(*vs1, lmul)?;
+                }
+                for index in (0..vl).filter(|index| active(*masked, *index)) {
+                    let bit = compare(
+                        *op,
+                        element(vregs, *vs2, index, sew),
+                        operand(rhs, index),
+                        sew,
+                    );
+                    set_mask_bit(&mut new, *vd, index, bit);
+                }
+                next_vector(*vd, group(&new, *vd, 1), ElementWidth::E8)
+            }
+            Instruction::VReduce {
+                vd,
+                vs2,
+                vs1,
+                op,
+                masked,
+            } => {
+                check_group(*vs2, lmul)?;
+                // With no elements, the destination isn't written at all
+                if vl == 0 {
+                    return Ok(ProcessorUpdate {
+                        nextpc: pc + 4,
+                        effects: vec![],
+                    });
+                }
+                let val = (0..vl)
+                    .filter(|index| active(*masked, *index))
+                    .fold(element(vregs, *vs1, 0, sew), |acc, index| {
+                        reduce(*op, acc, element(vregs, *vs2, index, sew), sew)
+                    });
+                set_element(&mut new, *vd, 0, sew, val);
+                next_vector(*vd, group(&new, *vd, 1), sew)
+            }
+            Instruction::VMask { vd, vs2, vs1, op } => {
+                for index in 0..vl {
+                    let (fst, snd) = (mask_bit(vregs, *vs2, index), mask_bit(vregs, *vs1, index));
+                    let bit = match op {
+                        VMaskOp::And => fst && snd,
+                        VMaskOp::Or => fst || snd,
+                        VMaskOp::Xor => fst != snd,
+                        VMaskOp::Nand => !(fst && snd),
+                        VMaskOp::Andn => fst && !snd,
+                    };
+                    set_mask_bit(&mut new, *vd, index, bit);
+                }
+                next_vector(*vd, group(&new, *vd, 1), ElementWidth::E8)
+            }
+            Instruction::VMove { vd, operand: rhs } => {
+                check_group(*vd, lmul)?;
+                for index in 0..vl {
+                    set_element(&mut new, *vd, index, sew, operand(rhs, index));
+                }
+                next_vector(*vd, group(&new, *vd, lmul), sew)
+            }
+            // Unlike other vector instructions, this ignores vl
+            Instruction::VMoveToScalar { rd, vs2 } => {
+                next_with(*rd, self.config.xlen.sext(element(vregs, *vs2, 0, sew)))
+            }
+            Instruction::VMoveFromScalar { vd, r1 } => {
+                if vl > 0 {
+                    set_element(&mut new, *vd, 0, sew, regs[r1]);
+                }
+                next_vector(*vd, group(&new, *vd, 1), sew)
+            }
+            Instruction::VCpop { rd, vs2, masked } => {
+                let count = (0..vl)
+                    .filter(|index| active(*masked, *index) && mask_bit(vregs, *vs2, *index))
+                    .count();
+                next_with(*rd, count as i64)
+            }
+            Instruction::Vid { vd, masked } => {
+                check_group(*vd, lmul)?;
+                for index in (0..vl).filter(|index| active(*masked, *index)) {
+                    set_element(&mut new, *vd, index, sew, index as i64);
+                }
+                next_vector(*vd, group(&new, *vd, lmul), sew)
+            }
+            other => unreachable!("{other} is not a vector instruction"),
+        };
+        Ok(update)
+    }
+
+    /// The number of registers in the group accessed by a load or store of
+    /// `width` elements. Fractional groups aren't supported.
+    fn emul(
+        &self,
+        width: ElementWidth,
+        sew: ElementWidth,
+        lmul: usize,
+    ) -> Result<usize, ExecErrorInner> {
+        let scaled = lmul * width.bytes();
+        if !scaled.is_multiple_of(sew.bytes()) || scaled / sew.bytes() > 8 {
+            Err(ExecErrorInner::IllegalVector(
+                "the element width doesn't fit a supported register group",
+            ))?
+        }
+        Ok(scaled / sew.bytes())
+    }
+
+    /// The address of element `index` of a unit-stride or strided access.
+    fn element_address(
+        &self,
+        base: Register,
+        stride: Option<Register>,
+        width: ElementWidth,
+        index: usize,
+    ) -> i64 {
+        let stride = match stride {
+            Some(stride) => self.regfile[stride],
+            None => width.bytes() as i64,
+        };
+        let offset = stride.wrapping_mul(index as i64);
+        self.config
+            .xlen
+            .address(self.regfile[base].wrapping_add(offset))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn elements() {
+        let mut vregs = vec![0; 32 * 16];
+        set_element(&mut vregs, VRegister::v2, 3, ElementWidth::E32, -2);
+        assert_eq!(element(&vregs, VRegister::v2, 3, ElementWidth::E32), -2);
+        assert_eq!(element(&vregs, VRegister::v2, 12, ElementWidth::E8), -2);
+        assert_eq!(element(&vregs, VRegister::v2, 13, ElementWidth::E8), -1);
+        // Groups continue into the next register
+        set_element(&mut vregs, VRegister::v2, 4, ElementWidth::E32, 7);
+        assert_eq!(element(&vregs, VRegister::v3, 0, ElementWidth::E32), 7);
+
+        set_mask_bit(&mut vregs, VRegister::v0, 9, true);
+        assert!(mask_bit(&vregs, VRegister::v0, 9));
+        assert_eq!(vregs[1], 0b10);
+    }
+
+    #[test]
+    fn operations() {
+        use ElementWidth::*;
+        assert_eq!(arith(VArithOp::Add, 127, 1, E8), -128);
+        assert_eq!(arith(VArithOp::Mul, 0x10000, 0x10000, E32), 0);
+        assert!(compare(VCompareOp::Ltu, 1, -1, E16));
+        assert!(!compare(VCompareOp::Lt, 1, -1, E16));
+        assert_eq!(reduce(VReduceOp::Maxu, 1, -1, E8), -1);
+        assert_eq!(reduce(VReduceOp::Max, 1, -1, E8), 1);
+    }
+}
@@ -0,0 +1,264 @@
+//! Exporting assembled programs as memory images: raw binary, Intel HEX, and
+//! the word-per-line text files Verilog's `$readmemh` and `$readmemb` load.
+
+use std::fmt::Write;
+
+use thiserror::Error;
+
+use crate::{encode::EncodeError, parse::Program};
+
+/// The format of an exported image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// Little-endian bytes, with no header.
+    Raw,
+    /// Intel HEX records, with extended linear addresses above 64 KiB.
+    IntelHex,
+    /// One hexadecimal word per line, for `$readmemh`.
+    ReadMemH,
+    /// One binary word per line, for `$readmemb`.
+    ReadMemB,
+}
+
+/// Where an image is placed in memory and how it's split into words.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Layout {
+    /// The address of the first instruction.
+    pub base: i64,
+
+    /// The number of bytes in each line of a `$readmem` file: 1, 2, 4 or 8.
+    /// Words are little-endian, and the image is padded with zeros to a whole
+    /// number of them.
+    pub word_bytes: usize,
+}
+
+impl Default for Layout {
+    fn default() -> Self {
+        Self {
+            base: 0,
+            word_bytes: 4,
+        }
+    }
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum ExportError {
+    #[error(transparent)]
+    Encode(#[from] EncodeError),
+
+    #[error("words must be 1, 2, 4 or 8 bytes, not {0}")]
+    WordBytes(usize),
+
+    #[error("base address {base:#x} is not a multiple of the {word_bytes}-byte word size")]
+    Unaligned { base: i64, word_bytes: usize },
+
+    /// Intel HEX addresses are 32 bits.
+    #[error("image at {0:#x} doesn't fit in a 32-bit address space")]
+    Address(i64),
+}
+
+/// Intel HEX records for `bytes` at `base`, 16 bytes to a line.
+pub fn intel_hex(bytes: &[u8], base: i64) -> Result<String, ExportError> {
+    let end = base + bytes.len() as i64;
+    if base < 0 || end > 1 << 32 {
+        Err(ExportError::Address(base))?
+    }
+    let mut hex = String::new();
+    let mut record = |kind: u8, addr: u16, data: &[u8]| {
+        let [hi, lo] = addr.to_be_bytes();
+        let header = [data.len() as u8, hi, lo, kind];
+        let sum = header
+            .iter()
+            .chain(data)
+            .fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+        hex.push(':');
+        for byte in header.iter().chain(data) {
+            write!(hex, "{byte:02X}").unwrap();
+        }
+        writeln!(hex, "{:02X}", sum.wrapping_neg()).unwrap();
+    };
+
+    let mut upper = 0;
+    let mut addr = base;
+    let mut rest = bytes;
+    while !rest.is_empty() {
+        if addr >> 16 != upper {
+            upper = addr >> 16;
+            record(0x04, 0, &(upper as u16).to_be_bytes());
+        }
+        // Records can't cross into the next 64 KiB
+        let len = rest.len().min(16).min((0x10000 - (addr & 0xffff)) as usize);
+        record(0x00, addr as u16, &rest[..len]);
+        addr += len as i64;
+        rest = &rest[len..];
+    }
+    record(0x01, 0, &[]);
+    Ok(hex)
+}
+
+/// A `$readmemh` or `$readmemb` file for `bytes` at `base`. Addresses in these
+/// files count words, so a nonzero base starts the file with `@` and the index
+/// of its first word.
+pub fn readmem(
+    bytes: &[u8],
+    base: i64,
+    word_bytes: usize,
+    binary: bool,
+) -> Result<String, ExportError> {
+    if !matches!(word_bytes, 1 | 2 | 4 | 8) {
+        Err(ExportError::WordBytes(word_bytes))?
+    }
+    if base % word_bytes as i64 != 0 {
+        Err(ExportError::Unaligned { base, word_bytes })?
+    }
+    let mut text = String::new();
+    if base != 0 {
+        writeln!(text, "@{:x}", base / word_bytes as i64).unwrap();
+    }
+    for chunk in bytes.chunks(word_bytes) {
+        let mut word = [0; 8];
+        word[..chunk.len()].copy_from_slice(chunk);
+        let word = u64::from_le_bytes(word);
+        if binary {
+            writeln!(text, "{word:0width$b}", width = 8 * word_bytes).unwrap();
+        } else {
+            writeln!(text, "{word:0width$x}", width = 2 * word_bytes).unwrap();
+        }
+    }
+    Ok(text)
+}
+
+impl Program {
+    /// Assemble the program at `layout.base` and export it in `format`. Text
+    /// formats are returned as their UTF-8 bytes.
+    pub fn export(&self, format: Format, layout: Layout) -> Result<Vec<u8>, ExportError> {
+        let mut program = self.clone();
+        program.rebase(layout.base);
+        let image = program.image_at(layout.base)?;
+        let Layout { base, word_bytes } = layout;
+        let text = match format {
+            Format::Raw => return Ok(image),
+            Format::IntelHex => intel_hex(&image, base)?,
+            Format::ReadMemH => readmem(&image, base, word_bytes, false)?,
+            Format::ReadMemB => readmem(&image, base, word_bytes, true)?,
+        };
+        Ok(text.into_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use indoc::indoc;
+
+    use super::*;
+
+    #[test]
+    fn formats() {
+        let program = indoc! {"
+            loop:
+            addi a0, x0, 5
+            bne a0, x0, loop
+            ret
+        "}
+        .parse::<Program>()
+        .unwrap();
+        let export = |format, base, word_bytes| {
+            let layout = Layout { base, word_bytes };
+            String::from_utf8(program.export(format, layout).unwrap()).unwrap()
+        };
+
+        let raw = program.export(Format::Raw, Layout::default()).unwrap();
+        assert_eq!(raw, program.image().unwrap());
+
+        assert_eq!(
+            export(Format::IntelHex, 0, 4),
+            indoc! {"
+                :0C00000013055000E31E05FE67800000A1
+                :00000001FF
+            "}
+        );
+        // The branch is relative, so only the addresses change
+        assert_eq!(
+            export(Format::IntelHex, 0x1fff8, 4),
+            indoc! {"
+                :020000040001F9
+                :08FFF80013055000E31E05FE95
+                :020000040002F8
+                :040000006780000015
+                :00000001FF
+            "}
+        );
+
+        assert_eq!(
+            export(Format::ReadMemH, 0, 4),
+            "00500513\nfe051ee3\n00008067\n"
+        );
+        assert_eq!(
+            export(Format::ReadMemH, 0x100, 8),
+            "@20\nfe051ee300500513\n0000000000008067\n"
+        );
+        assert_eq!(
+            export(Format::ReadMemB, 0, 2),
+            indoc! {"
+                0000010100010011
+                0000000001010000
+                0001111011100011
+                1111111000000101
+                1000000001100111
+                0000000000000000
+            "}
+        );
+
+        let layout = Layout {
+            base: 2,
+            word_bytes: 4,
+        };
+        assert_eq!(
+            program.export(Format::ReadMemH, layout),
+            Err(ExportError::Unaligned {
+                base: 2,
+                word_bytes: 4
+            })
+        );
+        let layout = Layout {
+            base: 0,
+            word_bytes: 3,
+        };
+        assert_eq!(
+            program.export(Format::ReadMemH, layout),
+            Err(ExportError::WordBytes(3))
+        );
+        assert_eq!(
+            intel_hex(&[0; 8], 0xffff_fffc),
+            Err(ExportError::Address(0xffff_fffc))
+        );
+    }
+
+    #[test]
+    fn pseudo_instructions() {
+        let program = indoc! {"
+            la a0, f
+            call f
+            f:
+            ret
+        "}
+        .parse::<Program>()
+        .unwrap();
+        let layout = Layout {
+            base: 0x1000,
+            word_bytes: 4,
+        };
+        let hex = program.export(Format::ReadMemH, layout).unwrap();
+        assert_eq!(
+            String::from_utf8(hex).unwrap(),
+            "@400\n00000517\n01050513\n00000097\n008080e7\n00008067\n"
+        );
+        let raw = program.export(Format::Raw, layout).unwrap();
+        assert_eq!(raw.len(), 20);
+        assert_eq!(raw, program.image().unwrap());
+        let intel = program.export(Format::IntelHex, layout).unwrap();
+        assert!(String::from_utf8(intel)
+            .unwrap()
+            .starts_with(":10100000170500001305050197000000E7808000"));
+    }
+}
@@ -2,6 +2,11 @@ use anyhow::{anyhow, Context};
 use serde::{Deserialize, Serialize};
 use std::{fmt, mem, ops::Range};
 
+use crate::{
+    executor::{custom::CustomInstructions, Xlen},
+    parse::Base,
+};
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum TokenInner {
     RightParen,
@@ -9,7 +14,9 @@ pub enum TokenInner {
     Comma,
     Colon,
     Minus,
-    Constant(i32),
+    /// Hex constants are zero extended, so `0xffffffff` is 2^32 - 1, and the
+    /// parser decides whether it means -1.
+    Constant(i64),
     Ident(String),
     SlashComment(String),
     HashComment(String),
@@ -42,6 +49,16 @@ impl Span {
     pub fn new(line: usize, columns: Range<usize>) -> Self {
         Self { line, columns }
     }
+
+    /// The line of the token, starting from 1.
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    /// The columns of the token, starting from 1.
+    pub fn columns(&self) -> Range<usize> {
+        self.columns.clone()
+    }
 }
 
 impl fmt::Display for Span {
@@ -185,7 +202,7 @@ impl Token {
     }
 
     /// Extract the value of a constant
-    pub fn unwrap_constant(self) -> (i32, Span) {
+    pub fn unwrap_constant(self) -> (i64, Span) {
         match self.inner {
             TokenInner::Constant(inner) => (inner, self.span),
             other => panic!("called unwrap ident on a {other}"),
@@ -199,6 +216,7 @@ type LexResult = anyhow::Result<Token>;
 /// There are a couple reasons this lexer should not be used directly:
 ///  - it is not peekable
 ///  - if it errors, it will just keep returning that error
+///
 /// The `Lexer` type wraps a `RawLexer` and takes cares of these things.
 /// However, it is still nice to have actual lexing functionality abstracted
 /// away in one place.
@@ -207,6 +225,9 @@ struct RawLexer<'a> {
     buf: &'a str,
     line: usize,
     char: usize,
+    /// Whether the next token starts a line or follows a label, which is where
+    /// mnemonics go.
+    mnemonic: bool,
 }
 
 impl<'a> RawLexer<'a> {
@@ -215,6 +236,7 @@ impl<'a> RawLexer<'a> {
             buf,
             line: 1,
             char: 1,
+            mnemonic: true,
         }
     }
 
@@ -228,7 +250,8 @@ impl<'a> RawLexer<'a> {
             for space in spaces.chars() {
                 if space == '\n' {
                     self.line += 1;
-                    self.char = 1
+                    self.char = 1;
+                    self.mnemonic = true
                 } else {
                     self.char += 1
                 }
@@ -265,6 +288,7 @@ impl<'a> RawLexer<'a> {
         // These will be referenced very frequently :)
         let line = self.line;
         let start = self.char;
+        let mnemonic = std::mem::replace(&mut self.mnemonic, false);
 
         // little utility for format errors with span info
         let fail_message =
@@ -277,6 +301,7 @@ impl<'a> RawLexer<'a> {
         } else if self.buf.starts_with(',') {
             Ok(Token::new(TokenInner::Comma, line, self.advance(1)))
         } else if self.buf.starts_with(':') {
+            self.mnemonic = true;
             Ok(Token::new(TokenInner::Colon, line, self.advance(1)))
         } else if self.buf.starts_with('-') {
             Ok(Token::new(TokenInner::Minus, line, self.advance(1)))
@@ -303,12 +328,12 @@ impl<'a> RawLexer<'a> {
                 let token_len = digits.len() + 2;
                 let hex = format!("0x{}", digits);
 
-                // We cannot parse negative numbers into i32 (even if i32 can hold
+                // We cannot parse negative numbers into i64 (even if i64 can hold
                 // negative numbers) because of "value out of bounds" errors. So
-                // we first parse into a u32 then cast to i32.
-                match parse_int::parse::<u32>(&hex) {
+                // we first parse into a u64 then cast to i64.
+                match parse_int::parse::<u64>(&hex) {
                     Ok(number) => Ok(Token::new(
-                        TokenInner::Constant(number as i32),
+                        TokenInner::Constant(number as i64),
                         line,
                         self.advance(token_len),
                     )),
@@ -329,7 +354,7 @@ impl<'a> RawLexer<'a> {
             }
         } else if let Some(digits) = self.buf.consume(|c| c.is_ascii_digit()) {
             let token_len = digits.len();
-            match parse_int::parse::<i32>(digits) {
+            match parse_int::parse::<i64>(digits) {
                 Ok(number) => Ok(Token::new(
                     TokenInner::Constant(number),
                     line,
@@ -343,9 +368,18 @@ impl<'a> RawLexer<'a> {
                     )
                 }),
             }
-        } else if let Some(label) = self.buf.consume(|c| c == '_' || c.is_alphanumeric()) {
+        } else if let Some(label) = self
+            .buf
+            .consume(|c| c == '_' || c == '.' || c.is_alphanumeric())
+        {
             // Note: parse labels last as they can contain numbers, but we don't want
-            // to parse 123 as a label
+            // to parse 123 as a label. Dots can only go in mnemonics like
+            // `amoadd.w`, and in directives and local labels, which start with one
+            let label = if mnemonic || label.starts_with('.') {
+                label
+            } else {
+                label.split('.').next().unwrap()
+            };
             Ok(Token::new(
                 TokenInner::Ident(label.to_string()),
                 line,
@@ -368,19 +402,57 @@ pub struct Lexer<'a> {
     inner: RawLexer<'a>,
     errored: bool,
     peek: Option<LexResult>,
+
+    /// The base ISA being parsed, which determines which registers exist.
+    base: Base,
+    /// Only used to name the ISA in errors.
+    xlen: Xlen,
+    /// Extra mnemonics to accept.
+    custom: CustomInstructions,
 }
 
 impl<'a> Lexer<'a> {
     pub fn new(source: &'a str) -> Self {
+        Self::with_base(source, Base::default())
+    }
+
+    pub fn with_base(source: &'a str, base: Base) -> Self {
         let mut inner = RawLexer::new(source);
         let peek = inner.next_from_buf();
         Lexer {
             inner,
             errored: false,
             peek,
+            base,
+            xlen: Xlen::default(),
+            custom: CustomInstructions::default(),
         }
     }
 
+    /// Parse for `xlen`.
+    pub fn with_xlen(mut self, xlen: Xlen) -> Self {
+        self.xlen = xlen;
+        self
+    }
+
+    /// Also accept the mnemonics of `custom`.
+    pub fn with_custom(mut self, custom: CustomInstructions) -> Self {
+        self.custom = custom;
+        self
+    }
+
+    pub fn base(&self) -> Base {
+        self.base
+    }
+
+    pub fn xlen(&self) -> Xlen {
+        self.xlen
+    }
+
+    pub fn custom(&self) -> &CustomInstructions {
+        &self.custom
+    }
+
     pub fn peek(&mut self) -> Option<&LexResult> {
         self.peek.as_ref()
     }
@@ -418,6 +490,9 @@ impl<'a> IntoIterator for RawLexer<'a> {
             inner: self,
             errored: false,
             peek,
+            base: Base::default(),
+            xlen: Xlen::default(),
+            custom: CustomInstructions::default(),
         }
     }
 }
@@ -485,10 +560,14 @@ mod tests {
 
     #[test]
     fn lex_negative() {
-        let mut lexer = Lexer::new("0x80000000");
+        let mut lexer = Lexer::new("0x80000000 0xffffffffffffffff");
+        assert_eq!(
+            lexer.next().unwrap().unwrap(),
+            Token::new(TokenInner::Constant(0x80000000), 1, 1..11)
+        );
         assert_eq!(
             lexer.next().unwrap().unwrap(),
-            Token::new(TokenInner::Constant(i32::MIN), 1, 1..11)
+            Token::new(TokenInner::Constant(-1), 1, 12..30)
         )
     }
 
@@ -524,6 +603,8 @@ mod tests {
             checka:
             loopa:
             69 -42 0xff
+            amoadd.w
+            done: lr.w a0.x, .L1
         "})
         .map(|token| token.unwrap())
         .collect::<Vec<_>>();
@@ -554,6 +635,15 @@ mod tests {
                 Token::new(TokenInner::Minus, 6, 4..5),
                 Token::new(TokenInner::Constant(42), 6, 5..7),
                 Token::new(TokenInner::Constant(255), 6, 8..12),
+                Token::new(TokenInner::Ident("amoadd.w".to_string()), 7, 1..9),
+                // Only mnemonics and names starting with a dot contain dots
+                Token::new(TokenInner::Ident("done".to_string()), 8, 1..5),
+                Token::new(TokenInner::Colon, 8, 5..6),
+                Token::new(TokenInner::Ident("lr.w".to_string()), 8, 7..11),
+                Token::new(TokenInner::Ident("a0".to_string()), 8, 12..14),
+                Token::new(TokenInner::Ident(".x".to_string()), 8, 14..16),
+                Token::new(TokenInner::Comma, 8, 16..17),
+                Token::new(TokenInner::Ident(".L1".to_string()), 8, 18..21),
             ]
         );
     }
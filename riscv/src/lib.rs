@@ -1,5 +1,11 @@
+pub mod compress;
+pub mod decode;
+pub mod elf;
+pub mod encode;
 pub mod executor;
+pub mod export;
 pub mod lex;
+pub mod listing;
 pub mod parse;
 
 // vec! like syntax for a hashmap
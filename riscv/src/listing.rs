@@ -0,0 +1,427 @@
+//! Assembly listings: the address, machine code, labels, source text and
+//! comment of every instruction side by side, like the ones handed out to
+//! check hand assembly against.
+//!
+//! Pseudo-instructions and compressed instructions also list the base
+//! instruction they assemble to, so `mv a0, a1` shows `addi a0, a1, 0`. The
+//! ones that assemble to two, like `call`, get a row for each, and the second
+//! row has no labels, source or comment.
+
+use std::{collections::HashMap, fmt::Write};
+
+use anyhow::Context;
+
+use crate::{
+    decode::{self, Decoder},
+    executor::Config,
+    lex::{Lexer, Span, TokenInner},
+    parse::{Instruction, Item, Program, Register},
+};
+
+/// One instruction of a listing, or one half of a pseudo-instruction that
+/// assembles to two.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Row {
+    /// Where the instruction is in memory
+    pub addr: i64,
+
+    /// The machine code, in the low 2 bytes for compressed instructions
+    pub encoding: u32,
+
+    /// The labels that point to the instruction, in source order
+    pub labels: Vec<String>,
+
+    /// The instruction as it was written, or nothing on the second row of a
+    /// pseudo-instruction
+    pub source: String,
+
+    /// The instruction that was assembled, which is compressed if the
+    /// assembler compressed it
+    pub instr: Instruction,
+
+    /// The base instruction it assembles to, if that isn't `instr` itself
+    pub expansion: Option<Instruction>,
+
+    /// The comment at the end of the line, without the `#` or `//`
+    pub comment: Option<String>,
+
+    /// The line of the source the instruction is on, starting from 1
+    pub line: usize,
+}
+
+impl Row {
+    /// The machine code in hexadecimal, 4 digits for compressed instructions
+    /// and 8 otherwise.
+    pub fn hex(&self) -> String {
+        let digits = if self.instr.size() == 2 { 4 } else { 8 };
+        format!("{:0digits$x}", self.encoding)
+    }
+}
+
+/// List `source` as the executor would load it with `config`: for its base
+/// ISA and custom instructions, compressed with [`Config::c`], and starting
+/// at [`Config::text_base`], or 0.
+pub fn listing(source: &str, config: &Config) -> anyhow::Result<Vec<Row>> {
+    let lexer = || {
+        Lexer::with_base(source, config.base)
+            .with_xlen(config.xlen)
+            .with_custom(config.custom.clone())
+    };
+    let mut program = Program::parse(&mut lexer()).context("failed to parse program")?;
+    if config.c {
+        program.compress();
+    }
+    let base = config.text_base.unwrap_or(0);
+    program.rebase(base);
+
+    // Parse again for where everything is, which the program doesn't keep
+    let mut items = vec![];
+    let mut lexer = lexer();
+    while let Some(item) = lexer.parse_item() {
+        items.push(item?);
+    }
+    let mut comments = HashMap::new();
+    for token in Lexer::new(source) {
+        let Ok(token) = token else { break };
+        let (inner, span) = token.split();
+        if let TokenInner::HashComment(text) | TokenInner::SlashComment(text) = inner {
+            comments.insert(span.line(), (span.columns().start, text.trim().to_string()));
+        }
+    }
+    let lines: Vec<_> = source.lines().collect();
+    // The text of an item runs until the next item or comment on its line
+    let text = |index: usize, span: &Span| {
+        let start = span.columns().start;
+        let end = items[index + 1..]
+            .iter()
+            .map(item_span)
+            .find(|next| next.line() == span.line())
+            .map(|next| next.columns().start)
+            .into_iter()
+            .chain(comments.get(&span.line()).map(|(column, _)| *column))
+            .min()
+            .unwrap_or(usize::MAX);
+        let line = lines.get(span.line() - 1).unwrap_or(&"");
+        let text: String = line.chars().take(end - 1).skip(start - 1).collect();
+        text.trim().to_string()
+    };
+
+    let decoder = Decoder::new().with_custom(config.custom.clone());
+    let names = label_names(&items, &program);
+    let mut rows = vec![];
+    let mut labels = vec![];
+    let mut asm = program.addresses().zip(&program.asm);
+    for (index, item) in items.iter().enumerate() {
+        let span = match item {
+            Item::Label { name, .. } => {
+                labels.push(name.clone());
+                continue;
+            }
+            Item::Instruction { span, .. } => span,
+        };
+        let (addr, instr) = asm.next().expect("one instruction per item");
+        let addr = base + addr;
+        let words = instr.encode(addr, &program)?;
+        for (half, (encoding, addr)) in words.into_iter().zip((addr..).step_by(4)).enumerate() {
+            let first = half == 0;
+            let expansion = decoder
+                .decode(encoding, addr)
+                .ok()
+                .map(|decoded| base_instruction(decoded.expanded().clone(), &names))
+                .filter(|expansion| expansion.to_string() != instr.to_string());
+            let comment = comments.get(&span.line()).map(|(_, text)| text.clone());
+            rows.push(Row {
+                addr,
+                encoding,
+                labels: std::mem::take(&mut labels),
+                source: if first {
+                    text(index, span)
+                } else {
+                    String::new()
+                },
+                instr: instr.clone(),
+                expansion,
+                comment: comment.filter(|_| first),
+                line: span.line(),
+            });
+        }
+    }
+    Ok(rows)
+}
+
+fn item_span(item: &Item) -> &Span {
+    match item {
+        Item::Instruction { span, .. } | Item::Label { span, .. } => span,
+    }
+}
+
+/// The first label in the source at each address, to name jump targets with.
+fn label_names(items: &[Item], program: &Program) -> HashMap<i64, String> {
+    let mut names = HashMap::new();
+    for item in items {
+        if let Item::Label { name, .. } = item {
+            let addr = program.labels[name] as i64;
+            names.entry(addr).or_insert_with(|| name.clone());
+        }
+    }
+    names
+}
+
+/// Undo the decoder's shorthands, so jumps show the `jal` or `jalr` they are,
+/// and name targets after the program's labels.
+fn base_instruction(instr: Instruction, names: &HashMap<i64, String>) -> Instruction {
+    let rename = |label: String| {
+        decode::label_address(&label)
+            .and_then(|addr| names.get(&addr).cloned())
+            .unwrap_or(label)
+    };
+    match instr {
+        Instruction::j { label } => Instruction::jal {
+            rd: Register::x0,
+            label: rename(label),
+        },
+        Instruction::jal { rd, label } => Instruction::jal {
+            rd,
+            label: rename(label),
+        },
+        Instruction::Branch { r1, r2, label, op } => Instruction::Branch {
+            r1,
+            r2,
+            label: rename(label),
+            op,
+        },
+        Instruction::jr { rs } => Instruction::jalr {
+            rd: Register::x0,
+            offset: 0,
+            r1: rs,
+        },
+        Instruction::ret {} => Instruction::jalr {
+            rd: Register::x0,
+            offset: 0,
+            r1: Register::ra,
+        },
+        instr => instr,
+    }
+}
+
+const HEADINGS: [&str; 6] = ["address", "code", "label", "source", "expansion", "comment"];
+
+/// The columns of a row as text.
+fn columns(row: &Row) -> [String; 6] {
+    let labels = row.labels.iter().map(|label| format!("{label}:"));
+    [
+        format!("{:08x}", row.addr),
+        row.hex(),
+        labels.collect::<Vec<_>>().join(" "),
+        row.source.clone(),
+        row.expansion
+            .as_ref()
+            .map_or(String::new(), ToString::to_string),
+        row.comment
+            .as_ref()
+            .map_or(String::new(), |text| format!("# {text}")),
+    ]
+}
+
+/// A plain text listing, with a column for each field lined up.
+pub fn text(rows: &[Row]) -> String {
+    let cells: Vec<_> = rows.iter().map(columns).collect();
+    let mut widths = HEADINGS.map(str::len);
+    for row in &cells {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    let mut text = String::new();
+    let headings = HEADINGS.map(str::to_string);
+    for row in std::iter::once(&headings).chain(&cells) {
+        let line: Vec<_> = row
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{cell:width$}"))
+            .collect();
+        writeln!(text, "{}", line.join("  ").trim_end()).unwrap();
+    }
+    text
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// An HTML table listing, with a `listing` class to style it by.
+pub fn html(rows: &[Row]) -> String {
+    let mut html = String::from("<table class=\"listing\">\n<thead>\n<tr>");
+    for heading in HEADINGS {
+        write!(html, "<th>{heading}</th>").unwrap();
+    }
+    html.push_str("</tr>\n</thead>\n<tbody>\n");
+    for row in rows {
+        html.push_str("<tr>");
+        for cell in columns(row) {
+            write!(html, "<td>{}</td>", escape(&cell)).unwrap();
+        }
+        html.push_str("</tr>\n");
+    }
+    html.push_str("</tbody>\n</table>\n");
+    html
+}
+
+#[cfg(test)]
+mod tests {
+    use indoc::indoc;
+
+    use super::*;
+
+    const SOURCE: &str = indoc! {"
+        # Sum 1 to 10
+        main:
+            li a0, 10        # counter
+            mv a1, zero
+        loop: add a1, a1, a0
+            addi a0, a0, -1  // count down
+            bnez a0, loop
+            call done
+        done:
+        end:
+            ret
+    "};
+
+    #[test]
+    fn rows() {
+        let rows = listing(SOURCE, &Config::default()).unwrap();
+        let summary: Vec<_> = rows
+            .iter()
+            .map(|row| {
+                let expansion = row.expansion.as_ref().map(ToString::to_string);
+                (row.addr, row.hex(), row.source.as_str(), expansion)
+            })
+            .collect();
+        let expansion = |text: &str| Some(text.to_string());
+        assert_eq!(
+            summary,
+            [
+                (
+                    0,
+                    "00a00513".into(),
+                    "li a0, 10",
+                    expansion("addi a0, x0, 10")
+                ),
+                (
+                    4,
+                    "00000593".into(),
+                    "mv a1, zero",
+                    expansion("addi a1, x0, 0")
+                ),
+                (8, "00a585b3".into(), "add a1, a1, a0", None),
+                (12, "fff50513".into(), "addi a0, a0, -1", None),
+                (
+                    16,
+                    "fe051ce3".into(),
+                    "bnez a0, loop",
+                    expansion("bne a0, x0, loop")
+                ),
+                (20, "00000097".into(), "call done", expansion("auipc ra, 0")),
+                (24, "008080e7".into(), "", expansion("jalr ra, 8(ra)")),
+                (28, "00008067".into(), "ret", expansion("jalr x0, 0(ra)")),
+            ]
+        );
+        assert_eq!(rows[0].labels, ["main"]);
+        assert_eq!(rows[0].comment.as_deref(), Some("counter"));
+        assert_eq!(rows[0].line, 3);
+        assert_eq!(rows[2].labels, ["loop"]);
+        assert_eq!(rows[3].comment.as_deref(), Some("count down"));
+        assert_eq!(rows[7].labels, ["done", "end"]);
+
+        // Compressed and moved to the text base
+        let mut config = Config::default();
+        config.c = true;
+        config.text_base = Some(0x00400000);
+        let rows = listing(SOURCE, &config).unwrap();
+        assert_eq!(rows[1].addr, 0x00400002);
+        assert_eq!(rows[1].hex(), "4581");
+        assert_eq!(rows[1].instr.to_string(), "c.li a1, 0");
+        assert_eq!(
+            rows[4].expansion.as_ref().unwrap().to_string(),
+            "bne a0, x0, loop"
+        );
+        assert_eq!(rows[5].hex(), "2009");
+    }
+
+    #[test]
+    fn expanded() {
+        let source = indoc! {"
+            li a0, 0x12345678
+            la a1, data  # address
+            call f
+            f: ret
+            data:
+        "};
+        let rows = listing(source, &Config::default()).unwrap();
+        let summary: Vec<_> = rows
+            .iter()
+            .map(|row| {
+                let expansion = row.expansion.as_ref().unwrap().to_string();
+                (row.addr, row.hex(), row.source.as_str(), expansion)
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (
+                    0,
+                    "12345537".into(),
+                    "li a0, 0x12345678",
+                    "lui a0, 74565".into()
+                ),
+                (4, "67850513".into(), "", "addi a0, a0, 1656".into()),
+                (8, "00000597".into(), "la a1, data", "auipc a1, 0".into()),
+                (12, "01458593".into(), "", "addi a1, a1, 20".into()),
+                (16, "00000097".into(), "call f", "auipc ra, 0".into()),
+                (20, "008080e7".into(), "", "jalr ra, 8(ra)".into()),
+                (24, "00008067".into(), "ret", "jalr x0, 0(ra)".into()),
+            ]
+        );
+        assert_eq!(rows[2].comment.as_deref(), Some("address"));
+        assert_eq!(rows[3].comment, None);
+        assert_eq!(rows[6].labels, ["f"]);
+        assert_eq!(rows[3].line, 2);
+
+        let text = text(&rows);
+        let lines: Vec<_> = text.lines().collect();
+        assert_eq!(
+            lines[4],
+            "0000000c  01458593                            addi a1, a1, 20"
+        );
+    }
+
+    #[test]
+    fn output() {
+        let rows = listing(SOURCE, &Config::default()).unwrap();
+        let text = text(&rows);
+        let lines: Vec<_> = text.lines().collect();
+        assert_eq!(lines.len(), 9);
+        assert_eq!(
+            lines[1],
+            "00000000  00a00513  main:       li a0, 10        addi a0, x0, 10   # counter"
+        );
+        assert_eq!(
+            lines[8],
+            "0000001c  00008067  done: end:  ret              jalr x0, 0(ra)"
+        );
+
+        let html = html(&rows[..1]);
+        assert!(html.starts_with("<table class=\"listing\">"));
+        assert!(html.contains(
+            "<tr><td>00000000</td><td>00a00513</td><td>main:</td><td>li a0, 10</td>\
+             <td>addi a0, x0, 10</td><td># counter</td></tr>"
+        ));
+        assert_eq!(
+            escape("a < b && \"c\""),
+            "a &lt; b &amp;&amp; &quot;c&quot;"
+        );
+    }
+}
@@ -1,9 +1,25 @@
-use std::io;
+use std::{
+    fs,
+    io::{self, Read, Write},
+    sync::mpsc,
+    thread,
+};
 
 use anyhow::Context;
-use riscv::{executor::Executor, lex::Lexer, parse::Program};
+use riscv::{
+    executor::{uart::Uart, Config, ExecErrorInner, Executor},
+    listing,
+};
 
 fn main() -> anyhow::Result<()> {
+    let args: Vec<_> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("listing") {
+        return print_listing(&args[1..]);
+    }
+    if args.first().map(String::as_str) == Some("run") {
+        return run(&args[1..]);
+    }
+
     let mut program = indoc::indoc! {"
         li a0, 0x100
         li a1, 0x100
@@ -123,6 +139,75 @@ fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
+/// `riscv listing [--html] [--compressed] FILE` prints an assembly listing of
+/// `FILE`, as text or an HTML table.
+fn print_listing(args: &[String]) -> anyhow::Result<()> {
+    let mut config = Config::default();
+    let mut html = false;
+    let mut path = None;
+    for arg in args {
+        match arg.as_str() {
+            "--html" => html = true,
+            "--compressed" => config.c = true,
+            _ => path = Some(arg),
+        }
+    }
+    let path = path.context("usage: riscv listing [--html] [--compressed] FILE")?;
+    let source = fs::read_to_string(path).with_context(|| format!("failed to read {path}"))?;
+    let rows = listing::listing(&source, &config)?;
+    if html {
+        print!("{}", listing::html(&rows));
+    } else {
+        print!("{}", listing::text(&rows));
+    }
+    Ok(())
+}
+
+/// `riscv run FILE` runs `FILE` with a UART connected to the terminal. Bytes
+/// from stdin are queued for the program as they arrive, so it can prompt for
+/// input, and what the program transmits goes to stdout as it runs.
+fn run(args: &[String]) -> anyhow::Result<()> {
+    let [path] = args else {
+        anyhow::bail!("usage: riscv run FILE");
+    };
+    let source = fs::read_to_string(path).with_context(|| format!("failed to read {path}"))?;
+    let mut config = Config::default();
+    config.uart = true;
+    // Nothing is reverted, and a run can wait for input forever
+    config.history_limit = Some(0);
+    let mut exec = Executor::from_source(&source, config)?;
+
+    // Reading blocks, so it happens on another thread
+    let (sender, input) = mpsc::channel();
+    thread::spawn(move || {
+        let mut stdin = io::stdin();
+        let mut buf = [0; 1024];
+        while let Ok(len @ 1..) = stdin.read(&mut buf) {
+            if sender.send(buf[..len].to_vec()).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut stdout = io::stdout();
+    let mut printed = 0;
+    loop {
+        for bytes in input.try_iter() {
+            exec.memory.device_mut::<Uart>().unwrap().push_input(&bytes);
+        }
+        let result = exec.execute();
+        let output = &exec.memory.device::<Uart>().unwrap().output()[printed..];
+        stdout.write_all(output)?;
+        stdout.flush()?;
+        printed += output.len();
+        match result {
+            Ok(_) => (),
+            Err(e) if matches!(e.error(), ExecErrorInner::Finished) => return Ok(()),
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
 fn repl(mut exec: Executor) {
     use crossterm::{
         execute,
@@ -5,6 +5,8 @@ use std::collections::HashMap;
 use std::str::FromStr;
 use thiserror::Error;
 
+use crate::encode::load_immediate_64;
+use crate::executor::Xlen;
 use crate::lex::Token;
 use crate::lex::{Lexer, Span, TokenInner};
 
@@ -28,55 +30,210 @@ pub enum RegisterParseError {
     ParseError(String),
 }
 
-impl TryFrom<Token> for Register {
-    type Error = RegisterParseError;
-
-    fn try_from(token: Token) -> Result<Self, Self::Error> {
-        match token.inner() {
-            TokenInner::Ident(ident) => ident.parse().map_err(RegisterParseError::ParseError),
-            other => Err(RegisterParseError::InvalidToken(other.to_string())),
-        }
-    }
-}
-
-/// Implement parse, display for Register
+/// Implement parse, display and conversion from a [`Token`] for a register type.
+/// Besides the ABI and numeric names, a register can have extra aliases, like
+/// `zero` for `x0`.
 macro_rules! register_impls {
-    ($( ($reg:ident = $xreg:ident) )*) => {
-        impl std::str::FromStr for $crate::parse::Register {
+    ($regty:ident { $( ($reg:ident = $xreg:ident) )* } $( $alias:literal => $aliased:ident )*) => {
+        impl std::str::FromStr for $crate::parse::$regty {
             type Err = String;
-            fn from_str(s: &str) -> Result<Register, Self::Err> {
+            fn from_str(s: &str) -> Result<$regty, Self::Err> {
                 match s.trim() {
                     $(
                         stringify!($reg) | stringify!($xreg)
-                            => Ok(Register::$reg),
+                            => Ok($regty::$reg),
+                    )*
+                    $(
+                        $alias => Ok($regty::$aliased),
                     )*
-                    "zero" => Ok(Register::x0),
                     unknown => Err(format!("unrecognized register {unknown}"))
                 }
             }
         }
-        impl std::fmt::Display for $crate::parse::Register {
+        impl std::fmt::Display for $crate::parse::$regty {
             fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
                 match self {
                     $(
-                        Register::$reg => write!(f, "{}", stringify!($reg)),
+                        $regty::$reg => write!(f, "{}", stringify!($reg)),
                     )*
                 }
             }
         }
+        impl TryFrom<Token> for $crate::parse::$regty {
+            type Error = RegisterParseError;
+
+            fn try_from(token: Token) -> Result<Self, Self::Error> {
+                match token.inner() {
+                    TokenInner::Ident(ident) => ident.parse().map_err(RegisterParseError::ParseError),
+                    other => Err(RegisterParseError::InvalidToken(other.to_string())),
+                }
+            }
+        }
+    }
+}
+
+register_impls! {
+    Register {
+       (x0 = x0) (ra = x1) (sp = x2) (gp = x3) (tp = x4)
+
+       (t0 = x5) (t1 = x6) (t2 = x7) (t3 = x28) (t4 = x29) (t5 = x30) (t6 = x31)
+
+       (a0 = x10) (a1 = x11) (a2 = x12) (a3 = x13)
+       (a4 = x14) (a5 = x15) (a6 = x16) (a7 = x17)
+
+       (s0 = x8) (s1 = x9) (s2 = x18) (s3 = x19) (s4 = x20) (s5 = x21) (s6 = x22)
+       (s7 = x23) (s8 = x24) (s9 = x25) (s10 = x26) (s11 = x27)
+    }
+    "zero" => x0
+}
+
+impl Register {
+    /// The architectural register number, as in the `10` of `x10`.
+    pub fn number(self) -> u32 {
+        use Register::*;
+        match self {
+            x0 => 0,
+            ra => 1,
+            sp => 2,
+            gp => 3,
+            tp => 4,
+            t0 => 5,
+            t1 => 6,
+            t2 => 7,
+            s0 => 8,
+            s1 => 9,
+            a0 => 10,
+            a1 => 11,
+            a2 => 12,
+            a3 => 13,
+            a4 => 14,
+            a5 => 15,
+            a6 => 16,
+            a7 => 17,
+            s2 => 18,
+            s3 => 19,
+            s4 => 20,
+            s5 => 21,
+            s6 => 22,
+            s7 => 23,
+            s8 => 24,
+            s9 => 25,
+            s10 => 26,
+            s11 => 27,
+            t3 => 28,
+            t4 => 29,
+            t5 => 30,
+            t6 => 31,
+        }
+    }
+
+    /// Whether the register exists in `base`. RV32E only has `x0`-`x15`.
+    pub fn available(&self, base: Base) -> bool {
+        use Register::*;
+        base == Base::I
+            || !matches!(
+                self,
+                a6 | a7 | s2 | s3 | s4 | s5 | s6 | s7 | s8 | s9 | s10 | s11 | t3 | t4 | t5 | t6
+            )
+    }
+}
+
+/// The base integer ISA.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Base {
+    /// The regular base ISA, with 32 integer registers.
+    #[default]
+    I,
+    /// The embedded base ISA, with only 16 integer registers.
+    E,
+}
+
+/// The letter of the base ISA, which follows the XLEN in its full name, like
+/// `RV32E`.
+impl fmt::Display for Base {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Base::I => write!(f, "I"),
+            Base::E => write!(f, "E"),
+        }
+    }
+}
+
+/// A register type that can be parsed by [`Lexer::register`].
+trait ParseRegister: TryFrom<Token, Error = RegisterParseError> + fmt::Display {
+    /// Whether the register exists in `base`.
+    fn available(&self, base: Base) -> bool;
+}
+
+impl ParseRegister for Register {
+    fn available(&self, base: Base) -> bool {
+        Register::available(self, base)
+    }
+}
+
+impl ParseRegister for FRegister {
+    fn available(&self, _base: Base) -> bool {
+        true
     }
 }
 
+/// A floating point register (RV32F). Unlike [`Register`], the variants are
+/// declared in architectural order, so `freg as usize` is the register number.
+#[allow(non_camel_case_types)]
+#[rustfmt::skip]
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy)]
+pub enum FRegister {
+    ft0, ft1, ft2, ft3, ft4, ft5, ft6, ft7,
+    fs0, fs1,
+    fa0, fa1, fa2, fa3, fa4, fa5, fa6, fa7,
+    fs2, fs3, fs4, fs5, fs6, fs7, fs8, fs9, fs10, fs11,
+    ft8, ft9, ft10, ft11,
+}
+
 register_impls! {
-   (x0 = x0) (ra = x1) (sp = x2) (gp = x3) (tp = x4)
+    FRegister {
+       (ft0 = f0) (ft1 = f1) (ft2 = f2) (ft3 = f3)
+       (ft4 = f4) (ft5 = f5) (ft6 = f6) (ft7 = f7)
+
+       (fs0 = f8) (fs1 = f9)
 
-   (t0 = x5) (t1 = x6) (t2 = x7) (t3 = x28) (t4 = x29) (t5 = x30) (t6 = x31)
+       (fa0 = f10) (fa1 = f11) (fa2 = f12) (fa3 = f13)
+       (fa4 = f14) (fa5 = f15) (fa6 = f16) (fa7 = f17)
+
+       (fs2 = f18) (fs3 = f19) (fs4 = f20) (fs5 = f21) (fs6 = f22)
+       (fs7 = f23) (fs8 = f24) (fs9 = f25) (fs10 = f26) (fs11 = f27)
+
+       (ft8 = f28) (ft9 = f29) (ft10 = f30) (ft11 = f31)
+    }
+}
+
+impl ParseRegister for VRegister {
+    fn available(&self, _base: Base) -> bool {
+        true
+    }
+}
 
-   (a0 = x10) (a1 = x11) (a2 = x12) (a3 = x13)
-   (a4 = x14) (a5 = x15) (a6 = x16) (a7 = x17)
+/// A vector register (RVV), in architectural order.
+#[allow(non_camel_case_types)]
+#[rustfmt::skip]
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy)]
+pub enum VRegister {
+    v0, v1, v2, v3, v4, v5, v6, v7,
+    v8, v9, v10, v11, v12, v13, v14, v15,
+    v16, v17, v18, v19, v20, v21, v22, v23,
+    v24, v25, v26, v27, v28, v29, v30, v31,
+}
 
-   (s0 = x8) (s1 = x9) (s2 = x18) (s3 = x19) (s4 = x20) (s5 = x21) (s6 = x22)
-   (s7 = x23) (s8 = x24) (s9 = x25) (s10 = x26) (s11 = x27)
+register_impls! {
+    VRegister {
+       (v0 = v0) (v1 = v1) (v2 = v2) (v3 = v3) (v4 = v4) (v5 = v5) (v6 = v6) (v7 = v7)
+       (v8 = v8) (v9 = v9) (v10 = v10) (v11 = v11)
+       (v12 = v12) (v13 = v13) (v14 = v14) (v15 = v15)
+       (v16 = v16) (v17 = v17) (v18 = v18) (v19 = v19)
+       (v20 = v20) (v21 = v21) (v22 = v22) (v23 = v23)
+       (v24 = v24) (v25 = v25) (v26 = v26) (v27 = v27)
+       (v28 = v28) (v29 = v29) (v30 = v30) (v31 = v31)
+    }
 }
 
 /// Declares an instruction type, as in `RegImm` or `Branch`.
@@ -99,6 +256,10 @@ register_impls! {
 ///     Fma
 /// }
 ///
+/// impl Vector {
+///     pub const ALL: &'static [Self] = &[Self::Broadcast, Self::Fma];
+/// }
+///
 /// impl FromStr for Vector {
 ///     type Err = anyhow::Error;
 ///     fn from_str(s: &str) -> anyhow::Result<Self> {
@@ -129,6 +290,11 @@ macro_rules! declare_instruction_set {
             $($opname),+
         }
 
+        impl $setname {
+            /// Every operation in the set.
+            pub const ALL: &'static [Self] = &[$(Self::$opname),+];
+        }
+
         impl ::std::str::FromStr for $setname {
             type Err = ::anyhow::Error;
 
@@ -163,6 +329,27 @@ declare_instruction_set!(
     Sra => "sra",
     Or => "or",
     And => "and",
+    Addw => "addw",
+    Subw => "subw",
+    Sllw => "sllw",
+    Srlw => "srlw",
+    Sraw => "sraw",
+    Sh1add => "sh1add",
+    Sh2add => "sh2add",
+    Sh3add => "sh3add",
+    Min => "min",
+    Max => "max",
+    Minu => "minu",
+    Maxu => "maxu",
+    Rol => "rol",
+    Ror => "ror",
+    Andn => "andn",
+    Orn => "orn",
+    Xnor => "xnor",
+    Bset => "bset",
+    Bclr => "bclr",
+    Binv => "binv",
+    Bext => "bext",
 );
 
 declare_instruction_set!(
@@ -177,11 +364,21 @@ declare_instruction_set!(
     Slli => "slli",
     Srli => "srli",
     Srai => "srai",
+    Addiw => "addiw",
+    Slliw => "slliw",
+    Srliw => "srliw",
+    Sraiw => "sraiw",
+    Rori => "rori",
+    Bseti => "bseti",
+    Bclri => "bclri",
+    Binvi => "binvi",
+    Bexti => "bexti",
 );
 
 declare_instruction_set!(
     StoreOp,
     "store",
+    Sd => "sd",
     Sw => "sw",
     Sh => "sh",
     Sb => "sb",
@@ -190,13 +387,39 @@ declare_instruction_set!(
 declare_instruction_set!(
     LoadOp,
     "load",
+    Ld => "ld",
     Lw => "lw",
+    Lwu => "lwu",
     Lh => "lh",
     Lhu => "lhu",
     Lb => "lb",
     Lbu => "lbu",
 );
 
+impl StoreOp {
+    /// The number of bytes stored.
+    pub fn size(self) -> i64 {
+        match self {
+            StoreOp::Sd => 8,
+            StoreOp::Sw => 4,
+            StoreOp::Sh => 2,
+            StoreOp::Sb => 1,
+        }
+    }
+}
+
+impl LoadOp {
+    /// The number of bytes loaded.
+    pub fn size(self) -> i64 {
+        match self {
+            LoadOp::Ld => 8,
+            LoadOp::Lw | LoadOp::Lwu => 4,
+            LoadOp::Lh | LoadOp::Lhu => 2,
+            LoadOp::Lb | LoadOp::Lbu => 1,
+        }
+    }
+}
+
 declare_instruction_set!(
     BranchOp,
     "branch",
@@ -227,6 +450,7 @@ declare_instruction_set!(
     LoadImmOp,
     "load-immediate",
     Lui => "lui",
+    Auipc => "auipc",
     Li => "li",
 );
 
@@ -236,8 +460,545 @@ declare_instruction_set!(
     Mv => "mv",
     Not => "not",
     Neg => "neg",
+    NegW => "negw",
+    SextW => "sext.w",
+    Clz => "clz",
+    Ctz => "ctz",
+    Cpop => "cpop",
+    SextB => "sext.b",
+    SextH => "sext.h",
+    ZextH => "zext.h",
+    Rev8 => "rev8",
+    OrcB => "orc.b",
+);
+
+declare_instruction_set!(
+    CompressedOp,
+    "compressed",
+    Addi4spn => "c.addi4spn",
+    Lw => "c.lw",
+    Sw => "c.sw",
+    Nop => "c.nop",
+    Addi => "c.addi",
+    Jal => "c.jal",
+    Li => "c.li",
+    Addi16sp => "c.addi16sp",
+    Lui => "c.lui",
+    Srli => "c.srli",
+    Srai => "c.srai",
+    Andi => "c.andi",
+    Sub => "c.sub",
+    Xor => "c.xor",
+    Or => "c.or",
+    And => "c.and",
+    J => "c.j",
+    Beqz => "c.beqz",
+    Bnez => "c.bnez",
+    Slli => "c.slli",
+    Lwsp => "c.lwsp",
+    Jr => "c.jr",
+    Mv => "c.mv",
+    Jalr => "c.jalr",
+    Add => "c.add",
+    Swsp => "c.swsp",
+);
+
+/// Optional extensions that can be enabled separately in the executor.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Extension {
+    /// Address generation
+    Zba,
+    /// Basic bit manipulation
+    Zbb,
+    /// Single-bit instructions
+    Zbs,
+    /// Vectors
+    V,
+    /// Compressed instructions
+    C,
+}
+
+impl Extension {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Extension::Zba => "Zba",
+            Extension::Zbb => "Zbb",
+            Extension::Zbs => "Zbs",
+            Extension::V => "V",
+            Extension::C => "C",
+        }
+    }
+}
+
+impl fmt::Display for Extension {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+declare_instruction_set!(
+    AmoOp,
+    "atomic memory",
+    Swap => "amoswap.w",
+    Add => "amoadd.w",
+    And => "amoand.w",
+    Or => "amoor.w",
+    Xor => "amoxor.w",
+    Min => "amomin.w",
+    Max => "amomax.w",
+    Minu => "amominu.w",
+    Maxu => "amomaxu.w",
+);
+
+declare_instruction_set!(
+    FRegRegOp,
+    "floating point register-register",
+    Add => "fadd.s",
+    Sub => "fsub.s",
+    Mul => "fmul.s",
+    Div => "fdiv.s",
+    Min => "fmin.s",
+    Max => "fmax.s",
+    Sgnj => "fsgnj.s",
+    Sgnjn => "fsgnjn.s",
+    Sgnjx => "fsgnjx.s",
+);
+
+impl FRegRegOp {
+    /// Whether the result of this operation can be inexact, meaning it can take
+    /// a rounding mode.
+    pub fn takes_rounding_mode(self) -> bool {
+        matches!(self, Self::Add | Self::Sub | Self::Mul | Self::Div)
+    }
+}
+
+declare_instruction_set!(
+    FFusedOp,
+    "fused multiply-add",
+    Madd => "fmadd.s",
+    Msub => "fmsub.s",
+    Nmsub => "fnmsub.s",
+    Nmadd => "fnmadd.s",
+);
+
+declare_instruction_set!(
+    FCompareOp,
+    "floating point comparison",
+    Eq => "feq.s",
+    Lt => "flt.s",
+    Le => "fle.s",
 );
 
+declare_instruction_set!(
+    FToIntOp,
+    "float-to-integer",
+    CvtW => "fcvt.w.s",
+    CvtWu => "fcvt.wu.s",
+    MvXW => "fmv.x.w",
+);
+
+declare_instruction_set!(
+    IntToFOp,
+    "integer-to-float",
+    CvtSW => "fcvt.s.w",
+    CvtSWu => "fcvt.s.wu",
+    MvWX => "fmv.w.x",
+);
+
+declare_instruction_set!(
+    ElementWidth,
+    "element width",
+    E8 => "e8",
+    E16 => "e16",
+    E32 => "e32",
+    E64 => "e64",
+);
+
+impl ElementWidth {
+    pub fn bytes(self) -> usize {
+        match self {
+            Self::E8 => 1,
+            Self::E16 => 2,
+            Self::E32 => 4,
+            Self::E64 => 8,
+        }
+    }
+
+    pub fn bits(self) -> u32 {
+        self.bytes() as u32 * 8
+    }
+
+    /// The width of a unit-stride or strided load or store, as in the `32` of
+    /// `vle32.v`.
+    fn from_bits(bits: &str) -> Option<Self> {
+        match bits {
+            "8" => Some(Self::E8),
+            "16" => Some(Self::E16),
+            "32" => Some(Self::E32),
+            "64" => Some(Self::E64),
+            _ => None,
+        }
+    }
+}
+
+/// The vector configuration set by `vsetvli`: the width of each element, how
+/// many registers are grouped together (LMUL), and whether tail and inactive
+/// elements may be overwritten. Only whole-register groups are supported.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct VType {
+    pub sew: ElementWidth,
+    /// 1, 2, 4 or 8
+    pub lmul: usize,
+    pub tail_agnostic: bool,
+    pub mask_agnostic: bool,
+}
+
+impl VType {
+    /// Encode as the value of the `vtype` CSR.
+    pub fn bits(self) -> i64 {
+        let vlmul = self.lmul.trailing_zeros() as i64;
+        let vsew = self.sew.bytes().trailing_zeros() as i64;
+        vlmul | vsew << 3 | (self.tail_agnostic as i64) << 6 | (self.mask_agnostic as i64) << 7
+    }
+
+    /// Decode the value of the `vtype` CSR. Returns `None` if `vill` is set or
+    /// the configuration isn't supported.
+    pub fn from_bits(bits: i64) -> Option<Self> {
+        if bits < 0 || bits >> 8 != 0 {
+            return None;
+        }
+        let lmul = match bits & 0b111 {
+            vlmul @ 0..=3 => 1 << vlmul,
+            _ => return None,
+        };
+        let sew = match (bits >> 3) & 0b111 {
+            0 => ElementWidth::E8,
+            1 => ElementWidth::E16,
+            2 => ElementWidth::E32,
+            3 => ElementWidth::E64,
+            _ => return None,
+        };
+        Some(Self {
+            sew,
+            lmul,
+            tail_agnostic: bits & (1 << 6) != 0,
+            mask_agnostic: bits & (1 << 7) != 0,
+        })
+    }
+}
+
+impl Default for VType {
+    fn default() -> Self {
+        Self {
+            sew: ElementWidth::E8,
+            lmul: 1,
+            tail_agnostic: false,
+            mask_agnostic: false,
+        }
+    }
+}
+
+impl fmt::Display for VType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ta = if self.tail_agnostic { "ta" } else { "tu" };
+        let ma = if self.mask_agnostic { "ma" } else { "mu" };
+        write!(f, "{}, m{}, {ta}, {ma}", self.sew, self.lmul)
+    }
+}
+
+/// The second source of a vector instruction, which picks between the `.vv`,
+/// `.vx` and `.vi` forms.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum VOperand {
+    Vector(VRegister),
+    Scalar(Register),
+    /// A 5-bit signed immediate
+    Immediate(i32),
+}
+
+impl VOperand {
+    /// The suffix of the instruction in this form, as in `vv`.
+    fn suffix(self) -> &'static str {
+        match self {
+            Self::Vector(_) => "vv",
+            Self::Scalar(_) => "vx",
+            Self::Immediate(_) => "vi",
+        }
+    }
+}
+
+impl fmt::Display for VOperand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Vector(reg) => write!(f, "{reg}"),
+            Self::Scalar(reg) => write!(f, "{reg}"),
+            Self::Immediate(imm) => write!(f, "{imm}"),
+        }
+    }
+}
+
+/// Formats the optional trailing `v0.t` operand of a masked vector instruction.
+fn mask_operand(masked: bool) -> &'static str {
+    if masked {
+        ", v0.t"
+    } else {
+        ""
+    }
+}
+
+/// The major opcodes reserved for custom extensions.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub enum CustomOpcode {
+    Custom0,
+    Custom1,
+    Custom2,
+    Custom3,
+}
+
+impl CustomOpcode {
+    /// The 7-bit opcode.
+    pub fn bits(self) -> u32 {
+        match self {
+            CustomOpcode::Custom0 => 0b0001011,
+            CustomOpcode::Custom1 => 0b0101011,
+            CustomOpcode::Custom2 => 0b1011011,
+            CustomOpcode::Custom3 => 0b1111011,
+        }
+    }
+}
+
+/// Where a custom instruction sits in the encoding space.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub struct CustomEncoding {
+    pub opcode: CustomOpcode,
+    pub funct3: u8,
+    /// Only used by the R format.
+    pub funct7: u8,
+}
+
+impl CustomEncoding {
+    pub fn new(opcode: CustomOpcode, funct3: u8, funct7: u8) -> Self {
+        Self {
+            opcode,
+            funct3,
+            funct7,
+        }
+    }
+}
+
+/// The operands a custom instruction takes.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub enum CustomFormat {
+    /// `rd, rs1, rs2`
+    R,
+    /// `rd, rs1, imm`, with a 12-bit signed immediate
+    I,
+    /// `rs2, offset(rs1)`, with a 12-bit signed offset
+    S,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum CustomOperands {
+    R {
+        rd: Register,
+        r1: Register,
+        r2: Register,
+    },
+    I {
+        rd: Register,
+        r1: Register,
+        imm: i32,
+    },
+    S {
+        r2: Register,
+        offset: i32,
+        r1: Register,
+    },
+}
+
+impl fmt::Display for CustomOperands {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CustomOperands::R { rd, r1, r2 } => write!(f, "{rd}, {r1}, {r2}"),
+            CustomOperands::I { rd, r1, imm } => write!(f, "{rd}, {r1}, {imm}"),
+            CustomOperands::S { r2, offset, r1 } => write!(f, "{r2}, {offset}({r1})"),
+        }
+    }
+}
+
+declare_instruction_set!(
+    VArithOp,
+    "vector arithmetic",
+    Add => "vadd",
+    Sub => "vsub",
+    Mul => "vmul",
+    And => "vand",
+    Or => "vor",
+    Xor => "vxor",
+);
+
+declare_instruction_set!(
+    VCompareOp,
+    "vector comparison",
+    Eq => "vmseq",
+    Ne => "vmsne",
+    Lt => "vmslt",
+    Ltu => "vmsltu",
+    Le => "vmsle",
+    Leu => "vmsleu",
+    Gt => "vmsgt",
+    Gtu => "vmsgtu",
+);
+
+declare_instruction_set!(
+    VReduceOp,
+    "vector reduction",
+    Sum => "vredsum",
+    And => "vredand",
+    Or => "vredor",
+    Xor => "vredxor",
+    Min => "vredmin",
+    Max => "vredmax",
+    Minu => "vredminu",
+    Maxu => "vredmaxu",
+);
+
+declare_instruction_set!(
+    VMaskOp,
+    "mask logical",
+    And => "vmand",
+    Or => "vmor",
+    Xor => "vmxor",
+    Nand => "vmnand",
+    Andn => "vmandn",
+);
+
+declare_instruction_set!(
+    RoundingMode,
+    "rounding mode",
+    Rne => "rne",
+    Rtz => "rtz",
+    Rdn => "rdn",
+    Rup => "rup",
+    Rmm => "rmm",
+    Dyn => "dyn",
+);
+
+impl RoundingMode {
+    /// Decode the `frm` field of `fcsr`. Returns `None` for the reserved modes.
+    pub fn from_frm(frm: u32) -> Option<Self> {
+        match frm {
+            0b000 => Some(Self::Rne),
+            0b001 => Some(Self::Rtz),
+            0b010 => Some(Self::Rdn),
+            0b011 => Some(Self::Rup),
+            0b100 => Some(Self::Rmm),
+            _ => None,
+        }
+    }
+
+    /// Formats the rounding mode as an optional trailing operand. The dynamic
+    /// rounding mode is the default, so it is left out.
+    fn operand(self) -> String {
+        match self {
+            Self::Dyn => String::new(),
+            other => format!(", {other}"),
+        }
+    }
+}
+
+declare_instruction_set!(
+    CsrRegOp,
+    "csr register",
+    Rw => "csrrw",
+    Rs => "csrrs",
+    Rc => "csrrc",
+);
+
+declare_instruction_set!(
+    CsrImmOp,
+    "csr immediate",
+    Rwi => "csrrwi",
+    Rsi => "csrrsi",
+    Rci => "csrrci",
+);
+
+/// Declares the set of control and status registers, with their names and
+/// 12-bit addresses. Works like [`declare_instruction_set`], but also generates
+/// conversions to and from the CSR address.
+macro_rules! declare_csrs {
+    ($($csr:ident => ($name:literal, $number:literal)),+ $(,)?) => {
+        /// A control and status register.
+        #[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+        pub enum Csr {
+            $($csr),+
+        }
+
+        impl Csr {
+            /// The 12-bit address of the CSR.
+            pub fn number(self) -> u16 {
+                match self {
+                    $(Self::$csr => $number,)+
+                }
+            }
+
+            pub fn from_number(number: u16) -> Option<Self> {
+                match number {
+                    $($number => Some(Self::$csr),)+
+                    _ => None,
+                }
+            }
+        }
+
+        impl FromStr for Csr {
+            type Err = anyhow::Error;
+
+            fn from_str(s: &str) -> anyhow::Result<Self> {
+                match s {
+                    $($name => Ok(Self::$csr),)+
+                    other => bail!("unknown csr: {other}"),
+                }
+            }
+        }
+
+        impl fmt::Display for Csr {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "{}", match self {
+                    $(Self::$csr => $name,)+
+                })
+            }
+        }
+    };
+}
+
+declare_csrs! {
+    Fflags => ("fflags", 0x001),
+    Frm => ("frm", 0x002),
+    Fcsr => ("fcsr", 0x003),
+    Vl => ("vl", 0xc20),
+    Vtype => ("vtype", 0xc21),
+    Vlenb => ("vlenb", 0xc22),
+    Sstatus => ("sstatus", 0x100),
+    Sie => ("sie", 0x104),
+    Stvec => ("stvec", 0x105),
+    Sscratch => ("sscratch", 0x140),
+    Sepc => ("sepc", 0x141),
+    Scause => ("scause", 0x142),
+    Stval => ("stval", 0x143),
+    Sip => ("sip", 0x144),
+    Satp => ("satp", 0x180),
+    Mstatus => ("mstatus", 0x300),
+    Medeleg => ("medeleg", 0x302),
+    Mideleg => ("mideleg", 0x303),
+    Mie => ("mie", 0x304),
+    Mtvec => ("mtvec", 0x305),
+    Mscratch => ("mscratch", 0x340),
+    Mepc => ("mepc", 0x341),
+    Mcause => ("mcause", 0x342),
+    Mtval => ("mtval", 0x343),
+    Mip => ("mip", 0x344),
+}
+
 #[rustfmt::skip]
 #[allow(non_camel_case_types)]
 #[derive(Serialize, Deserialize, Eq, PartialEq, Debug, Clone)]
@@ -247,10 +1008,49 @@ pub enum Instruction {
     Load { rd: Register, offset: i32, r1: Register, op: LoadOp },
     Store { r2: Register, offset: i32, r1: Register, op: StoreOp },
     Branch  { r1: Register, r2: Register, label: String, op: BranchOp },
-    LoadImm { rd: Register, imm: i32, op: LoadImmOp },
+    LoadImm { rd: Register, imm: i64, op: LoadImmOp },
     BranchZero { r1: Register, label: String, op: BranchZeroOp },
     Unary { rd: Register, r1: Register, op: UnaryOp },
 
+    // Atomics (RV32A). The address is always just (r1), with no offset
+    LoadReserved { rd: Register, r1: Register },
+    StoreConditional { rd: Register, r2: Register, r1: Register },
+    Amo { rd: Register, r2: Register, r1: Register, op: AmoOp },
+
+    // Single-precision floating point (RV32F)
+    FLoad { rd: FRegister, offset: i32, r1: Register },
+    FStore { r2: FRegister, offset: i32, r1: Register },
+    FRegReg { rd: FRegister, r1: FRegister, r2: FRegister, op: FRegRegOp, rm: RoundingMode },
+    FSqrt { rd: FRegister, r1: FRegister, rm: RoundingMode },
+    FFused { rd: FRegister, r1: FRegister, r2: FRegister, r3: FRegister, op: FFusedOp, rm: RoundingMode },
+    FCompare { rd: Register, r1: FRegister, r2: FRegister, op: FCompareOp },
+    FClass { rd: Register, r1: FRegister },
+    FToInt { rd: Register, r1: FRegister, op: FToIntOp, rm: RoundingMode },
+    IntToF { rd: FRegister, r1: Register, op: IntToFOp, rm: RoundingMode },
+
+    // Control and status registers (Zicsr)
+    CsrReg { rd: Register, csr: Csr, r1: Register, op: CsrRegOp },
+    CsrImm { rd: Register, csr: Csr, imm: i32, op: CsrImmOp },
+
+    // Vectors (a subset of RVV). Masked instructions only touch the elements
+    // whose bit is set in v0 (`v0.t`)
+    Vsetvli { rd: Register, r1: Register, vtype: VType },
+    // Note: without a stride, elements are packed next to each other
+    VLoad { vd: VRegister, r1: Register, stride: Option<Register>, width: ElementWidth, masked: bool },
+    VStore { vs3: VRegister, r1: Register, stride: Option<Register>, width: ElementWidth, masked: bool },
+    VArith { vd: VRegister, vs2: VRegister, operand: VOperand, op: VArithOp, masked: bool },
+    VCompare { vd: VRegister, vs2: VRegister, operand: VOperand, op: VCompareOp, masked: bool },
+    VReduce { vd: VRegister, vs2: VRegister, vs1: VRegister, op: VReduceOp, masked: bool },
+    VMask { vd: VRegister, vs2: VRegister, vs1: VRegister, op: VMaskOp },
+    // vmv.v.v, vmv.v.x and vmv.v.i
+    VMove { vd: VRegister, operand: VOperand },
+    // vmv.x.s: copy element 0 to an integer register
+    VMoveToScalar { rd: Register, vs2: VRegister },
+    // vmv.s.x: copy an integer register to element 0
+    VMoveFromScalar { vd: VRegister, r1: Register },
+    VCpop { rd: Register, vs2: VRegister, masked: bool },
+    Vid { vd: VRegister, masked: bool },
+
     // Calling and jumping
     call        { label: String },
     // Note: if a register is not provided, assume rd
@@ -261,6 +1061,134 @@ pub enum Instruction {
     j           { label: String },
     jr          { rs: Register },
     ret         {},
+
+    // Return from a machine-mode trap handler
+    mret        {},
+    // Return from a supervisor-mode trap handler
+    sret        {},
+    // Trap into the next privilege mode up
+    ecall       {},
+    // Order page table updates before later accesses. Note: both operands are
+    // optional and default to zero
+    sfence_vma  { vaddr: Register, asid: Register },
+    // Wait for an interrupt. Implemented as a nop, which the spec allows
+    wfi         {},
+
+    // An instruction registered with the lexer's custom instructions
+    Custom      { mnemonic: String, encoding: CustomEncoding, operands: CustomOperands },
+
+    // A 2-byte RVC instruction, which runs as the base instruction it expands to
+    Compressed  { op: CompressedOp, instr: Box<Instruction> },
+}
+
+impl Instruction {
+    /// Whether the instruction only exists in RV64.
+    pub fn is_rv64_only(&self) -> bool {
+        use {LoadOp::*, RegImmOp::*, RegRegOp::*, StoreOp::*, UnaryOp::*};
+        matches!(
+            self,
+            Instruction::Load { op: Ld | Lwu, .. }
+                | Instruction::Store { op: Sd, .. }
+                | Instruction::RegImm {
+                    op: Addiw | Slliw | Srliw | Sraiw,
+                    ..
+                }
+                | Instruction::RegReg {
+                    op: Addw | Subw | Sllw | Srlw | Sraw,
+                    ..
+                }
+                | Instruction::Unary {
+                    op: NegW | SextW,
+                    ..
+                }
+        )
+    }
+
+    /// The optional extension the instruction belongs to, if any.
+    pub fn extension(&self) -> Option<Extension> {
+        use {RegImmOp::*, RegRegOp::*, UnaryOp::*};
+        match self {
+            Instruction::RegReg {
+                op: Sh1add | Sh2add | Sh3add,
+                ..
+            } => Some(Extension::Zba),
+            Instruction::RegReg {
+                op: Min | Max | Minu | Maxu | Rol | Ror | Andn | Orn | Xnor,
+                ..
+            }
+            | Instruction::RegImm { op: Rori, .. }
+            | Instruction::Unary {
+                op: Clz | Ctz | Cpop | SextB | SextH | ZextH | Rev8 | OrcB,
+                ..
+            } => Some(Extension::Zbb),
+            Instruction::RegReg {
+                op: Bset | Bclr | Binv | Bext,
+                ..
+            }
+            | Instruction::RegImm {
+                op: Bseti | Bclri | Binvi | Bexti,
+                ..
+            } => Some(Extension::Zbs),
+            Instruction::Vsetvli { .. }
+            | Instruction::VLoad { .. }
+            | Instruction::VStore { .. }
+            | Instruction::VArith { .. }
+            | Instruction::VCompare { .. }
+            | Instruction::VReduce { .. }
+            | Instruction::VMask { .. }
+            | Instruction::VMove { .. }
+            | Instruction::VMoveToScalar { .. }
+            | Instruction::VMoveFromScalar { .. }
+            | Instruction::VCpop { .. }
+            | Instruction::Vid { .. } => Some(Extension::V),
+            Instruction::Compressed { .. } => Some(Extension::C),
+            _ => None,
+        }
+    }
+
+    /// The number of bytes the instruction takes up. Pseudo-instructions that
+    /// stand for two base instructions, like `call` or `li` with an immediate
+    /// that needs both `lui` and `addi`, take up 8. In RV64, `li` with an
+    /// immediate that doesn't fit in 32 bits can take up to 32.
+    pub fn size(&self) -> i64 {
+        match self {
+            Instruction::Compressed { .. } => 2,
+            Instruction::LoadImm {
+                rd,
+                imm,
+                op: LoadImmOp::Li,
+            } if i32::try_from(*imm).is_err() => 4 * load_immediate_64(*rd, *imm).len() as i64,
+            Instruction::LoadImm {
+                imm,
+                op: LoadImmOp::Li,
+                ..
+            } if !(-2048..2048).contains(imm) && imm & 0xfff != 0 => 8,
+            Instruction::la { .. } | Instruction::call { .. } => 8,
+            _ => 4,
+        }
+    }
+
+    /// The base instruction a compressed instruction expands to, or the
+    /// instruction itself.
+    pub fn expanded(&self) -> &Instruction {
+        match self {
+            Instruction::Compressed { instr, .. } => instr,
+            instr => instr,
+        }
+    }
+
+    /// The label the instruction jumps to or loads, if any.
+    pub fn label(&self) -> Option<&str> {
+        match self.expanded() {
+            Instruction::Branch { label, .. }
+            | Instruction::BranchZero { label, .. }
+            | Instruction::call { label }
+            | Instruction::jal { label, .. }
+            | Instruction::j { label }
+            | Instruction::la { label, .. } => Some(label),
+            _ => None,
+        }
+    }
 }
 
 impl fmt::Display for Instruction {
@@ -271,9 +1199,118 @@ impl fmt::Display for Instruction {
             Instruction::Load { rd, offset, r1, op } => write!(f, "{op} {rd}, {offset}({r1})"),
             Instruction::Store { r2, offset, r1, op } => write!(f, "{op} {r2}, {offset}({r1})"),
             Instruction::Branch { r1, r2, label, op } => write!(f, "{op} {r1}, {r2}, {label}"),
-            Instruction::LoadImm { rd, imm, op } => write!(f, "{op} {rd} {imm}"),
+            Instruction::LoadImm { rd, imm, op } => write!(f, "{op} {rd}, {imm}"),
             Instruction::BranchZero { r1, label, op } => write!(f, "{op} {r1}, {label}"),
             Instruction::Unary { rd, r1, op } => write!(f, "{op} {rd}, {r1}"),
+            Instruction::LoadReserved { rd, r1 } => write!(f, "lr.w {rd}, ({r1})"),
+            Instruction::StoreConditional { rd, r2, r1 } => write!(f, "sc.w {rd}, {r2}, ({r1})"),
+            Instruction::Amo { rd, r2, r1, op } => write!(f, "{op} {rd}, {r2}, ({r1})"),
+            Instruction::FLoad { rd, offset, r1 } => write!(f, "flw {rd}, {offset}({r1})"),
+            Instruction::FStore { r2, offset, r1 } => write!(f, "fsw {r2}, {offset}({r1})"),
+            Instruction::FRegReg { rd, r1, r2, op, rm } => {
+                write!(f, "{op} {rd}, {r1}, {r2}{}", rm.operand())
+            }
+            Instruction::FSqrt { rd, r1, rm } => write!(f, "fsqrt.s {rd}, {r1}{}", rm.operand()),
+            Instruction::FFused {
+                rd,
+                r1,
+                r2,
+                r3,
+                op,
+                rm,
+            } => write!(f, "{op} {rd}, {r1}, {r2}, {r3}{}", rm.operand()),
+            Instruction::FCompare { rd, r1, r2, op } => write!(f, "{op} {rd}, {r1}, {r2}"),
+            Instruction::FClass { rd, r1 } => write!(f, "fclass.s {rd}, {r1}"),
+            Instruction::FToInt { rd, r1, op, rm } => {
+                write!(f, "{op} {rd}, {r1}{}", rm.operand())
+            }
+            Instruction::IntToF { rd, r1, op, rm } => {
+                write!(f, "{op} {rd}, {r1}{}", rm.operand())
+            }
+            Instruction::CsrReg { rd, csr, r1, op } => write!(f, "{op} {rd}, {csr}, {r1}"),
+            Instruction::CsrImm { rd, csr, imm, op } => write!(f, "{op} {rd}, {csr}, {imm}"),
+            Instruction::Vsetvli { rd, r1, vtype } => write!(f, "vsetvli {rd}, {r1}, {vtype}"),
+            Instruction::VLoad {
+                vd,
+                r1,
+                stride,
+                width,
+                masked,
+            } => match stride {
+                Some(stride) => write!(
+                    f,
+                    "vlse{}.v {vd}, ({r1}), {stride}{}",
+                    width.bits(),
+                    mask_operand(*masked)
+                ),
+                None => write!(
+                    f,
+                    "vle{}.v {vd}, ({r1}){}",
+                    width.bits(),
+                    mask_operand(*masked)
+                ),
+            },
+            Instruction::VStore {
+                vs3,
+                r1,
+                stride,
+                width,
+                masked,
+            } => match stride {
+                Some(stride) => write!(
+                    f,
+                    "vsse{}.v {vs3}, ({r1}), {stride}{}",
+                    width.bits(),
+                    mask_operand(*masked)
+                ),
+                None => write!(
+                    f,
+                    "vse{}.v {vs3}, ({r1}){}",
+                    width.bits(),
+                    mask_operand(*masked)
+                ),
+            },
+            Instruction::VArith {
+                vd,
+                vs2,
+                operand,
+                op,
+                masked,
+            } => write!(
+                f,
+                "{op}.{} {vd}, {vs2}, {operand}{}",
+                operand.suffix(),
+                mask_operand(*masked)
+            ),
+            Instruction::VCompare {
+                vd,
+                vs2,
+                operand,
+                op,
+                masked,
+            } => write!(
+                f,
+                "{op}.{} {vd}, {vs2}, {operand}{}",
+                operand.suffix(),
+                mask_operand(*masked)
+            ),
+            Instruction::VReduce {
+                vd,
+                vs2,
+                vs1,
+                op,
+                masked,
+            } => write!(f, "{op}.vs {vd}, {vs2}, {vs1}{}", mask_operand(*masked)),
+            Instruction::VMask { vd, vs2, vs1, op } => write!(f, "{op}.mm {vd}, {vs2}, {vs1}"),
+            Instruction::VMove { vd, operand } => {
+                write!(f, "vmv.v.{} {vd}, {operand}", &operand.suffix()[1..])
+            }
+            Instruction::VMoveToScalar { rd, vs2 } => write!(f, "vmv.x.s {rd}, {vs2}"),
+            Instruction::VMoveFromScalar { vd, r1 } => write!(f, "vmv.s.x {vd}, {r1}"),
+            Instruction::VCpop { rd, vs2, masked } => {
+                write!(f, "vcpop.m {rd}, {vs2}{}", mask_operand(*masked))
+            }
+            Instruction::Vid { vd, masked } => write!(f, "vid.v {vd}{}", mask_operand(*masked)),
             Instruction::call { label } => write!(f, "call {label}"),
             Instruction::jal { rd, label } => write!(f, "jal {rd}, {label}"),
             Instruction::la { rd, label } => write!(f, "la {rd}, {label}"),
@@ -281,6 +1318,42 @@ impl fmt::Display for Instruction {
             Instruction::j { label } => write!(f, "j {label}"),
             Instruction::jr { rs } => write!(f, "jr {rs}"),
             Instruction::ret {} => write!(f, "ret"),
+            Instruction::mret {} => write!(f, "mret"),
+            Instruction::sret {} => write!(f, "sret"),
+            Instruction::ecall {} => write!(f, "ecall"),
+            Instruction::sfence_vma { vaddr, asid } => write!(f, "sfence.vma {vaddr}, {asid}"),
+            Instruction::wfi {} => write!(f, "wfi"),
+            Instruction::Custom {
+                mnemonic, operands, ..
+            } => write!(f, "{mnemonic} {operands}"),
+            Instruction::Compressed { op, instr } => {
+                use CompressedOp::*;
+                match (op, &**instr) {
+                    (Nop, _) => write!(f, "{op}"),
+                    (Addi4spn, Instruction::RegImm { rd, imm, .. }) => {
+                        write!(f, "{op} {rd}, sp, {imm}")
+                    }
+                    (Addi16sp, Instruction::RegImm { imm, .. }) => write!(f, "{op} sp, {imm}"),
+                    (_, Instruction::RegImm { rd, imm, .. }) => write!(f, "{op} {rd}, {imm}"),
+                    (_, Instruction::LoadImm { rd, imm, .. }) => write!(f, "{op} {rd}, {imm}"),
+                    (_, Instruction::RegReg { rd, r2, .. }) => write!(f, "{op} {rd}, {r2}"),
+                    (_, Instruction::Load { rd, offset, r1, .. }) => {
+                        write!(f, "{op} {rd}, {offset}({r1})")
+                    }
+                    (_, Instruction::Store { r2, offset, r1, .. }) => {
+                        write!(f, "{op} {r2}, {offset}({r1})")
+                    }
+                    (_, Instruction::Branch { r1, label, .. }) => write!(f, "{op} {r1}, {label}"),
+                    (_, Instruction::j { label } | Instruction::jal { label, .. }) => {
+                        write!(f, "{op} {label}")
+                    }
+                    (_, Instruction::jr { rs: r1 } | Instruction::jalr { r1, .. }) => {
+                        write!(f, "{op} {r1}")
+                    }
+                    (_, Instruction::ret {}) => write!(f, "{op} ra"),
+                    (_, instr) => write!(f, "{instr}"),
+                }
+            }
         }
     }
 }
@@ -343,167 +1416,921 @@ impl<'a> Lexer<'a> {
         let ident = self.ident()?;
         let (ident, span) = ident.unwrap_ident();
         if let Ok(TokenInner::Colon) = self.colon().map(|token| token.inner()) {
+            // Only local labels, like `.L1`, can contain dots
+            if ident.contains('.') && !ident.starts_with('.') {
+                bail!("label {ident} can't contain '.' at {span}");
+            }
             return Ok(Item::Label { name: ident, span });
         }
 
         // We technically don't even need type hints here! I think it improves
         // readability though
         let instruction = if let Ok(op) = ident.parse::<RegImmOp>() {
-            let rd = self.ident()?.try_into()?;
+            let rd = self.register()?;
             let _ = self.comma()?;
-            let r1 = self.ident()?.try_into()?;
+            let r1 = self.register()?;
             let _ = self.comma()?;
             let neg = self.minus().is_ok();
-            let mut imm: i32 = self.constant()?.unwrap_constant().0;
+            let mut imm = self.constant32()?;
             if neg {
-                imm = -imm
+                imm = imm.wrapping_neg()
             }
             Instruction::RegImm { rd, r1, imm, op }
         } else if let Ok(op) = ident.parse::<RegRegOp>() {
-            let rd = self.ident()?.try_into()?;
+            let rd = self.register()?;
             let _ = self.comma()?;
-            let r1 = self.ident()?.try_into()?;
+            let r1 = self.register()?;
             let _ = self.comma()?;
-            let r2 = self.ident()?.try_into()?;
+            let r2 = self.register()?;
             Instruction::RegReg { rd, r1, r2, op }
         } else if let Ok(op) = ident.parse::<BranchOp>() {
-            let r1 = self.ident()?.try_into()?;
+            let r1 = self.register()?;
             let _ = self.comma()?;
-            let r2 = self.ident()?.try_into()?;
+            let r2 = self.register()?;
             let _ = self.comma()?;
             let label = self.ident()?.unwrap_ident().0;
             Instruction::Branch { r1, r2, label, op }
         } else if let Ok(op) = ident.parse::<BranchZeroOp>() {
-            let r1 = self.ident()?.try_into()?;
+            let r1 = self.register()?;
             let _ = self.comma()?;
             let label = self.ident()?.unwrap_ident().0;
             Instruction::BranchZero { r1, label, op }
         } else if let Ok(op) = ident.parse::<UnaryOp>() {
-            let rd = self.ident()?.try_into()?;
+            let rd = self.register()?;
             let _ = self.comma()?;
-            let r1 = self.ident()?.try_into()?;
+            let r1 = self.register()?;
             Instruction::Unary { rd, r1, op }
         } else if let Ok(op) = ident.parse::<StoreOp>() {
-            let r2 = self.ident()?.try_into()?;
+            let r2 = self.register()?;
             let _ = self.comma()?;
             let neg = self.minus().is_ok();
-            let mut offset = self.constant()?.unwrap_constant().0;
+            let mut offset = self.constant32()?;
             if neg {
-                offset = -offset
+                offset = offset.wrapping_neg()
             }
             let _ = self.left_paren()?;
-            let r1 = self.ident()?.try_into()?;
+            let r1 = self.register()?;
             let _ = self.right_paren()?;
             Instruction::Store { r2, offset, r1, op }
         } else if let Ok(op) = ident.parse::<LoadOp>() {
-            let rd = self.ident()?.try_into()?;
+            let rd = self.register()?;
+            let _ = self.comma()?;
+            let neg = self.minus().is_ok();
+            let mut offset = self.constant32()?;
+            if neg {
+                offset = offset.wrapping_neg()
+            }
+            let _ = self.left_paren()?;
+            let r1 = self.register()?;
+            let _ = self.right_paren()?;
+            Instruction::Load { rd, offset, r1, op }
+        } else if let Ok(op) = ident.parse::<LoadImmOp>() {
+            let rd = self.register()?;
+            let _ = self.comma()?;
+            let neg = self.minus().is_ok();
+            let (mut imm, span) = self.constant()?.unwrap_constant();
+            if neg {
+                imm = imm.wrapping_neg()
+            }
+            // Only li in RV64 loads more than 32 bits
+            if op != LoadImmOp::Li || self.xlen() == Xlen::Rv32 {
+                imm = wrap_i32(imm, &span)? as i64;
+            }
+            Instruction::LoadImm { rd, imm, op }
+        } else if let Ok(op) = ident.parse::<AmoOp>() {
+            let rd = self.register()?;
+            let _ = self.comma()?;
+            let r2 = self.register()?;
+            let _ = self.comma()?;
+            let r1 = self.atomic_address()?;
+            Instruction::Amo { rd, r2, r1, op }
+        } else if let Ok(op) = ident.parse::<FRegRegOp>() {
+            let rd = self.register()?;
+            let _ = self.comma()?;
+            let r1 = self.register()?;
+            let _ = self.comma()?;
+            let r2 = self.register()?;
+            let rm = if op.takes_rounding_mode() {
+                self.rounding_mode()?
+            } else {
+                RoundingMode::Dyn
+            };
+            Instruction::FRegReg { rd, r1, r2, op, rm }
+        } else if let Ok(op) = ident.parse::<FFusedOp>() {
+            let rd = self.register()?;
+            let _ = self.comma()?;
+            let r1 = self.register()?;
+            let _ = self.comma()?;
+            let r2 = self.register()?;
+            let _ = self.comma()?;
+            let r3 = self.register()?;
+            let rm = self.rounding_mode()?;
+            Instruction::FFused {
+                rd,
+                r1,
+                r2,
+                r3,
+                op,
+                rm,
+            }
+        } else if let Ok(op) = ident.parse::<FCompareOp>() {
+            let rd = self.register()?;
+            let _ = self.comma()?;
+            let r1 = self.register()?;
+            let _ = self.comma()?;
+            let r2 = self.register()?;
+            Instruction::FCompare { rd, r1, r2, op }
+        } else if let Ok(op) = ident.parse::<FToIntOp>() {
+            let rd = self.register()?;
+            let _ = self.comma()?;
+            let r1 = self.register()?;
+            let rm = if op == FToIntOp::MvXW {
+                RoundingMode::Dyn
+            } else {
+                self.rounding_mode()?
+            };
+            Instruction::FToInt { rd, r1, op, rm }
+        } else if let Ok(op) = ident.parse::<IntToFOp>() {
+            let rd = self.register()?;
+            let _ = self.comma()?;
+            let r1 = self.register()?;
+            let rm = if op == IntToFOp::MvWX {
+                RoundingMode::Dyn
+            } else {
+                self.rounding_mode()?
+            };
+            Instruction::IntToF { rd, r1, op, rm }
+        } else if let Some(instruction) = self.vector_instruction(&ident)? {
+            instruction
+        } else if let Ok(op) = ident.parse::<CompressedOp>() {
+            self.compressed_instruction(op, &span)?
+        } else if let Ok(op) = ident.parse::<CsrRegOp>() {
+            let rd = self.register()?;
+            let _ = self.comma()?;
+            let csr = self.csr()?;
+            let _ = self.comma()?;
+            let r1 = self.register()?;
+            Instruction::CsrReg { rd, csr, r1, op }
+        } else if let Ok(op) = ident.parse::<CsrImmOp>() {
+            let rd = self.register()?;
+            let _ = self.comma()?;
+            let csr = self.csr()?;
+            let _ = self.comma()?;
+            let imm = self.csr_immediate()?;
+            Instruction::CsrImm { rd, csr, imm, op }
+        } else {
+            // call        { label: String },
+            // jal         { rd: Register, label: String },
+            // j           { label: String },
+            // jalr        { rd: Register, offset: i32, r1: Register },
+            // jr          { rs: Register },
+            // ret         {},
+            // la          { rd: Register, label: String }
+            match ident.as_str() {
+                "call" => {
+                    let label = self.ident()?.unwrap_ident().0;
+                    Instruction::call { label }
+                }
+                // Note: if a register is not provided, assume rd
+                "jal" => {
+                    let ident = self.ident()?;
+                    if let Ok(rd) = Register::try_from(ident.clone()) {
+                        // Register was provided, continue
+                        let rd = self.check_available(rd, ident.span())?;
+                        let _ = self.comma()?;
+                        let label = self.ident()?.unwrap_ident().0;
+                        Instruction::jal { rd, label }
+                    } else {
+                        // Assume register is ra
+                        Instruction::jal {
+                            rd: "ra".parse().expect(""),
+                            label: ident.unwrap_ident().0,
+                        }
+                    }
+                }
+                "la" => {
+                    let rd = self.register()?;
+                    let _ = self.comma()?;
+                    let label = self.ident()?.unwrap_ident().0;
+                    Instruction::la { rd, label }
+                }
+                // Note: if a register is not provided, assume 0(rd)
+                "jalr" => {
+                    let reg = self.register()?;
+                    if let Ok(TokenInner::Comma) = self.comma().map(|token| token.inner()) {
+                        let neg = self.minus().is_ok();
+                        let mut offset = self.constant32()?;
+                        if neg {
+                            offset = offset.wrapping_neg()
+                        }
+                        let _ = self.left_paren()?;
+                        let r1 = self.register()?;
+                        let _ = self.right_paren()?;
+                        Instruction::jalr {
+                            rd: reg,
+                            offset,
+                            r1,
+                        }
+                    } else {
+                        Instruction::jalr {
+                            rd: "ra".parse().expect(""),
+                            offset: 0,
+                            r1: reg,
+                        }
+                    }
+                }
+                "j" => {
+                    let label = self.ident()?.unwrap_ident().0;
+                    Instruction::j { label }
+                }
+                "jr" => {
+                    let rs = self.register()?;
+                    Instruction::jr { rs }
+                }
+                "ret" => Instruction::ret {},
+                "mret" => Instruction::mret {},
+                "sret" => Instruction::sret {},
+                "ecall" => Instruction::ecall {},
+                // Note: both operands are optional, and default to zero
+                "sfence.vma" => {
+                    let mut vaddr = Register::x0;
+                    let mut asid = Register::x0;
+                    let has_operand = matches!(
+                        self.peek(),
+                        Some(Ok(Token { inner: TokenInner::Ident(ident), .. }))
+                            if ident.parse::<Register>().is_ok()
+                    );
+                    if has_operand {
+                        vaddr = self.register()?;
+                        if self.comma().is_ok() {
+                            asid = self.register()?;
+                        }
+                    }
+                    Instruction::sfence_vma { vaddr, asid }
+                }
+                "wfi" => Instruction::wfi {},
+                "lr.w" => {
+                    let rd = self.register()?;
+                    let _ = self.comma()?;
+                    let r1 = self.atomic_address()?;
+                    Instruction::LoadReserved { rd, r1 }
+                }
+                "sc.w" => {
+                    let rd = self.register()?;
+                    let _ = self.comma()?;
+                    let r2 = self.register()?;
+                    let _ = self.comma()?;
+                    let r1 = self.atomic_address()?;
+                    Instruction::StoreConditional { rd, r2, r1 }
+                }
+                "flw" => {
+                    let rd = self.register()?;
+                    let _ = self.comma()?;
+                    let neg = self.minus().is_ok();
+                    let mut offset = self.constant32()?;
+                    if neg {
+                        offset = offset.wrapping_neg()
+                    }
+                    let _ = self.left_paren()?;
+                    let r1 = self.register()?;
+                    let _ = self.right_paren()?;
+                    Instruction::FLoad { rd, offset, r1 }
+                }
+                "fsw" => {
+                    let r2 = self.register()?;
+                    let _ = self.comma()?;
+                    let neg = self.minus().is_ok();
+                    let mut offset = self.constant32()?;
+                    if neg {
+                        offset = offset.wrapping_neg()
+                    }
+                    let _ = self.left_paren()?;
+                    let r1 = self.register()?;
+                    let _ = self.right_paren()?;
+                    Instruction::FStore { r2, offset, r1 }
+                }
+                "fsqrt.s" => {
+                    let rd = self.register()?;
+                    let _ = self.comma()?;
+                    let r1 = self.register()?;
+                    let rm = self.rounding_mode()?;
+                    Instruction::FSqrt { rd, r1, rm }
+                }
+                "fclass.s" => {
+                    let rd = self.register()?;
+                    let _ = self.comma()?;
+                    let r1 = self.register()?;
+                    Instruction::FClass { rd, r1 }
+                }
+                // Sign injection pseudo-instructions: fmv.s, fneg.s, fabs.s
+                "fmv.s" | "fneg.s" | "fabs.s" => {
+                    let rd = self.register()?;
+                    let _ = self.comma()?;
+                    let r1 = self.register()?;
+                    let op = match ident.as_str() {
+                        "fmv.s" => FRegRegOp::Sgnj,
+                        "fneg.s" => FRegRegOp::Sgnjn,
+                        _ => FRegRegOp::Sgnjx,
+                    };
+                    Instruction::FRegReg {
+                        rd,
+                        r1,
+                        r2: r1,
+                        op,
+                        rm: RoundingMode::Dyn,
+                    }
+                }
+                // CSR pseudo-instructions
+                "csrr" => {
+                    let rd = self.register()?;
+                    let _ = self.comma()?;
+                    let csr = self.csr()?;
+                    Instruction::CsrReg {
+                        rd,
+                        csr,
+                        r1: Register::x0,
+                        op: CsrRegOp::Rs,
+                    }
+                }
+                "csrw" | "csrs" | "csrc" => {
+                    let csr = self.csr()?;
+                    let _ = self.comma()?;
+                    let r1 = self.register()?;
+                    let op = match ident.as_str() {
+                        "csrw" => CsrRegOp::Rw,
+                        "csrs" => CsrRegOp::Rs,
+                        _ => CsrRegOp::Rc,
+                    };
+                    Instruction::CsrReg {
+                        rd: Register::x0,
+                        csr,
+                        r1,
+                        op,
+                    }
+                }
+                "csrwi" | "csrsi" | "csrci" => {
+                    let csr = self.csr()?;
+                    let _ = self.comma()?;
+                    let imm = self.csr_immediate()?;
+                    let op = match ident.as_str() {
+                        "csrwi" => CsrImmOp::Rwi,
+                        "csrsi" => CsrImmOp::Rsi,
+                        _ => CsrImmOp::Rci,
+                    };
+                    Instruction::CsrImm {
+                        rd: Register::x0,
+                        csr,
+                        imm,
+                        op,
+                    }
+                }
+                // Floating point CSR pseudo-instructions
+                "frcsr" | "frrm" | "frflags" => {
+                    let rd = self.register()?;
+                    let csr = match ident.as_str() {
+                        "frcsr" => Csr::Fcsr,
+                        "frrm" => Csr::Frm,
+                        _ => Csr::Fflags,
+                    };
+                    Instruction::CsrReg {
+                        rd,
+                        csr,
+                        r1: Register::x0,
+                        op: CsrRegOp::Rs,
+                    }
+                }
+                // Note: if only one register is provided, it is the source
+                "fscsr" | "fsrm" | "fsflags" => {
+                    let first = self.register()?;
+                    let (rd, r1) = if self.comma().is_ok() {
+                        (first, self.register()?)
+                    } else {
+                        (Register::x0, first)
+                    };
+                    let csr = match ident.as_str() {
+                        "fscsr" => Csr::Fcsr,
+                        "fsrm" => Csr::Frm,
+                        _ => Csr::Fflags,
+                    };
+                    Instruction::CsrReg {
+                        rd,
+                        csr,
+                        r1,
+                        op: CsrRegOp::Rw,
+                    }
+                }
+                other => {
+                    let Some(custom) = self.custom().get(other) else {
+                        bail!("unknown instruction: {other}");
+                    };
+                    let (format, encoding) = (custom.format, custom.encoding);
+                    Instruction::Custom {
+                        mnemonic: other.to_string(),
+                        encoding,
+                        operands: self.custom_operands(format)?,
+                    }
+                }
+            }
+        };
+        Ok(Item::Instruction {
+            instr: instruction,
+            span,
+        })
+    }
+
+    /// Parse the operands of a vector instruction. Returns `None` if `mnemonic`
+    /// isn't a vector instruction.
+    fn vector_instruction(&mut self, mnemonic: &str) -> anyhow::Result<Option<Instruction>> {
+        if mnemonic == "vsetvli" {
+            let rd = self.register()?;
+            let _ = self.comma()?;
+            let r1 = self.register()?;
+            let _ = self.comma()?;
+            let (sew, span) = self.ident()?.unwrap_ident();
+            let mut vtype = VType {
+                sew: sew
+                    .parse()
+                    .with_context(|| format!("bad element width at {span}"))?,
+                ..Default::default()
+            };
+            // LMUL and the tail and mask policies are optional
+            while self.comma().is_ok() {
+                let (setting, span) = self.ident()?.unwrap_ident();
+                match setting.as_str() {
+                    "m1" => vtype.lmul = 1,
+                    "m2" => vtype.lmul = 2,
+                    "m4" => vtype.lmul = 4,
+                    "m8" => vtype.lmul = 8,
+                    "ta" => vtype.tail_agnostic = true,
+                    "tu" => vtype.tail_agnostic = false,
+                    "ma" => vtype.mask_agnostic = true,
+                    "mu" => vtype.mask_agnostic = false,
+                    other => bail!("unsupported vector setting {other} at {span}"),
+                }
+            }
+            return Ok(Some(Instruction::Vsetvli { rd, r1, vtype }));
+        }
+
+        let Some((name, suffix)) = mnemonic.split_once('.') else {
+            return Ok(None);
+        };
+        let instruction = if let Some((load, bits)) = ["vlse", "vle", "vsse", "vse"]
+            .into_iter()
+            .find_map(|prefix| Some((prefix, name.strip_prefix(prefix)?)))
+        {
+            let Some(width) = ElementWidth::from_bits(bits).filter(|_| suffix == "v") else {
+                return Ok(None);
+            };
+            let reg = self.register()?;
+            let _ = self.comma()?;
+            let r1 = self.atomic_address()?;
+            let stride = if matches!(load, "vlse" | "vsse") {
+                let _ = self.comma()?;
+                Some(self.register()?)
+            } else {
+                None
+            };
+            let masked = self.vector_mask()?;
+            if load.starts_with("vl") {
+                Instruction::VLoad {
+                    vd: reg,
+                    r1,
+                    stride,
+                    width,
+                    masked,
+                }
+            } else {
+                Instruction::VStore {
+                    vs3: reg,
+                    r1,
+                    stride,
+                    width,
+                    masked,
+                }
+            }
+        } else if let Ok(op) = name.parse::<VArithOp>() {
+            let vd = self.register()?;
             let _ = self.comma()?;
-            let neg = self.minus().is_ok();
-            let mut offset = self.constant()?.unwrap_constant().0;
-            if neg {
-                offset = -offset
+            let vs2 = self.register()?;
+            let _ = self.comma()?;
+            let operand = self.vector_operand(suffix)?;
+            let masked = self.vector_mask()?;
+            Instruction::VArith {
+                vd,
+                vs2,
+                operand,
+                op,
+                masked,
             }
-            let _ = self.left_paren()?;
-            let r1 = self.ident()?.try_into()?;
-            let _ = self.right_paren()?;
-            Instruction::Load { rd, offset, r1, op }
-        } else if let Ok(op) = ident.parse::<LoadImmOp>() {
-            let rd = self.ident()?.try_into()?;
+        } else if let Ok(op) = name.parse::<VCompareOp>() {
+            let vd = self.register()?;
             let _ = self.comma()?;
-            let neg = self.minus().is_ok();
-            let mut imm = self.constant()?.unwrap_constant().0;
-            if neg {
-                imm = -imm
+            let vs2 = self.register()?;
+            let _ = self.comma()?;
+            let operand = self.vector_operand(suffix)?;
+            let masked = self.vector_mask()?;
+            Instruction::VCompare {
+                vd,
+                vs2,
+                operand,
+                op,
+                masked,
             }
-            Instruction::LoadImm { rd, imm, op }
+        } else if let Ok(op) = name.parse::<VReduceOp>() {
+            if suffix != "vs" {
+                bail!("unknown instruction: {mnemonic}");
+            }
+            let vd = self.register()?;
+            let _ = self.comma()?;
+            let vs2 = self.register()?;
+            let _ = self.comma()?;
+            let vs1 = self.register()?;
+            let masked = self.vector_mask()?;
+            Instruction::VReduce {
+                vd,
+                vs2,
+                vs1,
+                op,
+                masked,
+            }
+        } else if let Ok(op) = name.parse::<VMaskOp>() {
+            if suffix != "mm" {
+                bail!("unknown instruction: {mnemonic}");
+            }
+            let vd = self.register()?;
+            let _ = self.comma()?;
+            let vs2 = self.register()?;
+            let _ = self.comma()?;
+            let vs1 = self.register()?;
+            Instruction::VMask { vd, vs2, vs1, op }
         } else {
-            // call        { label: String },
-            // jal         { rd: Register, label: String },
-            // j           { label: String },
-            // jalr        { rd: Register, offset: i32, r1: Register },
-            // jr          { rs: Register },
-            // ret         {},
-            // la          { rd: Register, label: String }
-            match ident.as_str() {
-                "call" => {
-                    let label = self.ident()?.unwrap_ident().0;
-                    Instruction::call { label }
+            match mnemonic {
+                "vmv.v.v" | "vmv.v.x" | "vmv.v.i" => {
+                    let vd = self.register()?;
+                    let _ = self.comma()?;
+                    let operand = self.vector_operand(&suffix.replace('.', ""))?;
+                    Instruction::VMove { vd, operand }
                 }
-                // Note: if a register is not provided, assume rd
-                "jal" => {
-                    let ident = self.ident()?;
-                    if let Ok(rd) = ident.clone().try_into() {
-                        // Register was provided, continue
-                        let _ = self.comma()?;
-                        let label = self.ident()?.unwrap_ident().0;
-                        Instruction::jal { rd, label }
-                    } else {
-                        // Assume register is ra
-                        Instruction::jal {
-                            rd: "ra".parse().expect(""),
-                            label: ident.unwrap_ident().0,
-                        }
-                    }
+                "vmv.x.s" => {
+                    let rd = self.register()?;
+                    let _ = self.comma()?;
+                    let vs2 = self.register()?;
+                    Instruction::VMoveToScalar { rd, vs2 }
                 }
-                "la" => {
-                    let rd = self.ident()?.try_into()?;
+                "vmv.s.x" => {
+                    let vd = self.register()?;
                     let _ = self.comma()?;
-                    let label = self.ident()?.unwrap_ident().0;
-                    Instruction::la { rd, label }
+                    let r1 = self.register()?;
+                    Instruction::VMoveFromScalar { vd, r1 }
                 }
-                // Note: if a register is not provided, assume 0(rd)
-                "jalr" => {
-                    let reg = self.ident()?.try_into()?;
-                    if let Ok(TokenInner::Comma) = self.comma().map(|token| token.inner()) {
-                        let neg = self.minus().is_ok();
-                        let mut offset = self.constant()?.unwrap_constant().0;
-                        if neg {
-                            offset = -offset
-                        }
-                        let _ = self.left_paren()?;
-                        let r1 = self.ident()?.try_into()?;
-                        let _ = self.right_paren()?;
-                        Instruction::jalr {
-                            rd: reg,
-                            offset,
-                            r1,
-                        }
-                    } else {
-                        Instruction::jalr {
-                            rd: "ra".parse().expect(""),
-                            offset: 0,
-                            r1: reg,
-                        }
-                    }
+                "vcpop.m" => {
+                    let rd = self.register()?;
+                    let _ = self.comma()?;
+                    let vs2 = self.register()?;
+                    let masked = self.vector_mask()?;
+                    Instruction::VCpop { rd, vs2, masked }
                 }
-                "j" => {
-                    let label = self.ident()?.unwrap_ident().0;
-                    Instruction::j { label }
+                "vid.v" => {
+                    let vd = self.register()?;
+                    let masked = self.vector_mask()?;
+                    Instruction::Vid { vd, masked }
                 }
-                "jr" => {
-                    let rs = self.ident()?.try_into()?;
-                    Instruction::jr { rs }
+                _ => return Ok(None),
+            }
+        };
+        Ok(Some(instruction))
+    }
+
+    /// Parse the second source operand of a vector instruction with the given
+    /// suffix: `vv`, `vx` or `vi`.
+    fn vector_operand(&mut self, suffix: &str) -> anyhow::Result<VOperand> {
+        Ok(match suffix {
+            "vv" => VOperand::Vector(self.register()?),
+            "vx" => VOperand::Scalar(self.register()?),
+            "vi" => {
+                let neg = self.minus().is_ok();
+                let (mut imm, span) = self.constant()?.unwrap_constant();
+                if neg {
+                    imm = imm.wrapping_neg()
                 }
-                "ret" => Instruction::ret {},
-                other => bail!("unknown instruction: {other}"),
+                if !(-16..16).contains(&imm) {
+                    bail!("vector immediate must be between -16 and 15, but got {imm} at {span}");
+                }
+                VOperand::Immediate(imm as i32)
             }
+            other => bail!("unknown vector operand form .{other}"),
+        })
+    }
+
+    /// Parse the operands of a custom instruction in the given format.
+    fn custom_operands(&mut self, format: CustomFormat) -> anyhow::Result<CustomOperands> {
+        let immediate = |lexer: &mut Self| -> anyhow::Result<i32> {
+            let neg = lexer.minus().is_ok();
+            let (mut imm, span) = lexer.constant()?.unwrap_constant();
+            if neg {
+                imm = imm.wrapping_neg()
+            }
+            if !(-2048..2048).contains(&imm) {
+                bail!("immediate must be between -2048 and 2047, but got {imm} at {span}");
+            }
+            Ok(imm as i32)
         };
-        Ok(Item::Instruction {
-            instr: instruction,
-            span,
+        Ok(match format {
+            CustomFormat::R => {
+                let rd = self.register()?;
+                let _ = self.comma()?;
+                let r1 = self.register()?;
+                let _ = self.comma()?;
+                let r2 = self.register()?;
+                CustomOperands::R { rd, r1, r2 }
+            }
+            CustomFormat::I => {
+                let rd = self.register()?;
+                let _ = self.comma()?;
+                let r1 = self.register()?;
+                let _ = self.comma()?;
+                let imm = immediate(self)?;
+                CustomOperands::I { rd, r1, imm }
+            }
+            CustomFormat::S => {
+                let r2 = self.register()?;
+                let _ = self.comma()?;
+                let offset = immediate(self)?;
+                let _ = self.left_paren()?;
+                let r1 = self.register()?;
+                let _ = self.right_paren()?;
+                CustomOperands::S { r2, offset, r1 }
+            }
+        })
+    }
+
+    /// Parse the operands of a compressed instruction, checking they can be
+    /// encoded in 2 bytes.
+    fn compressed_instruction(
+        &mut self,
+        op: CompressedOp,
+        span: &Span,
+    ) -> anyhow::Result<Instruction> {
+        use {CompressedOp::*, Register::*};
+        let immediate = |lexer: &mut Self| -> anyhow::Result<i32> {
+            let neg = lexer.minus().is_ok();
+            let mut imm = lexer.constant32()?;
+            if neg {
+                imm = imm.wrapping_neg()
+            }
+            Ok(imm)
+        };
+        let address = |lexer: &mut Self| -> anyhow::Result<(i32, Register)> {
+            let offset = immediate(lexer)?;
+            let _ = lexer.left_paren()?;
+            let r1 = lexer.register()?;
+            let _ = lexer.right_paren()?;
+            Ok((offset, r1))
+        };
+        let instr = match op {
+            Nop => Instruction::RegImm {
+                rd: x0,
+                r1: x0,
+                imm: 0,
+                op: RegImmOp::Addi,
+            },
+            Addi4spn => {
+                let rd = self.register()?;
+                let _ = self.comma()?;
+                let r1 = self.register()?;
+                let _ = self.comma()?;
+                let imm = immediate(self)?;
+                let op = RegImmOp::Addi;
+                Instruction::RegImm { rd, r1, imm, op }
+            }
+            Addi16sp => {
+                let rd = self.register()?;
+                let _ = self.comma()?;
+                let imm = immediate(self)?;
+                let op = RegImmOp::Addi;
+                Instruction::RegImm {
+                    rd,
+                    r1: rd,
+                    imm,
+                    op,
+                }
+            }
+            Addi | Li | Slli | Srli | Srai | Andi => {
+                let rd = self.register()?;
+                let _ = self.comma()?;
+                let imm = immediate(self)?;
+                let (r1, op) = match op {
+                    Addi => (rd, RegImmOp::Addi),
+                    Li => (x0, RegImmOp::Addi),
+                    Slli => (rd, RegImmOp::Slli),
+                    Srli => (rd, RegImmOp::Srli),
+                    Srai => (rd, RegImmOp::Srai),
+                    _ => (rd, RegImmOp::Andi),
+                };
+                Instruction::RegImm { rd, r1, imm, op }
+            }
+            Lui => {
+                let rd = self.register()?;
+                let _ = self.comma()?;
+                // Negative immediates are the top of the 20-bit range
+                let imm = immediate(self)? & 0xfffff;
+                let op = LoadImmOp::Lui;
+                Instruction::LoadImm {
+                    rd,
+                    imm: imm.into(),
+                    op,
+                }
+            }
+            Sub | Xor | Or | And | Mv | Add => {
+                let rd = self.register()?;
+                let _ = self.comma()?;
+                let r2 = self.register()?;
+                let (r1, op) = match op {
+                    Sub => (rd, RegRegOp::Sub),
+                    Xor => (rd, RegRegOp::Xor),
+                    Or => (rd, RegRegOp::Or),
+                    And => (rd, RegRegOp::And),
+                    Mv => (x0, RegRegOp::Add),
+                    _ => (rd, RegRegOp::Add),
+                };
+                Instruction::RegReg { rd, r1, r2, op }
+            }
+            Lw | Lwsp => {
+                let rd = self.register()?;
+                let _ = self.comma()?;
+                let (offset, r1) = address(self)?;
+                let op = LoadOp::Lw;
+                Instruction::Load { rd, offset, r1, op }
+            }
+            Sw | Swsp => {
+                let r2 = self.register()?;
+                let _ = self.comma()?;
+                let (offset, r1) = address(self)?;
+                let op = StoreOp::Sw;
+                Instruction::Store { r2, offset, r1, op }
+            }
+            J => Instruction::j {
+                label: self.ident()?.unwrap_ident().0,
+            },
+            Jal => Instruction::jal {
+                rd: ra,
+                label: self.ident()?.unwrap_ident().0,
+            },
+            Beqz | Bnez => {
+                let r1 = self.register()?;
+                let _ = self.comma()?;
+                let label = self.ident()?.unwrap_ident().0;
+                let op = if op == Beqz {
+                    BranchOp::Beq
+                } else {
+                    BranchOp::Bne
+                };
+                Instruction::Branch {
+                    r1,
+                    r2: x0,
+                    label,
+                    op,
+                }
+            }
+            Jr => match self.register()? {
+                ra => Instruction::ret {},
+                rs => Instruction::jr { rs },
+            },
+            Jalr => Instruction::jalr {
+                rd: ra,
+                offset: 0,
+                r1: self.register()?,
+            },
+        };
+        if !op.fits(&instr) {
+            bail!("invalid operands for {op} at {span}: it can't encode {instr}");
+        }
+        Ok(Instruction::Compressed {
+            op,
+            instr: Box::new(instr),
         })
     }
+
+    /// Parse the optional trailing `v0.t` operand of a masked vector instruction.
+    fn vector_mask(&mut self) -> anyhow::Result<bool> {
+        if self.comma().is_err() {
+            return Ok(false);
+        }
+        let (mut mask, span) = self.ident()?.unwrap_ident();
+        // `v0.t` can also be lexed as `v0` followed by `.t`
+        let split = matches!(
+            self.peek(),
+            Some(Ok(Token { inner: TokenInner::Ident(suffix), .. })) if suffix.starts_with('.')
+        );
+        if split {
+            mask += &self.ident()?.unwrap_ident().0;
+        }
+        if mask != "v0.t" {
+            bail!("expected v0.t, found {mask} at {span}");
+        }
+        Ok(true)
+    }
+
+    /// Parse an optional trailing rounding mode operand, as in `fadd.s fa0, fa1,
+    /// fa2, rtz`. If there is none, the dynamic rounding mode from `frm` is used.
+    fn rounding_mode(&mut self) -> anyhow::Result<RoundingMode> {
+        if self.comma().is_err() {
+            return Ok(RoundingMode::Dyn);
+        }
+        let (rm, span) = self.ident()?.unwrap_ident();
+        rm.parse()
+            .with_context(|| format!("bad rounding mode at {span}"))
+    }
+
+    /// Parse a CSR operand, which can either be a name or a CSR number.
+    fn csr(&mut self) -> anyhow::Result<Csr> {
+        if let Ok(constant) = self.constant() {
+            let (number, span) = constant.unwrap_constant();
+            return u16::try_from(number)
+                .ok()
+                .and_then(Csr::from_number)
+                .ok_or_else(|| anyhow!("unknown csr number {number:#x} at {span}"));
+        }
+        let (name, span) = self.ident()?.unwrap_ident();
+        name.parse().with_context(|| format!("bad csr at {span}"))
+    }
+
+    /// Parse a constant for a 32-bit immediate or offset, see [`wrap_i32`].
+    fn constant32(&mut self) -> anyhow::Result<i32> {
+        let (val, span) = self.constant()?.unwrap_constant();
+        wrap_i32(val, &span)
+    }
+
+    /// Parse the 5-bit unsigned immediate of a CSR instruction.
+    fn csr_immediate(&mut self) -> anyhow::Result<i32> {
+        let (imm, span) = self.constant()?.unwrap_constant();
+        if !(0..32).contains(&imm) {
+            bail!("csr immediate must be between 0 and 31, but got {imm} at {span}");
+        }
+        Ok(imm as i32)
+    }
+
+    /// Parse a register, making sure it exists in the base ISA.
+    fn register<R: ParseRegister>(&mut self) -> anyhow::Result<R> {
+        let token = self.ident()?;
+        let span = token.span();
+        let reg = token.try_into()?;
+        self.check_available(reg, span)
+    }
+
+    /// Make sure a register parsed at `span` exists in the base ISA.
+    fn check_available<R: ParseRegister>(&self, reg: R, span: Span) -> anyhow::Result<R> {
+        if !reg.available(self.base()) {
+            let (bits, base) = (self.xlen().bits(), self.base());
+            bail!("register {reg} does not exist in RV{bits}{base} at {span}");
+        }
+        Ok(reg)
+    }
+
+    /// Parse the address operand of an atomic instruction. Atomics don't take an
+    /// offset, but like other assemblers we accept an explicit `0`, so both
+    /// `(a0)` and `0(a0)` are fine.
+    fn atomic_address(&mut self) -> anyhow::Result<Register> {
+        if let Ok(constant) = self.constant() {
+            let (offset, span) = constant.unwrap_constant();
+            if offset != 0 {
+                bail!("atomic instructions can't have an offset, but got {offset} at {span}");
+            }
+        }
+        let _ = self.left_paren()?;
+        let r1 = self.register()?;
+        let _ = self.right_paren()?;
+        Ok(r1)
+    }
+}
+
+/// Convert a constant for a 32-bit field. Constants up to `u32::MAX` wrap
+/// around, so `0xffffffff` is -1.
+fn wrap_i32(val: i64, span: &Span) -> anyhow::Result<i32> {
+    if !(i32::MIN as i64..=u32::MAX as i64).contains(&val) {
+        bail!("{val} doesn't fit in 32 bits at {span}");
+    }
+    Ok(val as i32)
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Program {
-    // The values of this map are program counters the labels point to. The value
-    // of a label divided by 4 points to the instruction in `asm` corresponding
-    // to the label with the keyed name.
-    labels: HashMap<String, usize>,
+    // The values of this map are program counters the labels point to.
+    // Instructions take up 2, 4 or 8 bytes (see `Instruction::size`), so see
+    // `addresses` for which instruction in `asm` a label points to.
+    pub labels: HashMap<String, usize>,
     pub asm: Vec<Instruction>,
 }
 
+/// Whether `mnemonic` is a built-in instruction. The parser only rejects
+/// mnemonics it doesn't know as unknown, before looking at any operands.
+pub fn is_builtin(mnemonic: &str) -> bool {
+    match Lexer::new(mnemonic).parse_item() {
+        Some(Err(error)) => error.to_string() != format!("unknown instruction: {mnemonic}"),
+        _ => true,
+    }
+}
+
 impl FromStr for Program {
     type Err = anyhow::Error;
 
@@ -534,7 +2361,7 @@ impl Program {
         let mut pc = 0;
         for item in items.iter() {
             let Item::Label { name, span } = &item else {
-                pc += 4;
+                pc += item.get_instruction().size() as usize;
                 continue;
             };
 
@@ -570,25 +2397,18 @@ impl Program {
         let mut pc = 0;
         for instr in items.iter() {
             let Item::Instruction { instr, .. } = instr else {
-                pc += 4;
                 continue;
             };
-            let label = match instr {
-                Instruction::Branch { label, .. } => label,
-                Instruction::BranchZero { label, .. } => label,
-                Instruction::call { label } => label,
-                Instruction::jal { label, .. } => label,
-                Instruction::j { label } => label,
-                Instruction::la { rd, label } => label,
-                _ => continue,
-            };
-            if !labels2spans.contains_key(label) {
-                errors.push(format!(
-                    // pad with 10 zeroes because the 0x prefix takes up 2 chars
-                    "undefined label <{label}> at pc {:#010x}: {}",
-                    pc, instr
-                ))
+            if let Some(label) = instr.label() {
+                if !labels2spans.contains_key(label) {
+                    errors.push(format!(
+                        // pad with 10 zeroes because the 0x prefix takes up 2 chars
+                        "undefined label <{label}> at pc {:#010x}: {}",
+                        pc, instr
+                    ))
+                }
             }
+            pc += instr.size();
         }
 
         if !errors.is_empty() {
@@ -610,13 +2430,33 @@ impl Program {
         })
     }
 
-    pub fn at(&self, pc: i32) -> Option<&Instruction> {
-        assert!(pc % 4 == 0, "pc was {pc}");
-        self.asm.get((pc / 4) as usize)
+    /// The instruction at `pc`, if there is one. Pcs in the middle of an
+    /// instruction never point to one.
+    pub fn at(&self, pc: i64) -> Option<&Instruction> {
+        self.addresses()
+            .zip(&self.asm)
+            .take_while(|(addr, _)| *addr <= pc)
+            .find_map(|(addr, instr)| (addr == pc).then_some(instr))
+    }
+
+    /// The pc of each instruction, starting from 0.
+    pub fn addresses(&self) -> impl Iterator<Item = i64> + '_ {
+        self.asm.iter().scan(0, |pc, instr| {
+            let addr = *pc;
+            *pc += instr.size();
+            Some(addr)
+        })
+    }
+
+    pub fn label(&self, label: &str) -> Option<i64> {
+        self.labels.get(label).map(|pc| *pc as i64)
     }
 
-    pub fn label(&self, label: &str) -> Option<i32> {
-        self.labels.get(label).map(|pc| *pc as i32)
+    /// Move the labels so they're addresses from `base` instead of pc 0.
+    pub fn rebase(&mut self, base: i64) {
+        for pc in self.labels.values_mut() {
+            *pc = (*pc as i64 + base) as usize;
+        }
     }
 }
 
@@ -699,6 +2539,22 @@ mod tests {
         .is_err());
     }
 
+    #[test]
+    fn dotted_label() {
+        let program = Program::try_from(indoc! {"
+            .L1:
+            j .L1
+        "})
+        .unwrap();
+        assert_eq!(program.labels, map![".L1".to_string() => 0]);
+
+        let error = Program::try_from("loop.a:").unwrap_err();
+        assert_eq!(
+            error.root_cause().to_string(),
+            "label loop.a can't contain '.' at [line 1, columns 1..7]"
+        );
+    }
+
     #[test]
     fn missing_label() {
         assert!(Program::try_from(indoc! {"
@@ -1169,4 +3025,303 @@ mod tests {
         ];
         assert_eq!(Program::try_from(source).unwrap().asm, instructions)
     }
+
+    #[test]
+    fn atomics() {
+        use Instruction::*;
+        use Register::*;
+        let source = indoc! {"
+            lr.w t0, (a0)
+            sc.w t1, t2, 0(a0)
+            amoadd.w a0, a1, (sp)
+            amomaxu.w zero, s1, (t6)
+        "};
+        let instructions = vec![
+            LoadReserved { rd: t0, r1: a0 },
+            StoreConditional {
+                rd: t1,
+                r2: t2,
+                r1: a0,
+            },
+            Amo {
+                rd: a0,
+                r2: a1,
+                r1: sp,
+                op: AmoOp::Add,
+            },
+            Amo {
+                rd: x0,
+                r2: s1,
+                r1: t6,
+                op: AmoOp::Maxu,
+            },
+        ];
+        assert_eq!(Program::try_from(source).unwrap().asm, instructions);
+
+        // Atomics don't take offsets
+        assert!(Program::try_from("lr.w t0, 4(a0)").is_err());
+        assert!(Program::try_from("amoswap.w t0, a0").is_err());
+    }
+
+    #[test]
+    fn floats() {
+        use FRegister::*;
+        use Instruction::*;
+        use Register::*;
+        let source = indoc! {"
+            flw ft0, -4(sp)
+            fsw f31, 8(a0)
+            fadd.s fa0, fa1, fs11, rtz
+            fmin.s fa0, fa1, fa2
+            fneg.s ft1, ft2
+            fnmadd.s f1, f2, f3, f4
+            fsqrt.s fs0, fs1, rmm
+            fle.s a0, fa0, fa1
+            fclass.s t0, ft11
+            fcvt.wu.s a0, fa0, rdn
+            fmv.w.x fa0, zero
+            csrrw t0, fcsr, t1
+            csrsi 0x001, 0x10
+            fsrm a0
+            frflags a1
+        "};
+        let instructions = vec![
+            FLoad {
+                rd: ft0,
+                offset: -4,
+                r1: sp,
+            },
+            FStore {
+                r2: ft11,
+                offset: 8,
+                r1: a0,
+            },
+            FRegReg {
+                rd: fa0,
+                r1: fa1,
+                r2: fs11,
+                op: FRegRegOp::Add,
+                rm: RoundingMode::Rtz,
+            },
+            FRegReg {
+                rd: fa0,
+                r1: fa1,
+                r2: fa2,
+                op: FRegRegOp::Min,
+                rm: RoundingMode::Dyn,
+            },
+            FRegReg {
+                rd: ft1,
+                r1: ft2,
+                r2: ft2,
+                op: FRegRegOp::Sgnjn,
+                rm: RoundingMode::Dyn,
+            },
+            FFused {
+                rd: ft1,
+                r1: ft2,
+                r2: ft3,
+                r3: ft4,
+                op: FFusedOp::Nmadd,
+                rm: RoundingMode::Dyn,
+            },
+            FSqrt {
+                rd: fs0,
+                r1: fs1,
+                rm: RoundingMode::Rmm,
+            },
+            FCompare {
+                rd: a0,
+                r1: fa0,
+                r2: fa1,
+                op: FCompareOp::Le,
+            },
+            FClass { rd: t0, r1: ft11 },
+            FToInt {
+                rd: a0,
+                r1: fa0,
+                op: FToIntOp::CvtWu,
+                rm: RoundingMode::Rdn,
+            },
+            IntToF {
+                rd: fa0,
+                r1: x0,
+                op: IntToFOp::MvWX,
+                rm: RoundingMode::Dyn,
+            },
+            CsrReg {
+                rd: t0,
+                csr: Csr::Fcsr,
+                r1: t1,
+                op: CsrRegOp::Rw,
+            },
+            CsrImm {
+                rd: x0,
+                csr: Csr::Fflags,
+                imm: 0x10,
+                op: CsrImmOp::Rsi,
+            },
+            CsrReg {
+                rd: x0,
+                csr: Csr::Frm,
+                r1: a0,
+                op: CsrRegOp::Rw,
+            },
+            CsrReg {
+                rd: a1,
+                csr: Csr::Fflags,
+                r1: x0,
+                op: CsrRegOp::Rs,
+            },
+        ];
+        let program = Program::try_from(source).unwrap();
+        assert_eq!(program.asm, instructions);
+
+        // Displaying round trips
+        let displayed = program
+            .asm
+            .iter()
+            .map(|instr| instr.to_string())
+            .collect::<Vec<_>>()
+            .join("\n");
+        assert_eq!(
+            Program::try_from(displayed.as_str()).unwrap().asm,
+            instructions
+        );
+
+        // Sign injection doesn't round
+        assert!(Program::try_from("fmax.s fa0, fa1, fa2, rne").is_err());
+        assert!(Program::try_from("fmv.x.w a0, fa0, rne").is_err());
+        assert!(Program::try_from("fadd.s fa0, fa1, fa2, bad").is_err());
+        assert!(Program::try_from("csrrwi a0, fcsr, 32").is_err());
+        assert!(Program::try_from("csrr a0, 0x7ff").is_err());
+    }
+
+    #[test]
+    fn embedded() {
+        let rv32e = |source| Program::parse(&mut Lexer::with_base(source, Base::E));
+        rv32e(indoc! {"
+            add a5, a0, x15
+            mv s1, s0
+            jal t2, end
+            end:
+        "})
+        .unwrap();
+
+        let error = rv32e(indoc! {"
+            add a5, a0, x15
+            add t0, a6, a1
+        "})
+        .unwrap_err();
+        assert_eq!(
+            error.root_cause().to_string(),
+            "register a6 does not exist in RV32E at [line 2, columns 9..11]"
+        );
+        // Registers are named by their ABI names
+        for (source, expected) in [
+            (
+                "mv x16, zero",
+                "register a6 does not exist in RV32E at [line 1, columns 4..7]",
+            ),
+            (
+                "lw s11, 0(sp)",
+                "register s11 does not exist in RV32E at [line 1, columns 4..7]",
+            ),
+            (
+                "sw a0, 0(t6)",
+                "register t6 does not exist in RV32E at [line 1, columns 10..12]",
+            ),
+            (
+                "jal a7, end\nend:",
+                "register a7 does not exist in RV32E at [line 1, columns 5..7]",
+            ),
+        ] {
+            let error = rv32e(source).unwrap_err();
+            assert_eq!(error.root_cause().to_string(), expected, "{source}");
+        }
+        let mut lexer = Lexer::with_base("mv x16, zero", Base::E).with_xlen(Xlen::Rv64);
+        assert_eq!(
+            Program::parse(&mut lexer)
+                .unwrap_err()
+                .root_cause()
+                .to_string(),
+            "register a6 does not exist in RV64E at [line 1, columns 4..7]"
+        );
+
+        // The full base ISA still has all of them
+        assert!(Program::try_from("add t0, a6, a1").is_ok());
+    }
+    #[test]
+    fn vectors() {
+        use Instruction::*;
+        use Register::*;
+        use VRegister::*;
+        let source = indoc! {"
+            vsetvli t0, a0, e16, m4, tu, ma
+            vlse32.v v8, (a1), t1, v0.t
+            vse8.v v4, (sp)
+            vadd.vi v1, v2, -16
+            vmsltu.vx v0, v8, a2
+            vredmax.vs v1, v8, v1
+            vmv.x.s a0, v1
+        "};
+        let instructions = vec![
+            Vsetvli {
+                rd: t0,
+                r1: a0,
+                vtype: VType {
+                    sew: ElementWidth::E16,
+                    lmul: 4,
+                    tail_agnostic: false,
+                    mask_agnostic: true,
+                },
+            },
+            VLoad {
+                vd: v8,
+                r1: a1,
+                stride: Some(t1),
+                width: ElementWidth::E32,
+                masked: true,
+            },
+            VStore {
+                vs3: v4,
+                r1: sp,
+                stride: None,
+                width: ElementWidth::E8,
+                masked: false,
+            },
+            VArith {
+                vd: v1,
+                vs2: v2,
+                operand: VOperand::Immediate(-16),
+                op: VArithOp::Add,
+                masked: false,
+            },
+            VCompare {
+                vd: v0,
+                vs2: v8,
+                operand: VOperand::Scalar(a2),
+                op: VCompareOp::Ltu,
+                masked: false,
+            },
+            VReduce {
+                vd: v1,
+                vs2: v8,
+                vs1: v1,
+                op: VReduceOp::Max,
+                masked: false,
+            },
+            VMoveToScalar { rd: a0, vs2: v1 },
+        ];
+        let program = Program::try_from(source).unwrap();
+        assert_eq!(program.asm, instructions);
+        // Displaying gives back the source
+        for (asm, line) in program.asm.iter().zip(source.lines()) {
+            assert_eq!(asm.to_string(), line);
+        }
+
+        assert!(Program::try_from("vadd.vi v1, v2, 16").is_err());
+        assert!(Program::try_from("vsetvli t0, a0, e128").is_err());
+        assert!(Program::try_from("vle32.v v1, 4(a0)").is_err());
+    }
 }